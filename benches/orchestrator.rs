@@ -0,0 +1,37 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Overhead of scheduling deployments and streaming their events, for growing numbers of nodes
+//! (see `deploy::simulate`). Run with `cargo bench`.
+
+use std::time::Duration;
+
+use deploy::simulate::{simulate, MockRunner, NullRenderer};
+
+/// Runs of each configuration, the fastest one is reported
+const RUNS: usize = 5;
+
+#[tokio::main]
+async fn main() {
+    let runner = MockRunner {
+        time: Duration::from_millis(1),
+        ..MockRunner::default()
+    };
+
+    for &(nodes, max_connections) in &[(10, 10), (100, 10), (500, 10), (500, 100)] {
+        let mut best = None;
+        for _ in 0..RUNS {
+            let report = simulate(nodes, max_connections, &runner, Box::new(NullRenderer)).await;
+            if best
+                .as_ref()
+                .is_none_or(|b: &deploy::simulate::SimulationReport| report.elapsed < b.elapsed)
+            {
+                best = Some(report);
+            }
+        }
+        if let Some(report) = best {
+            println!("{}", report);
+        }
+    }
+}
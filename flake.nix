@@ -95,17 +95,30 @@
                   };
               };
 
-            nixos = base:
+            # With `specialisation` set for the profile, activate-rs passes it as SPECIALISATION
+            nixos = base: let
+              toplevel = ''$PROFILE''${SPECIALISATION:+/specialisation/$SPECIALISATION}'';
+            in
               (custom // {
-                dryActivate = "$PROFILE/bin/switch-to-configuration dry-activate";
-                boot = "$PROFILE/bin/switch-to-configuration boot";
+                dryActivate = "${toplevel}/bin/switch-to-configuration dry-activate";
+                # WSL instances have no boot loader, they boot the system profile set before this
+                boot = ''
+                  if [[ "''${TARGET_PLATFORM:-}" != "wsl" ]]; then
+                    ${toplevel}/bin/switch-to-configuration boot
+                  fi
+                '';
               })
               base.config.system.build.toplevel
               ''
                 # work around https://github.com/NixOS/nixpkgs/issues/73404
                 cd /tmp
 
-                $PROFILE/bin/switch-to-configuration switch
+                if [[ "''${TARGET_PLATFORM:-}" == "wsl" && ! -d /run/systemd/system ]]; then
+                  # WSL without systemd, switch-to-configuration would fail to talk to it
+                  ${toplevel}/activate
+                else
+                  ${toplevel}/bin/switch-to-configuration switch
+                fi
 
                 # https://github.com/serokell/deploy-rs/issues/31
                 ${with base.config.boot.loader;
@@ -125,7 +138,19 @@
             noop = base: custom base ":";
           };
 
-          deployChecks = deploy: builtins.mapAttrs (_: check: check deploy) {
+          # The nodes generated by `nodesExpr` (see the README) are checked as they are generated without `--env` or tags
+          expandNodesExpr = deploy:
+            if deploy ? nodesExpr then
+              builtins.removeAttrs deploy [ "nodesExpr" ] // {
+                nodes = (deploy.nodes or { }) // (
+                  if builtins.isFunction deploy.nodesExpr
+                  then deploy.nodesExpr { environment = null; tags = [ ]; }
+                  else builtins.fromJSON (builtins.readFile deploy.nodesExpr)
+                );
+              }
+            else deploy;
+
+          deployChecks = deploy: builtins.mapAttrs (_: check: check (expandNodesExpr deploy)) {
             deploy-schema = deploy: final.runCommand "jsonschema-deploy-system" { } ''
               ${final.check-jsonschema}/bin/check-jsonschema --schemafile ${./interface.json} ${final.writeText "deploy.json" (builtins.toJSON deploy)} && touch $out
             '';
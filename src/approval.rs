@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Gating deployments on the `approvalCommand` setting.
+//!
+//! The command is run locally through `sh -c` with the deployment plan as JSON on stdin, and the
+//! deployment only goes ahead if it exits successfully. Its output is shown to the user, so it can
+//! e.g. say which ticket or chat message it is waiting for. With `--dry-activate`, the command and
+//! the plan it would get are only shown.
+
+use std::process::Stdio;
+
+use log::{debug, info};
+use serde::Serialize;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::trace;
+
+/// A profile as it is going to be deployed
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedProfile<'a> {
+    pub node: &'a str,
+    pub profile: &'a str,
+    pub hostname: &'a str,
+    pub ssh_user: &'a str,
+    pub user: &'a str,
+    pub path: &'a str,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan<'a> {
+    pub dry_activate: bool,
+    pub boot: bool,
+    pub profiles: Vec<PlannedProfile<'a>>,
+}
+
+#[derive(Error, Debug)]
+pub enum ApprovalError {
+    #[error("Failed to serialize the deployment plan: {0}")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to run approval command `{0}`: {1}")]
+    Spawn(String, std::io::Error),
+    #[error("Failed to pass the deployment plan to approval command `{0}`: {1}")]
+    Write(String, std::io::Error),
+    #[error("Failed to wait for approval command `{0}`: {1}")]
+    Wait(String, std::io::Error),
+    #[error("Deployment was not approved, `{0}` exited with code {1:?}")]
+    Denied(String, Option<i32>),
+}
+
+/// Runs `command` with `plan` on stdin, succeeding only if the command approves the deployment
+///
+/// For dry runs (`plan.dry_activate`), the command isn't run, and what it would get is logged
+/// instead.
+pub async fn request_approval(command: &str, plan: &Plan<'_>) -> Result<(), ApprovalError> {
+    let plan_json = serde_json::to_string_pretty(plan)?;
+
+    if plan.dry_activate {
+        info!(
+            "[dry run] Not requesting approval from `{}` (`sh -c`), it would get the deployment plan on stdin: {}",
+            command, plan_json
+        );
+        return Ok(());
+    }
+
+    info!("Requesting approval for the deployment from `{}`", command);
+    debug!("Deployment plan passed for approval: {}", plan_json);
+
+    let mut child = trace::spawn(
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped()),
+    )
+    .map_err(|e| ApprovalError::Spawn(command.to_string(), e))?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(plan_json.as_bytes()).await {
+        // The command doesn't have to read the plan
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => (),
+        Err(e) => return Err(ApprovalError::Write(command.to_string(), e)),
+        Ok(()) => (),
+    }
+    drop(stdin);
+
+    let status = trace::wait(&mut child)
+        .await
+        .map_err(|e| ApprovalError::Wait(command.to_string(), e))?;
+
+    match status.success() {
+        true => {
+            info!("Deployment approved by `{}`", command);
+            Ok(())
+        }
+        false => Err(ApprovalError::Denied(command.to_string(), status.code())),
+    }
+}
+
+#[test]
+fn test_plan_json() {
+    let plan = Plan {
+        dry_activate: false,
+        boot: true,
+        profiles: vec![PlannedProfile {
+            node: "web1",
+            profile: "system",
+            hostname: "web1.example.com",
+            ssh_user: "deploy",
+            user: "root",
+            path: "/nix/store/blah/etc",
+        }],
+    };
+
+    assert_eq!(
+        serde_json::to_value(&plan).unwrap(),
+        serde_json::json!({
+            "dryActivate": false,
+            "boot": true,
+            "profiles": [{
+                "node": "web1",
+                "profile": "system",
+                "hostname": "web1.example.com",
+                "sshUser": "deploy",
+                "user": "root",
+                "path": "/nix/store/blah/etc",
+            }],
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_dry_run_approval() {
+    let mut plan = Plan {
+        dry_activate: true,
+        boot: false,
+        profiles: Vec::new(),
+    };
+
+    // Not run for dry runs
+    assert!(request_approval("exit 1", &plan).await.is_ok());
+
+    plan.dry_activate = false;
+    assert!(matches!(
+        request_approval("exit 1", &plan).await,
+        Err(ApprovalError::Denied(_, Some(1)))
+    ));
+}
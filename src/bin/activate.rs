@@ -11,9 +11,9 @@ use clap::Clap;
 use tokio::fs;
 use tokio::process::Command;
 use tokio::sync::mpsc;
-use tokio::time::timeout;
+use tokio::time::{timeout_at, Instant};
 
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use std::env;
 use std::path::{Path, PathBuf};
@@ -24,6 +24,8 @@ use thiserror::Error;
 
 use log::{debug, error, info, warn};
 
+use deploy::data::ProfileEngine;
+
 /// Remote activation utility for deploy-rs
 #[derive(Clap, Debug)]
 #[clap(version = "1.0", author = "Serokell <https://serokell.io/>")]
@@ -40,10 +42,14 @@ struct Opts {
 }
 
 #[derive(Clap, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum SubCommand {
     Activate(ActivateOpts),
     Wait(WaitOpts),
     Revoke(RevokeOpts),
+    Status(StatusOpts),
+    List(ListOpts),
+    Rollback(RollbackOpts),
 }
 
 /// Activate a profile
@@ -71,6 +77,11 @@ struct ActivateOpts {
     #[clap(long)]
     confirm_timeout: u16,
 
+    /// Keep waiting for confirmation past the timeout while the deployer touched the heartbeat
+    /// file within this many seconds
+    #[clap(long)]
+    heartbeat_timeout: Option<u16>,
+
     /// Wait for confirmation after deployment and rollback if not confirmed
     #[clap(long)]
     magic_rollback: bool,
@@ -87,9 +98,81 @@ struct ActivateOpts {
     #[clap(long)]
     boot: bool,
 
+    /// The NixOS specialisation of the closure to switch into, passed to the activation script as
+    /// `SPECIALISATION`
+    #[clap(long)]
+    specialisation: Option<String>,
+
+    /// Absolute path of the directory to run the activation script in, instead of the profile
+    #[clap(long)]
+    working_dir: Option<String>,
+
     /// Path for any temporary files that may be needed during activation
     #[clap(long)]
     temp_path: PathBuf,
+
+    /// Refuse to activate the closure without a valid manifest signature from an allowed signer
+    #[clap(long)]
+    require_signed_manifest: bool,
+
+    /// Signature of the closure from a deploy manifest
+    #[clap(long)]
+    manifest_signature: Option<String>,
+
+    /// Label to record for the new generation
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Platform of the machine, for activation scripts handling its quirks
+    #[clap(long)]
+    target_platform: Option<deploy::data::TargetPlatform>,
+
+    /// How to switch the profile to the new generation (nix-env, nix-profile or lite), detected
+    /// from the profile if not given
+    #[clap(long)]
+    profile_engine: Option<ProfileEngine>,
+
+    /// Mandatory access control enforced on the machine (selinux or apparmor), to label the new
+    /// store paths for and check for denials after activating
+    #[clap(long)]
+    security_module: Option<deploy::data::SecurityModule>,
+
+    /// Fail (and roll back) if the security module denied anything during activation
+    #[clap(long)]
+    fail_on_denials: bool,
+
+    /// Run the activation script in a transient systemd service (with `systemd-run`)
+    #[clap(long)]
+    isolate: bool,
+
+    /// `MemoryMax=` of the service running the activation script
+    #[clap(long, requires = "isolate")]
+    isolation_memory_max: Option<String>,
+
+    /// `CPUQuota=` of the service running the activation script
+    #[clap(long, requires = "isolate")]
+    isolation_cpu_quota: Option<String>,
+
+    /// Seconds after which the service running the activation script is killed
+    #[clap(long, requires = "isolate")]
+    isolation_timeout: Option<u32>,
+
+    /// Slice of the service running the activation script
+    #[clap(long, requires = "isolate")]
+    isolation_slice: Option<String>,
+
+    /// Snapshot the filesystems given with --snapshot-dataset (zfs or btrfs) before activating,
+    /// restoring them when rolling back
+    #[clap(long)]
+    snapshot_type: Option<deploy::snapshot::SnapshotType>,
+
+    /// A ZFS dataset or the path of a btrfs subvolume to snapshot, can be given multiple times
+    #[clap(long = "snapshot-dataset", number_of_values = 1, requires = "snapshot-type")]
+    snapshot_datasets: Vec<String>,
+
+    /// How many snapshots of each dataset to keep after a successful activation
+    #[clap(long, requires = "snapshot-type")]
+    snapshot_keep: Option<usize>,
 }
 
 /// Wait for profile activation
@@ -119,6 +202,119 @@ struct RevokeOpts {
     /// The profile name
     #[clap(long, requires = "profile-user")]
     profile_name: Option<String>,
+
+    /// How to switch the profile back (nix-env, nix-profile or lite), detected from the profile
+    /// if not given
+    #[clap(long)]
+    profile_engine: Option<ProfileEngine>,
+}
+
+/// Print the state of a profile as JSON
+#[derive(Clap, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile-path","profile-user"])
+))]
+struct StatusOpts {
+    /// The profile path
+    #[clap(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[clap(long, requires = "profile-name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[clap(long, requires = "profile-user")]
+    profile_name: Option<String>,
+
+    /// Path to look for leftover canary files in
+    #[clap(long)]
+    temp_path: Option<PathBuf>,
+
+    /// Number of lines of the latest activation log (in --log-dir) to include
+    #[clap(long, default_value = "20")]
+    log_lines: usize,
+}
+
+/// Print the generations of a profile as JSON
+#[derive(Clap, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile-path","profile-user"])
+))]
+struct ListOpts {
+    /// The profile path
+    #[clap(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[clap(long, requires = "profile-name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[clap(long, requires = "profile-user")]
+    profile_name: Option<String>,
+}
+
+/// Switch a profile back to the newest generation with a label (or to a generation by number) and
+/// activate it
+#[derive(Clap, Debug)]
+#[clap(group(
+    clap::ArgGroup::new("profile")
+        .required(true)
+        .multiple(false)
+        .args(&["profile-path","profile-user"])
+))]
+#[clap(group(
+    clap::ArgGroup::new("target")
+        .required(true)
+        .multiple(false)
+        .args(&["label","generation"])
+))]
+struct RollbackOpts {
+    /// The profile path
+    #[clap(long)]
+    profile_path: Option<String>,
+    /// The profile user if explicit profile path is not specified
+    #[clap(long, requires = "profile-name")]
+    profile_user: Option<String>,
+    /// The profile name
+    #[clap(long, requires = "profile-user")]
+    profile_name: Option<String>,
+
+    /// The label of the generation to switch to
+    #[clap(long)]
+    label: Option<String>,
+    /// The number of the generation to switch to
+    #[clap(long)]
+    generation: Option<u64>,
+
+    /// How to switch the profile (nix-env, nix-profile or lite), detected from the profile if not
+    /// given
+    #[clap(long)]
+    profile_engine: Option<ProfileEngine>,
+
+    /// The NixOS specialisation of the generation to switch into, as when activating
+    #[clap(long)]
+    specialisation: Option<String>,
+
+    /// Absolute path of the directory to run the activation script in, instead of the profile
+    #[clap(long)]
+    working_dir: Option<String>,
+
+    /// Refuse to switch to the generation without a valid manifest signature of its closure from
+    /// an allowed signer, as when activating
+    #[clap(long, requires = "temp-path")]
+    require_signed_manifest: bool,
+
+    /// Signature of the closure of the generation from a deploy manifest
+    #[clap(long)]
+    manifest_signature: Option<String>,
+
+    /// Path for the temporary files of verifying the manifest signature
+    #[clap(long)]
+    temp_path: Option<PathBuf>,
 }
 
 #[derive(Error, Debug)]
@@ -141,14 +337,74 @@ pub enum DeactivateError {
     Reactivate(std::io::Error),
     #[error("Command for re-activating the last generation resulted in a bad exit code: {0:?}")]
     ReactivateExit(Option<i32>),
+    #[error("Failed to switch the profile back to the last generation: {0}")]
+    SwitchBack(std::io::Error),
 }
 
-pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
+/// The directory to run the activation script of `location` in: `working_dir` if given, otherwise
+/// `location` itself, or the directory containing it if it isn't a directory (single-file closures)
+fn activation_dir(location: &str, working_dir: Option<&str>) -> PathBuf {
+    match working_dir {
+        Some(dir) => PathBuf::from(dir),
+        None if Path::new(location).is_dir() => PathBuf::from(location),
+        None => Path::new(location)
+            .parent()
+            .map_or_else(|| PathBuf::from("/"), Path::to_path_buf),
+    }
+}
+
+pub async fn deactivate(
+    profile_path: &str,
+    profile_engine: ProfileEngine,
+    working_dir: Option<&str>,
+) -> Result<(), DeactivateError> {
     warn!("De-activating due to error");
 
+    match profile_engine {
+        ProfileEngine::NixEnv => nix_env_deactivate(profile_path).await?,
+        // `nix profile rollback` can't delete the generation rolled back from
+        ProfileEngine::NixProfile | ProfileEngine::Lite => {
+            let generation = deploy::status::drop_current_generation(Path::new(profile_path))
+                .map_err(DeactivateError::SwitchBack)?;
+            debug!("Switched back to generation {}", generation);
+        }
+    }
+
+    info!("Attempting to re-activate the last generation");
+
+    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
+        .env("PROFILE", profile_path)
+        .current_dir(activation_dir(profile_path, working_dir))
+        .status()
+        .await
+        .map_err(DeactivateError::Reactivate)?;
+
+    match re_activate_exit_status.code() {
+        Some(0) => (),
+        a => return Err(DeactivateError::ReactivateExit(a)),
+    };
+
+    Ok(())
+}
+
+/// Restores the snapshots taken before activating, then de-activates
+async fn roll_back(
+    profile_path: &str,
+    profile_engine: ProfileEngine,
+    working_dir: Option<&str>,
+    snapshots: &[deploy::snapshot::Snapshot],
+) -> Result<(), DeactivateError> {
+    if !snapshots.is_empty() && !deploy::snapshot::restore(snapshots).await {
+        warn!("Not all snapshots could be restored, rolling back the profile anyway");
+    }
+    deactivate(profile_path, profile_engine, working_dir).await
+}
+
+/// Rolls the profile back and deletes the generation rolled back from with `nix-env`
+async fn nix_env_deactivate(profile_path: &str) -> Result<(), DeactivateError> {
     let nix_env_rollback_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--rollback")
         .status()
         .await
@@ -163,7 +419,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     let nix_env_list_generations_out = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--list-generations")
         .output()
         .await
@@ -192,7 +448,7 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
 
     let nix_env_delete_generation_exit_status = Command::new("nix-env")
         .arg("-p")
-        .arg(&profile_path)
+        .arg(profile_path)
         .arg("--delete-generations")
         .arg(last_generation_id)
         .status()
@@ -204,20 +460,6 @@ pub async fn deactivate(profile_path: &str) -> Result<(), DeactivateError> {
         a => return Err(DeactivateError::DeleteGenExit(a)),
     };
 
-    info!("Attempting to re-activate the last generation");
-
-    let re_activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
-        .env("PROFILE", &profile_path)
-        .current_dir(&profile_path)
-        .status()
-        .await
-        .map_err(DeactivateError::Reactivate)?;
-
-    match re_activate_exit_status.code() {
-        Some(0) => (),
-        a => return Err(DeactivateError::ReactivateExit(a)),
-    };
-
     Ok(())
 }
 
@@ -237,29 +479,57 @@ pub enum ActivationConfirmationError {
 pub enum DangerZoneError {
     #[error("Timeout elapsed for confirmation")]
     TimesUp,
+    #[error("The deployer stopped sending heartbeats for {0}s without confirming")]
+    HeartbeatsStopped(u16),
     #[error("inotify stream ended without activation confirmation")]
     NoConfirmation,
     #[error("inotify encountered an error: {0}")]
     Watch(notify::Error),
 }
 
+/// When the heartbeat file at `heartbeat_path` stops keeping the activation alive, if it was
+/// touched at all
+fn heartbeat_deadline(heartbeat_path: &Path, heartbeat_timeout: u16) -> Option<Instant> {
+    let touched = std::fs::metadata(heartbeat_path).ok()?.modified().ok()?;
+    let since = SystemTime::now().duration_since(touched).unwrap_or_default();
+    Duration::from_secs(heartbeat_timeout as u64)
+        .checked_sub(since)
+        .map(|left| Instant::now() + left)
+}
+
 async fn danger_zone(
     mut events: mpsc::Receiver<Result<(), notify::Error>>,
-    confirm_timeout: u16,
+    mut deadline: Instant,
+    heartbeat: Option<(&Path, u16)>,
 ) -> Result<(), DangerZoneError> {
     info!("Waiting for confirmation event...");
 
-    match timeout(Duration::from_secs(confirm_timeout as u64), events.recv()).await {
-        Ok(Some(Ok(()))) => Ok(()),
-        Ok(Some(Err(e))) => Err(DangerZoneError::Watch(e)),
-        Ok(None) => Err(DangerZoneError::NoConfirmation),
-        Err(_) => Err(DangerZoneError::TimesUp),
+    loop {
+        return match timeout_at(deadline, events.recv()).await {
+            Ok(Some(Ok(()))) => Ok(()),
+            Ok(Some(Err(e))) => Err(DangerZoneError::Watch(e)),
+            Ok(None) => Err(DangerZoneError::NoConfirmation),
+            Err(_) => match heartbeat {
+                Some((heartbeat_path, heartbeat_timeout)) => {
+                    match heartbeat_deadline(heartbeat_path, heartbeat_timeout) {
+                        Some(x) => {
+                            debug!("The deployer is still alive, waiting on");
+                            deadline = x;
+                            continue;
+                        }
+                        None => Err(DangerZoneError::HeartbeatsStopped(heartbeat_timeout)),
+                    }
+                }
+                None => Err(DangerZoneError::TimesUp),
+            },
+        };
     }
 }
 
 pub async fn activation_confirmation(
     temp_path: PathBuf,
     confirm_timeout: u16,
+    heartbeat_timeout: Option<u16>,
     closure: String,
 ) -> Result<(), ActivationConfirmationError> {
     let lock_path = deploy::make_lock_path(&temp_path, &closure);
@@ -274,10 +544,31 @@ pub async fn activation_confirmation(
 
     debug!("Creating canary file");
 
-    fs::File::create(&lock_path)
+    // The countdown starts with the canary, whose content tells `wait` (and so the deployer) when
+    // it ends
+    let deadline = Instant::now() + Duration::from_secs(confirm_timeout as u64);
+    let deadline_unix = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        + confirm_timeout as u64;
+    fs::write(&lock_path, deadline_unix.to_string())
         .await
         .map_err(ActivationConfirmationError::CreateConfirmFile)?;
 
+    info!(
+        "The activation has to be confirmed within {}s, or it is rolled back",
+        confirm_timeout
+    );
+    if let Some(heartbeat_timeout) = heartbeat_timeout {
+        info!(
+            "Waiting longer while the deployer sends heartbeats at least every {}s",
+            heartbeat_timeout
+        );
+    }
+
+    let state_path = deploy::make_canary_state_path(&temp_path, &closure);
+    record_canary_state(&state_path, deploy::CanaryState::Created);
+
     debug!("Creating notify watcher");
 
     let (deleted, done) = mpsc::channel(1);
@@ -303,20 +594,76 @@ pub async fn activation_confirmation(
 
     watcher.watch(&lock_path, RecursiveMode::NonRecursive)?;
 
-    danger_zone(done, confirm_timeout)
-        .await
-        .map_err(|err| ActivationConfirmationError::WaitingError(err))
+    let heartbeat_path = deploy::make_heartbeat_path(&temp_path, &closure);
+    let result = danger_zone(
+        done,
+        deadline,
+        heartbeat_timeout.map(|t| (heartbeat_path.as_path(), t)),
+    )
+    .await;
+    let _ = fs::remove_file(&heartbeat_path).await;
+    record_canary_state(
+        &state_path,
+        match result {
+            Ok(()) => deploy::CanaryState::Confirmed,
+            Err(_) => deploy::CanaryState::RolledBack,
+        },
+    );
+
+    result.map_err(ActivationConfirmationError::WaitingError)
+}
+
+fn record_canary_state(state_path: &Path, state: deploy::CanaryState) {
+    if let Err(e) = deploy::write_canary_state(state_path, state) {
+        warn!("Failed to record the state of the canary in {}: {}", state_path.display(), e);
+    }
+}
+
+/// Creates the directory of this run, only accessible by the profile user
+fn create_temp_dir(temp_path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    debug!("Ensuring temporary directory {} exists", temp_path.display());
+
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(temp_path)
+}
+
+/// Removes the directory of this run, unless it was not created for it (as is the case if an
+/// older deploy-rs passed `tempPath` itself)
+fn remove_temp_dir(temp_path: &Path) {
+    if !deploy::is_run_temp_path(temp_path) {
+        return;
+    }
+
+    debug!("Removing temporary directory {}", temp_path.display());
+
+    if let Err(e) = std::fs::remove_dir_all(temp_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove temporary directory {}: {}", temp_path.display(), e);
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum WaitError {
+    #[error("Failed to create temporary directory: {0}")]
+    CreateTempDir(std::io::Error),
     #[error("Error creating watcher for activation: {0}")]
     Watcher(#[from] notify::Error),
     #[error("Error waiting for activation: {0}")]
     Waiting(#[from] DangerZoneError),
+    #[error("The activation was already rolled back before waiting for it")]
+    RolledBack,
 }
 pub async fn wait(temp_path: PathBuf, closure: String, activation_timeout: Option<u16>) -> Result<(), WaitError> {
     let lock_path = deploy::make_lock_path(&temp_path, &closure);
+    let deadline = Instant::now() + Duration::from_secs(activation_timeout.unwrap_or(240) as u64);
+
+    // The activation may not have created it yet
+    create_temp_dir(&temp_path).map_err(WaitError::CreateTempDir)?;
 
     let (created, done) = mpsc::channel(1);
 
@@ -352,18 +699,69 @@ pub async fn wait(temp_path: PathBuf, closure: String, activation_timeout: Optio
     // Avoid a potential race condition by checking for existence after watcher creation
     if fs::metadata(&lock_path).await.is_ok() {
         watcher.unwatch(&temp_path)?;
+        report_confirm_remaining(&lock_path).await;
         return Ok(());
     }
 
-    danger_zone(done, activation_timeout.unwrap_or(240)).await?;
+    // A fast activation may have created and removed the canary already
+    let state_path = deploy::make_canary_state_path(&temp_path, &closure);
+    match deploy::read_canary_state(&state_path) {
+        Some(deploy::CanaryState::Created) => {
+            info!("The canary file was already created, done waiting!");
+            report_confirm_remaining(&lock_path).await;
+            return Ok(());
+        }
+        Some(deploy::CanaryState::Confirmed) => {
+            info!("The activation was already confirmed, done waiting!");
+            let _ = fs::remove_file(&state_path).await;
+            return Ok(());
+        }
+        Some(deploy::CanaryState::RolledBack) => {
+            let _ = fs::remove_file(&state_path).await;
+            return Err(WaitError::RolledBack);
+        }
+        None => (),
+    }
+
+    danger_zone(done, deadline, None).await?;
 
     info!("Found canary file, done waiting!");
+    report_confirm_remaining(&lock_path).await;
 
     Ok(())
 }
 
+/// Tells the deployer how long it has left to confirm the activation, going by the deadline the
+/// activation wrote to the canary file
+async fn report_confirm_remaining(lock_path: &Path) {
+    // The deadline is written right after the canary is created
+    for _ in 0..20 {
+        let deadline = fs::read_to_string(lock_path)
+            .await
+            .ok()
+            .and_then(|c| c.trim().parse::<u64>().ok());
+        if let Some(deadline) = deadline {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let remaining = deadline.saturating_sub(now);
+            info!("{}s left to confirm the activation", remaining);
+            println!("{}{}", deploy::CONFIRM_REMAINING_PREFIX, remaining);
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    debug!("The canary file holds no deadline, it was created by an older deploy-rs");
+}
+
 #[derive(Error, Debug)]
 pub enum ActivateError {
+    #[error("Failed to create temporary directory: {0}")]
+    CreateTempDir(std::io::Error),
+
+    #[error("Can't switch the profile, {0} isn't writable ({1}). Set `profilePath` to a writable location (e.g. under /nix/var/nix/profiles/per-user), or bind-mount a writable state directory over {0}")]
+    ProfileDirNotWritable(PathBuf, std::io::Error),
     #[error("Failed to execute the command for setting profile: {0}")]
     SetProfile(std::io::Error),
     #[error("The command for setting profile resulted in a bad exit code: {0:?}")]
@@ -373,43 +771,196 @@ pub enum ActivateError {
     RunActivate(std::io::Error),
     #[error("The activation script resulted in a bad exit code: {0:?}")]
     RunActivateExit(Option<i32>),
+    #[error("{1} denied {0} access(es) during activation")]
+    SecurityDenials(usize, &'static str),
+
+    #[error("Failed to snapshot the filesystems before activating: {0}")]
+    Snapshot(#[from] deploy::snapshot::SnapshotError),
 
     #[error("There was an error de-activating after an error was encountered: {0}")]
     Deactivate(#[from] DeactivateError),
 
     #[error("Failed to get activation confirmation: {0}")]
     ActivationConfirmation(#[from] ActivationConfirmationError),
+
+    #[error("Refusing to activate {0}, it has no manifest signature")]
+    UnsignedClosure(String),
+    #[error("The closure has no specialisation `{0}`{1}")]
+    NoSpecialisation(String, String),
+    #[error("The working directory {0} of the activation isn't an existing directory (or isn't an absolute path)")]
+    WorkingDir(String),
+    #[error("Refusing to activate: {0}")]
+    Manifest(#[from] deploy::manifest::ManifestError),
+    #[error("{0}")]
+    DryActivateReport(#[from] deploy::dry_activate::DryActivateReportError),
+}
+
+/// Checks that `closure` has the NixOS specialisation `name`
+fn check_specialisation(closure: &str, name: &str) -> Result<(), ActivateError> {
+    let dir = Path::new(closure).join("specialisation");
+    if dir.join(name).join("bin/switch-to-configuration").exists() {
+        return Ok(());
+    }
+
+    let names: Vec<String> = std::fs::read_dir(&dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    Err(ActivateError::NoSpecialisation(
+        name.to_string(),
+        deploy::suggest::not_found_hint(name, "specialisation", &names),
+    ))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn activate(
     profile_path: String,
     closure: String,
     auto_rollback: bool,
     temp_path: PathBuf,
     confirm_timeout: u16,
+    heartbeat_timeout: Option<u16>,
     magic_rollback: bool,
     dry_activate: bool,
     boot: bool,
+    specialisation: Option<String>,
+    working_dir: Option<String>,
+    require_signed_manifest: bool,
+    manifest_signature: Option<String>,
+    label: Option<String>,
+    target_platform: Option<deploy::data::TargetPlatform>,
+    profile_engine: Option<ProfileEngine>,
+    security_module: Option<deploy::data::SecurityModule>,
+    fail_on_denials: bool,
+    isolation: Option<deploy::data::ActivationLimits>,
+    snapshot: Option<deploy::data::SnapshotSettings>,
 ) -> Result<(), ActivateError> {
+    create_temp_dir(&temp_path).map_err(ActivateError::CreateTempDir)?;
+    if let Some(parent) = temp_path.parent() {
+        deploy::remove_stale_canary_states(parent);
+    }
+
+    if !dry_activate {
+        deploy::status::check_profile_dir(Path::new(&profile_path))
+            .map_err(|(dir, e)| ActivateError::ProfileDirNotWritable(dir, e))?;
+    }
+
+    // Detected before switching, afterwards the profile is the closure whatever it was before
+    let profile_engine = profile_engine
+        .unwrap_or_else(|| deploy::status::detect_profile_engine(Path::new(&profile_path)));
+    debug!("Switching the profile with {}", profile_engine.as_str());
+
+    // Set for this process, so that re-activations when rolling back see it too
+    if let Some(target_platform) = target_platform {
+        env::set_var("TARGET_PLATFORM", target_platform.as_str());
+    }
+
+    if require_signed_manifest {
+        info!("Verifying the manifest signature of the closure");
+        let signature = manifest_signature.ok_or_else(|| ActivateError::UnsignedClosure(closure.clone()))?;
+        deploy::manifest::verify(
+            Path::new(deploy::manifest::ALLOWED_SIGNERS_PATH),
+            &closure,
+            &signature,
+            &temp_path,
+        )
+        .await?;
+    }
+
+    // Checked before anything changes, rather than failing to start the activation script
+    if let Some(dir) = &working_dir {
+        if !Path::new(dir).is_absolute() || !Path::new(dir).is_dir() {
+            return Err(ActivateError::WorkingDir(dir.clone()));
+        }
+    }
+
+    if let Some(specialisation) = &specialisation {
+        check_specialisation(&closure, specialisation)?;
+        info!("Switching into specialisation `{}`", specialisation);
+    }
+
+    // Taken before anything changes, so that restoring them undoes all the activation did
+    let snapshots = match &snapshot {
+        Some(snapshot) if !dry_activate => {
+            let name = deploy::snapshot::snapshot_name(chrono::Utc::now());
+            deploy::snapshot::take(snapshot.kind, &snapshot.datasets, &name).await?
+        }
+        _ => Vec::new(),
+    };
+
     if !dry_activate {
         info!("Activating profile");
-        let nix_env_set_exit_status = Command::new("nix-env")
-            .arg("-p")
-            .arg(&profile_path)
-            .arg("--set")
-            .arg(&closure)
-            .status()
-            .await
-            .map_err(ActivateError::SetProfile)?;
-        match nix_env_set_exit_status.code() {
-            Some(0) => (),
-            a => {
-                if auto_rollback && !dry_activate {
-                    deactivate(&profile_path).await?;
-                }
-                return Err(ActivateError::SetProfileExit(a));
+        match profile_engine {
+            ProfileEngine::NixEnv => {
+                let nix_env_set_exit_status = Command::new("nix-env")
+                    .arg("-p")
+                    .arg(&profile_path)
+                    .arg("--set")
+                    .arg(&closure)
+                    .status()
+                    .await
+                    .map_err(ActivateError::SetProfile)?;
+                match nix_env_set_exit_status.code() {
+                    Some(0) => (),
+                    a => {
+                        if auto_rollback && !dry_activate {
+                            roll_back(&profile_path, profile_engine, working_dir.as_deref(), &snapshots).await?;
+                        }
+                        return Err(ActivateError::SetProfileExit(a));
+                    }
+                };
             }
-        };
+            ProfileEngine::NixProfile => {
+                let nix_build_exit_status = Command::new("nix")
+                    .arg("--extra-experimental-features")
+                    .arg("nix-command")
+                    .arg("build")
+                    .arg("--no-link")
+                    .arg("--profile")
+                    .arg(&profile_path)
+                    .arg(&closure)
+                    .status()
+                    .await
+                    .map_err(ActivateError::SetProfile)?;
+                match nix_build_exit_status.code() {
+                    Some(0) => (),
+                    a => return Err(ActivateError::SetProfileExit(a)),
+                };
+            }
+            ProfileEngine::Lite => {
+                // Paths outside the store (as in tests) can't be collected, nor made roots
+                let gc_root = Path::new(&closure).starts_with("/nix/store");
+                let generation =
+                    deploy::status::set_generation(Path::new(&profile_path), &closure, gc_root)
+                        .map_err(ActivateError::SetProfile)?;
+                debug!("Switched the profile to generation {}", generation);
+            }
+        }
+
+        if let Some(label) = &label {
+            match deploy::status::record_label(Path::new(&profile_path), label) {
+                Ok(generation) => debug!("Labelled generation {:?} as {}", generation, label),
+                Err(e) => warn!("Failed to record the label of the new generation: {}", e),
+            }
+        }
+    }
+
+    let activation_started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    if security_module == Some(deploy::data::SecurityModule::Selinux) && !dry_activate {
+        info!("Restoring the SELinux contexts of the new store paths");
+        match deploy::security::restore_contexts(&closure).await {
+            Ok(true) => (),
+            Ok(false) => warn!("restorecon failed, the new store paths may be labelled wrongly"),
+            Err(e) => warn!("Failed to run restorecon: {}", e),
+        }
     }
 
     debug!("Running activation script");
@@ -420,11 +971,50 @@ pub async fn activate(
         &profile_path
     };
 
-    let activate_status = match Command::new(format!("{}/deploy-rs-activate", activation_location))
+    let activation_script = format!("{}/deploy-rs-activate", activation_location);
+    let working_directory = activation_dir(activation_location, working_dir.as_deref());
+    // Not left over from an earlier dry activation
+    let report_path = deploy::dry_activate::report_path(&temp_path);
+    if dry_activate {
+        let _ = fs::remove_file(&report_path).await;
+    }
+    let mut activate_command = match &isolation {
+        Some(limits) => {
+            info!("Running the activation script in a transient systemd service");
+            let mut command = Command::new("systemd-run");
+            command.args(limits.systemd_run_args());
+            // The service starts with an empty environment, it gets the one of activate-rs
+            for (name, _) in env::vars_os().filter(|(name, _)| {
+                !matches!(
+                    name.to_str(),
+                    Some("PROFILE" | "DRY_ACTIVATE" | "DRY_ACTIVATE_REPORT" | "BOOT" | "SPECIALISATION")
+                )
+            }) {
+                let mut arg = std::ffi::OsString::from("--setenv=");
+                arg.push(name);
+                command.arg(arg);
+            }
+            command
+                .arg("--setenv=PROFILE")
+                .arg("--setenv=DRY_ACTIVATE")
+                .arg("--setenv=DRY_ACTIVATE_REPORT")
+                .arg("--setenv=BOOT")
+                .arg("--setenv=SPECIALISATION")
+                .arg(format!("--working-directory={}", working_directory.display()))
+                .arg(&activation_script);
+            command
+        }
+        None => Command::new(&activation_script),
+    };
+
+    let activate_status = match activate_command
         .env("PROFILE", activation_location)
         .env("DRY_ACTIVATE", if dry_activate { "1" } else { "0" })
+        .env("DRY_ACTIVATE_REPORT", if dry_activate { report_path.as_os_str() } else { "".as_ref() })
         .env("BOOT", if boot { "1" } else { "0" })
-        .current_dir(activation_location)
+        // Only for this activation, rolling back re-activates the base configuration
+        .env("SPECIALISATION", specialisation.as_deref().unwrap_or(""))
+        .current_dir(&working_directory)
         .status()
         .await
         .map_err(ActivateError::RunActivate)
@@ -432,41 +1022,231 @@ pub async fn activate(
         Ok(x) => x,
         Err(e) => {
             if auto_rollback && !dry_activate {
-                deactivate(&profile_path).await?;
+                roll_back(&profile_path, profile_engine, working_dir.as_deref(), &snapshots).await?;
             }
             return Err(e);
         }
     };
 
-    if !dry_activate {
+    if dry_activate {
+        if let Some(report) = deploy::dry_activate::DryActivateReport::load(&report_path)? {
+            // Read back by the deploying side from stdout, the logs go to stderr
+            println!("{}", report.to_line());
+        }
+    } else {
         match activate_status.code() {
             Some(0) => (),
             a => {
                 if auto_rollback {
-                    deactivate(&profile_path).await?;
+                    roll_back(&profile_path, profile_engine, working_dir.as_deref(), &snapshots).await?;
                 }
                 return Err(ActivateError::RunActivateExit(a));
             }
         };
 
+        if let Some(security_module) = security_module {
+            let denials = deploy::security::denials_since(security_module, activation_started).await;
+            for denial in &denials {
+                warn!("{} denial during activation: {}", security_module.as_str(), denial);
+            }
+            if fail_on_denials && !denials.is_empty() {
+                if auto_rollback {
+                    roll_back(&profile_path, profile_engine, working_dir.as_deref(), &snapshots).await?;
+                }
+                return Err(ActivateError::SecurityDenials(denials.len(), security_module.as_str()));
+            }
+        }
+
         if !dry_activate {
             info!("Activation succeeded!");
         }
 
+        if boot && target_platform == Some(deploy::data::TargetPlatform::Wsl) {
+            info!("Restart the WSL instance (`wsl.exe --terminate <distribution>`) to boot into the new profile");
+        }
+
         if magic_rollback && !boot {
             info!("Magic rollback is enabled, setting up confirmation hook...");
-            if let Err(err) = activation_confirmation(temp_path, confirm_timeout, closure).await {
-                deactivate(&profile_path).await?;
+            if let Err(err) = activation_confirmation(temp_path, confirm_timeout, heartbeat_timeout, closure).await {
+                roll_back(&profile_path, profile_engine, working_dir.as_deref(), &snapshots).await?;
                 return Err(ActivateError::ActivationConfirmation(err));
             }
         }
+
+        if let Some(snapshot) = &snapshot {
+            deploy::snapshot::prune(
+                snapshot.kind,
+                &snapshot.datasets,
+                snapshot.keep.unwrap_or(deploy::snapshot::DEFAULT_KEEP),
+            )
+            .await;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum IntrospectError {
+    #[error("Failed to read the profile: {0}")]
+    Read(#[from] std::io::Error),
+    #[error("Failed to serialize the result: {0}")]
+    Serialize(#[from] serde_json::Error),
+}
+
+fn status(
+    profile_path: String,
+    temp_path: Option<PathBuf>,
+    log_dir: Option<String>,
+    log_lines: usize,
+) -> Result<(), IntrospectError> {
+    let status = deploy::status::profile_status(
+        Path::new(&profile_path),
+        temp_path.as_deref(),
+        log_dir.as_deref().map(Path::new),
+        log_lines,
+    )?;
+
+    println!("{}", serde_json::to_string(&status)?);
+    Ok(())
+}
+
+fn list(profile_path: String) -> Result<(), IntrospectError> {
+    let generations = deploy::status::list_generations(Path::new(&profile_path))?;
+
+    println!("{}", serde_json::to_string(&generations)?);
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum RollbackError {
+    #[error("Failed to read the generations of the profile: {0}")]
+    ListGen(std::io::Error),
+    #[error("No generation of the profile is labelled `{0}`")]
+    NoSuchLabel(String),
+    #[error("The profile has no generation {0}")]
+    NoSuchGeneration(u64),
+    #[error("Failed to execute the command for switching generations: {0}")]
+    SwitchGen(std::io::Error),
+    #[error("Failed to switch the profile to the generation: {0}")]
+    SwitchLink(std::io::Error),
+    #[error("The command for switching generations resulted in a bad exit code: {0:?}")]
+    SwitchGenExit(Option<i32>),
+    #[error("Failed to execute the activation script: {0}")]
+    RunActivate(std::io::Error),
+    #[error("The activation script resulted in a bad exit code: {0:?}")]
+    RunActivateExit(Option<i32>),
+    #[error("{0}")]
+    Activate(#[from] ActivateError),
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn rollback(
+    profile_path: String,
+    label: Option<String>,
+    generation: Option<u64>,
+    profile_engine: Option<ProfileEngine>,
+    specialisation: Option<String>,
+    working_dir: Option<String>,
+    manifest_signature: Option<(Option<String>, PathBuf)>,
+) -> Result<(), RollbackError> {
+    let profile_engine = profile_engine
+        .unwrap_or_else(|| deploy::status::detect_profile_engine(Path::new(&profile_path)));
+    let generation = match (label, generation) {
+        (Some(label), _) => {
+            deploy::status::find_labelled_generation(Path::new(&profile_path), &label)
+                .map_err(RollbackError::ListGen)?
+                .ok_or(RollbackError::NoSuchLabel(label))?
+        }
+        (None, number) => {
+            let number = number.unwrap_or_default();
+            deploy::status::list_generations(Path::new(&profile_path))
+                .map_err(RollbackError::ListGen)?
+                .into_iter()
+                .find(|g| g.number == number)
+                .ok_or(RollbackError::NoSuchGeneration(number))?
+        }
+    };
+    let name = generation
+        .label
+        .clone()
+        .unwrap_or_else(|| format!("generation {}", generation.number));
+
+    // Checked before anything changes, as when activating
+    if let Some((signature, temp_path)) = manifest_signature {
+        info!("Verifying the manifest signature of the closure");
+        let signature = signature.ok_or_else(|| ActivateError::UnsignedClosure(generation.path.clone()))?;
+        create_temp_dir(&temp_path).map_err(ActivateError::CreateTempDir)?;
+        let verified = deploy::manifest::verify(
+            Path::new(deploy::manifest::ALLOWED_SIGNERS_PATH),
+            &generation.path,
+            &signature,
+            &temp_path,
+        )
+        .await;
+        remove_temp_dir(&temp_path);
+        verified.map_err(ActivateError::from)?;
+    }
+    if let Some(dir) = &working_dir {
+        if !Path::new(dir).is_absolute() || !Path::new(dir).is_dir() {
+            return Err(ActivateError::WorkingDir(dir.clone()).into());
+        }
     }
+    if let Some(specialisation) = &specialisation {
+        check_specialisation(&generation.path, specialisation)?;
+        info!("Switching into specialisation `{}`", specialisation);
+    }
+
+    info!("Switching to generation {} ({})", generation.number, name);
+
+    match profile_engine {
+        ProfileEngine::NixEnv => {
+            let switch_exit_status = Command::new("nix-env")
+                .arg("-p")
+                .arg(&profile_path)
+                .arg("--switch-generation")
+                .arg(generation.number.to_string())
+                .status()
+                .await
+                .map_err(RollbackError::SwitchGen)?;
+
+            match switch_exit_status.code() {
+                Some(0) => (),
+                a => return Err(RollbackError::SwitchGenExit(a)),
+            };
+        }
+        // `nix-env` doesn't understand the `manifest.json` of `nix profile`, and lite profiles
+        // don't need it
+        ProfileEngine::NixProfile | ProfileEngine::Lite => {
+            deploy::status::switch_generation(Path::new(&profile_path), generation.number)
+                .map_err(RollbackError::SwitchLink)?
+        }
+    }
+
+    info!("Activating generation {}", generation.number);
+
+    let activate_exit_status = Command::new(format!("{}/deploy-rs-activate", profile_path))
+        .env("PROFILE", &profile_path)
+        .env("SPECIALISATION", specialisation.as_deref().unwrap_or(""))
+        .current_dir(activation_dir(&profile_path, working_dir.as_deref()))
+        .status()
+        .await
+        .map_err(RollbackError::RunActivate)?;
+
+    match activate_exit_status.code() {
+        Some(0) => (),
+        a => return Err(RollbackError::RunActivateExit(a)),
+    };
+
+    info!("Rolled back to {}", name);
 
     Ok(())
 }
 
-async fn revoke(profile_path: String) -> Result<(), DeactivateError> {
-    deactivate(profile_path.as_str()).await?;
+async fn revoke(profile_path: String, profile_engine: Option<ProfileEngine>) -> Result<(), DeactivateError> {
+    let profile_engine = profile_engine
+        .unwrap_or_else(|| deploy::status::detect_profile_engine(Path::new(&profile_path)));
+    deactivate(profile_path.as_str(), profile_engine, None).await?;
     Ok(())
 }
 
@@ -512,7 +1292,7 @@ fn get_profile_path(
                         let state_dir = env::var("XDG_STATE_HOME").or_else(|_| {
                             dirs::home_dir()
                                 .map(|h| {
-                                    format!("{}/.local/state", h.as_path().display().to_string())
+                                    format!("{}/.local/state", h.as_path().display())
                                 })
                                 .ok_or(GetProfilePathError::NoUserHome(profile_user))
                         })?;
@@ -528,7 +1308,7 @@ fn get_profile_path(
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Ensure that this process stays alive after the SSH connection dies
-    let mut signals = Signals::new(&[SIGHUP])?;
+    let mut signals = Signals::new([SIGHUP])?;
     std::thread::spawn(move || {
         for _ in signals.forever() {
             println!("Received SIGHUP - ignoring...");
@@ -537,33 +1317,97 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let opts: Opts = Opts::parse();
 
+    deploy::redact::register_env_secrets();
+
+    // Introspection prints JSON to stdout, so it mustn't write logs to files (flexi_logger
+    // announces those on stdout), the log directory is only read
+    let introspecting = matches!(opts.subcmd, SubCommand::Status(_) | SubCommand::List(_));
+
+    // Everything else is always logged to a file on the node, for `deploy logs`
+    let log_dir = match (introspecting, &opts.log_dir) {
+        (true, _) => None,
+        (false, Some(log_dir)) => Some(log_dir.clone()),
+        (false, None) => {
+            let temp_path = match &opts.subcmd {
+                SubCommand::Activate(activate_opts) => activate_opts.temp_path.as_path(),
+                SubCommand::Wait(wait_opts) => wait_opts.temp_path.as_path(),
+                _ => Path::new("/tmp"),
+            };
+            let dirs = vec![
+                PathBuf::from(deploy::ACTIVATION_LOG_DIR),
+                deploy::fallback_activation_log_dir(temp_path),
+            ];
+            deploy::first_writable_dir(&dirs).map(|dir| dir.display().to_string())
+        }
+    };
+
     deploy::init_logger(
-        opts.debug_logs,
-        opts.log_dir.as_deref(),
+        opts.debug_logs as u8,
+        false,
+        None,
+        log_dir.as_deref(),
+        &[],
         &match opts.subcmd {
-            SubCommand::Activate(_) => deploy::LoggerType::Activate,
+            SubCommand::Activate(_) | SubCommand::Status(_) | SubCommand::List(_) => {
+                deploy::LoggerType::Activate
+            }
             SubCommand::Wait(_) => deploy::LoggerType::Wait,
-            SubCommand::Revoke(_) => deploy::LoggerType::Revoke,
+            SubCommand::Revoke(_) | SubCommand::Rollback(_) => deploy::LoggerType::Revoke,
         },
     )?;
 
     let r = match opts.subcmd {
-        SubCommand::Activate(activate_opts) => activate(
-            get_profile_path(
-                activate_opts.profile_path,
-                activate_opts.profile_user,
-                activate_opts.profile_name,
-            )?,
-            activate_opts.closure,
-            activate_opts.auto_rollback,
-            activate_opts.temp_path,
-            activate_opts.confirm_timeout,
-            activate_opts.magic_rollback,
-            activate_opts.dry_activate,
-            activate_opts.boot,
-        )
-        .await
-        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+        SubCommand::Activate(activate_opts) => {
+            let temp_path = activate_opts.temp_path.clone();
+
+            let r = activate(
+                get_profile_path(
+                    activate_opts.profile_path,
+                    activate_opts.profile_user,
+                    activate_opts.profile_name,
+                )?,
+                activate_opts.closure,
+                activate_opts.auto_rollback,
+                activate_opts.temp_path,
+                activate_opts.confirm_timeout,
+                activate_opts.heartbeat_timeout,
+                activate_opts.magic_rollback,
+                activate_opts.dry_activate,
+                activate_opts.boot,
+                activate_opts.specialisation,
+                activate_opts.working_dir,
+                activate_opts.require_signed_manifest,
+                activate_opts.manifest_signature,
+                activate_opts.label,
+                activate_opts.target_platform,
+                activate_opts.profile_engine,
+                activate_opts.security_module,
+                activate_opts.fail_on_denials,
+                match activate_opts.isolate {
+                    true => Some(deploy::data::ActivationLimits {
+                        memory_max: activate_opts.isolation_memory_max,
+                        cpu_quota: activate_opts.isolation_cpu_quota,
+                        timeout: activate_opts.isolation_timeout,
+                        slice: activate_opts.isolation_slice,
+                    }),
+                    false => None,
+                },
+                match activate_opts.snapshot_type {
+                    Some(kind) => Some(deploy::data::SnapshotSettings {
+                        kind,
+                        datasets: activate_opts.snapshot_datasets,
+                        keep: activate_opts.snapshot_keep,
+                    }),
+                    None => None,
+                },
+            )
+            .await;
+
+            // Once the activation is done (and confirmed), nothing uses the directory anymore
+            remove_temp_dir(&temp_path);
+
+            r.map_err(|x| Box::new(x) as Box<dyn std::error::Error>)
+        }
 
         SubCommand::Wait(wait_opts) => wait(wait_opts.temp_path, wait_opts.closure, wait_opts.activation_timeout)
             .await
@@ -573,7 +1417,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             revoke_opts.profile_path,
             revoke_opts.profile_user,
             revoke_opts.profile_name,
+        )?, revoke_opts.profile_engine)
+        .await
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Status(status_opts) => status(
+            get_profile_path(
+                status_opts.profile_path,
+                status_opts.profile_user,
+                status_opts.profile_name,
+            )?,
+            status_opts.temp_path,
+            opts.log_dir,
+            status_opts.log_lines,
+        )
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::List(list_opts) => list(get_profile_path(
+            list_opts.profile_path,
+            list_opts.profile_user,
+            list_opts.profile_name,
         )?)
+        .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
+
+        SubCommand::Rollback(rollback_opts) => rollback(
+            get_profile_path(
+                rollback_opts.profile_path,
+                rollback_opts.profile_user,
+                rollback_opts.profile_name,
+            )?,
+            rollback_opts.label,
+            rollback_opts.generation,
+            rollback_opts.profile_engine,
+            rollback_opts.specialisation,
+            rollback_opts.working_dir,
+            match (rollback_opts.require_signed_manifest, rollback_opts.temp_path) {
+                (true, Some(temp_path)) => Some((rollback_opts.manifest_signature, temp_path)),
+                _ => None,
+            },
+        )
         .await
         .map_err(|x| Box::new(x) as Box<dyn std::error::Error>),
     };
@@ -10,9 +10,14 @@ use log::error;
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match cli::run(None).await {
         Ok(()) => (),
+        // The logger can't report its own initialization failure
+        Err(err @ cli::RunError::Logger(_)) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
         Err(err) => {
             error!("{}", err);
-            std::process::exit(1);
+            std::process::exit(err.category().code());
         }
     }
 
@@ -0,0 +1,258 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Failure bundles, archives with what's needed to look into a failed deployment of a node.
+//!
+//! For every node with a failed step, `deploy-failure-<node>-<timestamp>.tar.gz` is written,
+//! containing:
+//!
+//! - `events.log`: every event of the node with the time since the start of the run, including
+//!   all output of nix, ssh and the activation on the node
+//! - `commands.log`: the exact commands run for the node
+//! - `plan.toml`: what was going to be deployed to each profile of the node
+//! - `logs/`: the files in `--log-dir` written during the run
+//!
+//! Secrets known to [`crate::redact`] are redacted from all of them.
+
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::events::{Event, EventKind};
+use crate::redact::redact;
+use crate::render::Renderer;
+
+#[derive(Default)]
+struct NodeRecord {
+    node: String,
+    events: Vec<String>,
+    commands: Vec<String>,
+    plans: Vec<(String, String)>,
+    failed: bool,
+}
+
+/// Keeps track of the events of every node, writing a bundle for each failed one when finished
+pub struct FailureBundleRenderer {
+    dir: PathBuf,
+    log_dir: Option<PathBuf>,
+    started: Instant,
+    started_at: SystemTime,
+    nodes: Vec<NodeRecord>,
+}
+
+/// Replaces characters which don't belong in a file name
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl FailureBundleRenderer {
+    /// Bundles are written to `dir`, including the logs written to `log_dir` during the run
+    pub fn new(dir: PathBuf, log_dir: Option<PathBuf>) -> Self {
+        FailureBundleRenderer {
+            dir,
+            log_dir,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// The generated files of the bundle of `record`, by name
+    fn files(record: &NodeRecord) -> Vec<(&'static str, String)> {
+        let lines = |lines: &[String]| {
+            lines
+                .iter()
+                .map(|l| format!("{}\n", redact(l)))
+                .collect::<String>()
+        };
+
+        let plan = record
+            .plans
+            .iter()
+            .map(|(profile, plan)| format!("[{}]\n{}\n", profile, redact(plan)))
+            .collect::<String>();
+
+        vec![
+            ("events.log", lines(&record.events)),
+            ("commands.log", lines(&record.commands)),
+            ("plan.toml", plan),
+        ]
+    }
+
+    fn copy_logs(&self, logs: &Path) -> std::io::Result<()> {
+        let log_dir = match &self.log_dir {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        std::fs::create_dir_all(logs)?;
+        for entry in std::fs::read_dir(log_dir)? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            if !entry.file_type()?.is_file() || modified < self.started_at {
+                continue;
+            }
+
+            let content = String::from_utf8_lossy(&std::fs::read(entry.path())?).to_string();
+            std::fs::write(logs.join(entry.file_name()), redact(&content))?;
+        }
+
+        Ok(())
+    }
+
+    fn write_bundle(&self, record: &NodeRecord) -> std::io::Result<PathBuf> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let node = file_name_safe(&record.node);
+        let archive = self
+            .dir
+            .join(format!("deploy-failure-{}-{}.tar.gz", node, timestamp));
+
+        let content =
+            std::env::temp_dir().join(format!("deploy-rs-failure-{}-{}", crate::run_id(), node));
+        std::fs::create_dir_all(&content)?;
+
+        let result = (|| {
+            for (name, file) in Self::files(record) {
+                std::fs::write(content.join(name), file)?;
+            }
+            self.copy_logs(&content.join("logs"))?;
+
+            let mut tar = std::process::Command::new("tar");
+            tar.arg("-czf")
+                .arg(&archive)
+                .arg("-C")
+                .arg(&content)
+                .arg(".");
+            let trace = crate::trace::started(&tar);
+            let status = tar.status();
+            crate::trace::exited(trace, &status);
+            let status = status?;
+            match status.success() {
+                true => Ok(archive),
+                false => Err(std::io::Error::other(format!("tar exited with {}", status))),
+            }
+        })();
+
+        let _ = std::fs::remove_dir_all(&content);
+        result
+    }
+}
+
+impl Renderer for FailureBundleRenderer {
+    fn render(&mut self, event: &Event) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+
+        let record = match self.nodes.iter_mut().position(|r| r.node == event.node) {
+            Some(i) => &mut self.nodes[i],
+            None => {
+                self.nodes.push(NodeRecord {
+                    node: event.node.clone(),
+                    ..NodeRecord::default()
+                });
+                self.nodes.last_mut().unwrap()
+            }
+        };
+
+        let description = match &event.kind {
+            EventKind::Started(phase) => format!("{} started", phase),
+            EventKind::Finished(phase) => format!("{} finished", phase),
+            EventKind::Failed(phase, message) => {
+                record.failed = true;
+                format!("{} failed: {}", phase, message)
+            }
+            EventKind::Output(line) => line.clone(),
+            EventKind::Command(command) => {
+                record.commands.push(command.clone());
+                format!("running {}", command)
+            }
+            EventKind::Planned(plan) => {
+                record.plans.push((event.profile.clone(), plan.clone()));
+                return;
+            }
+            EventKind::Units(units) => format!("units: {}", units),
+            EventKind::Usage(phase, usage) => format!("{} used {}", phase, usage),
+            EventKind::Confirmed => "activation confirmed".to_string(),
+            EventKind::Pushed(bytes) => format!("pushed {}", crate::resources::format_bytes(*bytes)),
+        };
+        record.events.push(format!(
+            "[{:9.3}s] [{}] {}",
+            elapsed, event.profile, description
+        ));
+    }
+
+    fn finish(&mut self) {
+        for record in self.nodes.iter().filter(|r| r.failed) {
+            match self.write_bundle(record) {
+                Ok(path) => info!(
+                    "Wrote the failure bundle of {} to {}, attach it when reporting an issue",
+                    record.node,
+                    path.display()
+                ),
+                Err(e) => error!(
+                    "Failed to write the failure bundle of {}: {}",
+                    record.node, e
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_failure_bundle_files() {
+    use crate::events::Phase;
+
+    let mut renderer = FailureBundleRenderer::new(PathBuf::from("/nonexistent"), None);
+    let event = |node: &str, kind| Event {
+        node: node.to_string(),
+        profile: "system".to_string(),
+        kind,
+    };
+
+    renderer.render(&event(
+        "web1",
+        EventKind::Planned("path = \"/nix/store/aaaa\"\n".to_string()),
+    ));
+    renderer.render(&event("web1", EventKind::Started(Phase::Push)));
+    renderer.render(&event(
+        "web1",
+        EventKind::Command("\"nix\" \"copy\"".to_string()),
+    ));
+    renderer.render(&event(
+        "web1",
+        EventKind::Output("copying path".to_string()),
+    ));
+    renderer.render(&event(
+        "web1",
+        EventKind::Failed(Phase::Push, "exit code 1".to_string()),
+    ));
+    renderer.render(&event("db1", EventKind::Started(Phase::Push)));
+
+    assert_eq!(file_name_safe("web1.example.com/x"), "web1.example.com_x");
+    assert!(renderer.nodes[0].failed);
+    assert!(!renderer.nodes[1].failed);
+
+    let files = FailureBundleRenderer::files(&renderer.nodes[0]);
+    assert_eq!(files[0].0, "events.log");
+    let events: Vec<&str> = files[0].1.lines().collect();
+    assert_eq!(events.len(), 4);
+    assert!(events[1].ends_with("[system] running \"nix\" \"copy\""));
+    assert!(events[3].ends_with("[system] push failed: exit code 1"));
+    assert_eq!(files[1], ("commands.log", "\"nix\" \"copy\"\n".to_string()));
+    assert_eq!(
+        files[2],
+        (
+            "plan.toml",
+            "[system]\npath = \"/nix/store/aaaa\"\n\n".to_string()
+        )
+    );
+}
@@ -3,18 +3,24 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use std::collections::HashMap;
-use std::io::{stdin, stdout, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{stdin, stdout, IsTerminal, Write};
 
 use clap::{ArgMatches, Clap, FromArgMatches};
 
 use crate as deploy;
 
+use self::deploy::events::{emit, EventKind, EventStream, Phase};
+use self::deploy::orchestrator::{parallel_batch, rollout_wave, Orchestrator, PendingActivation};
+use self::deploy::render::OutputFormat;
+use self::deploy::severity::Severity;
+use self::deploy::summary::{Outcome, Summary};
 use self::deploy::{DeployFlake, ParseFlakeError};
+use futures_util::future::join_all;
 use futures_util::stream::{StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
 use serde::Serialize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use thiserror::Error;
 use tokio::process::Command;
@@ -36,15 +42,108 @@ pub struct Opts {
     /// Use the interactive prompt before deployment
     #[clap(short, long)]
     interactive: bool,
+    /// Never prompt (for sudo passwords, host keys or confirmation), failing with what to configure instead, e.g. in CI
+    #[clap(long, conflicts_with = "interactive")]
+    non_interactive: bool,
     /// Extra arguments to be passed to nix build
     extra_build_args: Vec<String>,
 
-    /// Print debug logs to output
+    /// Print debug logs to output (same as -v)
     #[clap(short, long)]
     debug_logs: bool,
+    /// Increase logging verbosity: -v prints debug logs, -vv adds trace logs of deploy-rs, -vvv trace logs of everything
+    #[clap(short, long, parse(from_occurrences))]
+    verbose: u8,
+    /// Per-module log levels, e.g. `push=debug,deploy=info` (modules are relative to deploy-rs)
+    #[clap(long)]
+    log_filter: Option<String>,
+    /// Only print errors and a summary at the end (everything is still logged to --log-dir)
+    #[clap(short, long, conflicts_with = "interactive")]
+    quiet: bool,
+    /// Prefix the output of each node with its name only, without colors (implied if stderr is not a terminal)
+    #[clap(long)]
+    plain: bool,
+    /// How to show the progress and output of the nodes (human, plain, json, github or quiet), overriding --plain and --quiet
+    #[clap(long, possible_values = deploy::render::OUTPUT_FORMATS)]
+    output_format: Option<OutputFormat>,
+    /// Print the progress of the nodes as JSON lines on stdout, for CI (`--output-format json`)
+    #[clap(long, conflicts_with = "output-format")]
+    json: bool,
+    /// Write a JUnit XML report with a test case per profile to this file
+    #[clap(long)]
+    report_junit: Option<PathBuf>,
+    /// Print how long each phase of each profile took at the end, with the CPU time, peak memory and bytes transferred of its builds and pushes
+    #[clap(long)]
+    timings: bool,
+    /// Write every command run (locally, and over SSH on the nodes) to this file with timestamps and exit codes, redacted, as a shell script for review
+    #[clap(long)]
+    trace_commands: Option<PathBuf>,
+    /// Directory to write an archive with the events, commands, plan and logs of each failed node to, for attaching to issues
+    #[clap(long, default_value = ".")]
+    failure_bundle_dir: PathBuf,
+    /// Don't write archives for failed nodes
+    #[clap(long)]
+    no_failure_bundles: bool,
+    /// Abort on errors in non-critical functionality (e.g. printing the deployment plan) instead of warning
+    #[clap(long)]
+    strict: bool,
+    /// Deploy profiles even if `allowedDeployers` doesn't include you (recorded in an audit log)
+    #[clap(long)]
+    override_restrictions: bool,
+    /// Maximum number of simultaneous SSH sessions and `nix copy`s across all nodes (the default matches OpenSSH's `MaxStartups`)
+    #[clap(long, default_value = "10")]
+    max_connections: usize,
+    /// Activate the profiles of this many nodes at the same time, in waves (each waiting for its activations to be confirmed), overriding `rollout.maxUnavailable`
+    #[clap(long)]
+    max_unavailable: Option<usize>,
+    /// Maximum number of closures built at the same time; nodes are pushed to and activated while the others build
+    #[clap(long, default_value = "1")]
+    max_builds: usize,
+    /// Instead of deploying, simulate a deployment to this many nodes with mocked commands and report the overhead of deploy-rs itself
+    #[clap(long)]
+    simulate_nodes: Option<usize>,
+    /// Apply the overrides of this environment (from `environments` in the deployment)
+    #[clap(long)]
+    env: Option<String>,
+    /// JSON file with variables for the evaluation, replacing the `deploy-vars` flake input (or passed as `deployVars` without flakes)
+    #[clap(long)]
+    vars: Option<PathBuf>,
+    /// Only deploy the closures recorded in this manifest (see `deploy plan`), passing on their signatures
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+    /// Skip profiles whose closure is already the current generation on the node (not building, pushing or activating them)
+    #[clap(long)]
+    skip_if_unchanged: bool,
+    /// Skip nodes none of whose profiles changed since they were last deployed (according to the deployment history, or else the node)
+    #[clap(long)]
+    changed_only: bool,
+    /// Deploy to nodes under maintenance (`maintenance` in the flake, or `deploy maintenance set`) as well instead of skipping them
+    #[clap(long)]
+    include_maintenance: bool,
+    /// Only deploy to the nodes with any of these tags (in their `tags`), comma-separated; `web+prod` selects the nodes with both
+    #[clap(long)]
+    tags: Option<deploy::TagFilter>,
+    /// Print which settings of the flake the command line overrides, for each node, before deploying
+    #[clap(long)]
+    explain_overrides: bool,
+    /// Build and push the profiles without activating them, e.g. to copy big closures ahead of time (see `deploy schedule push`)
+    #[clap(long)]
+    push_only: bool,
+    /// Only deploy the profiles the last deployment didn't deploy, with its targets unless given (skipping checks, and builds of unchanged closures)
+    #[clap(long)]
+    retry_failed: bool,
+    /// Where to keep the state of deploy-rs (last deployment, history, schedule, audit log) instead of `$XDG_STATE_HOME/deploy-rs`
+    #[clap(long)]
+    state_dir: Option<PathBuf>,
+    /// Label for the new generations (`git describe` of the flake by default), shown by `activate-rs list` and selectable with `activate-rs rollback --label`
+    #[clap(long)]
+    label: Option<String>,
     /// Directory to print logs to (including the background activation process)
     #[clap(long)]
     log_dir: Option<String>,
+    /// Encrypt the log file in --log-dir to this age recipient (an `age1...` or SSH public key), can be given multiple times; read it with `deploy decrypt-logs`
+    #[clap(long = "log-recipient", number_of_values = 1, requires = "log-dir")]
+    log_recipients: Vec<String>,
 
     /// Keep the build outputs of each built profile
     #[clap(short, long)]
@@ -56,6 +155,9 @@ pub struct Opts {
     /// Skip the automatic pre-build checks
     #[clap(short, long)]
     skip_checks: bool,
+    /// Only build the `deploy-*` checks of the flake (see `deployChecks`) before deploying, instead of running all of them with `nix flake check` after
+    #[clap(long, conflicts_with = "skip-checks")]
+    deploy_checks_only: bool,
 
     /// Build on remote host
     #[clap(long)]
@@ -67,18 +169,26 @@ pub struct Opts {
     /// Override the profile user with the given value
     #[clap(long)]
     profile_user: Option<String>,
-    /// Override the SSH options used
-    #[clap(long, allow_hyphen_values = true)]
-    ssh_opts: Option<String>,
-    /// Override if the connecting to the target node should be considered fast
+    /// Override the SSH options used, split like a shell would (can be given multiple times)
+    #[clap(long, allow_hyphen_values = true, number_of_values = 1)]
+    ssh_opts: Vec<String>,
+    /// Override the host to reach the nodes through (`[user@]host[:port]`, as with `ssh -J`)
     #[clap(long)]
-    fast_connection: Option<bool>,
+    jump_host: Option<String>,
+    /// Override if the connecting to the target node should be considered fast (true, false or auto to measure it)
+    #[clap(long)]
+    fast_connection: Option<deploy::data::FastConnection>,
     /// Override if a rollback should be attempted if activation fails
     #[clap(long)]
     auto_rollback: Option<bool>,
-    /// Override hostname used for the node
-    #[clap(long)]
+    /// Override hostname used for the node, e.g. to deploy to a rescue system or a cloned machine
+    /// (only when deploying a single node)
+    #[clap(long, visible_alias = "target-host")]
     hostname: Option<String>,
+    /// Don't check the host key of `--hostname` (nor remember it), for machines whose key isn't
+    /// known yet
+    #[clap(long, requires = "hostname")]
+    skip_host_key_check: bool,
     /// Make activation wait for confirmation, or roll back after a period of time
     #[clap(long)]
     magic_rollback: Option<bool>,
@@ -94,6 +204,9 @@ pub struct Opts {
     /// Show what will be activated on the machines
     #[clap(long)]
     dry_activate: bool,
+    /// Fail if the report of any dry activation has warnings
+    #[clap(long, requires = "dry-activate")]
+    fail_on_dry_activate_warnings: bool,
     /// Don't activate, but update the boot loader to boot into the new profile
     #[clap(long)]
     boot: bool,
@@ -106,22 +219,283 @@ pub struct Opts {
     /// Prompt for sudo password during activation.
     #[clap(long)]
     interactive_sudo: Option<bool>,
+
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(Clap, Debug, Clone)]
+enum SubCommand {
+    Doctor(DoctorOpts),
+    Plan(PlanOpts),
+    Schedule(ScheduleOpts),
+    SetupKeys(SetupKeysOpts),
+    PruneHistory(PruneHistoryOpts),
+    DecryptLogs(DecryptLogsOpts),
+    Clone(CloneOpts),
+    Watch(WatchOpts),
+    Logs(LogsOpts),
+    Confirm(ConfirmOpts),
+    Rollback(RollbackOpts),
+    Import(ImportOpts),
+    Maintenance(MaintenanceOpts),
+    Locks(LocksOpts),
+}
+
+/// Check the local environment and the connectivity to each node, printing a report with hints
+#[derive(Clap, Debug, Clone)]
+struct DoctorOpts {
+    /// The flake to check
+    target: Option<String>,
+    /// Only run the local checks, don't connect to the nodes
+    #[clap(long)]
+    skip_remote: bool,
+}
+
+/// Write a manifest of the closures that are going to be deployed, optionally signed
+#[derive(Clap, Debug, Clone)]
+struct PlanOpts {
+    /// The flake to plan the deployment of
+    target: Option<String>,
+    /// Sign the closure of every profile with this SSH key (its public key is enough if the private key is in the SSH agent)
+    #[clap(long)]
+    sign: Option<PathBuf>,
+    /// Write the manifest to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Instead of the manifest, print what changed since the last deployment (`last`) or the run with this id in the deployment history
+    #[clap(long)]
+    against: Option<String>,
+}
+
+/// Install SSH public keys for `sshUser` on the nodes, logging in as `bootstrapSshUser` or with a password, and check that they work
+#[derive(Clap, Debug, Clone)]
+struct SetupKeysOpts {
+    /// The flake with the nodes to set up
+    target: Option<String>,
+    /// A public key to install, can be given multiple times (defaults to ~/.ssh/id_ed25519.pub, id_ecdsa.pub or id_rsa.pub)
+    #[clap(long = "key")]
+    keys: Vec<PathBuf>,
+}
+
+/// Schedule pushes for later, e.g. to copy big closures to the nodes off-peak
+#[derive(Clap, Debug, Clone)]
+struct ScheduleOpts {
+    #[clap(subcommand)]
+    action: ScheduleAction,
+}
+
+/// Remove old entries from the deployment history (by default those older than 90 days)
+#[derive(Clap, Debug, Clone)]
+struct PruneHistoryOpts {
+    /// Remove the entries older than this, e.g. `30d` or `12h`
+    #[clap(long, parse(try_from_str = deploy::state::parse_age))]
+    older_than: Option<std::time::Duration>,
+    /// Remove the oldest entries until the history takes at most this much space, e.g. `10M`
+    #[clap(long, parse(try_from_str = deploy::state::parse_size))]
+    max_size: Option<u64>,
+    /// Only print what would be removed
+    #[clap(long)]
+    dry_run: bool,
+}
+
+/// Deploy the profiles of a node to other machines with the same settings, e.g. to image a batch
+/// of identical machines
+#[derive(Clap, Debug, Clone)]
+struct CloneOpts {
+    /// The template node (and optionally the profile), e.g. `.#node` or `.#node.system`
+    target: String,
+    /// The addresses of the machines to deploy to, separated by commas
+    #[clap(long, required = true, use_delimiter = true)]
+    to: Vec<String>,
+}
+
+/// Deploy, and redeploy whenever the flake changes, e.g. to iterate on a staging VM
+#[derive(Clap, Debug, Clone)]
+struct WatchOpts {
+    /// The flake to watch and deploy (optionally with node and profile), it has to be in a local directory
+    target: Option<String>,
+    /// Only deploy to the nodes with this tag (in their `tags`)
+    #[clap(long)]
+    tag: Option<String>,
+    /// How long the flake has to stay unchanged before it is deployed, in milliseconds
+    #[clap(long, default_value = "500")]
+    debounce: u64,
+}
+
+/// Print the log of the last activation on a node, as kept on the node by `activate-rs`
+#[derive(Clap, Debug, Clone)]
+struct LogsOpts {
+    /// The node to fetch the log from, e.g. `.#node`
+    target: String,
+    /// Print the log of the activation before the last one
+    #[clap(long)]
+    previous: bool,
+}
+
+/// Print the `deploy` output for the nodes of a Colmena hive, a morph network or a NixOps network,
+/// as a starting point for migrating to deploy-rs
+#[derive(Clap, Debug, Clone)]
+struct ImportOpts {
+    /// The tool the network file is written for (colmena, morph or nixops)
+    #[clap(long, possible_values = deploy::import::FOREIGN_TOOLS)]
+    from: deploy::import::ForeignTool,
+    /// The network file, e.g. `./hive.nix`
+    file: PathBuf,
+    /// Print the settings of the nodes as JSON instead of Nix
+    #[clap(long)]
+    json: bool,
+    /// Write to this file instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Mark nodes as under maintenance, so deployments skip them unless `--include-maintenance` is given
+#[derive(Clap, Debug, Clone)]
+struct MaintenanceOpts {
+    #[clap(subcommand)]
+    action: MaintenanceAction,
+}
+
+#[derive(Clap, Debug, Clone)]
+enum MaintenanceAction {
+    Set(MaintenanceSetOpts),
+    Clear(MaintenanceClearOpts),
+    List,
+}
+
+/// Mark nodes as under maintenance
+#[derive(Clap, Debug, Clone)]
+struct MaintenanceSetOpts {
+    /// The names of the nodes
+    #[clap(required = true)]
+    nodes: Vec<String>,
+    /// When the maintenance ends, `HH:MM` (the next time it comes around) or `YYYY-MM-DD HH:MM`, in local time (until cleared by default)
+    #[clap(long)]
+    until: Option<String>,
+    /// Why the nodes are under maintenance, shown when skipping them
+    #[clap(long)]
+    reason: Option<String>,
+}
+
+/// Remove the maintenance marks of nodes
+#[derive(Clap, Debug, Clone)]
+struct MaintenanceClearOpts {
+    /// The names of the nodes
+    #[clap(required = true)]
+    nodes: Vec<String>,
+}
+
+/// Show or remove the locks on nodes (`lock`) shared by everyone deploying them
+#[derive(Clap, Debug, Clone)]
+struct LocksOpts {
+    #[clap(subcommand)]
+    action: LocksAction,
+}
+
+#[derive(Clap, Debug, Clone)]
+enum LocksAction {
+    List(LocksListOpts),
+    Steal(LocksStealOpts),
+}
+
+/// List the locks held on the nodes of a flake
+#[derive(Clap, Debug, Clone)]
+struct LocksListOpts {
+    /// The flake whose nodes to list the locks of
+    target: Option<String>,
+}
+
+/// Remove the locks of nodes, e.g. left behind by a deployment that was killed
+#[derive(Clap, Debug, Clone)]
+struct LocksStealOpts {
+    /// The node (or flake, for all of its nodes) to remove the locks of, e.g. `.#node`
+    target: String,
+}
+
+/// Confirm the activations a deployment that crashed or was killed left waiting for confirmation
+#[derive(Clap, Debug, Clone)]
+struct ConfirmOpts {
+    /// The id of the run to complete the confirmations of, as logged when confirming failed
+    #[clap(long)]
+    resume: String,
+}
+
+/// Roll the profile of a node back to one of its earlier generations, showing the closure diff
+/// first
+#[derive(Clap, Debug, Clone)]
+#[clap(group(
+    clap::ArgGroup::new("choice")
+        .required(true)
+        .multiple(false)
+        .args(&["interactive","generation"])
+))]
+struct RollbackOpts {
+    /// The profile to roll back, e.g. `.#node.system` (or `.#node` if it has a single profile)
+    target: String,
+    /// Pick the generation from a list of the generations with their dates and labels
+    #[clap(long)]
+    interactive: bool,
+    /// The number of the generation to roll back to
+    #[clap(long)]
+    generation: Option<u64>,
+}
+
+/// Print log files written with --log-recipient, decrypted
+#[derive(Clap, Debug, Clone)]
+struct DecryptLogsOpts {
+    /// The age identity file (or SSH private key) of one of the recipients
+    #[clap(short, long)]
+    identity: PathBuf,
+    /// The encrypted log files
+    #[clap(required = true)]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Clap, Debug, Clone)]
+enum ScheduleAction {
+    Push(SchedulePushOpts),
+    List,
+    Cancel(ScheduleCancelOpts),
+    Run,
+}
+
+/// Record a push of the profiles (`deploy --push-only`) to be run by `deploy schedule run` at the given time
+#[derive(Clap, Debug, Clone)]
+struct SchedulePushOpts {
+    /// When to push, `HH:MM` (the next time it comes around) or `YYYY-MM-DD HH:MM`, in local time
+    #[clap(long)]
+    at: String,
+    /// The flake to push
+    #[clap(group = "push")]
+    target: Option<String>,
+    /// A list of flakes to push alternatively
+    #[clap(long, group = "push")]
+    targets: Option<Vec<String>>,
+}
+
+/// Remove a scheduled push
+#[derive(Clap, Debug, Clone)]
+struct ScheduleCancelOpts {
+    /// The id of the push, as shown by `deploy schedule list`
+    id: String,
 }
 
 /// Returns if the available Nix installation supports flakes
-async fn test_flake_support() -> Result<bool, std::io::Error> {
+pub async fn test_flake_support() -> Result<bool, std::io::Error> {
     debug!("Checking for flake support");
 
-    Ok(Command::new("nix")
-        .arg("eval")
-        .arg("--expr")
-        .arg("builtins.getFlake")
-        // This will error on some machines "intentionally", and we don't really need that printing
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .await?
-        .success())
+    Ok(deploy::trace::status(
+        Command::new("nix")
+            .arg("eval")
+            .arg("--expr")
+            .arg("builtins.getFlake")
+            // This will error on some machines "intentionally", and we don't really need that printing
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )
+    .await?
+    .success())
 }
 
 #[derive(Error, Debug)]
@@ -130,13 +504,126 @@ pub enum CheckDeploymentError {
     NixCheck(#[from] std::io::Error),
     #[error("Nix checking command resulted in a bad exit code: {0:?}")]
     NixCheckExit(Option<i32>),
+    #[error("Listing the deploy checks of the flake resulted in a bad exit code: {0:?}")]
+    ListDeployChecksExit(Option<i32>),
+    #[error("Failed to parse the deploy checks of the flake: {0}")]
+    ParseDeployChecks(serde_json::Error),
+    #[error("Deploy check `{0}` failed, refusing to deploy (use --skip-checks to deploy anyway): exit code {1:?}")]
+    DeployCheckExit(String, Option<i32>),
+}
+
+/// The current system and the names of the `deploy-*` checks the flake has for it
+#[derive(serde::Deserialize, Debug)]
+struct DeployChecks {
+    system: String,
+    names: Vec<String>,
+}
+
+/// Builds the `deploy-*` checks of the flake for the current system (those of `deployChecks`),
+/// before anything else is checked or deployed
+async fn run_deploy_checks(
+    supports_flakes: bool,
+    repo: &str,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    quiet: bool,
+) -> Result<(), CheckDeploymentError> {
+    let filter = r#"builtins.filter (n: builtins.substring 0 7 n == "deploy-")"#;
+
+    if !supports_flakes {
+        let checks = format!("(let r = import {}/.; x = (if builtins.isFunction r then (r {}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}})", repo, deploy::vars::call_args_expr(vars));
+        let check_output = deploy::trace::output(
+            Command::new("nix-build")
+                .arg("--no-out-link")
+                .arg("-E")
+                .arg(format!("let checks = {0}; in map (n: checks.${{n}}) ({1} (builtins.attrNames checks))", checks, filter))
+                .args(extra_build_args)
+                .stdout(deploy::child_stdio(quiet))
+                .stderr(deploy::child_stdio(quiet)),
+        )
+        .await?;
+        deploy::log_child_output("nix-build of the deploy checks", &check_output);
+
+        return match check_output.status.code() {
+            Some(0) => Ok(()),
+            a => Err(CheckDeploymentError::DeployCheckExit("deploy-*".to_string(), a)),
+        };
+    }
+
+    // `builtins.currentSystem` needs an impure evaluation
+    let mut list_command = Command::new("nix");
+    list_command
+        .arg("eval")
+        .arg("--impure")
+        .arg("--json")
+        .arg(format!("{}#checks", repo))
+        .arg("--apply")
+        .arg(format!(
+            "checks: {{ system = builtins.currentSystem; names = {} (builtins.attrNames (checks.${{builtins.currentSystem}} or {{ }})); }}",
+            filter
+        ));
+    if let Some(vars) = vars {
+        list_command.args(vars.flake_args());
+    }
+    let list_output = deploy::trace::output(list_command.args(extra_build_args)).await?;
+    let deploy_checks: DeployChecks = match list_output.status.code() {
+        Some(0) => serde_json::from_slice(&list_output.stdout).map_err(CheckDeploymentError::ParseDeployChecks)?,
+        _ if String::from_utf8_lossy(&list_output.stderr).contains("does not provide attribute") => {
+            debug!("The flake in {} has no checks", repo);
+            return Ok(());
+        }
+        a => {
+            deploy::log_child_output("nix eval of the deploy checks", &list_output);
+            return Err(CheckDeploymentError::ListDeployChecksExit(a));
+        }
+    };
+
+    if deploy_checks.names.is_empty() {
+        warn!("The flake in {} has no deploy checks for {}, add them with deploy-rs.lib.<system>.deployChecks", repo, deploy_checks.system);
+    }
+    for name in &deploy_checks.names {
+        info!("Building deploy check `{}`", name);
+
+        let mut build_command = Command::new("nix");
+        build_command
+            .arg("build")
+            .arg("--no-link")
+            .arg(format!("{}#checks.{}.{}", repo, deploy_checks.system, name));
+        if let Some(vars) = vars {
+            build_command.args(vars.flake_args());
+        }
+        let build_output = deploy::trace::output(
+            build_command
+                .args(extra_build_args)
+                .stdout(deploy::child_stdio(quiet))
+                .stderr(deploy::child_stdio(quiet)),
+        )
+        .await?;
+        deploy::log_child_output("nix build of a deploy check", &build_output);
+
+        match build_output.status.code() {
+            Some(0) => (),
+            a => return Err(CheckDeploymentError::DeployCheckExit(name.clone(), a)),
+        }
+    }
+
+    Ok(())
 }
 
 async fn check_deployment(
     supports_flakes: bool,
     repo: &str,
     extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    quiet: bool,
+    deploy_checks_only: bool,
 ) -> Result<(), CheckDeploymentError> {
+    info!("Running deploy checks for flake in {}", repo);
+    run_deploy_checks(supports_flakes, repo, extra_build_args, vars, quiet).await?;
+    if deploy_checks_only {
+        return Ok(());
+    }
+
     info!("Running checks for flake in {}", repo);
 
     let mut check_command = match supports_flakes {
@@ -146,17 +633,26 @@ async fn check_deployment(
 
     if supports_flakes {
         check_command.arg("flake").arg("check").arg(repo);
+        if let Some(vars) = vars {
+            check_command.args(vars.flake_args());
+        }
     } else {
         check_command.arg("-E")
                 .arg("--no-out-link")
-                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {{}}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo));
+                .arg(format!("let r = import {}/.; x = (if builtins.isFunction r then (r {}) else r); in if x ? checks then x.checks.${{builtins.currentSystem}} else {{}}", repo, deploy::vars::call_args_expr(vars)));
     }
 
     check_command.args(extra_build_args);
 
-    let check_status = check_command.status().await?;
+    let check_output = deploy::trace::output(
+        check_command
+            .stdout(deploy::child_stdio(quiet))
+            .stderr(deploy::child_stdio(quiet)),
+    )
+    .await?;
+    deploy::log_child_output("nix flake check", &check_output);
 
-    match check_status.code() {
+    match check_output.status.code() {
         Some(0) => (),
         a => return Err(CheckDeploymentError::NixCheckExit(a)),
     };
@@ -164,6 +660,94 @@ async fn check_deployment(
     Ok(())
 }
 
+/// A function of `deploy` leaving only `node`, with the profiles `profiles` evaluates to (with
+/// the node as `node`). If `node` is a guest, the node it runs on is kept too, without profiles
+/// or other guests.
+fn select_node_expr(node: &str, profiles: &str) -> String {
+    format!(
+        r#"
+          deploy:
+          let
+            only = node: node // {{
+              profiles = {1};
+              guests = {{ }};
+            }};
+            hosts = builtins.filter
+              (host: (deploy.nodes.${{host}}.guests or {{ }}) ? "{0}")
+              (builtins.attrNames deploy.nodes);
+          in
+          (deploy // {{
+            nodes =
+              if deploy.nodes ? "{0}" then {{
+                "{0}" = only deploy.nodes."{0}";
+              }} else builtins.listToAttrs (map (host: {{
+                name = host;
+                value = deploy.nodes.${{host}} // {{
+                  profiles = {{ }};
+                  guests = {{
+                    "{0}" = only deploy.nodes.${{host}}.guests."{0}";
+                  }};
+                }};
+              }}) hosts);
+          }})
+        "#,
+        node, profiles
+    )
+}
+
+/// What a `deploy.nodesExpr` generating nodes is called with
+#[derive(Debug, Default)]
+pub struct NodesExprArgs<'a> {
+    /// The environment selected with `--env`
+    pub environment: Option<&'a str>,
+    /// The tags the deployment is limited to
+    pub tags: &'a [String],
+}
+
+impl<'a> NodesExprArgs<'a> {
+    pub fn new(cmd_overrides: &'a deploy::CmdOverrides, tags: &'a [String]) -> Self {
+        NodesExprArgs {
+            environment: cmd_overrides.environment.as_deref(),
+            tags,
+        }
+    }
+}
+
+/// `s` as a Nix string literal
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+            .replace('\n', "\\n")
+    )
+}
+
+/// A function of `deploy` adding the nodes generated by `deploy.nodesExpr` to `deploy.nodes`.
+/// It is either a function, called with `args`, or a derivation (or path) of a JSON file.
+fn expand_nodes_expr(args: &NodesExprArgs<'_>) -> String {
+    let tags: Vec<String> = args.tags.iter().map(|t| nix_string(t)).collect();
+    format!(
+        r#"
+          deploy:
+          let
+            generated =
+              if builtins.isFunction deploy.nodesExpr
+              then deploy.nodesExpr {{ environment = {0}; tags = [ {1} ]; }}
+              else builtins.fromJSON (builtins.readFile deploy.nodesExpr);
+          in
+          if deploy ? nodesExpr then
+            builtins.removeAttrs deploy [ "nodesExpr" ] // {{
+              nodes = (deploy.nodes or {{ }}) // generated;
+            }}
+          else deploy
+        "#,
+        args.environment.map_or_else(|| "null".to_string(), nix_string),
+        tags.join(" ")
+    )
+}
+
 #[derive(Error, Debug)]
 pub enum GetDeploymentDataError {
     #[error("Failed to execute nix eval command: {0}")]
@@ -178,13 +762,20 @@ pub enum GetDeploymentDataError {
     DecodeJson(#[from] serde_json::error::Error),
     #[error("Impossible happened: profile is set but node is not")]
     ProfileNoNode,
+    #[error("{0}")]
+    Templates(#[from] deploy::data::TemplateError),
+    #[error("{0}")]
+    Secret(#[from] deploy::secret_resolver::SecretError),
 }
 
 /// Evaluates the Nix in the given `repo` and return the processed Data from it
-async fn get_deployment_data(
+pub async fn get_deployment_data(
     supports_flakes: bool,
     flakes: &[deploy::DeployFlake<'_>],
     extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    nodes_args: &NodesExprArgs<'_>,
+    quiet: bool,
 ) -> Result<Vec<deploy::data::Data>, GetDeploymentDataError> {
     futures_util::stream::iter(flakes).then(|flake| async move {
 
@@ -202,44 +793,32 @@ async fn get_deployment_data(
             .arg(format!("{}#deploy", flake.repo))
             // We use --apply instead of --expr so that we don't have to deal with builtins.getFlake
             .arg("--apply");
-        match (&flake.node, &flake.profile) {
+        let select = match (&flake.node, &flake.profile) {
             (Some(node), Some(profile)) => {
                 // Ignore all nodes and all profiles but the one we're evaluating
-                c.arg(format!(
-                    r#"
-                      deploy:
-                      (deploy // {{
-                        nodes = {{
-                          "{0}" = deploy.nodes."{0}" // {{
-                            profiles = {{
-                              inherit (deploy.nodes."{0}".profiles) "{1}";
-                            }};
-                          }};
-                        }};
-                      }})
-                     "#,
-                    node, profile
-                ))
+                select_node_expr(
+                    node,
+                    &format!(r#"{{ inherit (node.profiles) "{}"; }}"#, profile),
+                )
             }
             (Some(node), None) => {
                 // Ignore all nodes but the one we're evaluating
-                c.arg(format!(
-                    r#"
-                      deploy:
-                      (deploy // {{
-                        nodes = {{
-                          inherit (deploy.nodes) "{}";
-                        }};
-                      }})
-                    "#,
-                    node
-                ))
+                select_node_expr(node, "node.profiles")
             }
             (None, None) => {
                 // We need to evaluate all profiles of all nodes anyway, so just do it strictly
-                c.arg("deploy: deploy")
+                "deploy: deploy".to_string()
             }
             (None, Some(_)) => return Err(GetDeploymentDataError::ProfileNoNode),
+        };
+        // Generated nodes are selected from like the others
+        c.arg(format!(
+            "deploy: ({}) (({}) deploy)",
+            select,
+            expand_nodes_expr(nodes_args)
+        ));
+        if let Some(vars) = vars {
+            c.args(vars.flake_args());
         }
     } else {
         c
@@ -248,29 +827,38 @@ async fn get_deployment_data(
             .arg("--json")
             .arg("--eval")
             .arg("-E")
-            .arg(format!("let r = import {}/.; in if builtins.isFunction r then (r {{}}).deploy else r.deploy", flake.repo))
-    };
+            .arg(format!("({}) (let r = import {}/.; in if builtins.isFunction r then (r {}).deploy else r.deploy)", expand_nodes_expr(nodes_args), flake.repo, deploy::vars::call_args_expr(vars)));
+    }
 
     c.args(extra_build_args);
 
-    let build_child = c
-        .stdout(Stdio::piped())
-        .spawn()
+    let build_child = deploy::trace::spawn(c.stdout(Stdio::piped()).stderr(deploy::child_stdio(quiet)))
         .map_err(GetDeploymentDataError::NixEval)?;
 
-    let build_output = build_child
-        .wait_with_output()
+    let mut build_output = deploy::trace::wait_with_output(build_child)
         .await
         .map_err(GetDeploymentDataError::NixEvalOut)?;
 
+    // The evaluation result itself is not worth logging
+    let data_json = std::mem::take(&mut build_output.stdout);
+    deploy::log_child_output("nix eval", &build_output);
+
     match build_output.status.code() {
         Some(0) => (),
         a => return Err(GetDeploymentDataError::NixEvalExit(a)),
     };
 
-    let data_json = String::from_utf8(build_output.stdout)?;
+    let data_json = String::from_utf8(data_json)?;
+
+    let mut data_json: serde_json::Value = serde_json::from_str(&data_json)?;
+    deploy::secret_resolver::resolve(&mut data_json).await?;
+
+    let mut data: deploy::data::Data = serde_json::from_value(data_json)?;
+    data.apply_templates()?;
+    data.expand_guests()?;
+    data.expand_standbys()?;
 
-    Ok(serde_json::from_str(&data_json)?)
+    Ok(data)
 }).try_collect().await
 }
 
@@ -278,6 +866,7 @@ async fn get_deployment_data(
 struct PromptPart<'a> {
     user: &'a str,
     ssh_user: &'a str,
+    ssh_user_source: deploy::SshUserSource,
     path: &'a str,
     hostname: &'a str,
     ssh_opts: &'a [String],
@@ -293,19 +882,20 @@ fn print_deployment(
     let mut part_map: HashMap<String, HashMap<String, PromptPart>> = HashMap::new();
 
     for (_, data, defs) in parts {
+        let part = PromptPart {
+            user: &defs.profile_user,
+            ssh_user: &defs.ssh_user,
+            ssh_user_source: defs.ssh_user_source,
+            path: &data.profile.profile_settings.path,
+            hostname: data.hostname,
+            ssh_opts: &data.merged_settings.ssh_opts,
+        };
+        emit(data.node_name, data.profile_name, EventKind::Planned(toml::to_string(&part)?));
+
         part_map
             .entry(data.node_name.to_string())
-            .or_insert_with(HashMap::new)
-            .insert(
-                data.profile_name.to_string(),
-                PromptPart {
-                    user: &defs.profile_user,
-                    ssh_user: &defs.ssh_user,
-                    path: &data.profile.profile_settings.path,
-                    hostname: &data.node.node_settings.hostname,
-                    ssh_opts: &data.merged_settings.ssh_opts,
-                },
-            );
+            .or_default()
+            .insert(data.profile_name.to_string(), part);
     }
 
     let toml = toml::to_string(&part_map)?;
@@ -314,6 +904,49 @@ fn print_deployment(
 
     Ok(())
 }
+/// Asks every distinct `approvalCommand` to approve the deployment of the profiles it is set for
+async fn request_approvals(
+    parts: &[(
+        &deploy::DeployFlake<'_>,
+        deploy::DeployData<'_>,
+        deploy::DeployDefs,
+    )],
+    dry_activate: bool,
+    boot: bool,
+) -> Result<(), deploy::approval::ApprovalError> {
+    let mut commands: Vec<&str> = Vec::new();
+    for (_, data, _) in parts {
+        if let Some(command) = data.merged_settings.approval_command.as_deref() {
+            if !commands.contains(&command) {
+                commands.push(command);
+            }
+        }
+    }
+
+    for command in commands {
+        let plan = deploy::approval::Plan {
+            dry_activate,
+            boot,
+            profiles: parts
+                .iter()
+                .filter(|(_, data, _)| data.merged_settings.approval_command.as_deref() == Some(command))
+                .map(|(_, data, defs)| deploy::approval::PlannedProfile {
+                    node: data.node_name,
+                    profile: data.profile_name,
+                    hostname: data.hostname,
+                    ssh_user: &defs.ssh_user,
+                    user: &defs.profile_user,
+                    path: &data.profile.profile_settings.path,
+                })
+                .collect(),
+        };
+
+        deploy::approval::request_approval(command, &plan).await?;
+    }
+
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum PromptDeploymentError {
     #[error("Failed to make printable TOML of deployment: {0}")]
@@ -334,8 +967,12 @@ fn prompt_deployment(
     )],
 ) -> Result<(), PromptDeploymentError> {
     print_deployment(parts)?;
+    prompt_yes("Are you sure you want to deploy these profiles?", "Do you want to deploy these profiles?")
+}
 
-    info!("Are you sure you want to deploy these profiles?");
+/// Asks `question`, failing unless the answer is yes (asking `again` if it was unclear)
+fn prompt_yes(question: &str, again: &str) -> Result<(), PromptDeploymentError> {
+    info!("{}", question);
     print!("> ");
 
     stdout()
@@ -349,7 +986,7 @@ fn prompt_deployment(
 
     if !yn::yes(&s) {
         if yn::is_somewhat_yes(&s) {
-            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". Do you want to deploy these profiles?");
+            info!("Sounds like you might want to continue, to be more clear please just say \"yes\". {}", again);
             print!("> ");
 
             stdout()
@@ -378,32 +1015,140 @@ fn prompt_deployment(
     Ok(())
 }
 
+/// A prompt deploy can't show, with what to configure instead
+#[derive(Error, Debug)]
+pub enum NonInteractiveError {
+    #[error("Node {0} needs a sudo password (`interactiveSudo`), but {2}; allow `{1}` to use sudo on it without a password (NOPASSWD) and unset `interactiveSudo`")]
+    SudoPassword(String, String, &'static str),
+    #[error("Choosing the generation to roll back to needs a prompt, but --non-interactive was given; pass --generation instead")]
+    RollbackChoice,
+    #[error("Rollback --interactive asks for confirmation, but --non-interactive was given; leave out one of them")]
+    RollbackConfirmation,
+    #[error("The profiles to confirm need a sudo password (`interactiveSudo`), but {0}")]
+    ConfirmSudoPassword(&'static str),
+}
+
+const NON_INTERACTIVE: &str = "--non-interactive was given";
+const NO_TERMINAL: &str = "there is no terminal to ask for it on";
+
+/// Makes sudo read the password from stdin, and asks for it
+fn prompt_sudo_password(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &mut deploy::DeployDefs,
+    non_interactive: bool,
+) -> Result<(), NonInteractiveError> {
+    let ssh_user = deploy_defs.ssh_user.clone();
+    let unavailable = |reason| NonInteractiveError::SudoPassword(deploy_data.node_name.to_string(), ssh_user, reason);
+    if non_interactive {
+        return Err(unavailable(NON_INTERACTIVE));
+    }
+
+    warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+
+    if deploy_data.merged_settings.sudo.is_some() {
+        warn!("Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' option. Deployment may fail if the custom command ignores stdin.");
+    } else {
+        // this configures sudo to hide the password prompt and accept input from stdin
+        // at the time of writing, deploy_defs.sudo defaults to 'sudo -u root' when using user=root and sshUser as non-root
+        let original = deploy_defs.sudo.clone().unwrap_or("sudo".to_string());
+        deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
+    }
+
+    info!("You will now be prompted for the sudo password for {}.", deploy_data.hostname);
+    // Fails without a terminal, e.g. in CI, rather than going on with no password
+    let sudo_password = rpassword::prompt_password(format!("(sudo for {}) Password: ", deploy_data.hostname))
+        .map_err(|_| unavailable(NO_TERMINAL))?;
+    deploy::redact::register_secret(&sudo_password);
+
+    deploy_defs.sudo_password = Some(sudo_password);
+    Ok(())
+}
+
 #[derive(Error, Debug)]
 pub enum RunDeployError {
     #[error("Failed to deploy profile to node {0}: {1}")]
     DeployProfile(String, deploy::deploy::DeployProfileError),
-    #[error("Failed to build profile on node {0}: {0}")]
+    #[error("Failed to build profile on node {0}: {1}")]
     BuildProfile(String,  deploy::push::PushProfileError),
-    #[error("Failed to push profile to node {0}: {0}")]
+    #[error("Failed to push profile to node {0}: {1}")]
     PushProfile(String,  deploy::push::PushProfileError),
-    #[error("No profile named `{0}` was found")]
-    ProfileNotFound(String),
-    #[error("No node named `{0}` was found")]
-    NodeNotFound(String),
+    #[error("Refusing to deploy profile to node {0}: {1}")]
+    Provenance(String, deploy::provenance::ProvenanceError),
+    #[error("Profile {1} of node {0} was not pushed, as building the closure it shares with an earlier profile failed")]
+    SharedBuild(String, String),
+    #[error("{0}")]
+    Maintenance(#[from] deploy::maintenance::MaintenanceError),
+    #[error("The migrations of group `{1}` failed on node {0}: {2}")]
+    Migrations(String, String, deploy::migrations::MigrationsError),
+    #[error("The migrations of group `{0}` can't run, pushing the profile to its leader {1} failed")]
+    MigrationsLeader(String, String),
+    #[error("The dry activations reported {0} warning(s)")]
+    DryActivateWarnings(usize),
+    #[error("`--hostname` overrides the address of a single node, but nodes {} were selected", .0.join(", "))]
+    HostnameForManyNodes(Vec<String>),
+    #[error("No profile named `{0}` was found on node `{1}`{2}")]
+    ProfileNotFound(String, String, String),
+    #[error("No node named `{0}` was found{1}")]
+    NodeNotFound(String, String),
     #[error("Profile was provided without a node name")]
     ProfileWithoutNode,
+    #[error("No selected profile of node `{0}` targets container `{1}`")]
+    NoProfilesInContainer(String, String),
     #[error("Error processing deployment definitions: {0}")]
     DeployDataDefs(#[from] deploy::DeployDataDefsError),
+    #[error("{0}")]
+    Environment(#[from] deploy::EnvironmentError),
     #[error("Failed to make printable TOML of deployment: {0}")]
     TomlFormat(#[from] toml::ser::Error),
     #[error("{0}")]
     PromptDeployment(#[from] PromptDeploymentError),
+    #[error("{0}")]
+    NonInteractive(#[from] NonInteractiveError),
+    #[error("{0}")]
+    Lock(#[from] deploy::locks::LockError),
     #[error("Failed to revoke profile for node {0}: {1}")]
     RevokeProfile(String, deploy::deploy::RevokeProfileError),
+    #[error("{0}")]
+    Approval(#[from] deploy::approval::ApprovalError),
+    #[error("{0}")]
+    Restriction(#[from] deploy::restrictions::RestrictionError),
+    #[error("Profile {1} of node {0} is not in the manifest")]
+    NotInManifest(String, String),
+    #[error("The closure of profile {1} of node {0} doesn't match the manifest, which has {2}")]
+    ManifestMismatch(String, String, String),
+    #[error("Profile {1} of node {0} requires a signed manifest, pass one signed with `deploy plan --sign` with --manifest")]
+    UnsignedProfile(String, String),
+    #[error("Failed to check sudo on node {0}: {1}")]
+    SudoPreflight(String, deploy::sudo::SudoPreflightError),
+    #[error("Profile {1} of node {0} can't be deployed: {2}")]
+    ExternalDependencies(String, String, deploy::dependencies::DependencyError),
+    #[error("Profile {1} of node {0} requires an unavailable fact: {2}")]
+    Facts(String, String, deploy::facts::FactsError),
     #[error("Deployment to node {0} failed, rolled back to previous generation")]
     Rollback(String)
 }
 
+fn node_not_found(data: &deploy::data::Data, node_name: &str) -> RunDeployError {
+    let nodes: Vec<&str> = data.nodes.keys().map(String::as_str).collect();
+    RunDeployError::NodeNotFound(
+        node_name.to_string(),
+        deploy::suggest::not_found_hint(node_name, "node", &nodes),
+    )
+}
+
+fn profile_not_found(
+    node_name: &str,
+    node: &deploy::data::Node,
+    profile_name: &str,
+) -> RunDeployError {
+    let profiles: Vec<&str> = node.node_settings.profiles.keys().map(String::as_str).collect();
+    RunDeployError::ProfileNotFound(
+        profile_name.to_string(),
+        node_name.to_string(),
+        deploy::suggest::not_found_hint(profile_name, "profile", &profiles),
+    )
+}
+
 type ToDeploy<'a> = Vec<(
     &'a deploy::DeployFlake<'a>,
     &'a deploy::data::Data,
@@ -411,35 +1156,24 @@ type ToDeploy<'a> = Vec<(
     (&'a str, &'a deploy::data::Profile),
 )>;
 
-async fn run_deploy(
-    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
-    data: Vec<deploy::data::Data>,
-    supports_flakes: bool,
-    check_sigs: bool,
-    interactive: bool,
-    cmd_overrides: &deploy::CmdOverrides,
-    keep_result: bool,
-    result_path: Option<&str>,
-    extra_build_args: &[String],
-    debug_logs: bool,
-    dry_activate: bool,
-    boot: bool,
-    log_dir: &Option<String>,
-    rollback_succeeded: bool,
-) -> Result<(), RunDeployError> {
-    let to_deploy: ToDeploy = deploy_flakes
+/// Resolves the nodes and profiles selected by each flake, in deployment order
+fn select_profiles<'a>(
+    deploy_flakes: &'a [deploy::DeployFlake<'a>],
+    data: &'a [deploy::data::Data],
+) -> Result<ToDeploy<'a>, RunDeployError> {
+    Ok(deploy_flakes
         .iter()
-        .zip(&data)
+        .zip(data)
         .map(|(deploy_flake, data)| {
             let to_deploys: ToDeploy = match (&deploy_flake.node, &deploy_flake.profile) {
                 (Some(node_name), Some(profile_name)) => {
                     let node = match data.nodes.get(node_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                        None => return Err(node_not_found(data, node_name)),
                     };
                     let profile = match node.node_settings.profiles.get(profile_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::ProfileNotFound(profile_name.clone())),
+                        None => return Err(profile_not_found(node_name, node, profile_name)),
                     };
 
                     vec![(
@@ -452,7 +1186,7 @@ async fn run_deploy(
                 (Some(node_name), None) => {
                     let node = match data.nodes.get(node_name) {
                         Some(x) => x,
-                        None => return Err(RunDeployError::NodeNotFound(node_name.clone())),
+                        None => return Err(node_not_found(data, node_name)),
                     };
 
                     let mut profiles_list: Vec<(&str, &deploy::data::Profile)> = Vec::new();
@@ -465,9 +1199,7 @@ async fn run_deploy(
                     {
                         let profile = match node.node_settings.profiles.get(profile_name) {
                             Some(x) => x,
-                            None => {
-                                return Err(RunDeployError::ProfileNotFound(profile_name.clone()))
-                            }
+                            None => return Err(profile_not_found(node_name, node, profile_name)),
                         };
 
                         if !profiles_list.iter().any(|(n, _)| n == profile_name) {
@@ -495,9 +1227,7 @@ async fn run_deploy(
                             let profile = match node.node_settings.profiles.get(profile_name) {
                                 Some(x) => x,
                                 None => {
-                                    return Err(RunDeployError::ProfileNotFound(
-                                        profile_name.clone(),
-                                    ))
+                                    return Err(profile_not_found(node_name, node, profile_name))
                                 }
                             };
 
@@ -518,186 +1248,1889 @@ async fn run_deploy(
                 }
                 (None, Some(_)) => return Err(RunDeployError::ProfileWithoutNode),
             };
-            Ok(to_deploys)
+
+            let to_deploys: ToDeploy = match &deploy_flake.container {
+                Some(container) => {
+                    let in_container: ToDeploy = to_deploys
+                        .into_iter()
+                        .filter(|(_, _, _, (_, profile))| {
+                            profile.profile_settings.container.as_ref() == Some(container)
+                        })
+                        .collect();
+                    if in_container.is_empty() {
+                        return Err(RunDeployError::NoProfilesInContainer(
+                            deploy_flake.node.clone().unwrap_or_default(),
+                            container.clone(),
+                        ));
+                    }
+                    in_container
+                }
+                None => to_deploys,
+            };
+            Ok(with_standbys(to_deploys))
         })
         .collect::<Result<Vec<ToDeploy>, RunDeployError>>()?
         .into_iter()
         .flatten()
-        .collect();
+        .collect())
+}
 
-    let mut parts: Vec<(
-        &deploy::DeployFlake<'_>,
+/// Selects the profiles of the standbys of the selected nodes as well, each right before the same
+/// profile of its node, so it is pushed and staged before the node is activated (and a failure
+/// stops the deployment before the node is touched)
+fn with_standbys(to_deploy: ToDeploy<'_>) -> ToDeploy<'_> {
+    let mut ordered: ToDeploy = Vec::new();
+
+    for &(deploy_flake, data, (node_name, node), (profile_name, profile)) in &to_deploy {
+        if let Some(primary) = &node.node_settings.standby_of {
+            if to_deploy
+                .iter()
+                .any(|(_, _, (n, _), (p, _))| n == primary && *p == profile_name)
+            {
+                continue;
+            }
+        }
+
+        let standby = data
+            .nodes
+            .get_key_value(&deploy::data::standby_name(node_name))
+            .filter(|_| node.node_settings.standby_hostname.is_some());
+        if let Some((standby_name, standby_node)) = standby {
+            if let Some((standby_profile_name, standby_profile)) =
+                standby_node.node_settings.profiles.get_key_value(profile_name)
+            {
+                ordered.push((
+                    deploy_flake,
+                    data,
+                    (standby_name.as_str(), standby_node),
+                    (standby_profile_name.as_str(), standby_profile),
+                ));
+            }
+        }
+
+        ordered.push((deploy_flake, data, (node_name, node), (profile_name, profile)));
+    }
+
+    ordered
+}
+
+/// Applies the checks made before anything connects to the nodes of `to_deploy`: leaves out the
+/// nodes under maintenance (unless `--include-maintenance`, recording them as skipped in
+/// `summary`), checks `allowedDeployers`, and that `manifest` has the profiles (signed, for those
+/// with `requireSignedManifest`)
+async fn gate_profiles<'a>(
+    to_deploy: ToDeploy<'a>,
+    cmd_overrides: &deploy::CmdOverrides,
+    manifest: Option<&deploy::manifest::Manifest>,
+    summary: &mut Summary,
+) -> Result<ToDeploy<'a>, RunDeployError> {
+    let maintenance = deploy::maintenance::Maintenance::load(&deploy::maintenance::maintenance_path())?;
+    let now = chrono::Local::now().timestamp();
+    let mut maintenance_nodes: Vec<&str> = Vec::new();
+    let mut selected = Vec::new();
+    for part in to_deploy {
+        let (_, _, (node_name, node), (profile_name, _)) = part;
+        let mark = maintenance.active(node_name, now);
+        if !node.node_settings.maintenance && mark.is_none() {
+            selected.push(part);
+            continue;
+        }
+
+        if !maintenance_nodes.contains(&node_name) {
+            maintenance_nodes.push(node_name);
+            let why = match mark {
+                Some(mark) => format!("marked {}", mark),
+                None => "according to the flake".to_string(),
+            };
+            match cmd_overrides.include_maintenance {
+                true => warn!("Node `{}` is under maintenance ({}), deploying it anyway", node_name, why),
+                false => warn!("Skipping node `{}`, which is under maintenance ({}); pass --include-maintenance to deploy it anyway", node_name, why),
+            }
+        }
+
+        if cmd_overrides.include_maintenance {
+            selected.push(part);
+        } else {
+            summary.add(node_name, profile_name);
+            summary.set(node_name, profile_name, Outcome::Skipped, Some("under maintenance".to_string()));
+        }
+    }
+
+    let mut identity = None;
+    for &(_, data, (node_name, node), (profile_name, profile)) in &selected {
+        let (_, merged_settings) = deploy::settings::merge(
+            &data.generic_settings,
+            deploy::select_environment(data, cmd_overrides)?,
+            node,
+            node_name,
+            profile,
+            cmd_overrides,
+        );
+        if let Some(allowed_deployers) = &merged_settings.allowed_deployers {
+            if identity.is_none() {
+                identity = Some(deploy::restrictions::Identity::current().await);
+            }
+            deploy::restrictions::check(
+                identity.as_ref().unwrap(),
+                allowed_deployers,
+                node_name,
+                profile_name,
+                cmd_overrides.override_restrictions,
+            )?;
+        }
+
+        let entry = manifest.and_then(|m| m.entry(node_name, profile_name));
+        if manifest.is_some() && entry.is_none() {
+            return Err(RunDeployError::NotInManifest(
+                node_name.to_string(),
+                profile_name.to_string(),
+            ));
+        }
+        if merged_settings.require_signed_manifest.unwrap_or(false)
+            && entry.and_then(|e| e.signature.as_ref()).is_none()
+        {
+            return Err(RunDeployError::UnsignedProfile(
+                node_name.to_string(),
+                profile_name.to_string(),
+            ));
+        }
+    }
+
+    Ok(selected)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_deploy(
+    deploy_flakes: Vec<deploy::DeployFlake<'_>>,
+    data: Vec<deploy::data::Data>,
+    supports_flakes: bool,
+    check_sigs: bool,
+    interactive: bool,
+    cmd_overrides: &deploy::CmdOverrides,
+    keep_result: bool,
+    result_path: Option<&str>,
+    extra_build_args: &[String],
+    debug_logs: bool,
+    dry_activate: bool,
+    boot: bool,
+    log_dir: &Option<String>,
+    rollback_succeeded: bool,
+    manifest: Option<&deploy::manifest::Manifest>,
+    retry: Option<&deploy::run_state::RunState>,
+    summary: &mut Summary,
+    locks: &mut deploy::locks::Locks,
+) -> Result<(), RunDeployError> {
+    let to_deploy: Vec<_> = select_profiles(&deploy_flakes, &data)?
+        .into_iter()
+        .filter(|(_, _, (node_name, _), (profile_name, _))| {
+            retry.is_none_or(|r| r.entry(node_name, profile_name).is_some())
+        })
+        .collect();
+
+    // Nodes under maintenance are left out, and restrictions checked, before anything connects to them
+    let any_selected = !to_deploy.is_empty();
+    let to_deploy = gate_profiles(to_deploy, cmd_overrides, manifest, summary).await?;
+    if to_deploy.is_empty() && any_selected {
+        info!("All selected nodes are under maintenance, nothing to deploy");
+        return Ok(());
+    }
+
+    if let Some(hostname) = &cmd_overrides.hostname {
+        let mut nodes: Vec<String> = to_deploy.iter().map(|(_, _, (node_name, _), _)| node_name.to_string()).collect();
+        nodes.sort();
+        nodes.dedup();
+        if nodes.len() > 1 {
+            return Err(RunDeployError::HostnameForManyNodes(nodes));
+        }
+        if let Some(node) = nodes.first() {
+            warn!(
+                "Deploying node `{}` to {} instead of its own hostname{}",
+                node,
+                hostname,
+                match cmd_overrides.skip_host_key_check {
+                    true => ", without checking its host key",
+                    false => "",
+                }
+            );
+        }
+    }
+
+    let mut parts: Vec<(
+        &deploy::DeployFlake<'_>,
         deploy::DeployData,
         deploy::DeployDefs,
     )> = Vec::new();
 
-    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+    let mut repo_labels: HashMap<&str, Option<String>> = HashMap::new();
+    // The bootstrap SSH user to connect as instead of `sshUser`, by node
+    let mut bootstrap_users: HashMap<&str, Option<String>> = HashMap::new();
+    // The owner of the store of nodes running single-user Nix, by node
+    let mut store_owners: HashMap<&str, Option<String>> = HashMap::new();
+    let mut sudo_quirks: HashMap<(&str, Option<String>), deploy::sudo::SudoQuirks> = HashMap::new();
+    let mut overrides: Vec<(&str, &str, Vec<deploy::settings::Override>)> = Vec::new();
+
+    for (deploy_flake, data, (node_name, node), (profile_name, profile)) in to_deploy {
+        if cmd_overrides.explain_overrides {
+            let profile_overrides = deploy::settings::overrides(
+                &data.generic_settings,
+                deploy::select_environment(data, cmd_overrides)?,
+                node,
+                node_name,
+                profile,
+                cmd_overrides,
+            );
+            overrides.push((node_name, profile_name, profile_overrides));
+        }
+
+        let mut deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            deploy::select_environment(data, cmd_overrides)?,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            debug_logs,
+            log_dir.as_deref(),
+        );
+
+        if let Some(bootstrap_user) = deploy_data.merged_settings.bootstrap_ssh_user.clone() {
+            if !bootstrap_users.contains_key(node_name) {
+                let defs = deploy_data.defs()?;
+                let ssh_user = defs.ssh_user;
+                let use_bootstrap = !defs.local
+                    && !deploy::deploy::can_log_in(&deploy_data, &ssh_user).await
+                    && deploy::deploy::can_log_in(&deploy_data, &bootstrap_user).await;
+                if use_bootstrap {
+                    info!("Can't log in to node `{}` as `{}` (yet), connecting as bootstrap user `{}`", node_name, ssh_user, bootstrap_user);
+                }
+                bootstrap_users.insert(node_name, Some(bootstrap_user).filter(|_| use_bootstrap));
+            }
+            if let Some(bootstrap_user) = &bootstrap_users[node_name] {
+                deploy_data.use_ssh_user(bootstrap_user.clone());
+            }
+        }
+
+        let store_owner = match deploy_data.merged_settings.single_user_nix {
+            Some(false) => None,
+            Some(true) => Some(deploy_data.defs()?.ssh_user),
+            None => {
+                if !store_owners.contains_key(node_name) {
+                    let defs = deploy_data.defs()?;
+                    let owner = deploy::deploy::detect_single_user_store(&deploy_data, &defs).await;
+                    if let Some(owner) = &owner {
+                        info!("Node `{}` runs single-user Nix (without a daemon) owned by `{}`", node_name, owner);
+                        if owner != &defs.ssh_user {
+                            warn!("Connecting to node `{}` as `{}`, which needs sudo to write to the store of `{}`; set `sshUser` to `{}` to not need it", node_name, defs.ssh_user, owner, owner);
+                        }
+                    }
+                    store_owners.insert(node_name, owner);
+                }
+                store_owners[node_name].clone()
+            }
+        };
+        if let Some(owner) = &store_owner {
+            deploy_data.use_single_user_store(owner.clone());
+        }
+
+        let mut deploy_defs = deploy_data.defs()?;
+        deploy_defs.single_user_store = store_owner.is_some();
+
+        if !deploy_defs.local && deploy_data.merged_settings.ssh_transport == Some(deploy::data::SshTransport::Native) {
+            if let Err(e) = deploy::native_ssh::destination(&deploy_data, &deploy_defs) {
+                warn!("{}, using ssh for profile `{}` of node `{}` instead", e, profile_name, node_name);
+            }
+        }
+
+        if !deploy_defs.local {
+            if let Some((path, ssh_config)) = deploy::ssh_config::load(deploy_data.hostname) {
+                debug!(
+                    "SSH config for `{}` of node `{}`: {}",
+                    deploy_data.hostname,
+                    node_name,
+                    deploy::ssh_config::describe_matches(&path, &ssh_config)
+                );
+            }
+        }
+
+        deploy_defs.label = match &cmd_overrides.label {
+            Some(label) => Some(label.clone()),
+            None => {
+                if !repo_labels.contains_key(deploy_flake.repo) {
+                    repo_labels.insert(deploy_flake.repo, describe_repo(deploy_flake.repo).await);
+                }
+                repo_labels[deploy_flake.repo].clone()
+            }
+        };
+
+        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+            prompt_sudo_password(&deploy_data, &mut deploy_defs, cmd_overrides.non_interactive)?;
+        }
+
+        if deploy_data.merged_settings.sudo_preflight.unwrap_or(false) && deploy_defs.sudo.is_some() {
+            let key = (node_name, deploy_defs.sudo.clone());
+            if !sudo_quirks.contains_key(&key) {
+                let quirks = deploy::sudo::preflight(&deploy_data, &mut deploy_defs)
+                    .await
+                    .map_err(|e| RunDeployError::SudoPreflight(node_name.to_string(), e))?;
+                sudo_quirks.insert(key.clone(), quirks);
+            }
+            deploy_defs.sudo_tty = sudo_quirks[&key].requires_tty;
+        }
+
+        if let Some(manifest) = manifest {
+            match manifest.entry(node_name, profile_name) {
+                Some(entry) if entry.path == profile.profile_settings.path => {
+                    deploy_defs.manifest_signature = entry.signature.clone();
+                }
+                Some(entry) => {
+                    return Err(RunDeployError::ManifestMismatch(
+                        node_name.to_string(),
+                        profile_name.to_string(),
+                        entry.path.clone(),
+                    ))
+                }
+                None => {
+                    return Err(RunDeployError::NotInManifest(
+                        node_name.to_string(),
+                        profile_name.to_string(),
+                    ))
+                }
+            }
+        }
+
+        if let Some(entry) = retry.and_then(|r| r.entry(node_name, profile_name)) {
+            if entry.path != profile.profile_settings.path {
+                warn!("The closure of profile `{}` of node `{}` changed since the failed deployment, deploying the new one", profile_name, node_name);
+            }
+        }
+
+        summary.add(node_name, profile_name);
+        parts.push((deploy_flake, deploy_data, deploy_defs));
+    }
+
+    if cmd_overrides.explain_overrides {
+        let labelled = deploy::settings::label_overrides(&overrides);
+        if labelled.is_empty() {
+            info!("The command line doesn't override any setting of the flake");
+        }
+        let color = std::io::stderr().is_terminal();
+        for (label, o) in labelled {
+            info!("[{}] {}", label, o.describe(color));
+        }
+    }
+
+    // Taken before anything is built, the caller releases them whichever way the deployment ends
+    if !dry_activate {
+        for (_, deploy_data, _) in &parts {
+            let lock = match &deploy_data.merged_settings.lock {
+                Some(lock) if !locks.holds(deploy_data.node_name) => lock,
+                _ => continue,
+            };
+            info!("Locking node `{}`", deploy_data.node_name);
+            locks.take(lock.url.parse()?, deploy_data.node_name, lock.ttl).await?;
+        }
+    }
+
+    // Facts are gathered as profiles are activated, in the order of `parts`
+    for (i, (_, deploy_data, _)) in parts.iter().enumerate() {
+        let earlier: Vec<(&str, &str)> = parts[..i]
+            .iter()
+            .map(|(_, data, _)| (data.node_name, data.profile_name))
+            .collect();
+
+        deploy::facts::check_requires(&deploy_data.profile.profile_settings.requires, &earlier)
+            .map_err(|e| {
+                RunDeployError::Facts(
+                    deploy_data.node_name.to_string(),
+                    deploy_data.profile_name.to_string(),
+                    e,
+                )
+            })?;
+    }
+
+    let orchestrator = Orchestrator::new(cmd_overrides.max_connections);
+
+    {
+        let orchestrator = &orchestrator;
+        let checks = join_all(
+            parts
+                .iter()
+                .filter(|(_, data, _)| !data.profile.profile_settings.external_dependencies.is_empty())
+                .map(|(_, deploy_data, deploy_defs)| async move {
+                    let _permit = orchestrator.connect(deploy_data.node_name, 1).await;
+                    (deploy_data, deploy::dependencies::check(deploy_data, deploy_defs).await)
+                }),
+        )
+        .await;
+
+        for (deploy_data, check) in checks {
+            if let Err(e) = check {
+                summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Failed, Some(e.to_string()));
+                return Err(RunDeployError::ExternalDependencies(
+                    deploy_data.node_name.to_string(),
+                    deploy_data.profile_name.to_string(),
+                    e,
+                ));
+            }
+        }
+    }
+
+    // Profiles that are already up to date are left alone, but still provide facts to later ones
+    let mut unchanged = Vec::new();
+    if cmd_overrides.changed_only {
+        let deployed = deploy::run_state::last_deployed();
+        let (orchestrator, deployed) = (&orchestrator, &deployed);
+        let checks = join_all(parts.iter().map(|(_, deploy_data, deploy_defs)| async move {
+            let key = (deploy_data.node_name.to_string(), deploy_data.profile_name.to_string());
+            match deployed.get(&key) {
+                Some(path) => Ok(*path == deploy_data.profile.profile_settings.path),
+                // Deployed from elsewhere, or before the history recorded it
+                None => {
+                    let _permit = orchestrator.connect(deploy_data.node_name, 1).await;
+                    deploy::deploy::is_unchanged(deploy_data, deploy_defs).await
+                }
+            }
+        }))
+        .await;
+
+        let mut changed_nodes = HashSet::new();
+        for ((_, deploy_data, _), check) in parts.iter().zip(checks) {
+            match check {
+                Ok(true) => (),
+                Ok(false) => {
+                    changed_nodes.insert(deploy_data.node_name.to_string());
+                }
+                Err(e) => {
+                    warn!("Failed to check whether profile `{}` of node `{}` changed, deploying it: {}", deploy_data.profile_name, deploy_data.node_name, e);
+                    changed_nodes.insert(deploy_data.node_name.to_string());
+                }
+            }
+        }
+
+        let (changed, up_to_date): (Vec<_>, Vec<_>) = parts
+            .into_iter()
+            .partition(|(_, deploy_data, _)| changed_nodes.contains(deploy_data.node_name));
+        let mut up_to_date_nodes: Vec<&str> = up_to_date.iter().map(|(_, data, _)| data.node_name).collect();
+        up_to_date_nodes.dedup();
+        for node_name in up_to_date_nodes {
+            info!("Node `{}` is up to date", node_name);
+        }
+        for (_, deploy_data, _) in &up_to_date {
+            summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Skipped, Some("up to date".to_string()));
+        }
+        unchanged.extend(up_to_date);
+        parts = changed;
+
+        if parts.is_empty() {
+            info!("All nodes are up to date, nothing to deploy");
+            return Ok(());
+        }
+    }
+    if cmd_overrides.skip_if_unchanged {
+        let orchestrator = &orchestrator;
+        let checks = join_all(parts.iter().map(|(_, deploy_data, deploy_defs)| async move {
+            let _permit = orchestrator.connect(deploy_data.node_name, 1).await;
+            deploy::deploy::is_unchanged(deploy_data, deploy_defs).await
+        }))
+        .await;
+
+        let mut changed = Vec::new();
+        for (part, check) in parts.into_iter().zip(checks) {
+            let (node_name, profile_name) = (part.1.node_name, part.1.profile_name);
+            match check {
+                Ok(true) => {
+                    info!("Profile `{}` of node `{}` is already up to date", profile_name, node_name);
+                    summary.set(node_name, profile_name, Outcome::Skipped, Some("already up to date".to_string()));
+                    unchanged.push(part);
+                }
+                Ok(false) => changed.push(part),
+                Err(e) => {
+                    warn!("Failed to check whether profile `{}` of node `{}` changed, deploying it: {}", profile_name, node_name, e);
+                    changed.push(part);
+                }
+            }
+        }
+        parts = changed;
+
+        if parts.is_empty() {
+            info!("All profiles are already up to date, nothing to deploy");
+            return Ok(());
+        }
+    }
+
+    if interactive {
+        prompt_deployment(&parts[..])?;
+    } else {
+        Severity::non_critical(cmd_overrides.strict)
+            .check("Printing the deployment plan", print_deployment(&parts[..]))?;
+    }
+
+    request_approvals(&parts[..], dry_activate, boot).await?;
+
+    let push_data = |i: usize| {
+        let (deploy_flake, deploy_data, deploy_defs) = &parts[i];
+        deploy::push::PushProfileData {
+            supports_flakes,
+            check_sigs,
+            repo: deploy_flake.repo,
+            deploy_data,
+            deploy_defs,
+            keep_result,
+            result_path,
+            extra_build_args,
+        }
+    };
+
+    // Identical machines (which are of the same system) share closures, each is only built once
+    let closures: Vec<(&str, bool)> = parts
+        .iter()
+        .map(|(_, data, _)| {
+            (
+                data.profile.profile_settings.path.as_str(),
+                data.merged_settings.remote_build.unwrap_or(false),
+            )
+        })
+        .collect();
+    let groups = deploy::orchestrator::build_groups(&closures);
+    if groups.len() < parts.len() {
+        info!(
+            "Building {} unique closure(s) for {} profile(s)",
+            groups.len(),
+            parts.len()
+        );
+    }
+
+    // Each closure is pushed to its nodes as soon as it is built, while the next ones build, and
+    // the activations below start as soon as the profiles are pushed
+    let build_slots = tokio::sync::Semaphore::new(cmd_overrides.max_builds.max(1));
+    let mut pushed_senders = Vec::new();
+    let mut pushed = Vec::new();
+    for _ in &parts {
+        let (sender, receiver) = tokio::sync::oneshot::channel::<Result<(), RunDeployError>>();
+        pushed_senders.push(Some(sender));
+        pushed.push(receiver);
+    }
+    let pipelines = join_all(groups.iter().map(|group| {
+        let senders: Vec<_> = group
+            .iter()
+            .map(|&i| (i, pushed_senders[i].take().unwrap()))
+            .collect();
+        let (push_data, orchestrator, build_slots) = (&push_data, &orchestrator, &build_slots);
+        async move {
+            let data = push_data(group[0]);
+            let deploy_data = data.deploy_data;
+            let (node_name, profile_name) = (deploy_data.node_name, deploy_data.profile_name);
+            let path = &deploy_data.profile.profile_settings.path;
+            let remote_build = deploy_data.merged_settings.remote_build.unwrap_or(false);
+
+            let built = if retry.and_then(|r| r.entry(node_name, profile_name)).is_some_and(|e| &e.path == path)
+                && !remote_build
+                && !keep_result
+                && Path::new(path).exists()
+            {
+                info!("The closure of profile `{}` of node `{}` was already built by the failed deployment", profile_name, node_name);
+                Ok(())
+            } else {
+                let _slot = build_slots.acquire().await;
+                let _permit = match remote_build {
+                    true => Some(orchestrator.connect(node_name, 1).await),
+                    false => None,
+                };
+                emit(node_name, profile_name, EventKind::Started(Phase::Build));
+                let built = deploy::push::build_profile(data).await;
+                match &built {
+                    Ok(()) => emit(node_name, profile_name, EventKind::Finished(Phase::Build)),
+                    Err(e) => emit(node_name, profile_name, EventKind::Failed(Phase::Build, e.to_string())),
+                }
+                built
+            };
+
+            // Without a closure, the other profiles sharing it are told so by their dropped senders,
+            // see `wait_pushed`
+            let mut senders = senders.into_iter();
+            if let Err(e) = built {
+                let (_, sender) = senders.next().unwrap();
+                let _ = sender.send(Err(RunDeployError::BuildProfile(node_name.to_string(), e)));
+                return;
+            }
+
+            join_all(senders.map(|(i, sender)| async move {
+                let data = push_data(i);
+                let shared_node_name = data.deploy_data.node_name;
+                if i != group[0] {
+                    info!(
+                        "Reusing the closure of profile `{}` of node `{}` for profile `{}` of node `{}`",
+                        profile_name, node_name, data.deploy_data.profile_name, shared_node_name
+                    );
+                    if keep_result {
+                        if let Err(e) = deploy::push::add_result_link(&data).await {
+                            let _ = sender.send(Err(RunDeployError::BuildProfile(shared_node_name.to_string(), e)));
+                            return;
+                        }
+                    }
+                }
+                if let Some(allowed_signers) = &data.deploy_data.merged_settings.allowed_signers {
+                    if let Err(e) = deploy::provenance::check(data.deploy_data, data.deploy_defs, allowed_signers).await {
+                        let _ = sender.send(Err(RunDeployError::Provenance(shared_node_name.to_string(), e)));
+                        return;
+                    }
+                }
+                let result = orchestrator.push(data).await;
+                let _ = sender.send(result.map_err(|e| RunDeployError::PushProfile(shared_node_name.to_string(), e)));
+            }))
+            .await;
+        }
+    }));
+
+    let activations = async {
+        if cmd_overrides.push_only {
+            let mut push_error = None;
+            for ((_, deploy_data, _), receiver) in parts.iter().zip(&mut pushed) {
+                match wait_pushed(receiver, deploy_data).await {
+                    Ok(()) => summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Succeeded, Some("pushed, not activated".to_string())),
+                    Err(e) => {
+                        summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Failed, Some(e.to_string()));
+                        push_error.get_or_insert(e);
+                    }
+                }
+            }
+            return match push_error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            };
+        }
+
+        let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+        let mut dry_activate_warnings = 0;
+        let mut facts = deploy::facts::Facts::default();
+        for (_, deploy_data, _) in &unchanged {
+            facts
+                .gather(
+                    deploy_data.node_name,
+                    deploy_data.hostname,
+                    deploy_data.profile_name,
+                    &deploy_data.profile.profile_settings.path,
+                )
+                .await;
+        }
+
+        let pending: Vec<PendingActivation> = parts
+            .iter()
+            .map(|(_, deploy_data, _)| PendingActivation {
+                node: deploy_data.node_name,
+                parallel: deploy_data.profile.profile_settings.parallel,
+                requires: &deploy_data.profile.profile_settings.requires,
+            })
+            .collect();
+        let mut activated: Vec<(&str, &str)> = unchanged
+            .iter()
+            .map(|(_, deploy_data, _)| (deploy_data.node_name, deploy_data.profile_name))
+            .collect();
+
+        let migration_profiles: Vec<_> = parts
+            .iter()
+            .map(|(_, deploy_data, _)| {
+                (deploy_data.node_name, deploy_data.profile_name, deploy_data.profile.profile_settings.migrations.as_ref())
+            })
+            .collect();
+        let mut migration_groups = deploy::migrations::plan(&migration_profiles);
+        // The pushes of leaders waited for ahead of their turn, to run the migrations
+        let mut pushed_early: HashMap<usize, Result<(), RunDeployError>> = HashMap::new();
+
+        // Run all deployments
+        // In case of an error rollback any previoulsy made deployment.
+        // Rollbacks adhere to the global seeting to auto_rollback and secondary
+        // the profile's configuration
+        let mut next = 0;
+        while next < parts.len() {
+            // With a rollout, the batch is a wave of nodes activated at the same time
+            let rollout = parts[next].1.merged_settings.rollout.as_ref();
+            let batch_len = match rollout {
+                Some(rollout) => rollout_wave(&pending[next..], &activated, rollout.max_unavailable),
+                None => {
+                    let max_parallel = parts[next].1.node.node_settings.max_parallel_activations.unwrap_or(4);
+                    parallel_batch(&pending[next..], &activated, max_parallel)
+                }
+            };
+            let batch = &parts[next..next + batch_len];
+
+            // A profile which failed to build or push fails the deployment like a failed activation
+            let mut failure = None;
+            for (i, ((_, deploy_data, _), receiver)) in batch.iter().zip(&mut pushed[next..]).enumerate() {
+                let result = match pushed_early.remove(&(next + i)) {
+                    Some(result) => result,
+                    None => wait_pushed(receiver, deploy_data).await,
+                };
+                if let Err(e) = result {
+                    summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Failed, Some(e.to_string()));
+                    failure.get_or_insert((deploy_data, e));
+                }
+            }
+            let batch_range = next..next + batch.len();
+            next += batch.len();
+
+            // The migrations of the groups activated for the first time run before anything of them is
+            if failure.is_none() {
+                let (due, later): (Vec<_>, Vec<_>) = migration_groups
+                    .into_iter()
+                    .partition(|group| group.members.iter().any(|i| batch_range.contains(i)));
+                migration_groups = later;
+
+                for group in due {
+                    let (_, leader_data, leader_defs) = &parts[group.leader];
+                    if dry_activate || boot {
+                        info!("Not running the migrations of group `{}` on node `{}` for a dry activation or --boot", group.name, leader_data.node_name);
+                        continue;
+                    }
+
+                    // The leader's closure has to be on it, even if its turn to be activated comes later
+                    if !batch_range.contains(&group.leader) && !pushed_early.contains_key(&group.leader) {
+                        let result = wait_pushed(&mut pushed[group.leader], leader_data).await;
+                        pushed_early.insert(group.leader, result);
+                    }
+                    let result = match pushed_early.get(&group.leader) {
+                        Some(Err(_)) => Err(RunDeployError::MigrationsLeader(group.name.clone(), leader_data.node_name.to_string())),
+                        _ => {
+                            let migrations = leader_data.profile.profile_settings.migrations.as_ref().unwrap();
+                            deploy::migrations::run(leader_data, leader_defs, migrations).await.map_err(|e| {
+                                RunDeployError::Migrations(leader_data.node_name.to_string(), group.name.clone(), e)
+                            })
+                        }
+                    };
+
+                    if let Err(e) = result {
+                        error!("{}", e);
+                        for &i in group.members.iter().filter(|i| batch_range.contains(i)) {
+                            let deploy_data = &parts[i].1;
+                            summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Failed, Some(e.to_string()));
+                        }
+                        failure = Some((leader_data, e));
+                        break;
+                    }
+                }
+            }
+
+            // Before anything was activated, there is nothing to roll back
+            let mut failure = match failure {
+                Some((_, e)) if succeeded.is_empty() => return Err(e),
+                failure => failure,
+            };
+
+            if failure.is_none() {
+                // The profiles of a batch are activated together, or with a rollout, those of each
+                // node of the wave one after the other (stopping at a failure), the nodes together
+                let mut runs: Vec<Vec<usize>> = Vec::new();
+                for (i, (_, deploy_data, _)) in batch.iter().enumerate() {
+                    match runs.last_mut() {
+                        Some(run) if rollout.is_none() || batch[run[0]].1.node_name == deploy_data.node_name => run.push(i),
+                        _ => runs.push(vec![i]),
+                    }
+                }
+                if runs.len() > 1 {
+                    let names: Vec<&str> = runs.iter().map(|run| batch[run[0]].1.node_name).collect();
+                    info!("Activating nodes {} at the same time", names.join(", "));
+                } else if batch.len() > 1 && rollout.is_none() {
+                    let names: Vec<&str> = batch.iter().map(|(_, deploy_data, _)| deploy_data.profile_name).collect();
+                    info!("Activating profiles {} of node `{}` in parallel", names.join(", "), batch[0].1.node_name);
+                }
+
+                let mut envs = Vec::new();
+                for (_, deploy_data, _) in batch {
+                    envs.push(facts.resolve(&deploy_data.profile.profile_settings.requires).map_err(|e| {
+                        RunDeployError::Facts(deploy_data.node_name.to_string(), deploy_data.profile_name.to_string(), e)
+                    })?);
+                }
+
+                let (envs, orchestrator) = (&envs, &orchestrator);
+                let results = join_all(runs.iter().map(|run| async move {
+                    // With magic rollback, the activation is waited for (and confirmed) over a second session
+                    let sessions = run.iter().map(|&i| match batch[i].1.merged_settings.magic_rollback.unwrap_or(true) {
+                        true => 2,
+                        false => 1,
+                    });
+                    let sessions: usize = match rollout {
+                        Some(_) => sessions.max().unwrap_or(1),
+                        None => sessions.sum(),
+                    };
+                    let _permit = orchestrator.connect(batch[run[0]].1.node_name, sessions).await;
+
+                    match rollout {
+                        Some(_) => {
+                            let mut results = Vec::new();
+                            for &i in run {
+                                let result = activate_profile(&batch[i].1, &batch[i].2, dry_activate, boot, &envs[i]).await;
+                                let failed = result.is_err();
+                                results.push(result);
+                                if failed {
+                                    break;
+                                }
+                            }
+                            results
+                        }
+                        None => join_all(run.iter().map(|&i| activate_profile(&batch[i].1, &batch[i].2, dry_activate, boot, &envs[i]))).await,
+                    }
+                }))
+                .await;
+
+                // Profiles activated next to a failed one are rolled back along with the earlier ones
+                for (i, result) in runs.iter().zip(results).flat_map(|(run, results)| run.iter().zip(results)) {
+                    let (_, deploy_data, deploy_defs) = &batch[*i];
+                    match result {
+                        Ok(activated_profile) => {
+                            if let Some(units) = &activated_profile.units {
+                                emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Units(units.clone()));
+                            }
+                            emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Finished(Phase::Activate));
+                            let message = match &activated_profile.report {
+                                Some(report) => {
+                                    dry_activate_warnings += report.warnings.len();
+                                    Some(report.to_string())
+                                }
+                                None => activated_profile.units.map(|u| u.to_string()),
+                            };
+                            summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Succeeded, message);
+                            facts
+                                .gather(
+                                    deploy_data.node_name,
+                                    deploy_data.hostname,
+                                    deploy_data.profile_name,
+                                    &deploy_data.profile.profile_settings.path,
+                                )
+                                .await;
+                            activated.push((deploy_data.node_name, deploy_data.profile_name));
+                            succeeded.push((deploy_data, deploy_defs))
+                        }
+                        Err(e) => {
+                            emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Failed(Phase::Activate, e.to_string()));
+                            error!("{}", e);
+                            summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::Failed, Some(e.to_string()));
+                            failure.get_or_insert((deploy_data, RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e)));
+                        }
+                    }
+                }
+            }
+
+            if let Some((deploy_data, e)) = failure {
+                if dry_activate {
+                    info!("dry run, not rolling back");
+                }
+                if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
+                    info!("Revoking previous deploys");
+                    // revoking all previous deploys
+                    // (adheres to profile configuration if not set explicitely by
+                    //  the command line)
+                    for (deploy_data, deploy_defs) in &succeeded {
+                        if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
+                            let _permit = orchestrator.connect(deploy_data.node_name, 1).await;
+                            emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Started(Phase::Revoke));
+                            deploy::deploy::revoke(deploy_data, deploy_defs).await.map_err(|e| {
+                                emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Failed(Phase::Revoke, e.to_string()));
+                                RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
+                            })?;
+                            emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Finished(Phase::Revoke));
+                            summary.set(deploy_data.node_name, deploy_data.profile_name, Outcome::RolledBack, None);
+                        }
+                    }
+                    return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
+                }
+                return Err(e);
+            }
+        }
+
+        if cmd_overrides.fail_on_dry_activate_warnings && dry_activate_warnings > 0 {
+            return Err(RunDeployError::DryActivateWarnings(dry_activate_warnings));
+        }
+
+        Ok(())
+    };
+
+    // Once the activations are done (or failed), builds and pushes still running are abandoned
+    let result = {
+        futures_util::pin_mut!(pipelines, activations);
+        match futures_util::future::select(activations, pipelines).await {
+            futures_util::future::Either::Left((result, _)) => result,
+            futures_util::future::Either::Right((_, activations)) => activations.await,
+        }
+    };
+
+    for ((node_name, profile_name), bytes) in orchestrator.pushed() {
+        summary.set_pushed(&node_name, &profile_name, bytes);
+    }
+
+    result
+}
+
+/// Activates a profile (see [`deploy::deploy::deploy_profile`]) of a batch
+async fn activate_profile(
+    deploy_data: &deploy::DeployData<'_>,
+    deploy_defs: &deploy::DeployDefs,
+    dry_activate: bool,
+    boot: bool,
+    env: &[(String, String)],
+) -> Result<deploy::deploy::Activated, deploy::deploy::DeployProfileError> {
+    emit(deploy_data.node_name, deploy_data.profile_name, EventKind::Started(Phase::Activate));
+    // Standbys only get the profile for their next boot, to fail over to
+    let boot = boot || deploy_data.node.node_settings.standby_of.is_some();
+    deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot, env).await
+}
+
+/// Waits for the closure of a profile to be built and pushed
+async fn wait_pushed(
+    receiver: &mut tokio::sync::oneshot::Receiver<Result<(), RunDeployError>>,
+    deploy_data: &deploy::DeployData<'_>,
+) -> Result<(), RunDeployError> {
+    // The sender is dropped without a result when building a closure shared with an earlier
+    // profile failed
+    receiver.await.unwrap_or_else(|_| {
+        Err(RunDeployError::SharedBuild(
+            deploy_data.node_name.to_string(),
+            deploy_data.profile_name.to_string(),
+        ))
+    })
+}
+
+/// The directory of a flake in a local checkout
+fn local_flake_dir(repo: &str) -> Option<&Path> {
+    let path = repo
+        .strip_prefix("path:")
+        .or_else(|| repo.strip_prefix("git+file://"))
+        .unwrap_or(repo);
+    let path = Path::new(path.split('?').next().unwrap_or(path));
+
+    Some(path).filter(|path| path.is_dir())
+}
+
+/// `git describe` of a flake in a local git checkout, the default label of new generations
+async fn describe_repo(repo: &str) -> Option<String> {
+    let path = local_flake_dir(repo)?;
+
+    let output = deploy::trace::output(
+        Command::new("git")
+            .arg("-C")
+            .arg(path)
+            .args(["describe", "--always", "--dirty", "--tags"])
+            .stderr(Stdio::null()),
+    )
+    .await
+    .ok()?;
+
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|l| !l.is_empty()),
+        false => None,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("Failed to deploy profile: {0}")]
+    DeployProfile(#[from] deploy::deploy::DeployProfileError),
+    #[error("Failed to push profile: {0}")]
+    PushProfile(#[from] deploy::push::PushProfileError),
+    #[error("Failed to test for flake support: {0}")]
+    FlakeTest(std::io::Error),
+    #[error("Failed to write the command trace to {0}: {1}")]
+    TraceCommands(PathBuf, std::io::Error),
+    #[error("Failed to check deployment: {0}")]
+    CheckDeployment(#[from] CheckDeploymentError),
+    #[error("Failed to evaluate deployment data: {0}")]
+    GetDeploymentData(#[from] GetDeploymentDataError),
+    #[error("Error parsing flake: {0}")]
+    ParseFlake(#[from] deploy::ParseFlakeError),
+    #[error("Error initiating logger: {0}")]
+    Logger(#[from] deploy::InitLoggerError),
+    #[error("{0}")]
+    RunDeploy(#[from] RunDeployError),
+    #[error("{0}")]
+    Doctor(#[from] deploy::doctor::DoctorError),
+    #[error("{0}")]
+    Manifest(#[from] deploy::manifest::ManifestError),
+    #[error("{0}")]
+    Vars(#[from] deploy::vars::VarsError),
+    #[error("{0}")]
+    RunState(#[from] deploy::run_state::RunStateError),
+    #[error("{0}")]
+    Schedule(#[from] deploy::schedule::ScheduleError),
+    #[error("{0}")]
+    State(#[from] deploy::state::StateError),
+    #[error("{0}")]
+    EncryptedLog(#[from] deploy::encrypted_log::EncryptedLogError),
+    #[error("{0}")]
+    Clone(#[from] deploy::CloneError),
+    #[error("{0}")]
+    PendingConfirm(#[from] deploy::pending_confirm::PendingConfirmError),
+    #[error("{0}")]
+    PlanDiff(#[from] deploy::plan_diff::PlanDiffError),
+    #[error("Failed to roll back: {0}")]
+    Rollback(#[from] deploy::rollback::RollbackError),
+    #[error("{0}")]
+    Logs(#[from] deploy::logs::LogsError),
+    #[error("`deploy logs` fetches the log of a single node, but nodes {} were selected", .0.join(", "))]
+    LogsNodes(Vec<String>),
+    #[error("`deploy rollback` rolls back a single profile, but {0} were selected")]
+    RollbackProfiles(usize),
+    #[error("The profile has no generation {0} other than the current one")]
+    NoSuchGeneration(u64),
+    #[error("{0}")]
+    Watch(#[from] deploy::watch::WatchError),
+    #[error("`deploy watch` needs a flake in a local directory, not {0}")]
+    WatchNotLocal(String),
+    #[error("Node `{0}` doesn't have the tags `{1}`")]
+    NotTagged(String, String),
+    #[error("{0}")]
+    Tags(String),
+    #[error("Failed to parse the SSH options `{0}`: {1}")]
+    SshOpts(String, shell_words::ParseError),
+    #[error("Failed to run a scheduled push: {0}")]
+    ScheduledPush(std::io::Error),
+    #[error("{0}")]
+    Keys(#[from] deploy::keys::KeysError),
+    #[error("Setting up the keys failed on {0} node(s)")]
+    SetupKeys(usize),
+    #[error("Failed to serialize the manifest: {0}")]
+    SerializeManifest(serde_json::Error),
+    #[error("Failed to write the manifest: {0}")]
+    WriteManifest(std::io::Error),
+    #[error("{0}")]
+    Maintenance(#[from] deploy::maintenance::MaintenanceError),
+    #[error("Failed to import the network: {0}")]
+    Import(#[from] deploy::import::ImportError),
+    #[error("Failed to write the imported nodes: {0}")]
+    WriteImport(std::io::Error),
+    #[error("{0}")]
+    Notification(#[from] deploy::notifications::NotificationError),
+}
+
+impl RunError {
+    /// The kind of problem the error is, for the exit code of deploy
+    pub fn category(&self) -> deploy::severity::ExitCategory {
+        match self {
+            RunError::PushProfile(e)
+            | RunError::RunDeploy(RunDeployError::BuildProfile(_, e))
+            | RunError::RunDeploy(RunDeployError::PushProfile(_, e)) => e.category(),
+            RunError::RunDeploy(RunDeployError::NonInteractive(_))
+            | RunError::SshOpts(..) => {
+                deploy::severity::ExitCategory::Configuration
+            }
+            _ => deploy::severity::ExitCategory::Failure,
+        }
+    }
+}
+
+async fn run_locks(
+    deploy_flakes: Vec<DeployFlake<'_>>,
+    action: &LocksAction,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    quiet: bool,
+) -> Result<(), RunError> {
+    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let data = get_deployment_data(supports_flakes, &deploy_flakes, extra_build_args, vars, &NodesExprArgs::new(cmd_overrides, &[]), quiet).await?;
+
+    // The lock settings of each node, from its first profile
+    let mut nodes: Vec<(&str, deploy::data::LockSettings)> = Vec::new();
+    for (_, data, (node_name, node), (profile_name, profile)) in select_profiles(&deploy_flakes, &data)? {
+        if nodes.iter().any(|(n, _)| *n == node_name) {
+            continue;
+        }
+        let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
+        let deploy_data = deploy::make_deploy_data(&data.generic_settings, environment, node, node_name, profile, profile_name, cmd_overrides, false, None);
+        if let Some(lock) = deploy_data.merged_settings.lock {
+            nodes.push((node_name, lock));
+        }
+    }
+
+    match action {
+        LocksAction::List(_) => {
+            let mut urls: Vec<&str> = nodes.iter().map(|(_, lock)| lock.url.as_str()).collect();
+            urls.sort_unstable();
+            urls.dedup();
+            let mut any = false;
+            for url in urls {
+                let backend = url.parse().map_err(RunDeployError::from)?;
+                for (node, holder) in deploy::locks::list(&backend).await.map_err(RunDeployError::from)? {
+                    println!("{}: locked by {}", node, holder);
+                    any = true;
+                }
+            }
+            if !any {
+                info!("No node is locked");
+            }
+        }
+        LocksAction::Steal(_) => {
+            for (node, lock) in &nodes {
+                let backend = lock.url.parse().map_err(RunDeployError::from)?;
+                match deploy::locks::holder(&backend, node).await.map_err(RunDeployError::from)? {
+                    Some(holder) => {
+                        deploy::locks::remove(&backend, node).await.map_err(RunDeployError::from)?;
+                        warn!("Removed the lock of node `{}` held by {}", node, holder);
+                    }
+                    None => info!("Node `{}` isn't locked", node),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_maintenance(action: &MaintenanceAction) -> Result<(), RunError> {
+    use deploy::maintenance::{maintenance_path, Maintenance};
+    use deploy::schedule::{format_at, parse_at};
+
+    let path = maintenance_path();
+    let now = chrono::Local::now();
+    let mut maintenance = Maintenance::load(&path)?;
+
+    match action {
+        MaintenanceAction::Set(set_opts) => {
+            let until = set_opts.until.as_deref().map(|until| parse_at(until, now)).transpose()?;
+            for node in &set_opts.nodes {
+                maintenance.set(node, now.timestamp(), until.map(|u| u.timestamp()), set_opts.reason.clone());
+            }
+            maintenance.save(&path, now.timestamp())?;
+
+            match until {
+                Some(until) => info!("Marked {} as under maintenance until {}", set_opts.nodes.join(", "), format_at(until.timestamp())),
+                None => info!("Marked {} as under maintenance until cleared", set_opts.nodes.join(", ")),
+            }
+        }
+        MaintenanceAction::Clear(clear_opts) => {
+            for node in &clear_opts.nodes {
+                maintenance.clear(node)?;
+            }
+            maintenance.save(&path, now.timestamp())?;
+
+            info!("Cleared the maintenance marks of {}", clear_opts.nodes.join(", "));
+        }
+        MaintenanceAction::List => {
+            for (node, mark) in &maintenance.nodes {
+                if mark.is_active(now.timestamp()) {
+                    println!("{}  {}", node, mark);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_import(import_opts: &ImportOpts) -> Result<(), RunError> {
+    let nodes = deploy::import::evaluate(import_opts.from, &import_opts.file).await?;
+    if nodes.is_empty() {
+        warn!("Found no nodes in {}", import_opts.file.display());
+    }
+
+    let imported = match import_opts.json {
+        // Serializing a `serde_json::Value` can't fail
+        true => format!(
+            "{}\n",
+            serde_json::to_string_pretty(&deploy::import::to_json(import_opts.from, &nodes)).unwrap_or_default()
+        ),
+        false => deploy::import::to_nix(import_opts.from, &import_opts.file, &nodes),
+    };
+
+    match &import_opts.output {
+        Some(output) => {
+            std::fs::write(output, imported).map_err(RunError::WriteImport)?;
+            info!("Wrote {} node(s) to {}", nodes.len(), output.display());
+        }
+        None => print!("{}", imported),
+    }
+
+    Ok(())
+}
+
+async fn run_setup_keys(
+    deploy_flakes: Vec<DeployFlake<'_>>,
+    setup_keys_opts: &SetupKeysOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    quiet: bool,
+) -> Result<(), RunError> {
+    let keys = deploy::keys::read_keys(&setup_keys_opts.keys)?;
+
+    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let data = get_deployment_data(supports_flakes, &deploy_flakes, extra_build_args, vars, &NodesExprArgs::new(cmd_overrides, &[]), quiet).await?;
+
+    let mut done = std::collections::HashSet::new();
+    let mut failed = 0;
+    // Keys are installed for `sshUser`, which is the same for all profiles of a node
+    for (_, data, (node_name, node), (profile_name, profile)) in select_profiles(&deploy_flakes, &data)? {
+        if !done.insert(node_name) {
+            continue;
+        }
+
+        let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
         let deploy_data = deploy::make_deploy_data(
             &data.generic_settings,
+            environment,
             node,
             node_name,
             profile,
             profile_name,
             cmd_overrides,
-            debug_logs,
-            log_dir.as_deref(),
+            false,
+            None,
         );
 
-        let mut deploy_defs = deploy_data.defs()?;
+        if let Err(e) = deploy::keys::setup_node(&deploy_data, &keys).await {
+            error!("{}", e);
+            failed += 1;
+        }
+    }
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            warn!("Interactive sudo is enabled! Using a sudo password is less secure than correctly configured SSH keys.\nPlease use keys in production environments.");
+    match failed {
+        0 => Ok(()),
+        n => Err(RunError::SetupKeys(n)),
+    }
+}
 
-            if deploy_data.merged_settings.sudo.is_some() {
-                warn!("Custom sudo commands should be configured to accept password input from stdin when using the 'interactive sudo' option. Deployment may fail if the custom command ignores stdin.");
-            } else {
-                // this configures sudo to hide the password prompt and accept input from stdin
-                // at the time of writing, deploy_defs.sudo defaults to 'sudo -u root' when using user=root and sshUser as non-root
-                let original = deploy_defs.sudo.unwrap_or("sudo".to_string());
-                deploy_defs.sudo = Some(format!("{} -S -p \"\"", original));
+async fn run_plan(
+    deploy_flakes: Vec<DeployFlake<'_>>,
+    plan_opts: &PlanOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    quiet: bool,
+) -> Result<(), RunError> {
+    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let data = get_deployment_data(supports_flakes, &deploy_flakes, extra_build_args, vars, &NodesExprArgs::new(cmd_overrides, &[]), quiet).await?;
+    // Loaded first, so that a missing deployment to compare with doesn't wait for the signing
+    let against = plan_opts.against.as_deref().map(deploy::plan_diff::load).transpose()?;
+
+    let mut entries = Vec::new();
+    let mut plan = Vec::new();
+    for (_, data, (node_name, node), (profile_name, profile)) in select_profiles(&deploy_flakes, &data)? {
+        let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
+        let deploy_data = deploy::make_deploy_data(
+            &data.generic_settings,
+            environment,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        );
+        let path = &profile.profile_settings.path;
+        plan.push(deploy::plan_diff::PlannedProfile::new(&deploy_data));
+
+        let signature = match &plan_opts.sign {
+            Some(key) => {
+                info!("Signing the closure of profile {} of node {}", profile_name, node_name);
+                Some(deploy::manifest::sign(key, path).await?)
             }
+            None => None,
+        };
+
+        entries.push(deploy::manifest::ManifestEntry {
+            node: node_name.to_string(),
+            profile: profile_name.to_string(),
+            hostname: deploy_data.hostname.to_string(),
+            path: path.clone(),
+            signature,
+        });
+    }
 
-            info!("You will now be prompted for the sudo password for {}.", node.node_settings.hostname);
-            let sudo_password = rpassword::prompt_password(format!("(sudo for {}) Password: ", node.node_settings.hostname)).unwrap_or("".to_string());
+    let manifest = serde_json::to_string_pretty(&deploy::manifest::Manifest::new(entries))
+        .map_err(RunError::SerializeManifest)?;
 
-            deploy_defs.sudo_password = Some(sudo_password);
+    match &plan_opts.output {
+        Some(output) => {
+            std::fs::write(output, format!("{}\n", manifest)).map_err(RunError::WriteManifest)?;
+            info!("Wrote the manifest to {}", output.display());
         }
+        None if against.is_some() => (),
+        None => println!("{}", manifest),
+    }
 
-        parts.push((deploy_flake, deploy_data, deploy_defs));
+    if let Some(against) = against {
+        let changes = deploy::plan_diff::diff(&against, &plan);
+        if changes.is_empty() {
+            info!("Nothing changed since {}", plan_opts.against.as_deref().unwrap_or_default());
+        }
+        for change in changes {
+            println!("{}", change);
+        }
     }
 
-    if interactive {
-        prompt_deployment(&parts[..])?;
-    } else {
-        print_deployment(&parts[..])?;
-    }
-
-    let data_iter = || {
-        parts.iter().map(
-            |(deploy_flake, deploy_data, deploy_defs)| deploy::push::PushProfileData {
-                supports_flakes,
-                check_sigs,
-                repo: deploy_flake.repo,
-                deploy_data,
-                deploy_defs,
-                keep_result,
-                result_path,
-                extra_build_args,
-            },
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_logs(
+    deploy_flakes: Vec<DeployFlake<'_>>,
+    logs_opts: &LogsOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    log_dir: Option<&str>,
+    quiet: bool,
+) -> Result<(), RunError> {
+    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let data = get_deployment_data(supports_flakes, &deploy_flakes, extra_build_args, vars, &NodesExprArgs::new(cmd_overrides, &[]), quiet).await?;
+
+    // Any profile of the node will do, they all reach it the same way
+    let selected = select_profiles(&deploy_flakes, &data)?;
+    let mut nodes: Vec<String> = selected.iter().map(|(_, _, (node_name, _), _)| node_name.to_string()).collect();
+    nodes.dedup();
+    let (_, data, (node_name, node), (profile_name, profile)) = match (&selected[..], nodes.len()) {
+        ([selected, ..], 1) => selected,
+        _ => return Err(RunError::LogsNodes(nodes)),
+    };
+    let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
+    let deploy_data = deploy::make_deploy_data(
+        &data.generic_settings,
+        environment,
+        node,
+        node_name,
+        profile,
+        profile_name,
+        cmd_overrides,
+        false,
+        None,
+    );
+    let deploy_defs = deploy_data.defs().map_err(RunDeployError::from)?;
+
+    let (path, log) = deploy::logs::fetch(&deploy_data, &deploy_defs, log_dir, logs_opts.previous).await?;
+    if !quiet {
+        info!("{} on node {}:", path, node_name);
+    }
+    print!("{}", log);
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_rollback(
+    deploy_flakes: Vec<DeployFlake<'_>>,
+    rollback_opts: &RollbackOpts,
+    cmd_overrides: &deploy::CmdOverrides,
+    extra_build_args: &[String],
+    vars: Option<&deploy::vars::Vars>,
+    manifest: Option<&deploy::manifest::Manifest>,
+    prompt: bool,
+    quiet: bool,
+) -> Result<(), RunError> {
+    let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
+    let data = get_deployment_data(supports_flakes, &deploy_flakes, extra_build_args, vars, &NodesExprArgs::new(cmd_overrides, &[]), quiet).await?;
+
+    let selected = select_profiles(&deploy_flakes, &data)?;
+    if selected.len() != 1 {
+        return Err(RunError::RollbackProfiles(selected.len()));
+    }
+    // Gated like deployments, before anything connects to the node
+    let selected = gate_profiles(selected, cmd_overrides, manifest, &mut Summary::new()).await?;
+    let (_, data, (node_name, node), (profile_name, profile)) = match &selected[..] {
+        [selected] => selected,
+        _ => return Ok(()),
+    };
+    let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
+    let deploy_data = deploy::make_deploy_data(
+        &data.generic_settings,
+        environment,
+        node,
+        node_name,
+        profile,
+        profile_name,
+        cmd_overrides,
+        false,
+        None,
+    );
+    let mut deploy_defs = deploy_data.defs().map_err(RunDeployError::from)?;
+
+    let generations = deploy::rollback::list_generations(&deploy_data, &deploy_defs).await?;
+    let generation = match rollback_opts.generation {
+        Some(number) => deploy::rollback::parse_choice(&number.to_string(), &generations)
+            .ok_or(RunError::NoSuchGeneration(number))?,
+        None if cmd_overrides.non_interactive => return Err(RunDeployError::from(NonInteractiveError::RollbackChoice).into()),
+        None => {
+            let list: Vec<String> = generations.iter().map(deploy::rollback::describe).collect();
+            info!("Generations of profile {} of node {}:\n{}", profile_name, node_name, list.join("\n"));
+            loop {
+                print!("Generation to roll back to (empty to cancel)> ");
+                stdout().flush().map_err(PromptDeploymentError::StdoutFlush).map_err(RunDeployError::from)?;
+                let mut s = String::new();
+                stdin().read_line(&mut s).map_err(PromptDeploymentError::StdinRead).map_err(RunDeployError::from)?;
+                if s.trim().is_empty() {
+                    return Err(RunDeployError::from(PromptDeploymentError::Cancelled).into());
+                }
+                match deploy::rollback::parse_choice(&s, &generations) {
+                    Some(generation) => break generation,
+                    None => warn!("`{}` isn't the number of a generation other than the current one", s.trim()),
+                }
+            }
+        }
+    };
+
+    // The manifest must be of the generation rolled back to, whose signature activate-rs checks
+    if let Some(entry) = manifest.and_then(|m| m.entry(node_name, profile_name)) {
+        if entry.path != generation.path {
+            return Err(RunDeployError::ManifestMismatch(
+                node_name.to_string(),
+                profile_name.to_string(),
+                entry.path.clone(),
+            )
+            .into());
+        }
+        deploy_defs.manifest_signature = entry.signature.clone();
+    }
+
+    if let Some(current) = generations.iter().find(|g| g.current) {
+        let diff = deploy::rollback::diff_closures(&deploy_data, &deploy_defs, &current.path, &generation.path).await?;
+        info!(
+            "Changes from generation {} (current) to generation {}:\n{}",
+            current.number,
+            generation.number,
+            diff.trim_end()
+        );
+    }
+
+    if let Some(command) = deploy_data.merged_settings.approval_command.as_deref() {
+        let plan = deploy::approval::Plan {
+            dry_activate: false,
+            boot: false,
+            profiles: vec![deploy::approval::PlannedProfile {
+                node: node_name,
+                profile: profile_name,
+                hostname: deploy_data.hostname,
+                ssh_user: &deploy_defs.ssh_user,
+                user: &deploy_defs.profile_user,
+                path: &generation.path,
+            }],
+        };
+        deploy::approval::request_approval(command, &plan).await.map_err(RunDeployError::from)?;
+    }
+    if rollback_opts.interactive && cmd_overrides.non_interactive {
+        return Err(RunDeployError::from(NonInteractiveError::RollbackConfirmation).into());
+    }
+    if prompt || rollback_opts.interactive {
+        prompt_yes(
+            &format!("Are you sure you want to roll back profile {} of node {} to generation {}?", profile_name, node_name, generation.number),
+            "Do you want to roll back?",
         )
+        .map_err(RunDeployError::from)?;
+    }
+
+    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+        prompt_sudo_password(&deploy_data, &mut deploy_defs, cmd_overrides.non_interactive).map_err(RunDeployError::from)?;
+    }
+
+    // Like deployments, so a rollback can't race one running on the node
+    let mut locks = deploy::locks::Locks::new(chrono::Utc::now().timestamp());
+    if let Some(lock) = &deploy_data.merged_settings.lock {
+        info!("Locking node `{}`", node_name);
+        let backend = lock.url.parse().map_err(RunDeployError::from)?;
+        locks.take(backend, node_name, lock.ttl).await.map_err(RunDeployError::from)?;
+    }
+    let result = deploy::rollback::rollback(&deploy_data, &deploy_defs, generation.number).await;
+    let released = locks.release_all(Severity::non_critical(cmd_overrides.strict)).await;
+    result?;
+    released.map_err(RunDeployError::from)?;
+    info!("Rolled back profile {} of node {} to generation {}", profile_name, node_name, generation.number);
+
+    Ok(())
+}
+
+async fn run_confirm(confirm_opts: &ConfirmOpts, non_interactive: bool) -> Result<(), RunError> {
+    use deploy::pending_confirm::PendingConfirmation;
+
+    let pending = PendingConfirmation::load_run(&confirm_opts.resume)?;
+    let sudo_password = match pending.iter().any(|p| p.interactive_sudo) {
+        true if non_interactive => {
+            return Err(RunDeployError::from(NonInteractiveError::ConfirmSudoPassword(NON_INTERACTIVE)).into())
+        }
+        true => Some(
+            rpassword::prompt_password("(sudo for confirming) Password: ")
+                .map_err(|_| RunDeployError::from(NonInteractiveError::ConfirmSudoPassword(NO_TERMINAL)))?,
+        ),
+        false => None,
     };
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::build_profile(data).await.map_err(|e| {
-            RunDeployError::BuildProfile(node_name, e)
-        })?;
+    let mut first_error = None;
+    for pending in &pending {
+        if pending.remaining() == Some(0) {
+            warn!(
+                "The confirmation window of profile {} of node {} has passed, it was probably rolled back",
+                pending.profile, pending.node
+            );
+        }
+        match pending.confirm(sudo_password.as_deref()).await {
+            Ok(()) => {
+                info!("Confirmed profile {} of node {}", pending.profile, pending.node);
+                pending.remove(&confirm_opts.resume);
+            }
+            Err(e) => {
+                error!("{}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
     }
+}
 
-    for data in data_iter() {
-        let node_name: String = data.deploy_data.node_name.to_string();
-        deploy::push::push_profile(data).await.map_err(|e| {
-            RunDeployError::PushProfile(node_name, e)
-        })?;
+fn run_prune_history(prune_opts: &PruneHistoryOpts) -> Result<(), RunError> {
+    let max_age = match (prune_opts.older_than, prune_opts.max_size) {
+        (None, None) => Some(std::time::Duration::from_secs(90 * 24 * 60 * 60)),
+        (older_than, _) => older_than,
+    };
+
+    let removed = deploy::state::prune_history(max_age, prune_opts.max_size, prune_opts.dry_run)?;
+    for entry in &removed {
+        debug!("{} {}", if prune_opts.dry_run { "Would remove" } else { "Removed" }, entry.path.display());
     }
+    info!(
+        "{} {} history entr{} ({} bytes) from {}",
+        if prune_opts.dry_run { "Would remove" } else { "Removed" },
+        removed.len(),
+        if removed.len() == 1 { "y" } else { "ies" },
+        removed.iter().map(|e| e.size).sum::<u64>(),
+        deploy::state::history_dir().display()
+    );
+
+    Ok(())
+}
 
-    let mut succeeded: Vec<(&deploy::DeployData, &deploy::DeployDefs)> = vec![];
+async fn run_schedule(action: &ScheduleAction) -> Result<(), RunError> {
+    use deploy::schedule::{format_at, parse_at, schedule_path, Schedule};
 
-    // Run all deployments
-    // In case of an error rollback any previoulsy made deployment.
-    // Rollbacks adhere to the global seeting to auto_rollback and secondary
-    // the profile's configuration
-    for (_, deploy_data, deploy_defs) in &parts {
-        if let Err(e) = deploy::deploy::deploy_profile(deploy_data, deploy_defs, dry_activate, boot).await
-        {
-            error!("{}", e);
-            if dry_activate {
-                info!("dry run, not rolling back");
-            }
-            if rollback_succeeded && cmd_overrides.auto_rollback.unwrap_or(true) {
-                info!("Revoking previous deploys");
-                // revoking all previous deploys
-                // (adheres to profile configuration if not set explicitely by
-                //  the command line)
-                for (deploy_data, deploy_defs) in &succeeded {
-                    if deploy_data.merged_settings.auto_rollback.unwrap_or(true) {
-                        deploy::deploy::revoke(*deploy_data, *deploy_defs).await.map_err(|e| {
-                            RunDeployError::RevokeProfile(deploy_data.node_name.to_string(), e)
-                        })?;
+    let path = schedule_path();
+
+    match action {
+        ScheduleAction::Push(push_opts) => {
+            let at = parse_at(&push_opts.at, chrono::Local::now())?;
+            let targets = match (&push_opts.targets, &push_opts.target) {
+                (Some(targets), _) => targets.clone(),
+                (None, target) => vec![target.clone().unwrap_or_else(|| ".".to_string())],
+            };
+            let working_dir = std::env::current_dir().map_err(RunError::ScheduledPush)?;
+
+            let mut schedule = Schedule::load(&path)?;
+            let id = schedule.add(at, targets.clone(), working_dir);
+            schedule.save(&path)?;
+
+            info!(
+                "Scheduled push {} of {} at {}, run by `deploy schedule run`",
+                id,
+                targets.join(", "),
+                format_at(at.timestamp())
+            );
+        }
+        ScheduleAction::List => {
+            for job in Schedule::load(&path)?.jobs {
+                println!("{}  {}  {}", job.id, format_at(job.at), job.targets.join(" "));
+            }
+        }
+        ScheduleAction::Cancel(cancel_opts) => {
+            let mut schedule = Schedule::load(&path)?;
+            let job = schedule.remove(&cancel_opts.id)?;
+            schedule.save(&path)?;
+
+            info!("Cancelled the push of {} at {}", job.targets.join(", "), format_at(job.at));
+        }
+        ScheduleAction::Run => {
+            let deploy = std::env::current_exe().map_err(RunError::ScheduledPush)?;
+            info!("Running scheduled pushes from {}", path.display());
+
+            loop {
+                let mut schedule = Schedule::load(&path)?;
+                let now = chrono::Local::now().timestamp();
+
+                let job = match schedule.next() {
+                    Some(job) if job.at <= now => job.clone(),
+                    // Jobs scheduled in the meantime are picked up within a minute
+                    next => {
+                        let wait = next.map_or(60, |j| (j.at - now).min(60)) as u64;
+                        tokio::time::sleep(std::time::Duration::from_secs(wait)).await;
+                        continue;
                     }
+                };
+
+                schedule.remove(&job.id)?;
+                schedule.save(&path)?;
+
+                info!("Running scheduled push {} of {}", job.id, job.targets.join(", "));
+                let status = deploy::trace::status(
+                    Command::new(&deploy)
+                        .current_dir(&job.working_dir)
+                        .arg("--state-dir")
+                        .arg(deploy::state::dir())
+                        .arg("--push-only")
+                        .arg("--targets")
+                        .args(&job.targets),
+                )
+                .await
+                .map_err(RunError::ScheduledPush)?;
+
+                match status.success() {
+                    true => info!("Scheduled push {} succeeded", job.id),
+                    false => error!("Scheduled push {} failed ({})", job.id, status),
                 }
-                return Err(RunDeployError::Rollback(deploy_data.node_name.to_string()));
             }
-            return Err(RunDeployError::DeployProfile(deploy_data.node_name.to_string(), e))
         }
-        succeeded.push((deploy_data, deploy_defs))
     }
 
     Ok(())
 }
 
-#[derive(Error, Debug)]
-pub enum RunError {
-    #[error("Failed to deploy profile: {0}")]
-    DeployProfile(#[from] deploy::deploy::DeployProfileError),
-    #[error("Failed to push profile: {0}")]
-    PushProfile(#[from] deploy::push::PushProfileError),
-    #[error("Failed to test for flake support: {0}")]
-    FlakeTest(std::io::Error),
-    #[error("Failed to check deployment: {0}")]
-    CheckDeployment(#[from] CheckDeploymentError),
-    #[error("Failed to evaluate deployment data: {0}")]
-    GetDeploymentData(#[from] GetDeploymentDataError),
-    #[error("Error parsing flake: {0}")]
-    ParseFlake(#[from] deploy::ParseFlakeError),
-    #[error("Error initiating logger: {0}")]
-    Logger(#[from] flexi_logger::FlexiLoggerError),
-    #[error("{0}")]
-    RunDeploy(#[from] RunDeployError),
-}
-
 pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
     let opts = match args {
         Some(o) => <Opts as FromArgMatches>::from_arg_matches(o),
         None => Opts::parse(),
     };
 
+    let verbosity = opts.verbose.max(opts.debug_logs as u8);
+
+    let output_format = match opts.json {
+        true => OutputFormat::Json,
+        false => opts.output_format.unwrap_or_else(|| OutputFormat::default_for(opts.plain, opts.quiet)),
+    };
+    // `--output-format quiet` is `--quiet`, for the logs as well
+    let opts = Opts {
+        quiet: opts.quiet || output_format == OutputFormat::Quiet,
+        ..opts
+    };
+
+    deploy::redact::register_env_secrets();
+
     deploy::init_logger(
-        opts.debug_logs,
+        verbosity,
+        opts.quiet,
+        opts.log_filter.as_deref(),
         opts.log_dir.as_deref(),
+        &opts.log_recipients,
         &deploy::LoggerType::Deploy,
     )?;
 
+    if let Some(path) = &opts.trace_commands {
+        deploy::trace::start(path).map_err(|e| RunError::TraceCommands(path.clone(), e))?;
+    }
+
     if opts.dry_activate && opts.boot {
         error!("Cannot use both --dry-activate & --boot!");
     }
 
-    let deploys = opts
-        .clone()
-        .targets
-        .unwrap_or_else(|| vec![opts.clone().target.unwrap_or_else(|| ".".to_string())]);
+    if let Some(state_dir) = &opts.state_dir {
+        deploy::state::set_dir(state_dir.clone());
+    }
+
+    let retry = match opts.retry_failed {
+        true => Some(deploy::run_state::RunState::load()?),
+        false => None,
+    };
+    if let Some(retry) = &retry {
+        info!(
+            "Retrying {} profile(s) the last deployment (run {}) didn't deploy",
+            retry.failed.len(),
+            retry.run_id
+        );
+    }
 
-    let deploy_flakes: Vec<DeployFlake> = deploys
-        .iter()
-        .map(|f| deploy::parse_flake(f.as_str()))
-        .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?;
+    let clone = match &opts.subcmd {
+        Some(SubCommand::Clone(clone_opts)) => Some(clone_opts.clone()),
+        _ => None,
+    };
+    let watch = match &opts.subcmd {
+        Some(SubCommand::Watch(watch_opts)) => Some(watch_opts.clone()),
+        _ => None,
+    };
+
+    let deploys = match (&clone, &watch, opts.clone().targets, opts.clone().target, &retry) {
+        (Some(clone), _, _, _, _) => vec![clone.target.clone()],
+        (None, Some(watch), _, _, _) => vec![watch.target.clone().unwrap_or_else(|| ".".to_string())],
+        (None, None, Some(targets), _, _) => targets,
+        (None, None, None, Some(target), _) => vec![target],
+        (None, None, None, None, Some(retry)) => retry.targets.clone(),
+        (None, None, None, None, None) => vec![".".to_string()],
+    };
 
     let cmd_overrides = deploy::CmdOverrides {
-        ssh_user: opts.ssh_user,
-        profile_user: opts.profile_user,
-        ssh_opts: opts.ssh_opts,
+        ssh_user: opts.ssh_user.clone(),
+        profile_user: opts.profile_user.clone(),
+        ssh_opts: match opts.ssh_opts.is_empty() {
+            true => None,
+            false => Some(deploy::settings::split_ssh_opts(&opts.ssh_opts).map_err(|(opts, e)| RunError::SshOpts(opts, e))?),
+        },
+        jump_host: opts.jump_host.clone(),
+        env_ssh_opts: deploy::settings::env_ssh_opts(|name| std::env::var(name).ok()),
         fast_connection: opts.fast_connection,
         auto_rollback: opts.auto_rollback,
-        hostname: opts.hostname,
+        hostname: opts.hostname.clone(),
+        skip_host_key_check: opts.skip_host_key_check,
         magic_rollback: opts.magic_rollback,
-        temp_path: opts.temp_path,
+        temp_path: opts.temp_path.clone(),
         confirm_timeout: opts.confirm_timeout,
         activation_timeout: opts.activation_timeout,
         dry_activate: opts.dry_activate,
         remote_build: opts.remote_build,
-        sudo: opts.sudo,
-        interactive_sudo: opts.interactive_sudo
+        sudo: opts.sudo.clone(),
+        interactive_sudo: opts.interactive_sudo,
+        quiet: opts.quiet,
+        strict: opts.strict,
+        override_restrictions: opts.override_restrictions,
+        max_connections: opts.max_connections,
+        max_unavailable: opts.max_unavailable,
+        max_builds: opts.max_builds,
+        environment: opts.env.clone(),
+        skip_if_unchanged: opts.skip_if_unchanged,
+        changed_only: opts.changed_only,
+        include_maintenance: opts.include_maintenance,
+        non_interactive: opts.non_interactive,
+        explain_overrides: opts.explain_overrides,
+        label: opts.label.clone(),
+        push_only: opts.push_only,
+        fail_on_dry_activate_warnings: opts.fail_on_dry_activate_warnings,
+    };
+
+    let vars = opts
+        .vars
+        .as_deref()
+        .map(deploy::vars::Vars::prepare)
+        .transpose()?;
+
+    match &opts.subcmd {
+        Some(SubCommand::Doctor(doctor_opts)) => {
+            let target = doctor_opts.target.as_deref().unwrap_or(".");
+            let flake = deploy::parse_flake(target)?;
+            deploy::doctor::run_doctor(
+                &[flake],
+                &cmd_overrides,
+                &opts.extra_build_args,
+                doctor_opts.skip_remote,
+            )
+            .await?;
+            return Ok(());
+        }
+        Some(SubCommand::Schedule(schedule_opts)) => {
+            run_schedule(&schedule_opts.action).await?;
+            return Ok(());
+        }
+        Some(SubCommand::PruneHistory(prune_opts)) => {
+            run_prune_history(prune_opts)?;
+            return Ok(());
+        }
+        Some(SubCommand::DecryptLogs(decrypt_opts)) => {
+            for file in &decrypt_opts.files {
+                deploy::encrypted_log::decrypt(file, &decrypt_opts.identity)?;
+            }
+            return Ok(());
+        }
+        Some(SubCommand::SetupKeys(setup_keys_opts)) => {
+            let target = setup_keys_opts.target.as_deref().unwrap_or(".");
+            let flake = deploy::parse_flake(target)?;
+            run_setup_keys(vec![flake], setup_keys_opts, &cmd_overrides, &opts.extra_build_args, vars.as_ref(), opts.quiet).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Plan(plan_opts)) => {
+            let target = plan_opts.target.as_deref().unwrap_or(".");
+            let flake = deploy::parse_flake(target)?;
+            run_plan(vec![flake], plan_opts, &cmd_overrides, &opts.extra_build_args, vars.as_ref(), opts.quiet).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Rollback(rollback_opts)) => {
+            let flake = deploy::parse_flake(&rollback_opts.target)?;
+            let manifest = opts.manifest.as_deref().map(deploy::manifest::Manifest::load).transpose()?;
+            run_rollback(vec![flake], rollback_opts, &cmd_overrides, &opts.extra_build_args, vars.as_ref(), manifest.as_ref(), opts.interactive, opts.quiet).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Logs(logs_opts)) => {
+            let flake = deploy::parse_flake(&logs_opts.target)?;
+            run_logs(vec![flake], logs_opts, &cmd_overrides, &opts.extra_build_args, vars.as_ref(), opts.log_dir.as_deref(), opts.quiet).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Confirm(confirm_opts)) => {
+            run_confirm(confirm_opts, opts.non_interactive).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Locks(locks_opts)) => {
+            let target = match &locks_opts.action {
+                LocksAction::List(list_opts) => list_opts.target.as_deref().unwrap_or("."),
+                LocksAction::Steal(steal_opts) => steal_opts.target.as_str(),
+            };
+            let flake = deploy::parse_flake(target)?;
+            run_locks(vec![flake], &locks_opts.action, &cmd_overrides, &opts.extra_build_args, vars.as_ref(), opts.quiet).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Maintenance(maintenance_opts)) => {
+            run_maintenance(&maintenance_opts.action)?;
+            return Ok(());
+        }
+        Some(SubCommand::Import(import_opts)) => {
+            run_import(import_opts).await?;
+            return Ok(());
+        }
+        Some(SubCommand::Clone(_)) | Some(SubCommand::Watch(_)) | None => (),
+    }
+
+    if let Some(nodes) = opts.simulate_nodes {
+        let renderer = output_format.renderer(std::io::stderr().is_terminal());
+        let runner = deploy::simulate::MockRunner::default();
+        let report = deploy::simulate::simulate(nodes, opts.max_connections, &runner, renderer).await;
+        if opts.quiet {
+            println!("Simulated deployment: {}", report);
+        } else {
+            info!("Simulated deployment: {}", report);
+        }
+        return Ok(());
+    }
+
+    let watch = match &watch {
+        Some(watch) => watch,
+        None => return run_deployment(&opts, &deploys, clone.as_ref(), retry.as_ref(), opts.tags.as_ref(), &cmd_overrides, vars.as_ref(), output_format).await,
+    };
+
+    let tags = match &watch.tag {
+        Some(tag) => Some(tag.parse().map_err(RunError::Tags)?),
+        None => opts.tags.clone(),
     };
+    let repo = deploy::parse_flake(&deploys[0])?.repo;
+    let dir = local_flake_dir(repo).ok_or_else(|| RunError::WatchNotLocal(repo.to_string()))?;
+    let mut watcher = deploy::watch::FlakeWatcher::new(dir)?;
+    let debounce = std::time::Duration::from_millis(watch.debounce);
+    loop {
+        // A failed deployment is retried with the next change
+        if let Err(e) = run_deployment(&opts, &deploys, None, None, tags.as_ref(), &cmd_overrides, vars.as_ref(), output_format).await {
+            error!("{}", e);
+        }
+
+        info!("Watching {} for changes", dir.display());
+        let changed = watcher.changed(debounce).await?;
+        info!("{} file(s) changed, deploying {}", changed.len(), deploys[0]);
+    }
+}
+
+/// Evaluates and deploys `deploys` (or clones the node of `clone`), only to the nodes selected by
+/// `tags` if given
+#[allow(clippy::too_many_arguments)]
+async fn run_deployment(
+    opts: &Opts,
+    deploys: &[String],
+    clone: Option<&CloneOpts>,
+    retry: Option<&deploy::run_state::RunState>,
+    tags: Option<&deploy::TagFilter>,
+    cmd_overrides: &deploy::CmdOverrides,
+    vars: Option<&deploy::vars::Vars>,
+    output_format: OutputFormat,
+) -> Result<(), RunError> {
+    let verbosity = opts.verbose.max(opts.debug_logs as u8);
+    let mut deploy_flakes: Vec<DeployFlake> = deploys
+        .iter()
+        .map(|f| deploy::parse_flake(f.as_str()))
+        .collect::<Result<Vec<DeployFlake>, ParseFlakeError>>()?;
+
+    let manifest = opts
+        .manifest
+        .as_deref()
+        .map(deploy::manifest::Manifest::load)
+        .transpose()?;
 
     let supports_flakes = test_flake_support().await.map_err(RunError::FlakeTest)?;
 
@@ -705,30 +3138,144 @@ pub async fn run(args: Option<&ArgMatches>) -> Result<(), RunError> {
         warn!("A Nix version without flakes support was detected, support for this is work in progress");
     }
 
-    if !opts.skip_checks {
+    // The checks passed for the deployment being retried
+    if !opts.skip_checks && retry.is_none() {
         for deploy_flake in &deploy_flakes {
-            check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args).await?;
+            check_deployment(supports_flakes, deploy_flake.repo, &opts.extra_build_args, vars, opts.quiet, opts.deploy_checks_only).await?;
         }
     }
     let result_path = opts.result_path.as_deref();
-    let data = get_deployment_data(supports_flakes, &deploy_flakes, &opts.extra_build_args).await?;
-    run_deploy(
+    let tag_names = tags.map(deploy::TagFilter::tags).unwrap_or_default();
+    let nodes_args = NodesExprArgs::new(cmd_overrides, &tag_names);
+    let mut data = get_deployment_data(supports_flakes, &deploy_flakes, &opts.extra_build_args, vars, &nodes_args, opts.quiet).await?;
+    if let Some(clone) = clone {
+        deploy::clone_node(&mut deploy_flakes[0], &mut data[0], &clone.to)?;
+        info!("Deploying {} to {} machine(s): {}", clone.target, clone.to.len(), clone.to.join(", "));
+    }
+    if let Some(tags) = tags {
+        for data in &mut data {
+            data.nodes.retain(|_, node| tags.matches(&node.node_settings.tags));
+        }
+        for deploy_flake in &deploy_flakes {
+            if let Some(node) = &deploy_flake.node {
+                if data.iter().all(|data| !data.nodes.contains_key(node)) {
+                    let not_tagged = Err(RunError::NotTagged(node.clone(), tags.to_string()));
+                    Severity::non_critical(opts.strict).check::<(), _>("Selecting the node", not_tagged)?;
+                }
+            }
+        }
+    }
+    let mut renderers = vec![output_format.renderer(std::io::stderr().is_terminal())];
+    if let Some(path) = &opts.report_junit {
+        renderers.push(Box::new(deploy::render::JunitRenderer::new(path.clone())));
+    }
+    if opts.timings {
+        renderers.push(Box::new(deploy::render::TimingsRenderer::default()));
+    }
+    if !opts.no_failure_bundles {
+        renderers.push(Box::new(deploy::bundle::FailureBundleRenderer::new(
+            opts.failure_bundle_dir.clone(),
+            opts.log_dir.as_ref().map(PathBuf::from),
+        )));
+    }
+    // Each flake's notifications report on its own nodes, nothing is sent for dry activations
+    let mut notifications: Vec<(&deploy::notifications::Notifications, Vec<String>)> = Vec::new();
+    for data in data.iter().filter(|_| !opts.dry_activate) {
+        if let Some(n) = &data.notifications {
+            let nodes = data.nodes.keys().cloned();
+            match notifications.iter_mut().find(|(other, _)| *other == n) {
+                Some((_, same)) => same.extend(nodes),
+                None => notifications.push((n, nodes.collect())),
+            }
+        }
+    }
+    let mut notification_errors = Vec::new();
+    for (notifications, nodes) in notifications {
+        let renderer = deploy::notifications::NotificationRenderer::new(notifications.clone(), nodes);
+        notification_errors.push(renderer.errors());
+        renderers.push(Box::new(renderer));
+    }
+    let event_stream = EventStream::start(Box::new(deploy::render::Renderers(renderers)));
+    let mut paths: Vec<(String, String, String)> = Vec::new();
+    let mut plan = Vec::new();
+    for (_, data, (node_name, node), (profile_name, profile)) in select_profiles(&deploy_flakes, &data)? {
+        paths.push((node_name.to_string(), profile_name.to_string(), profile.profile_settings.path.clone()));
+        let environment = deploy::select_environment(data, cmd_overrides).map_err(RunDeployError::from)?;
+        plan.push(deploy::plan_diff::PlannedProfile::new(&deploy::make_deploy_data(
+            &data.generic_settings,
+            environment,
+            node,
+            node_name,
+            profile,
+            profile_name,
+            cmd_overrides,
+            false,
+            None,
+        )));
+    }
+    let mut summary = Summary::new();
+    let mut locks = deploy::locks::Locks::new(chrono::Utc::now().timestamp());
+    let result = run_deploy(
         deploy_flakes,
         data,
         supports_flakes,
         opts.checksigs,
         opts.interactive,
-        &cmd_overrides,
+        cmd_overrides,
         opts.keep_result,
         result_path,
         &opts.extra_build_args,
-        opts.debug_logs,
+        verbosity > 0,
         opts.dry_activate,
         opts.boot,
         &opts.log_dir,
         opts.rollback_succeeded.unwrap_or(true),
+        manifest.as_ref(),
+        retry,
+        &mut summary,
+        &mut locks,
     )
-    .await?;
+    .await;
+    let released = locks.release_all(Severity::non_critical(opts.strict)).await;
+    deploy::multiplex::close_all().await;
+    deploy::ssh_wrapper::remove_shims();
+    event_stream.finish().await;
+    // Reported after the deployment's own error, if any
+    let mut notified = Ok(());
+    for error in notification_errors.iter().flat_map(|errors| errors.take()) {
+        if let Err(e) = Severity::non_critical(opts.strict).check::<(), _>("Notifying", Err(error)) {
+            notified = notified.and(Err(e));
+        }
+    }
+
+    if !summary.is_empty() {
+        let mut run_state = deploy::run_state::RunState::new(deploys.to_vec(), &summary, &paths);
+        run_state.target_host = cmd_overrides.hostname.clone();
+        run_state.skip_host_key_check = cmd_overrides.skip_host_key_check;
+        run_state.plan = plan;
+        // Nothing was activated
+        if opts.dry_activate || opts.push_only {
+            run_state.deployed.clear();
+        }
+        Severity::non_critical(opts.strict).check("Saving the state of the deployment", run_state.save())?;
+    }
+
+    let ignored_errors = deploy::severity::ignored_errors();
+    if ignored_errors > 0 {
+        warn!("{} non-critical error(s) were ignored, use --strict to abort on them", ignored_errors);
+    }
+
+    if !summary.is_empty() {
+        if opts.quiet {
+            print!("{}", summary.render_table());
+        } else {
+            info!("Deployment summary:\n{}", summary.render_table());
+        }
+    }
+
+    result?;
+    released.map_err(RunDeployError::from)?;
+    notified?;
 
     Ok(())
 }
@@ -3,9 +3,11 @@
 // SPDX-License-Identifier: MPL-2.0
 
 use merge::Merge;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
+use std::str::FromStr;
+use thiserror::Error;
 
 #[derive(Deserialize, Debug, Clone, Merge)]
 pub struct GenericSettings {
@@ -20,11 +22,15 @@ pub struct GenericSettings {
     #[merge(strategy = merge::vec::append)]
     pub ssh_opts: Vec<String>,
     #[serde(rename(deserialize = "fastConnection"))]
-    pub fast_connection: Option<bool>,
+    pub fast_connection: Option<FastConnection>,
     #[serde(rename(deserialize = "autoRollback"))]
     pub auto_rollback: Option<bool>,
     #[serde(rename(deserialize = "confirmTimeout"))]
     pub confirm_timeout: Option<u16>,
+    /// With magic rollback, wait for the confirmation for as long as the deployer sends heartbeats
+    /// at most this many seconds apart, instead of `confirmTimeout`
+    #[serde(rename(deserialize = "heartbeatTimeout"))]
+    pub heartbeat_timeout: Option<u16>,
     #[serde(rename(deserialize = "activationTimeout"))]
     pub activation_timeout: Option<u16>,
     #[serde(rename(deserialize = "tempPath"))]
@@ -37,6 +43,337 @@ pub struct GenericSettings {
     pub remote_build: Option<bool>,
     #[serde(rename(deserialize = "interactiveSudo"))]
     pub interactive_sudo: Option<bool>,
+    /// Check how sudo behaves on the node before deploying, and adapt to it
+    #[serde(rename(deserialize = "sudoPreflight"))]
+    pub sudo_preflight: Option<bool>,
+    #[serde(rename(deserialize = "approvalCommand"))]
+    pub approval_command: Option<String>,
+    #[serde(rename(deserialize = "allowedDeployers"))]
+    pub allowed_deployers: Option<Vec<String>>,
+    #[serde(rename(deserialize = "requireSignedManifest"))]
+    pub require_signed_manifest: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nix_options",
+        rename(deserialize = "nixOptions")
+    )]
+    #[merge(strategy = merge_nix_options)]
+    pub nix_options: BTreeMap<String, String>,
+    #[serde(rename(deserialize = "profileEngine"))]
+    pub profile_engine: Option<ProfileEngine>,
+    #[serde(rename(deserialize = "bootstrapSshUser"))]
+    pub bootstrap_ssh_user: Option<String>,
+    #[serde(rename(deserialize = "pushStallTimeout"))]
+    pub push_stall_timeout: Option<u16>,
+    #[serde(rename(deserialize = "pushStallRetries"))]
+    pub push_stall_retries: Option<u8>,
+    #[serde(rename(deserialize = "pushStrategy"))]
+    pub push_strategy: Option<PushStrategy>,
+    /// Public keys of the caches (`name:key`, e.g. `cache.nixos.org-1:6NCH...`) one of which has to
+    /// have signed every path of the closure
+    #[serde(rename(deserialize = "allowedSigners"))]
+    pub allowed_signers: Option<Vec<String>>,
+    /// Share one SSH connection to the node between all sessions deploy-rs opens
+    #[serde(rename(deserialize = "sshMultiplexing"))]
+    pub ssh_multiplexing: Option<bool>,
+    /// Reach the node through this SSH destination (`[user@]host[:port]`, or several separated
+    /// by commas), as with `ssh -J`
+    #[serde(rename(deserialize = "sshJumpHost"))]
+    pub ssh_jump_host: Option<String>,
+    /// The command ssh is run through (e.g. `tsh ssh`), given the arguments ssh would get
+    #[serde(rename(deserialize = "sshWrapper"))]
+    pub ssh_wrapper: Option<Vec<String>>,
+    /// How the commands deploying the profile are run on the node
+    #[serde(rename(deserialize = "sshTransport"))]
+    pub ssh_transport: Option<SshTransport>,
+    /// Copy closures to this SSH destination once and from there to the nodes
+    #[serde(rename(deserialize = "relayHost"))]
+    pub relay_host: Option<String>,
+    #[serde(rename(deserialize = "singleUserNix"))]
+    pub single_user_nix: Option<bool>,
+    #[serde(rename(deserialize = "securityModule"))]
+    pub security_module: Option<SecurityModule>,
+    #[serde(rename(deserialize = "failOnDenials"))]
+    pub fail_on_denials: Option<bool>,
+    #[serde(rename(deserialize = "activationIsolation"))]
+    pub activation_isolation: Option<bool>,
+    #[serde(rename(deserialize = "activationLimits"))]
+    pub activation_limits: Option<ActivationLimits>,
+    pub snapshot: Option<SnapshotSettings>,
+    pub lock: Option<LockSettings>,
+    pub rollout: Option<Rollout>,
+}
+
+/// Whether the connection to a node is fast enough to copy whole closures to it, instead of
+/// letting it substitute what it can
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FastConnection {
+    Fast,
+    Slow,
+    /// Measure the connection before the first copy to the node
+    Auto,
+}
+
+impl FromStr for FastConnection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => Ok(FastConnection::Fast),
+            "false" => Ok(FastConnection::Slow),
+            "auto" => Ok(FastConnection::Auto),
+            _ => Err(format!(
+                "invalid value `{}` for fastConnection, expected true, false or \"auto\"",
+                s
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FastConnection {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Bool(bool),
+            String(String),
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Bool(true) => Ok(FastConnection::Fast),
+            Value::Bool(false) => Ok(FastConnection::Slow),
+            Value::String(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// How profiles are switched to new generations on the node
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProfileEngine {
+    /// `nix-env --set` and `nix-env --rollback`
+    #[default]
+    NixEnv,
+    /// `nix build --profile`, for new-style profiles (created with `nix profile`), which
+    /// `nix-env` refuses to touch
+    NixProfile,
+    /// Switching the profile symlink to a new generation link directly, without running Nix,
+    /// for nodes with too little memory for it
+    Lite,
+}
+
+impl ProfileEngine {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProfileEngine::NixEnv => "nix-env",
+            ProfileEngine::NixProfile => "nix-profile",
+            ProfileEngine::Lite => "lite",
+        }
+    }
+}
+
+impl FromStr for ProfileEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nix-env" => Ok(ProfileEngine::NixEnv),
+            "nix-profile" => Ok(ProfileEngine::NixProfile),
+            "lite" => Ok(ProfileEngine::Lite),
+            _ => Err(format!(
+                "invalid value `{}` for profileEngine, expected \"nix-env\", \"nix-profile\" or \"lite\"",
+                s
+            )),
+        }
+    }
+}
+
+/// How closures get into the store of the node
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PushStrategy {
+    /// `nix copy --to ssh://...`
+    #[default]
+    Copy,
+    /// The node copies from a `nix-serve` of the local store, through a reverse SSH tunnel
+    Serve,
+    /// The missing paths are exported, uploaded with `sftp` and imported on the node
+    Sftp,
+}
+
+/// How commands are run on nodes over SSH (`sshTransport`)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SshTransport {
+    /// The `ssh` binary
+    #[default]
+    External,
+    /// libssh2, see [`crate::native_ssh`]
+    Native,
+}
+
+/// Limits for activation scripts run in a transient systemd service (`activationIsolation`)
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ActivationLimits {
+    /// `MemoryMax=` of the service, e.g. `"2G"`
+    pub memory_max: Option<String>,
+    /// `CPUQuota=` of the service, e.g. `"50%"`
+    pub cpu_quota: Option<String>,
+    /// Seconds after which the activation script is killed
+    pub timeout: Option<u32>,
+    /// The slice the service is put in, `deploy-rs.slice` if not set
+    pub slice: Option<String>,
+}
+
+impl ActivationLimits {
+    /// The options of `systemd-run` running a command in a transient service with these limits,
+    /// waiting for it and passing its output through
+    pub fn systemd_run_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "--wait".to_string(),
+            "--collect".to_string(),
+            "--pipe".to_string(),
+            "--quiet".to_string(),
+            "--service-type=exec".to_string(),
+            "--description=deploy-rs activation".to_string(),
+            format!(
+                "--slice={}",
+                self.slice.as_deref().unwrap_or("deploy-rs.slice")
+            ),
+        ];
+        if let Some(memory_max) = &self.memory_max {
+            args.push(format!("--property=MemoryMax={}", memory_max));
+        }
+        if let Some(cpu_quota) = &self.cpu_quota {
+            args.push(format!("--property=CPUQuota={}", cpu_quota));
+        }
+        if let Some(timeout) = self.timeout {
+            args.push(format!("--property=RuntimeMaxSec={}", timeout));
+        }
+        args
+    }
+}
+
+/// Activating the profiles of several nodes at once, in waves
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Rollout {
+    /// How many nodes may be activating (and waiting for confirmation) at the same time
+    #[serde(rename(deserialize = "maxUnavailable"))]
+    pub max_unavailable: usize,
+}
+
+/// A lock on the node shared by everyone deploying it, see [`crate::locks`]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct LockSettings {
+    /// Where the locks are kept, `ssh://host/dir`, `s3://bucket/prefix` or `etcd://host:port/prefix`
+    pub url: String,
+    /// Seconds after which a lock left behind may be replaced, never by default
+    pub ttl: Option<u64>,
+}
+
+/// Filesystem snapshots taken on the node before activating, see [`crate::snapshot`]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotSettings {
+    #[serde(rename = "type")]
+    pub kind: crate::snapshot::SnapshotType,
+    /// The ZFS datasets, or the paths of the btrfs subvolumes
+    pub datasets: Vec<String>,
+    /// How many snapshots of each dataset to keep, [`crate::snapshot::DEFAULT_KEEP`] if not set
+    pub keep: Option<usize>,
+}
+
+/// Mandatory access control enforced on the node, see [`crate::security`]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityModule {
+    Selinux,
+    Apparmor,
+}
+
+impl SecurityModule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityModule::Selinux => "selinux",
+            SecurityModule::Apparmor => "apparmor",
+        }
+    }
+}
+
+impl FromStr for SecurityModule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "selinux" => Ok(SecurityModule::Selinux),
+            "apparmor" => Ok(SecurityModule::Apparmor),
+            _ => Err(format!(
+                "invalid value `{}` for securityModule, expected \"selinux\" or \"apparmor\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Platforms whose activation differs from that of a regular machine
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetPlatform {
+    /// NixOS-WSL instances, which may run without systemd and have no boot loader
+    Wsl,
+}
+
+impl TargetPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TargetPlatform::Wsl => "wsl",
+        }
+    }
+}
+
+impl FromStr for TargetPlatform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wsl" => Ok(TargetPlatform::Wsl),
+            _ => Err(format!(
+                "invalid value `{}` for targetPlatform, expected \"wsl\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Options set in a more specific place take precedence, others are kept
+fn merge_nix_options(left: &mut BTreeMap<String, String>, right: BTreeMap<String, String>) {
+    for (name, value) in right {
+        left.entry(name).or_insert(value);
+    }
+}
+
+/// Takes numbers, booleans and lists as well, formatted like in `nix.conf`
+fn deserialize_nix_options<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    fn format(value: serde_json::Value) -> Result<String, String> {
+        match value {
+            serde_json::Value::String(s) => Ok(s),
+            serde_json::Value::Number(n) => Ok(n.to_string()),
+            serde_json::Value::Bool(b) => Ok(b.to_string()),
+            serde_json::Value::Array(values) => Ok(values
+                .into_iter()
+                .map(format)
+                .collect::<Result<Vec<_>, _>>()?
+                .join(" ")),
+            other => Err(format!("unsupported value for a Nix option: {}", other)),
+        }
+    }
+
+    BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| Ok((name, format(value).map_err(serde::de::Error::custom)?)))
+        .collect()
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -49,6 +386,42 @@ pub struct NodeSettings {
         rename(deserialize = "profilesOrder")
     )]
     pub profiles_order: Vec<String>,
+    #[serde(rename(deserialize = "uplinkGroup"))]
+    pub uplink_group: Option<String>,
+    #[serde(default, rename(deserialize = "inheritsFrom"))]
+    pub inherits_from: Vec<String>,
+    /// Virtual machines (e.g. microvm.nix guests) running on the node, only reachable through it
+    #[serde(default)]
+    pub guests: HashMap<String, Node>,
+    /// For guests whose store is shared with the node they run on, copy to that node instead
+    #[serde(default, rename(deserialize = "hostStore"))]
+    pub host_store: bool,
+    /// The port SSH listens on, unless the hostname gives one (`host:2222`)
+    #[serde(rename(deserialize = "sshPort"))]
+    pub ssh_port: Option<u16>,
+    /// For guests, the `user@hostname` of the node they run on, used as ProxyJump
+    #[serde(skip)]
+    pub jump_host: Option<String>,
+    /// How many `parallel` profiles of the node may be activated at the same time
+    #[serde(rename(deserialize = "maxParallelActivations"))]
+    pub max_parallel_activations: Option<usize>,
+    /// Free-form labels of the node, e.g. `dev` for the nodes `deploy watch --tag dev` deploys to
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The node is under maintenance, deployments skip it unless `--include-maintenance` is given
+    #[serde(default)]
+    pub maintenance: bool,
+    /// The hostname of a failover machine the profiles of the node are staged on for the next boot
+    #[serde(rename(deserialize = "standbyHostname"))]
+    pub standby_hostname: Option<String>,
+    /// For the standby of a node, the name of that node
+    #[serde(skip)]
+    pub standby_of: Option<String>,
+}
+
+/// The name of the node the standby of node `node_name` is deployed as
+pub fn standby_name(node_name: &str) -> String {
+    format!("{}-standby", node_name)
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -56,6 +429,39 @@ pub struct ProfileSettings {
     pub path: String,
     #[serde(rename(deserialize = "profilePath"))]
     pub profile_path: Option<String>,
+    /// Environment variables for the activation, set to facts about nodes deployed earlier
+    #[serde(default)]
+    pub requires: HashMap<String, String>,
+    /// Name of a NixOS container on the node to activate the profile in
+    pub container: Option<String>,
+    #[serde(rename(deserialize = "targetPlatform"))]
+    pub target_platform: Option<TargetPlatform>,
+    /// Whether the profile may be activated at the same time as the other `parallel` profiles of
+    /// the node next to it in the activation order
+    #[serde(default)]
+    pub parallel: bool,
+    /// URLs and `host:port` endpoints the node must reach for the profile to work
+    #[serde(default, rename(deserialize = "externalDependencies"))]
+    pub external_dependencies: Vec<String>,
+    /// The NixOS specialisation of the closure to switch into instead of its base configuration
+    pub specialisation: Option<String>,
+    pub migrations: Option<Migrations>,
+    /// Absolute path of the directory to run the activation script in, by default the profile
+    /// itself (or the directory containing it, if the profile isn't a directory)
+    #[serde(rename(deserialize = "workingDir"))]
+    pub working_dir: Option<String>,
+}
+
+/// A command run on one node of a group before the profiles of the group are activated, see
+/// [`crate::migrations`]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Migrations {
+    /// Shell command run in the new closure
+    pub command: String,
+    /// The profiles with the same group run the migrations once, by default those with the same name
+    pub group: Option<String>,
+    /// The node to run the migrations on, by default the first of the group to be activated
+    pub leader: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -74,9 +480,420 @@ pub struct Node {
     pub node_settings: NodeSettings,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnvironmentNode {
+    pub hostname: Option<String>,
+    #[serde(flatten)]
+    pub generic_settings: GenericSettings,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Environment {
+    #[serde(flatten)]
+    pub generic_settings: GenericSettings,
+    #[serde(default)]
+    pub nodes: HashMap<String, EnvironmentNode>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Data {
     #[serde(flatten)]
     pub generic_settings: GenericSettings,
     pub nodes: HashMap<String, Node>,
+    #[serde(default)]
+    pub environments: HashMap<String, Environment>,
+    #[serde(rename(deserialize = "nodeDefaults"))]
+    pub node_defaults: Option<GenericSettings>,
+    #[serde(default, rename(deserialize = "nodeTemplates"))]
+    pub node_templates: HashMap<String, GenericSettings>,
+    pub notifications: Option<crate::notifications::Notifications>,
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("Node `{0}` inherits from `{1}`, but there is no such node template{2}")]
+    Unknown(String, String, String),
+    #[error("Guest `{0}` of node `{1}` has the same name as another node")]
+    DuplicateGuest(String, String),
+    #[error("Guest `{0}` of node `{1}` has guests itself, which isn't supported")]
+    NestedGuests(String, String),
+    #[error("The standby of node `{1}` would be node `{0}`, but there is another node with that name")]
+    DuplicateStandby(String, String),
+}
+
+/// The option making ssh connect to a guest through the node it runs on
+pub fn proxy_jump_opt(jump_host: &str) -> String {
+    format!("-oProxyJump={}", jump_host)
+}
+
+/// Makes `ssh_opts` go through `jump_host` (`sshJumpHost`) first: before the node a guest runs on,
+/// or straight to the node otherwise
+pub fn add_jump_host(ssh_opts: &mut Vec<String>, jump_host: &str) {
+    let prefix = proxy_jump_opt("");
+    match ssh_opts.iter_mut().find(|opt| opt.starts_with(&prefix)) {
+        Some(opt) => *opt = format!("{},{}", proxy_jump_opt(jump_host), &opt[prefix.len()..]),
+        None => ssh_opts.push(proxy_jump_opt(jump_host)),
+    }
+}
+
+/// The `ssh_opts` of a guest, connecting to the node it runs on (`jump_host`) instead
+pub fn without_guest_jump(ssh_opts: &[String], jump_host: &str) -> Vec<String> {
+    let guest_jump = format!(",{}", jump_host);
+    ssh_opts
+        .iter()
+        .filter(|opt| **opt != proxy_jump_opt(jump_host))
+        .map(|opt| match opt.starts_with(&proxy_jump_opt("")) {
+            true => opt.strip_suffix(&guest_jump).unwrap_or(opt).to_string(),
+            false => opt.clone(),
+        })
+        .collect()
+}
+
+fn inherit(
+    node_name: &str,
+    node: &mut Node,
+    node_defaults: &Option<GenericSettings>,
+    node_templates: &HashMap<String, GenericSettings>,
+) -> Result<(), TemplateError> {
+    for template_name in node.node_settings.inherits_from.iter().rev() {
+        let template = match node_templates.get(template_name) {
+            Some(x) => x,
+            None => {
+                let names: Vec<&str> = node_templates.keys().map(String::as_str).collect();
+                return Err(TemplateError::Unknown(
+                    node_name.to_string(),
+                    template_name.clone(),
+                    crate::suggest::not_found_hint(template_name, "node template", &names),
+                ));
+            }
+        };
+        node.generic_settings.merge(template.clone());
+    }
+
+    if let Some(defaults) = node_defaults {
+        node.generic_settings.merge(defaults.clone());
+    }
+
+    Ok(())
+}
+
+impl Data {
+    /// Merges the `nodeTemplates` each node inherits from (later ones taking precedence) and then
+    /// `nodeDefaults` into the settings of the node, which take precedence over both
+    pub fn apply_templates(&mut self) -> Result<(), TemplateError> {
+        let Data {
+            nodes,
+            node_defaults,
+            node_templates,
+            ..
+        } = self;
+
+        for (node_name, node) in nodes.iter_mut() {
+            inherit(node_name, node, node_defaults, node_templates)?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns the `guests` of every node into nodes of their own, reached with the node they run
+    /// on as ProxyJump. Templates are applied to them as well, so this goes after
+    /// [`Data::apply_templates`].
+    pub fn expand_guests(&mut self) -> Result<(), TemplateError> {
+        let mut expanded = Vec::new();
+
+        for (host_name, host) in self.nodes.iter_mut() {
+            let ssh_user = host
+                .generic_settings
+                .ssh_user
+                .as_ref()
+                .or(self.generic_settings.ssh_user.as_ref());
+            let mut jump_host = match ssh_user {
+                Some(user) => format!("{}@{}", user, host.node_settings.hostname),
+                None => host.node_settings.hostname.clone(),
+            };
+            if let (Some(port), (_, None)) = (
+                host.node_settings.ssh_port,
+                crate::settings::split_port(&host.node_settings.hostname),
+            ) {
+                jump_host = format!("{}:{}", jump_host, port);
+            }
+
+            for (guest_name, mut guest) in host.node_settings.guests.drain() {
+                if !guest.node_settings.guests.is_empty() {
+                    return Err(TemplateError::NestedGuests(guest_name, host_name.clone()));
+                }
+
+                inherit(&guest_name, &mut guest, &self.node_defaults, &self.node_templates)?;
+                guest
+                    .generic_settings
+                    .ssh_opts
+                    .push(proxy_jump_opt(&jump_host));
+                guest.node_settings.jump_host = Some(jump_host.clone());
+
+                expanded.push((guest_name, host_name.clone(), guest));
+            }
+        }
+
+        for (guest_name, host_name, guest) in expanded {
+            if self.nodes.contains_key(&guest_name) {
+                return Err(TemplateError::DuplicateGuest(guest_name, host_name));
+            }
+            self.nodes.insert(guest_name, guest);
+        }
+
+        Ok(())
+    }
+
+    /// Adds a node for the standby of every node with a `standbyHostname`, named with
+    /// [`standby_name`], which has the same settings and profiles but the standby's hostname. This
+    /// goes after [`Data::expand_guests`], so guests can have standbys too.
+    pub fn expand_standbys(&mut self) -> Result<(), TemplateError> {
+        let mut expanded = Vec::new();
+
+        for (node_name, node) in &self.nodes {
+            if let Some(standby_hostname) = &node.node_settings.standby_hostname {
+                let mut standby = node.clone();
+                standby.node_settings.hostname = standby_hostname.clone();
+                standby.node_settings.standby_hostname = None;
+                standby.node_settings.standby_of = Some(node_name.clone());
+
+                expanded.push((standby_name(node_name), node_name.clone(), standby));
+            }
+        }
+
+        for (standby_name, node_name, standby) in expanded {
+            if self.nodes.contains_key(&standby_name) {
+                return Err(TemplateError::DuplicateStandby(standby_name, node_name));
+            }
+            self.nodes.insert(standby_name, standby);
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fast_connection() {
+    let settings = |value| -> GenericSettings {
+        serde_json::from_value(serde_json::json!({ "fastConnection": value })).unwrap()
+    };
+
+    assert_eq!(settings(serde_json::json!(true)).fast_connection, Some(FastConnection::Fast));
+    assert_eq!(settings(serde_json::json!(false)).fast_connection, Some(FastConnection::Slow));
+    assert_eq!(settings(serde_json::json!("auto")).fast_connection, Some(FastConnection::Auto));
+    assert!(serde_json::from_value::<GenericSettings>(serde_json::json!({ "fastConnection": "yes" })).is_err());
+    assert_eq!("auto".parse(), Ok(FastConnection::Auto));
+}
+
+#[test]
+fn test_activation_limits() {
+    let limits: ActivationLimits = serde_json::from_value(serde_json::json!({
+        "memoryMax": "2G",
+        "timeout": 600,
+    }))
+    .unwrap();
+
+    assert_eq!(
+        &limits.systemd_run_args()[6..],
+        [
+            "--slice=deploy-rs.slice",
+            "--property=MemoryMax=2G",
+            "--property=RuntimeMaxSec=600",
+        ]
+    );
+}
+
+#[test]
+fn test_nix_options() {
+    let node: Node = serde_json::from_value(serde_json::json!({
+        "hostname": "slow.example.com",
+        "profiles": {},
+        "nixOptions": {
+            "http-connections": 4,
+            "narinfo-cache-negative-ttl": "0",
+            "substituters": ["https://cache.nixos.org", "https://cache.example.com"],
+        },
+    }))
+    .unwrap();
+    let mut settings = node.generic_settings;
+    settings.merge(
+        serde_json::from_value(serde_json::json!({
+            "nixOptions": { "http-connections": 25, "connect-timeout": 5 },
+        }))
+        .unwrap(),
+    );
+
+    let options: Vec<(&str, &str)> = settings
+        .nix_options
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    assert_eq!(
+        options,
+        vec![
+            ("connect-timeout", "5"),
+            ("http-connections", "4"),
+            ("narinfo-cache-negative-ttl", "0"),
+            (
+                "substituters",
+                "https://cache.nixos.org https://cache.example.com"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_expand_guests() {
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "admin",
+        "nodeTemplates": { "vm": { "user": "root" } },
+        "nodes": {
+            "hypervisor": {
+                "hostname": "hv.example.com",
+                "profiles": {},
+                "guests": {
+                    "builder": {
+                        "hostname": "10.0.0.2",
+                        "inheritsFrom": ["vm"],
+                        "hostStore": true,
+                        "profiles": {},
+                    },
+                },
+            },
+        },
+    }))
+    .unwrap();
+
+    data.apply_templates().unwrap();
+    data.expand_guests().unwrap();
+
+    let builder = &data.nodes["builder"];
+    assert_eq!(builder.node_settings.hostname, "10.0.0.2");
+    assert_eq!(
+        builder.node_settings.jump_host.as_deref(),
+        Some("admin@hv.example.com")
+    );
+    assert!(builder.node_settings.host_store);
+    assert_eq!(
+        builder.generic_settings.ssh_opts,
+        vec!["-oProxyJump=admin@hv.example.com"]
+    );
+    assert_eq!(builder.generic_settings.user.as_deref(), Some("root"));
+    assert!(data.nodes["hypervisor"].node_settings.guests.is_empty());
+
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "builder": { "hostname": "builder.example.com", "profiles": {} },
+            "hypervisor": {
+                "hostname": "hv.example.com",
+                "profiles": {},
+                "guests": { "builder": { "hostname": "10.0.0.2", "profiles": {} } },
+            },
+        },
+    }))
+    .unwrap();
+    assert!(matches!(
+        data.expand_guests(),
+        Err(TemplateError::DuplicateGuest(guest, host)) if guest == "builder" && host == "hypervisor"
+    ));
+}
+
+#[test]
+fn test_add_jump_host() {
+    let mut ssh_opts = vec!["-p".to_string(), "2222".to_string()];
+    add_jump_host(&mut ssh_opts, "admin@bastion.example.com:2200");
+    assert_eq!(ssh_opts, vec!["-p", "2222", "-oProxyJump=admin@bastion.example.com:2200"]);
+
+    // Guests are reached through the bastion, then the node they run on
+    let mut ssh_opts = vec![proxy_jump_opt("admin@hv.example.com")];
+    add_jump_host(&mut ssh_opts, "bastion.example.com");
+    assert_eq!(ssh_opts, vec!["-oProxyJump=bastion.example.com,admin@hv.example.com"]);
+    assert_eq!(
+        without_guest_jump(&ssh_opts, "admin@hv.example.com"),
+        vec!["-oProxyJump=bastion.example.com"]
+    );
+    let guest_opts = vec![proxy_jump_opt("admin@hv.example.com")];
+    assert!(without_guest_jump(&guest_opts, "admin@hv.example.com").is_empty());
+}
+
+#[test]
+fn test_expand_standbys() {
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "db1": {
+                "hostname": "db1.example.com",
+                "standbyHostname": "db1-failover.example.com",
+                "sshUser": "admin",
+                "profiles": { "system": { "path": "/nix/store/blah-system" } },
+            },
+            "web1": { "hostname": "web1.example.com", "profiles": {} },
+        },
+    }))
+    .unwrap();
+
+    data.expand_standbys().unwrap();
+
+    assert_eq!(data.nodes.len(), 3);
+    let standby = &data.nodes["db1-standby"];
+    assert_eq!(standby.node_settings.hostname, "db1-failover.example.com");
+    assert_eq!(standby.node_settings.standby_of.as_deref(), Some("db1"));
+    assert_eq!(standby.node_settings.standby_hostname, None);
+    assert_eq!(standby.generic_settings.ssh_user.as_deref(), Some("admin"));
+    assert!(standby.node_settings.profiles.contains_key("system"));
+    assert_eq!(data.nodes["db1"].node_settings.standby_of, None);
+
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "nodes": {
+            "db1": { "hostname": "db1.example.com", "standbyHostname": "10.0.0.3", "profiles": {} },
+            "db1-standby": { "hostname": "10.0.0.4", "profiles": {} },
+        },
+    }))
+    .unwrap();
+    assert!(matches!(
+        data.expand_standbys(),
+        Err(TemplateError::DuplicateStandby(standby, node)) if standby == "db1-standby" && node == "db1"
+    ));
+}
+
+#[test]
+fn test_apply_templates() {
+    let mut data: Data = serde_json::from_value(serde_json::json!({
+        "nodeDefaults": { "sshUser": "admin", "sudo": "doas -u" },
+        "nodeTemplates": {
+            "baseServer": { "sshUser": "deploy", "sshOpts": ["-p", "2222"] },
+            "euRegion": { "sshUser": "eu-deploy", "tempPath": "/var/tmp" },
+        },
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "inheritsFrom": ["baseServer", "euRegion"],
+                "tempPath": "/tmp",
+                "profiles": {},
+            },
+            "db1": { "hostname": "db1.example.com", "profiles": {} },
+        },
+    }))
+    .unwrap();
+
+    data.apply_templates().unwrap();
+
+    let web1 = &data.nodes["web1"].generic_settings;
+    assert_eq!(web1.ssh_user.as_deref(), Some("eu-deploy"));
+    assert_eq!(web1.temp_path, Some(PathBuf::from("/tmp")));
+    assert_eq!(web1.ssh_opts, vec!["-p", "2222"]);
+    assert_eq!(web1.sudo.as_deref(), Some("doas -u"));
+
+    let db1 = &data.nodes["db1"].generic_settings;
+    assert_eq!(db1.ssh_user.as_deref(), Some("admin"));
+
+    data.nodes
+        .get_mut("db1")
+        .unwrap()
+        .node_settings
+        .inherits_from
+        .push("baseSever".to_string());
+    assert!(matches!(
+        data.apply_templates(),
+        Err(TemplateError::Unknown(_, _, hint)) if hint.contains("did you mean `baseServer`?")
+    ));
 }
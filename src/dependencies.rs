@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Endpoints a profile needs once activated (`externalDependencies`), e.g. the registry its
+//! containers are pulled from or a license server.
+//!
+//! Before anything is built, each of them is probed from the node (in the container of the
+//! profile, if any) in a single session: URLs with `curl`, which succeeds on any HTTP response,
+//! and TCP endpoints by opening a connection with bash's `/dev/tcp`. The deployment stops if any
+//! of them is unreachable.
+
+use log::{debug, info};
+use thiserror::Error;
+
+use crate::deploy::{in_container, node_command, shell_quote};
+use crate::trace;
+use crate::{DeployData, DeployDefs};
+
+/// How long each probe may take, in seconds
+const PROBE_TIMEOUT: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dependency {
+    /// An `http://` or `https://` URL
+    Url(String),
+    /// A `host:port` (or `tcp://host:port`) endpoint
+    Tcp { host: String, port: u16 },
+}
+
+impl std::str::FromStr for Dependency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Dependency::Url(s.to_string()));
+        }
+
+        let invalid = || {
+            format!(
+                "invalid external dependency `{}`, expected a http(s) URL or host:port",
+                s
+            )
+        };
+        let endpoint = s.strip_prefix("tcp://").unwrap_or(s);
+        let (host, port) = endpoint.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let valid_host = !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || ".-_:".contains(c));
+        if !valid_host {
+            return Err(invalid());
+        }
+
+        Ok(Dependency::Tcp {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Dependency {
+    /// The shell command succeeding if the dependency is reachable
+    fn probe_command(&self) -> String {
+        match self {
+            Dependency::Url(url) => format!(
+                "curl --silent --show-error --output /dev/null --max-time {} {}",
+                PROBE_TIMEOUT,
+                shell_quote(url)
+            ),
+            Dependency::Tcp { host, port } => format!(
+                "timeout {} bash -c {}",
+                PROBE_TIMEOUT,
+                shell_quote(&format!("exec 3<>/dev/tcp/{}/{}", host, port))
+            ),
+        }
+    }
+}
+
+/// The script probing `dependencies`, printing the unreachable ones with the reason, one per line
+/// and separated by a tab
+fn probe_script(dependencies: &[(&str, Dependency)]) -> String {
+    dependencies
+        .iter()
+        .map(|(name, dependency)| {
+            format!(
+                "out=$( {{ {}; }} 2>&1 ) || printf '%s\\t%s\\n' {} \"$(echo ${{out:-unreachable}})\"",
+                dependency.probe_command(),
+                shell_quote(name)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// The unreachable dependencies and why in the output of [`probe_script`]
+fn parse_unreachable(output: &str) -> Vec<(String, String)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, reason)| (name.to_string(), reason.trim().to_string()))
+        .collect()
+}
+
+fn describe_unreachable(unreachable: &[(String, String)]) -> String {
+    unreachable
+        .iter()
+        .map(|(name, reason)| format!("{} ({})", name, reason))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Error, Debug)]
+pub enum DependencyError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Failed to probe the external dependencies on the node: {0}")]
+    Ssh(std::io::Error),
+    #[error("Probing the external dependencies on the node resulted in a bad exit code: {0:?}")]
+    Exit(Option<i32>),
+    #[error("External dependencies unreachable from the node: {}", describe_unreachable(.0))]
+    Unreachable(Vec<(String, String)>),
+}
+
+/// Checks that the `externalDependencies` of the profile are reachable from its node
+pub async fn check(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+) -> Result<(), DependencyError> {
+    let names = &deploy_data.profile.profile_settings.external_dependencies;
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    let dependencies = names
+        .iter()
+        .map(|name| Ok((name.as_str(), name.parse()?)))
+        .collect::<Result<Vec<(&str, Dependency)>, String>>()
+        .map_err(DependencyError::Invalid)?;
+
+    info!(
+        "Checking {} external dependencies of profile `{}` from node `{}`",
+        dependencies.len(),
+        deploy_data.profile_name,
+        deploy_data.node_name
+    );
+
+    let script = probe_script(&dependencies);
+    debug!("Constructed dependency probes: {}", script);
+
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, script))
+            .stdin(std::process::Stdio::null()),
+    )
+    .await
+    .map_err(DependencyError::Ssh)?;
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(DependencyError::Exit(a)),
+    };
+
+    let unreachable = parse_unreachable(&String::from_utf8_lossy(&output.stdout));
+    match unreachable.is_empty() {
+        true => Ok(()),
+        false => Err(DependencyError::Unreachable(unreachable)),
+    }
+}
+
+#[test]
+fn test_dependencies() {
+    assert_eq!(
+        "https://registry.example.com/v2/".parse(),
+        Ok(Dependency::Url("https://registry.example.com/v2/".to_string()))
+    );
+    assert_eq!(
+        "tcp://license.example.com:27000".parse(),
+        Ok(Dependency::Tcp {
+            host: "license.example.com".to_string(),
+            port: 27000
+        })
+    );
+    assert_eq!(
+        "[fd00::1]:5432".parse(),
+        Ok(Dependency::Tcp {
+            host: "fd00::1".to_string(),
+            port: 5432
+        })
+    );
+    assert!("license.example.com".parse::<Dependency>().is_err());
+    assert!("$(reboot):22".parse::<Dependency>().is_err());
+
+    let script = probe_script(&[("db:5432", "db:5432".parse().unwrap())]);
+    assert_eq!(
+        script,
+        "out=$( { timeout 10 bash -c 'exec 3<>/dev/tcp/db/5432'; } 2>&1 ) || printf '%s\\t%s\\n' 'db:5432' \"$(echo ${out:-unreachable})\""
+    );
+
+    assert_eq!(
+        parse_unreachable("db:5432\tbash: connect: Connection refused\n"),
+        vec![(
+            "db:5432".to_string(),
+            "bash: connect: Connection refused".to_string()
+        )]
+    );
+}
@@ -4,11 +4,19 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use std::path::Path;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::{io::AsyncWriteExt, process::Command};
 
+use crate::events::{emit, wait_with_output_events, EventKind, SpawnWithEvents};
+use crate::data::{ActivationLimits, ProfileEngine, SecurityModule, SnapshotSettings, SshTransport, TargetPlatform};
+use crate::native_ssh::NativeSshError;
+use crate::pending_confirm::PendingConfirmation;
+use crate::dry_activate::DryActivateReport;
+use crate::units::UnitChanges;
+use crate::trace;
 use crate::{DeployDataDefsError, DeployDefs, ProfileInfo};
 
 struct ActivateCommandData<'a> {
@@ -19,10 +27,35 @@ struct ActivateCommandData<'a> {
     temp_path: &'a Path,
     confirm_timeout: u16,
     magic_rollback: bool,
+    heartbeat_timeout: Option<u16>,
     debug_logs: bool,
     log_dir: Option<&'a str>,
     dry_activate: bool,
     boot: bool,
+    specialisation: Option<&'a str>,
+    working_dir: Option<&'a str>,
+    require_signed_manifest: bool,
+    manifest_signature: Option<&'a str>,
+    env: &'a [(String, String)],
+    label: Option<&'a str>,
+    target_platform: Option<TargetPlatform>,
+    profile_engine: Option<ProfileEngine>,
+    security_module: Option<SecurityModule>,
+    fail_on_denials: bool,
+    isolation: Option<&'a ActivationLimits>,
+    snapshot: Option<&'a SnapshotSettings>,
+}
+
+static NO_LIMITS: ActivationLimits = ActivationLimits {
+    memory_max: None,
+    cpu_quota: None,
+    timeout: None,
+    slice: None,
+};
+
+/// Quotes `s` for a POSIX shell
+pub(crate) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
 fn build_activate_command(data: &ActivateCommandData) -> String {
@@ -61,6 +94,13 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
 
     if data.magic_rollback {
         self_activate_command = format!("{} --magic-rollback", self_activate_command);
+
+        if let Some(heartbeat_timeout) = data.heartbeat_timeout {
+            self_activate_command = format!(
+                "{} --heartbeat-timeout {}",
+                self_activate_command, heartbeat_timeout
+            );
+        }
     }
 
     if data.auto_rollback {
@@ -75,6 +115,122 @@ fn build_activate_command(data: &ActivateCommandData) -> String {
         self_activate_command = format!("{} --boot", self_activate_command);
     }
 
+    if let Some(specialisation) = data.specialisation {
+        self_activate_command = format!(
+            "{} --specialisation {}",
+            self_activate_command,
+            shell_quote(specialisation)
+        );
+    }
+
+    if let Some(working_dir) = data.working_dir {
+        self_activate_command = format!(
+            "{} --working-dir {}",
+            self_activate_command,
+            shell_quote(working_dir)
+        );
+    }
+
+    if data.require_signed_manifest {
+        self_activate_command = format!("{} --require-signed-manifest", self_activate_command);
+    }
+
+    if let Some(signature) = data.manifest_signature {
+        self_activate_command = format!(
+            "{} --manifest-signature {}",
+            self_activate_command,
+            shell_quote(signature)
+        );
+    }
+
+    if let Some(label) = data.label {
+        self_activate_command = format!("{} --label {}", self_activate_command, shell_quote(label));
+    }
+
+    if let Some(target_platform) = data.target_platform {
+        self_activate_command = format!(
+            "{} --target-platform {}",
+            self_activate_command,
+            target_platform.as_str()
+        );
+    }
+
+    if let Some(profile_engine) = data.profile_engine {
+        self_activate_command = format!(
+            "{} --profile-engine {}",
+            self_activate_command,
+            profile_engine.as_str()
+        );
+    }
+
+    if let Some(security_module) = data.security_module {
+        self_activate_command = format!(
+            "{} --security-module {}",
+            self_activate_command,
+            security_module.as_str()
+        );
+    }
+
+    if data.fail_on_denials {
+        self_activate_command = format!("{} --fail-on-denials", self_activate_command);
+    }
+
+    if let Some(limits) = data.isolation {
+        self_activate_command = format!("{} --isolate", self_activate_command);
+        if let Some(memory_max) = &limits.memory_max {
+            self_activate_command = format!(
+                "{} --isolation-memory-max {}",
+                self_activate_command,
+                shell_quote(memory_max)
+            );
+        }
+        if let Some(cpu_quota) = &limits.cpu_quota {
+            self_activate_command = format!(
+                "{} --isolation-cpu-quota {}",
+                self_activate_command,
+                shell_quote(cpu_quota)
+            );
+        }
+        if let Some(timeout) = limits.timeout {
+            self_activate_command =
+                format!("{} --isolation-timeout {}", self_activate_command, timeout);
+        }
+        if let Some(slice) = &limits.slice {
+            self_activate_command = format!(
+                "{} --isolation-slice {}",
+                self_activate_command,
+                shell_quote(slice)
+            );
+        }
+    }
+
+    if let Some(snapshot) = data.snapshot {
+        self_activate_command = format!(
+            "{} --snapshot-type {}",
+            self_activate_command,
+            snapshot.kind.as_str()
+        );
+        for dataset in &snapshot.datasets {
+            self_activate_command = format!(
+                "{} --snapshot-dataset {}",
+                self_activate_command,
+                shell_quote(dataset)
+            );
+        }
+        if let Some(keep) = snapshot.keep {
+            self_activate_command = format!("{} --snapshot-keep {}", self_activate_command, keep);
+        }
+    }
+
+    if !data.env.is_empty() {
+        let assignments: Vec<String> = data
+            .env
+            .iter()
+            .map(|(name, value)| shell_quote(&format!("{}={}", name, value)))
+            .collect();
+        self_activate_command = format!("env {} {}", assignments.join(" "), self_activate_command);
+    }
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -107,14 +263,131 @@ fn test_activation_command_builder() {
             temp_path,
             confirm_timeout,
             magic_rollback,
+            heartbeat_timeout: None,
             debug_logs,
             log_dir,
             dry_activate,
             boot,
+            specialisation: None,
+            working_dir: None,
+            require_signed_manifest: false,
+            manifest_signature: None,
+            env: &[],
+            label: None,
+            target_platform: None,
+            profile_engine: None,
+            security_module: None,
+            fail_on_denials: false,
+            isolation: None,
+            snapshot: None,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback"
             .to_string(),
     );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &sudo,
+            profile_info,
+            closure,
+            auto_rollback,
+            temp_path,
+            confirm_timeout,
+            magic_rollback,
+            heartbeat_timeout: None,
+            debug_logs: false,
+            log_dir: None,
+            dry_activate,
+            boot: true,
+            specialisation: Some("gpu"),
+            working_dir: Some("/var/lib/app"),
+            require_signed_manifest: false,
+            manifest_signature: None,
+            env: &[],
+            label: None,
+            target_platform: Some(TargetPlatform::Wsl),
+            profile_engine: Some(ProfileEngine::Lite),
+            security_module: Some(SecurityModule::Selinux),
+            fail_on_denials: true,
+            isolation: Some(&ActivationLimits {
+                memory_max: Some("2G".to_string()),
+                timeout: Some(600),
+                ..Default::default()
+            }),
+            snapshot: Some(&SnapshotSettings {
+                kind: crate::snapshot::SnapshotType::Zfs,
+                datasets: vec!["rpool/var".to_string(), "rpool/db".to_string()],
+                keep: Some(5),
+            }),
+        }),
+        "sudo -u test /nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --auto-rollback --boot --specialisation 'gpu' --working-dir '/var/lib/app' --target-platform wsl --profile-engine lite --security-module selinux --fail-on-denials --isolate --isolation-memory-max '2G' --isolation-timeout 600 --snapshot-type zfs --snapshot-dataset 'rpool/var' --snapshot-dataset 'rpool/db' --snapshot-keep 5"
+            .to_string(),
+    );
+
+    assert_eq!(
+        build_activate_command(&ActivateCommandData {
+            sudo: &sudo,
+            profile_info,
+            closure,
+            auto_rollback,
+            temp_path,
+            confirm_timeout,
+            magic_rollback,
+            heartbeat_timeout: Some(20),
+            debug_logs: false,
+            log_dir: None,
+            dry_activate,
+            boot,
+            specialisation: None,
+            working_dir: None,
+            require_signed_manifest: false,
+            manifest_signature: None,
+            env: &[
+                ("DB_HOST".to_string(), "db.internal".to_string()),
+                ("MOTD".to_string(), "it's up".to_string()),
+            ],
+            label: Some("v1.2.0-3-gdeadbee"),
+            target_platform: None,
+            profile_engine: None,
+            security_module: None,
+            fail_on_denials: false,
+            isolation: None,
+            snapshot: None,
+        }),
+        "sudo -u test env 'DB_HOST=db.internal' 'MOTD=it'\\''s up' /nix/store/blah/etc/activate-rs activate '/nix/store/blah/etc' --profile-path '/blah/profiles/test' --temp-path '/tmp' --confirm-timeout 30 --magic-rollback --heartbeat-timeout 20 --auto-rollback --label 'v1.2.0-3-gdeadbee'"
+            .to_string(),
+    );
+
+    // The signature comes from the plan file, it can't break out of its quotes
+    let command = build_activate_command(&ActivateCommandData {
+        sudo: &sudo,
+        profile_info,
+        closure,
+        auto_rollback: false,
+        temp_path,
+        confirm_timeout,
+        magic_rollback: false,
+        heartbeat_timeout: None,
+        debug_logs: false,
+        log_dir: None,
+        dry_activate,
+        boot,
+        specialisation: None,
+        working_dir: None,
+        require_signed_manifest: true,
+        manifest_signature: Some("c2ln'; touch /pwned; echo '"),
+        env: &[],
+        label: None,
+        target_platform: None,
+        profile_engine: None,
+        security_module: None,
+        fail_on_denials: false,
+        isolation: None,
+        snapshot: None,
+    });
+    assert!(command.ends_with(
+        r#"--require-signed-manifest --manifest-signature 'c2ln'\''; touch /pwned; echo '\'''"#
+    ));
 }
 
 struct WaitCommandData<'a> {
@@ -183,6 +456,7 @@ struct RevokeCommandData<'a> {
     profile_info: ProfileInfo,
     debug_logs: bool,
     log_dir: Option<&'a str>,
+    profile_engine: Option<ProfileEngine>,
 }
 
 fn build_revoke_command(data: &RevokeCommandData) -> String {
@@ -212,6 +486,14 @@ fn build_revoke_command(data: &RevokeCommandData) -> String {
         }
     );
 
+    if let Some(profile_engine) = data.profile_engine {
+        self_activate_command = format!(
+            "{} --profile-engine {}",
+            self_activate_command,
+            profile_engine.as_str()
+        );
+    }
+
     if let Some(sudo_cmd) = &data.sudo {
         self_activate_command = format!("{} {}", sudo_cmd, self_activate_command);
     }
@@ -235,14 +517,69 @@ fn test_revoke_command_builder() {
             closure,
             profile_info,
             debug_logs,
-            log_dir
+            log_dir,
+            profile_engine: None,
         }),
         "sudo -u test /nix/store/blah/etc/activate-rs --debug-logs --log-dir /tmp/something.txt revoke --profile-path '/nix/var/nix/per-user/user/profile'"
             .to_string(),
     );
 }
 
-async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deploy_defs: &DeployDefs) -> Result<(), std::io::Error> {
+pub(crate) struct StatusCommandData<'a> {
+    pub sudo: &'a Option<String>,
+    pub closure: &'a str,
+    pub profile_info: &'a ProfileInfo,
+    pub temp_path: &'a Path,
+}
+
+/// Builds the command printing the state of the profile as JSON (see [`crate::status`]), with
+/// the `activate-rs` of `closure`, exiting with 100 if the closure isn't on the target
+pub(crate) fn build_status_command(data: &StatusCommandData) -> String {
+    let mut self_status_command = format!(
+        "{}/activate-rs status {} --temp-path '{}'",
+        data.closure,
+        match &data.profile_info {
+            ProfileInfo::ProfilePath { profile_path } =>
+                format!("--profile-path '{}'", profile_path),
+            ProfileInfo::ProfileUserAndName {
+                profile_user,
+                profile_name,
+            } => format!(
+                "--profile-user {} --profile-name {}",
+                profile_user, profile_name
+            ),
+        },
+        data.temp_path.display()
+    );
+
+    if let Some(sudo_cmd) = &data.sudo {
+        self_status_command = format!("{} {}", sudo_cmd, self_status_command);
+    }
+
+    format!(
+        "test -x '{}/activate-rs' || exit 100; {}",
+        data.closure, self_status_command
+    )
+}
+
+#[test]
+fn test_status_command_builder() {
+    assert_eq!(
+        build_status_command(&StatusCommandData {
+            sudo: &Some("sudo -u test".to_string()),
+            closure: "/nix/store/blah/etc",
+            profile_info: &ProfileInfo::ProfileUserAndName {
+                profile_user: "test".to_string(),
+                profile_name: "hello".to_string(),
+            },
+            temp_path: Path::new("/tmp"),
+        }),
+        "test -x '/nix/store/blah/etc/activate-rs' || exit 100; sudo -u test /nix/store/blah/etc/activate-rs status --profile-user test --profile-name hello --temp-path '/tmp'"
+            .to_string(),
+    );
+}
+
+pub(crate) async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deploy_defs: &DeployDefs) -> Result<(), std::io::Error> {
     match ssh_activate_child.stdin.as_mut() {
         Some(stdin) => {
             let _ = stdin.write_all(format!("{}\n",deploy_defs.sudo_password.clone().unwrap_or("".to_string())).as_bytes()).await;
@@ -250,8 +587,7 @@ async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deplo
         }
         None => {
             Err(
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
+                std::io::Error::other(
                     "Failed to open stdin for sudo command",
                 )
             )
@@ -259,6 +595,160 @@ async fn handle_sudo_stdin(ssh_activate_child: &mut tokio::process::Child, deplo
     }
 }
 
+/// `command` to be run on the node, wrapped to run inside the container the profile targets
+pub(crate) fn in_container(deploy_defs: &super::DeployDefs, command: String) -> String {
+    match &deploy_defs.container_command {
+        Some(container_command) => format!("{} {}", container_command, shell_quote(&command)),
+        None => command,
+    }
+}
+
+/// A command running a shell command (passed as the next argument) on the node: over SSH, or
+/// directly if the node is the deploying machine
+pub(crate) fn node_command(deploy_data: &super::DeployData<'_>, deploy_defs: &super::DeployDefs) -> Command {
+    let argv = node_argv(deploy_data, deploy_defs, &deploy_data.ssh_opts());
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    command
+}
+
+/// The program and arguments of [`node_command`], connecting with `ssh_opts`
+fn node_argv(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    ssh_opts: &[String],
+) -> Vec<String> {
+    match deploy_defs.local {
+        true => vec!["sh".to_string(), "-c".to_string()],
+        false => {
+            let mut argv = crate::ssh_wrapper::argv(deploy_data.ssh_wrapper());
+            argv.push(format!("{}@{}", deploy_defs.ssh_user, deploy_data.hostname));
+            if deploy_defs.sudo_tty {
+                argv.push("-tt".to_string());
+            }
+            argv.extend(ssh_opts.iter().cloned());
+            argv
+        }
+    }
+}
+
+/// A command started on the node with [`spawn_on_node`]
+pub(crate) enum NodeChild {
+    External(tokio::process::Child),
+    Native(tokio::task::JoinHandle<Result<std::process::Output, NativeSshError>>),
+}
+
+impl NodeChild {
+    /// Waits for the command to exit, emitting its output as events (see
+    /// [`wait_with_output_events`])
+    pub(crate) async fn wait_with_output(
+        self,
+        node: &str,
+        profile: &str,
+    ) -> Result<std::process::Output, std::io::Error> {
+        match self {
+            NodeChild::External(child) => wait_with_output_events(child, node, profile).await,
+            NodeChild::Native(task) => Ok(task.await.map_err(NativeSshError::from)??),
+        }
+    }
+}
+
+/// Starts `command` on the node with the `sshTransport` of the profile, piping in the sudo
+/// password with `interactiveSudo`
+pub(crate) async fn spawn_on_node(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    command: String,
+) -> Result<NodeChild, std::io::Error> {
+    let interactive_sudo = deploy_data.merged_settings.interactive_sudo.unwrap_or(false);
+
+    // Nodes the native transport can't reach like `ssh` would are reached with `ssh`
+    let destination = match deploy_data.merged_settings.ssh_transport {
+        Some(SshTransport::Native) if !deploy_defs.local => {
+            crate::native_ssh::destination(deploy_data, deploy_defs).ok()
+        }
+        _ => None,
+    };
+    if let Some(destination) = destination {
+        let stdin = match interactive_sudo {
+            true => Some(format!("{}\n", deploy_defs.sudo_password.clone().unwrap_or_default())),
+            false => None,
+        };
+        return Ok(NodeChild::Native(tokio::spawn(crate::native_ssh::output(
+            destination,
+            command,
+            stdin,
+            deploy_data.node_name.to_string(),
+            deploy_data.profile_name.to_string(),
+        ))));
+    }
+
+    let mut child = node_command(deploy_data, deploy_defs)
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn_with_events(deploy_data.node_name, deploy_data.profile_name)?;
+
+    if interactive_sudo {
+        trace!("Piping in sudo password");
+        handle_sudo_stdin(&mut child, deploy_defs).await?;
+    }
+
+    Ok(NodeChild::External(child))
+}
+
+/// The owner of the Nix store of the node if it runs single-user Nix (no daemon) for a user other
+/// than root, whose store only that user can write to
+pub async fn detect_single_user_store(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+) -> Option<String> {
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(
+                "if [ -S /nix/var/nix/daemon-socket/socket ]; then echo daemon; \
+                 else stat -c %U /nix/store 2>/dev/null || stat -f %Su /nix/store; fi",
+            )
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await
+    .ok()?;
+
+    if !output.status.success() {
+        debug!(
+            "Failed to find out whether node `{}` runs a Nix daemon, assuming it does",
+            deploy_data.node_name
+        );
+        return None;
+    }
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "daemon" | "root" | "" => None,
+        owner => Some(owner.to_string()),
+    }
+}
+
+/// Whether `ssh_user` can log in to the node with a key (or agent), without prompting
+pub async fn can_log_in(deploy_data: &super::DeployData<'_>, ssh_user: &str) -> bool {
+    let status = trace::status(
+        deploy_data
+            .ssh_command()
+            .arg("-oBatchMode=yes")
+            .arg("-oConnectTimeout=10")
+            .args(deploy_data.ssh_opts())
+            .arg(format!("{}@{}", ssh_user, deploy_data.hostname))
+            .arg("true")
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null()),
+    )
+    .await;
+
+    matches!(status.map(|s| s.code()), Ok(Some(0)))
+}
+
 #[derive(Error, Debug)]
 pub enum ConfirmProfileError {
     #[error("Failed to run confirmation command over SSH (the server should roll back): {0}")]
@@ -269,21 +759,12 @@ pub enum ConfirmProfileError {
     SSHConfirmExit(Option<i32>),
 }
 
-pub async fn confirm_profile(
+/// The command confirming the activation of the profile on the node, by removing its canary file
+fn confirm_command(
     deploy_data: &super::DeployData<'_>,
     deploy_defs: &super::DeployDefs,
     temp_path: &Path,
-    ssh_addr: &str,
-) -> Result<(), ConfirmProfileError> {
-    let mut ssh_confirm_command = Command::new("ssh");
-    ssh_confirm_command
-        .arg(ssh_addr)
-        .stdin(std::process::Stdio::piped());
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_confirm_command.arg(ssh_opt);
-    }
-
+) -> String {
     let lock_path = super::make_lock_path(temp_path, &deploy_data.profile.profile_settings.path);
 
     let mut confirm_command = format!("rm {}", lock_path.display());
@@ -291,34 +772,122 @@ pub async fn confirm_profile(
         confirm_command = format!("{} {}", sudo_cmd, confirm_command);
     }
 
-    debug!(
-        "Attempting to run command to confirm deployment: {}",
-        confirm_command
-    );
+    debug!("Constructed confirm command: {}", confirm_command);
 
-    let mut ssh_confirm_child = ssh_confirm_command
-        .arg(confirm_command)
-        .spawn()
-        .map_err(ConfirmProfileError::SSHConfirm)?;
-    
-    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-        trace!("[confirm] Piping in sudo password");
-        handle_sudo_stdin(&mut ssh_confirm_child, deploy_defs)
-            .await
-            .map_err(ConfirmProfileError::SSHConfirm)?;
+    in_container(deploy_defs, confirm_command)
+}
+
+/// The program and arguments of [`confirm_command`]
+fn confirm_argv(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+) -> Vec<String> {
+    // Not multiplexed, as the control sockets are gone once this deploy-rs exits
+    let mut argv = node_argv(deploy_data, deploy_defs, &deploy_data.merged_settings.ssh_opts);
+    argv.push(confirm_command(deploy_data, deploy_defs, temp_path));
+    argv
+}
+
+/// The program and arguments sending a heartbeat for the activation of the profile, by touching
+/// its heartbeat file
+fn heartbeat_argv(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+) -> Vec<String> {
+    let heartbeat_path =
+        super::make_heartbeat_path(temp_path, &deploy_data.profile.profile_settings.path);
+
+    let mut heartbeat_command = format!("touch {}", heartbeat_path.display());
+    if let Some(sudo_cmd) = &deploy_defs.sudo {
+        heartbeat_command = format!("{} {}", sudo_cmd, heartbeat_command);
     }
 
-    let ssh_confirm_exit_status = ssh_confirm_child
-        .wait()
-        .await
-        .map_err(ConfirmProfileError::SSHConfirm)?; 
+    let mut argv = node_argv(deploy_data, deploy_defs, &deploy_data.ssh_opts());
+    argv.push(in_container(deploy_defs, heartbeat_command));
+    argv
+}
+
+/// Sends heartbeats with `argv` every `interval` until dropped, piping in `sudo_password` if set
+struct Heartbeats(tokio::task::JoinHandle<()>);
+
+impl Heartbeats {
+    fn start(argv: Vec<String>, sudo_password: Option<String>, interval: Duration) -> Self {
+        Heartbeats(tokio::spawn(async move {
+            loop {
+                // Failures are expected until the activation waits for its confirmation
+                let sent = async {
+                    let mut child = trace::spawn(
+                        Command::new(&argv[0])
+                            .args(&argv[1..])
+                            .stdin(std::process::Stdio::piped())
+                            .stdout(std::process::Stdio::null())
+                            .stderr(std::process::Stdio::null()),
+                    )?;
+                    if let (Some(stdin), Some(password)) = (child.stdin.as_mut(), &sudo_password) {
+                        stdin.write_all(format!("{}\n", password).as_bytes()).await?;
+                    }
+                    drop(child.stdin.take());
+                    trace::wait(&mut child).await
+                };
+                match sent.await {
+                    Ok(status) if status.success() => trace!("Sent a heartbeat"),
+                    Ok(status) => debug!("Sending a heartbeat failed: {}", status),
+                    Err(e) => debug!("Sending a heartbeat failed: {}", e),
+                }
+                tokio::time::sleep(interval).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Heartbeats {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
 
-    match ssh_confirm_exit_status.code() {
+/// How often heartbeats are sent for `heartbeat_timeout`: a few times per timeout, so that a
+/// slow or lost one doesn't cause a rollback
+fn heartbeat_interval(heartbeat_timeout: u16) -> Duration {
+    Duration::from_secs((heartbeat_timeout as u64 / 3).max(1))
+}
+
+pub async fn confirm_profile(
+    deploy_data: &super::DeployData<'_>,
+    deploy_defs: &super::DeployDefs,
+    temp_path: &Path,
+    deadline: Option<Instant>,
+) -> Result<(), ConfirmProfileError> {
+    let ssh_confirm_output = spawn_on_node(
+        deploy_data,
+        deploy_defs,
+        confirm_command(deploy_data, deploy_defs, temp_path),
+    )
+    .await
+    .map_err(ConfirmProfileError::SSHConfirm)?
+    .wait_with_output(deploy_data.node_name, deploy_data.profile_name)
+    .await
+    .map_err(ConfirmProfileError::SSHConfirm)?;
+
+    match ssh_confirm_output.status.code() {
         Some(0) => (),
         a => return Err(ConfirmProfileError::SSHConfirmExit(a)),
     };
 
-    info!("Deployment confirmed.");
+    match deadline {
+        Some(deadline) => info!(
+            "Deployment confirmed, {}s before it would have been rolled back.",
+            deadline.saturating_duration_since(Instant::now()).as_secs()
+        ),
+        None => info!("Deployment confirmed."),
+    }
+    emit(
+        deploy_data.node_name,
+        deploy_data.profile_name,
+        EventKind::Confirmed,
+    );
 
     Ok(())
 }
@@ -345,6 +914,17 @@ pub enum DeployProfileError {
     Confirm(#[from] ConfirmProfileError),
     #[error("Deployment data invalid: {0}")]
     InvalidDeployDataDefs(#[from] DeployDataDefsError),
+    #[error("Failed to parse the dry activation report (is activate-rs of the same version?): {0}")]
+    DryActivateReport(serde_json::Error),
+}
+
+/// What the activation of a profile reported
+#[derive(Debug, Default)]
+pub struct Activated {
+    /// The unit changes `switch-to-configuration` (or the dry activation report) reported
+    pub units: Option<UnitChanges>,
+    /// The report of a dry activation, if its script wrote one
+    pub report: Option<DryActivateReport>,
 }
 
 pub async fn deploy_profile(
@@ -352,7 +932,8 @@ pub async fn deploy_profile(
     deploy_defs: &super::DeployDefs,
     dry_activate: bool,
     boot: bool,
-) -> Result<(), DeployProfileError> {
+    env: &[(String, String)],
+) -> Result<Activated, DeployProfileError> {
     if !dry_activate {
         info!(
             "Activating profile `{}` for node `{}`",
@@ -360,10 +941,7 @@ pub async fn deploy_profile(
         );
     }
 
-    let temp_path: &Path = match &deploy_data.merged_settings.temp_path {
-        Some(x) => x,
-        None => Path::new("/tmp"),
-    };
+    let temp_path: &Path = &deploy_defs.temp_path;
 
     let confirm_timeout = deploy_data.merged_settings.confirm_timeout.unwrap_or(30);
 
@@ -378,60 +956,88 @@ pub async fn deploy_profile(
         profile_info: &deploy_data.get_profile_info()?,
         closure: &deploy_data.profile.profile_settings.path,
         auto_rollback,
-        temp_path: temp_path,
+        temp_path,
         confirm_timeout,
         magic_rollback,
+        heartbeat_timeout: deploy_data.merged_settings.heartbeat_timeout,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
         dry_activate,
         boot,
+        require_signed_manifest: deploy_data
+            .merged_settings
+            .require_signed_manifest
+            .unwrap_or(false),
+        manifest_signature: deploy_defs.manifest_signature.as_deref(),
+        env,
+        label: deploy_defs.label.as_deref(),
+        target_platform: deploy_data.profile.profile_settings.target_platform,
+        profile_engine: deploy_data.merged_settings.profile_engine,
+        security_module: deploy_data.merged_settings.security_module,
+        fail_on_denials: deploy_data.merged_settings.fail_on_denials.unwrap_or(false),
+        specialisation: deploy_data.profile.profile_settings.specialisation.as_deref(),
+        working_dir: deploy_data.profile.profile_settings.working_dir.as_deref(),
+        isolation: match deploy_data.merged_settings.activation_isolation {
+            Some(true) => Some(
+                deploy_data
+                    .merged_settings
+                    .activation_limits
+                    .as_ref()
+                    .unwrap_or(&NO_LIMITS),
+            ),
+            _ => None,
+        },
+        snapshot: deploy_data.merged_settings.snapshot.as_ref(),
     });
 
     debug!("Constructed activation command: {}", self_activate_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
-
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
-
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
-    }
+    let units;
+    let mut report = None;
 
     if !magic_rollback || dry_activate || boot {
-        let mut ssh_activate_child = ssh_activate_command
-            .arg(self_activate_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHSpawnActivate)?;
-
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            trace!("[activate] Piping in sudo password");
-            handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
-                .await
-                .map_err(DeployProfileError::SSHActivatePipe)?;
-        }
-
-        let ssh_activate_exit_status = ssh_activate_child
-            .wait()
-            .await
-            .map_err(DeployProfileError::SSHActivate)?;
+        let ssh_activate_output = spawn_on_node(
+            deploy_data,
+            deploy_defs,
+            in_container(deploy_defs, self_activate_command),
+        )
+        .await
+        .map_err(DeployProfileError::SSHSpawnActivate)?
+        .wait_with_output(deploy_data.node_name, deploy_data.profile_name)
+        .await
+        .map_err(DeployProfileError::SSHActivate)?;
 
-        match ssh_activate_exit_status.code() {
+        match ssh_activate_output.status.code() {
             Some(0) => (),
             a => return Err(DeployProfileError::SSHActivateExit(a)),
         };
 
+        units = activation_units(&ssh_activate_output);
+        if dry_activate {
+            report = DryActivateReport::from_output(&String::from_utf8_lossy(&ssh_activate_output.stdout))
+                .transpose()
+                .map_err(DeployProfileError::DryActivateReport)?;
+        }
+
+        if let Some(report) = &report {
+            info!(
+                "Dry activation of profile `{}` for node `{}`: {}",
+                deploy_data.profile_name, deploy_data.node_name, report
+            );
+            for warning in &report.warnings {
+                warn!("[{}] Dry activation warning: {}", deploy_data.node_name, warning);
+            }
+        }
         if dry_activate {
             info!("Completed dry-activate!");
         } else if boot {
             info!("Success activating for next boot, done!");
+            if deploy_data.profile.profile_settings.target_platform == Some(TargetPlatform::Wsl) {
+                info!(
+                    "Node `{}` is a WSL instance, it boots into the new profile after `wsl.exe --terminate <distribution>` (or `wsl.exe --shutdown`) on its Windows host",
+                    deploy_data.node_name
+                );
+            }
         } else {
             info!("Success activating, done!");
         }
@@ -439,48 +1045,60 @@ pub async fn deploy_profile(
         let self_wait_command = build_wait_command(&WaitCommandData {
             sudo: &deploy_defs.sudo,
             closure: &deploy_data.profile.profile_settings.path,
-            temp_path: temp_path,
-            activation_timeout: activation_timeout,
+            temp_path,
+            activation_timeout,
             debug_logs: deploy_data.debug_logs,
             log_dir: deploy_data.log_dir,
         });
 
         debug!("Constructed wait command: {}", self_wait_command);
 
-        let mut ssh_activate_child = ssh_activate_command
-            .arg(self_activate_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHSpawnActivate)?;
-
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            trace!("[activate] Piping in sudo password");
-            handle_sudo_stdin(&mut ssh_activate_child, deploy_defs)
-                .await
-                .map_err(DeployProfileError::SSHActivatePipe)?;
-        }
+        // Recorded before activating, so a crash of deploy-rs from here on can be recovered from
+        // with `deploy confirm --resume` before the node rolls back
+        let mut pending = PendingConfirmation::new(
+            deploy_data.node_name,
+            deploy_data.profile_name,
+            confirm_argv(deploy_data, deploy_defs, temp_path),
+            deploy_data.merged_settings.interactive_sudo.unwrap_or(false),
+        )
+        .save(super::run_id());
+
+        let ssh_activate_child = spawn_on_node(
+            deploy_data,
+            deploy_defs,
+            in_container(deploy_defs, self_activate_command),
+        )
+        .await
+        .map_err(DeployProfileError::SSHSpawnActivate)?;
+
+        // Sent until the activation is confirmed, or given up on
+        let _heartbeats = deploy_data.merged_settings.heartbeat_timeout.map(|heartbeat_timeout| {
+            Heartbeats::start(
+                heartbeat_argv(deploy_data, deploy_defs, temp_path),
+                match deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+                    true => deploy_defs.sudo_password.clone(),
+                    false => None,
+                },
+                heartbeat_interval(heartbeat_timeout),
+            )
+        });
 
         info!("Creating activation waiter");
 
-        let mut ssh_wait_command = Command::new("ssh");
-        ssh_wait_command
-            .arg(&ssh_addr)
-            .stdin(std::process::Stdio::piped());
-        
-        for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-            ssh_wait_command.arg(ssh_opt);
-        }
-
         let (send_activate, recv_activate) = tokio::sync::oneshot::channel();
         let (send_activated, recv_activated) = tokio::sync::oneshot::channel();
 
+        let node_name = deploy_data.node_name.to_string();
+        let profile_name = deploy_data.profile_name.to_string();
+
         let thread = tokio::spawn(async move {
-            let o = ssh_activate_child.wait_with_output().await;
+            let o = ssh_activate_child.wait_with_output(&node_name, &profile_name).await;
 
-            let maybe_err = match o {
-                Err(x) => Some(DeployProfileError::SSHActivate(x)),
-                Ok(ref x) => match x.status.code() {
-                    Some(0) => None,
-                    a => Some(DeployProfileError::SSHActivateExit(a)),
+            let (maybe_err, output) = match o {
+                Err(x) => (Some(DeployProfileError::SSHActivate(x)), None),
+                Ok(x) => match x.status.code() {
+                    Some(0) => (None, Some(x)),
+                    a => (Some(DeployProfileError::SSHActivateExit(a)), None),
                 },
             };
 
@@ -489,46 +1107,75 @@ pub async fn deploy_profile(
             }
 
             send_activated.send(()).unwrap();
-        });
 
-        let mut ssh_wait_child = ssh_wait_command
-            .arg(self_wait_command)
-            .spawn()
-            .map_err(DeployProfileError::SSHWait)?;
+            output
+        });
 
-        if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-            trace!("[wait] Piping in sudo password");
-            handle_sudo_stdin(&mut ssh_wait_child, deploy_defs)
-                .await
-                .map_err(DeployProfileError::SSHActivatePipe)?;
-        }
+        let ssh_wait_child = spawn_on_node(
+            deploy_data,
+            deploy_defs,
+            in_container(deploy_defs, self_wait_command),
+        )
+        .await
+        .map_err(DeployProfileError::SSHWait)?;
 
-        tokio::select! {
-            x = ssh_wait_child.wait() => {
+        let confirm_deadline = tokio::select! {
+            x = ssh_wait_child.wait_with_output(deploy_data.node_name, deploy_data.profile_name) => {
                 debug!("Wait command ended");
-                match x.map_err(DeployProfileError::SSHWait)?.code() {
+                let x = x.map_err(DeployProfileError::SSHWait)?;
+                match x.status.code() {
                     Some(0) => (),
                     a => return Err(DeployProfileError::SSHWaitExit(a)),
                 };
+                super::parse_confirm_remaining(&String::from_utf8_lossy(&x.stdout))
+                    .inspect(|remaining| pending.set_deadline(*remaining))
+                    .map(|remaining| Instant::now() + Duration::from_secs(remaining))
             },
             x = recv_activate => {
                 debug!("Activate command exited with an error");
                 return Err(x.unwrap());
             },
-        }
+        };
 
-        info!("Success activating, attempting to confirm activation");
+        match confirm_deadline {
+            Some(deadline) => info!(
+                "Success activating, attempting to confirm activation ({}s left)",
+                deadline.saturating_duration_since(Instant::now()).as_secs()
+            ),
+            None => info!("Success activating, attempting to confirm activation"),
+        }
 
-        let c = confirm_profile(deploy_data, deploy_defs, temp_path, &ssh_addr).await;
+        let c = confirm_profile(deploy_data, deploy_defs, temp_path, confirm_deadline).await;
+        if c.is_err() {
+            info!(
+                "Retry confirming with `deploy confirm --resume {}` before the node rolls back",
+                super::run_id()
+            );
+            pending.keep();
+        }
         recv_activated.await.unwrap();
         c?;
 
-        thread
+        units = thread
             .await
-            .map_err(|x| DeployProfileError::SSHActivate(x.into()))?;
+            .map_err(|x| DeployProfileError::SSHActivate(x.into()))?
+            .and_then(|output| activation_units(&output));
     }
 
-    Ok(())
+    Ok(Activated {
+        // What the script reported is more reliable than what was read from its output
+        units: report.as_ref().and_then(DryActivateReport::unit_changes).or(units),
+        report,
+    })
+}
+
+/// The unit changes `switch-to-configuration` reported in the output of an activation
+fn activation_units(output: &std::process::Output) -> Option<UnitChanges> {
+    crate::units::parse_switch_output(&format!(
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    ))
 }
 
 #[derive(Error, Debug)]
@@ -554,39 +1201,20 @@ pub async fn revoke(
         profile_info: deploy_data.get_profile_info()?,
         debug_logs: deploy_data.debug_logs,
         log_dir: deploy_data.log_dir,
+        profile_engine: deploy_data.merged_settings.profile_engine,
     });
 
     debug!("Constructed revoke command: {}", self_revoke_command);
 
-    let hostname = match deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &deploy_data.node.node_settings.hostname,
-    };
-
-    let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, hostname);
-
-    let mut ssh_activate_command = Command::new("ssh");
-    ssh_activate_command
-        .arg(&ssh_addr)
-        .stdin(std::process::Stdio::piped());
-
-    for ssh_opt in &deploy_data.merged_settings.ssh_opts {
-        ssh_activate_command.arg(&ssh_opt);
-    }
-
-    let mut ssh_revoke_child = ssh_activate_command
-        .arg(self_revoke_command)
-        .spawn()
-        .map_err(RevokeProfileError::SSHSpawnRevoke)?;
-
-    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
-        trace!("[revoke] Piping in sudo password");
-        handle_sudo_stdin(&mut ssh_revoke_child, deploy_defs)
-            .await
-            .map_err(RevokeProfileError::SSHRevoke)?;
-    }
-
-    let result = ssh_revoke_child.wait_with_output().await;
+    let result = spawn_on_node(
+        deploy_data,
+        deploy_defs,
+        in_container(deploy_defs, self_revoke_command),
+    )
+    .await
+    .map_err(RevokeProfileError::SSHSpawnRevoke)?
+    .wait_with_output(deploy_data.node_name, deploy_data.profile_name)
+    .await;
 
     match result {
         Err(x) => Err(RevokeProfileError::SSHRevoke(x)),
@@ -596,3 +1224,85 @@ pub async fn revoke(
         },
     }
 }
+
+/// Builds a command printing the closure the profile currently points to, without sudo (profiles
+/// are readable by everyone) and without relying on the `activate-rs` of any closure
+pub(crate) fn build_current_closure_command(profile_info: &ProfileInfo) -> String {
+    match profile_info {
+        ProfileInfo::ProfilePath { profile_path } => format!("readlink -f '{}'", profile_path),
+        ProfileInfo::ProfileUserAndName {
+            profile_user,
+            profile_name,
+        } if profile_user == "root" => match &profile_name[..] {
+            "system" => "readlink -f /nix/var/nix/profiles/system".to_string(),
+            _ => format!(
+                "readlink -f /nix/var/nix/profiles/per-user/root/{}",
+                profile_name
+            ),
+        },
+        // Same lookup as `activate-rs`, see `get_profile_path` there
+        ProfileInfo::ProfileUserAndName {
+            profile_user,
+            profile_name,
+        } => format!(
+            "if [ -d /nix/var/nix/profiles/per-user/{0} ]; then readlink -f /nix/var/nix/profiles/per-user/{0}/{1}; else readlink -f ~{0}/.local/state/nix/profiles/{1}; fi",
+            profile_user, profile_name
+        ),
+    }
+}
+
+#[test]
+fn test_current_closure_command_builder() {
+    assert_eq!(
+        build_current_closure_command(&ProfileInfo::ProfileUserAndName {
+            profile_user: "root".to_string(),
+            profile_name: "system".to_string(),
+        }),
+        "readlink -f /nix/var/nix/profiles/system"
+    );
+    assert_eq!(
+        build_current_closure_command(&ProfileInfo::ProfileUserAndName {
+            profile_user: "alice".to_string(),
+            profile_name: "hello".to_string(),
+        }),
+        "if [ -d /nix/var/nix/profiles/per-user/alice ]; then readlink -f /nix/var/nix/profiles/per-user/alice/hello; else readlink -f ~alice/.local/state/nix/profiles/hello; fi"
+    );
+}
+
+#[derive(Error, Debug)]
+pub enum CurrentClosureError {
+    #[error("Failed to run readlink over SSH: {0}")]
+    SSH(std::io::Error),
+
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+}
+
+/// Whether the profile already points to the closure being deployed
+pub async fn is_unchanged(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<bool, CurrentClosureError> {
+    let current_closure_command = build_current_closure_command(&deploy_data.get_profile_info()?);
+
+    debug!("Constructed current closure command: {}", current_closure_command);
+
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, current_closure_command))
+            .stdin(std::process::Stdio::null()),
+    )
+    .await
+    .map_err(CurrentClosureError::SSH)?;
+
+    // A missing profile (or a failing readlink) simply means there is something to deploy
+    let current = String::from_utf8_lossy(&output.stdout);
+    debug!(
+        "Profile `{}` of node `{}` currently points to `{}`",
+        deploy_data.profile_name,
+        deploy_data.node_name,
+        current.trim()
+    );
+
+    Ok(output.status.success() && current.trim() == deploy_data.profile.profile_settings.path)
+}
@@ -0,0 +1,801 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use log::{debug, info};
+use std::path::Path;
+use std::process::Stdio;
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::{cli, data, trace, CmdOverrides, DeployFlake};
+
+/// Oldest Nix release with the flake and `nix copy` features deploy-rs relies on
+const MIN_NIX_VERSION: (u32, u32) = (2, 4);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        CheckResult {
+            name: name.into(),
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        CheckResult {
+            name: name.into(),
+            status: CheckStatus::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: impl Into<String>, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        CheckResult {
+            name: name.into(),
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DoctorError {
+    #[error("{0} of the environment checks failed, see the report above")]
+    ChecksFailed(usize),
+}
+
+/// Runs a command, returning its exit code and the combined, trimmed output
+async fn probe(command: &mut Command) -> Result<(Option<i32>, String), std::io::Error> {
+    debug!("Running doctor probe: {:?}", command);
+
+    let output = trace::output(command.stdin(Stdio::null())).await?;
+
+    let mut text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(stderr.trim());
+    }
+
+    Ok((output.status.code(), text))
+}
+
+fn parse_nix_version(version_output: &str) -> Option<(u32, u32)> {
+    let version = version_output.split_whitespace().last()?;
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+#[test]
+fn test_parse_nix_version() {
+    assert_eq!(parse_nix_version("nix (Nix) 2.18.1"), Some((2, 18)));
+    assert_eq!(parse_nix_version("nix (Nix) 2.4pre20210908_3c56f62"), Some((2, 4)));
+    assert_eq!(parse_nix_version("nix-env (Nix) 2.3.16"), Some((2, 3)));
+    assert_eq!(parse_nix_version("garbage"), None);
+}
+
+async fn check_nix_version() -> CheckResult {
+    let name = "nix version";
+
+    match probe(Command::new("nix").arg("--version")).await {
+        Err(e) => CheckResult::fail(
+            name,
+            format!("failed to run `nix`: {}", e),
+            "install Nix (https://nixos.org/download) and make sure `nix` is in PATH",
+        ),
+        Ok((Some(0), out)) => match parse_nix_version(&out) {
+            Some(v) if v >= MIN_NIX_VERSION => CheckResult::pass(name, out),
+            Some(_) => CheckResult::fail(
+                name,
+                out,
+                format!(
+                    "upgrade Nix to {}.{} or newer",
+                    MIN_NIX_VERSION.0, MIN_NIX_VERSION.1
+                ),
+            ),
+            None => CheckResult::warn(
+                name,
+                format!("could not parse version from `{}`", out),
+                "make sure `nix` refers to a regular Nix installation",
+            ),
+        },
+        Ok((code, out)) => CheckResult::fail(
+            name,
+            format!("`nix --version` exited with {:?}: {}", code, out),
+            "check your Nix installation",
+        ),
+    }
+}
+
+async fn check_flake_support() -> CheckResult {
+    let name = "experimental features";
+
+    match cli::test_flake_support().await {
+        Ok(true) => CheckResult::pass(name, "`nix-command` and `flakes` are enabled"),
+        Ok(false) => CheckResult::warn(
+            name,
+            "flakes are not enabled, falling back to the legacy (non-flake) mode",
+            "add `experimental-features = nix-command flakes` to your nix.conf",
+        ),
+        Err(e) => CheckResult::fail(
+            name,
+            format!("failed to test for flake support: {}", e),
+            "make sure `nix` is in PATH",
+        ),
+    }
+}
+
+async fn check_ssh() -> CheckResult {
+    let name = "ssh client";
+
+    match probe(Command::new("ssh").arg("-V")).await {
+        Ok((Some(0), out)) => CheckResult::pass(name, out),
+        Ok((code, out)) => CheckResult::fail(
+            name,
+            format!("`ssh -V` exited with {:?}: {}", code, out),
+            "install an OpenSSH client",
+        ),
+        Err(e) => CheckResult::fail(
+            name,
+            format!("failed to run `ssh`: {}", e),
+            "install an OpenSSH client and make sure `ssh` is in PATH",
+        ),
+    }
+}
+
+async fn check_credentials() -> Vec<CheckResult> {
+    let mut results = Vec::new();
+
+    let name = "ssh agent";
+    results.push(match std::env::var("SSH_AUTH_SOCK") {
+        Err(_) => CheckResult::warn(
+            name,
+            "SSH_AUTH_SOCK is not set",
+            "start an ssh-agent and `ssh-add` your key, or configure `IdentityFile` in ~/.ssh/config",
+        ),
+        Ok(_) => match probe(Command::new("ssh-add").arg("-l")).await {
+            Ok((Some(0), out)) => {
+                CheckResult::pass(name, format!("{} key(s) loaded", out.lines().count()))
+            }
+            Ok((_, out)) => CheckResult::warn(
+                name,
+                out,
+                "`ssh-add` your deployment key so non-interactive logins work",
+            ),
+            Err(e) => CheckResult::warn(
+                name,
+                format!("failed to run `ssh-add`: {}", e),
+                "make sure the OpenSSH client tools are in PATH",
+            ),
+        },
+    });
+
+    if let Ok(local_key) = std::env::var("LOCAL_KEY") {
+        let name = "signing key";
+        results.push(if Path::new(&local_key).is_file() {
+            CheckResult::pass(name, format!("LOCAL_KEY points to {}", local_key))
+        } else {
+            CheckResult::fail(
+                name,
+                format!("LOCAL_KEY is set to {}, which is not a readable file", local_key),
+                "point LOCAL_KEY at a key generated by `nix-store --generate-binary-cache-key`",
+            )
+        });
+    }
+
+    results
+}
+
+async fn check_flake(
+    supports_flakes: bool,
+    flake: &DeployFlake<'_>,
+    cmd_overrides: &CmdOverrides,
+    extra_build_args: &[String],
+) -> (CheckResult, Option<data::Data>) {
+    let name = format!("evaluate {}", flake.repo);
+
+    let nodes_args = cli::NodesExprArgs::new(cmd_overrides, &[]);
+    match cli::get_deployment_data(supports_flakes, std::slice::from_ref(flake), extra_build_args, None, &nodes_args, false)
+        .await
+    {
+        Ok(mut data) => {
+            let data = data.remove(0);
+            (
+                CheckResult::pass(name, format!("{} node(s) found", data.nodes.len())),
+                Some(data),
+            )
+        }
+        Err(e) => (
+            CheckResult::fail(
+                name,
+                e.to_string(),
+                "run `nix eval .#deploy` to see the full evaluation error",
+            ),
+            None,
+        ),
+    }
+}
+
+/// A profile deployed through a [`Target`]
+struct TargetProfile {
+    profile_name: String,
+    closure: String,
+    profile_info: crate::ProfileInfo,
+}
+
+/// Everything needed to reach one node as one SSH user
+struct Target {
+    node_name: String,
+    /// For the standby of a node, the name of that node
+    standby_of: Option<String>,
+    ssh_addr: String,
+    ssh_opts: Vec<String>,
+    ssh_wrapper: Option<Vec<String>>,
+    sudo: Option<String>,
+    interactive_sudo: bool,
+    temp_path: String,
+    profiles: Vec<TargetProfile>,
+}
+
+fn ssh_command(target: &Target, remote_command: &str) -> Command {
+    let mut command = crate::ssh_wrapper::command(target.ssh_wrapper.as_deref());
+    command
+        .arg(&target.ssh_addr)
+        .arg("-o")
+        .arg("BatchMode=yes")
+        .arg("-o")
+        .arg("ConnectTimeout=10");
+
+    for ssh_opt in &target.ssh_opts {
+        command.arg(ssh_opt);
+    }
+
+    command.arg(remote_command);
+    command
+}
+
+/// The statuses of the profiles of a target, by profile name, as far as they could be read
+type ProfileStatuses = Vec<(String, crate::status::ProfileStatus)>;
+
+async fn check_target(target: &Target) -> (Vec<CheckResult>, ProfileStatuses) {
+    let mut results = Vec::new();
+    let mut statuses = Vec::new();
+
+    let name = format!("connect to `{}` ({})", target.node_name, target.ssh_addr);
+    match probe(&mut ssh_command(target, "true")).await {
+        Ok((Some(0), _)) => results.push(CheckResult::pass(name, "ok")),
+        Ok((code, out)) => {
+            results.push(CheckResult::fail(
+                name,
+                format!("exited with {:?}: {}", code, out),
+                "check the hostname, `sshUser` and that your key is authorized on the node",
+            ));
+            return (results, statuses);
+        }
+        Err(e) => {
+            results.push(CheckResult::fail(
+                name,
+                format!("failed to run ssh: {}", e),
+                "make sure `ssh` is in PATH",
+            ));
+            return (results, statuses);
+        }
+    }
+
+    let name = format!("remote nix on `{}`", target.node_name);
+    results.push(match probe(&mut ssh_command(target, "nix-env --version")).await {
+        Ok((Some(0), out)) => CheckResult::pass(name, out),
+        Ok((code, out)) => CheckResult::fail(
+            name,
+            format!("exited with {:?}: {}", code, out),
+            "make sure Nix is installed on the node and in PATH for non-interactive SSH sessions",
+        ),
+        Err(e) => CheckResult::fail(name, e.to_string(), "make sure `ssh` is in PATH"),
+    });
+
+    if let Some(sudo) = &target.sudo {
+        let name = format!("sudo on `{}`", target.node_name);
+        if target.interactive_sudo {
+            results.push(CheckResult::pass(
+                name,
+                "skipped, `interactiveSudo` will prompt for a password",
+            ));
+        } else {
+            results.push(
+                match probe(&mut ssh_command(target, &format!("{} true", sudo))).await {
+                    Ok((Some(0), _)) => CheckResult::pass(name, format!("`{}` works", sudo)),
+                    Ok((code, out)) => CheckResult::fail(
+                        name,
+                        format!("`{}` exited with {:?}: {}", sudo, code, out),
+                        "allow passwordless sudo for the SSH user, or set `interactiveSudo = true`",
+                    ),
+                    Err(e) => CheckResult::fail(name, e.to_string(), "make sure `ssh` is in PATH"),
+                },
+            );
+        }
+    }
+
+    let name = format!("temp path on `{}`", target.node_name);
+    let mut test_command = format!("test -d '{0}' -a -w '{0}'", target.temp_path);
+    if let (Some(sudo), false) = (&target.sudo, target.interactive_sudo) {
+        test_command = format!("{} {}", sudo, test_command);
+    }
+    results.push(match probe(&mut ssh_command(target, &test_command)).await {
+        Ok((Some(0), _)) => CheckResult::pass(name, format!("{} is writable", target.temp_path)),
+        Ok(_) => CheckResult::fail(
+            name,
+            format!("{} is missing or not writable by the profile user", target.temp_path),
+            "create it or point `tempPath` at a directory the profile user can write to",
+        ),
+        Err(e) => CheckResult::fail(name, e.to_string(), "make sure `ssh` is in PATH"),
+    });
+
+    for profile in &target.profiles {
+        results.push(check_activate(target, profile).await);
+        let (result, status) = check_profile_status(target, profile).await;
+        results.push(result);
+        statuses.extend(status.map(|s| (profile.profile_name.clone(), s)));
+    }
+
+    (results, statuses)
+}
+
+/// The command running the `activate-rs` of `closure` (as the profile user with `sudo`) to see
+/// that it can run on the node at all, exiting with 100 if the closure isn't there yet
+fn activate_probe_command(closure: &str, sudo: Option<&str>) -> String {
+    let activate = crate::deploy::shell_quote(&format!("{}/activate-rs", closure));
+    let mut run = format!("{} --help", activate);
+    if let Some(sudo) = sudo {
+        run = format!("{} {}", sudo, run);
+    }
+    format!("test -x {} || exit 100; {} > /dev/null", activate, run)
+}
+
+async fn check_activate(target: &Target, profile: &TargetProfile) -> CheckResult {
+    let name = format!("activate-rs of `{}.{}`", target.node_name, profile.profile_name);
+
+    let sudo = target.sudo.as_deref().filter(|_| !target.interactive_sudo);
+    let command = activate_probe_command(&profile.closure, sudo);
+    match probe(&mut ssh_command(target, &command)).await {
+        Ok((Some(0), _)) => CheckResult::pass(name, "runs on the node"),
+        Ok((Some(100), _)) => CheckResult::pass(name, "skipped, the new closure isn't on the node yet"),
+        Ok((code, out)) => CheckResult::fail(
+            name,
+            format!("`activate-rs --help` exited with {:?}: {}", code, out),
+            "check that the profile is built for the node's system and that the store isn't mounted noexec",
+        ),
+        Err(e) => CheckResult::fail(name, e.to_string(), "make sure `ssh` is in PATH"),
+    }
+}
+
+async fn check_profile_status(
+    target: &Target,
+    profile: &TargetProfile,
+) -> (CheckResult, Option<crate::status::ProfileStatus>) {
+    let name = format!("profile `{}.{}`", target.node_name, profile.profile_name);
+
+    let no_sudo = None;
+    let status_command = crate::deploy::build_status_command(&crate::deploy::StatusCommandData {
+        sudo: match target.interactive_sudo {
+            true => &no_sudo,
+            false => &target.sudo,
+        },
+        closure: &profile.closure,
+        profile_info: &profile.profile_info,
+        temp_path: Path::new(&target.temp_path),
+    });
+
+    let out = match probe(&mut ssh_command(target, &status_command)).await {
+        Ok((Some(0), out)) => out,
+        Ok((Some(100), _)) => {
+            return (
+                CheckResult::pass(name, "skipped, the new closure isn't on the node yet"),
+                None,
+            )
+        }
+        Ok((code, out)) => {
+            return (
+                CheckResult::warn(
+                    name,
+                    format!("`activate-rs status` exited with {:?}: {}", code, out),
+                    "the profile may have been deployed by an older deploy-rs without `activate-rs status`",
+                ),
+                None,
+            )
+        }
+        Err(e) => {
+            return (
+                CheckResult::fail(name, e.to_string(), "make sure `ssh` is in PATH"),
+                None,
+            )
+        }
+    };
+
+    let status: crate::status::ProfileStatus = match out.lines().next().map(serde_json::from_str) {
+        Some(Ok(x)) => x,
+        _ => {
+            return (
+                CheckResult::warn(
+                    name,
+                    format!("unexpected output from `activate-rs status`: {}", out),
+                    "the profile may have been deployed by an older deploy-rs",
+                ),
+                None,
+            )
+        }
+    };
+
+    let detail = match status.current_generation {
+        Some(generation) => format!("generation {}, activate-rs {}", generation, status.version),
+        None => format!("not deployed yet, activate-rs {}", status.version),
+    };
+
+    let result = if let Some(dir) = &status.read_only_profile_dir {
+        CheckResult::fail(
+            name,
+            format!("{}, {} isn't writable by the profile user", detail, dir),
+            "set `profilePath` to a writable location, or bind-mount a writable state directory over it",
+        )
+    } else if !status.canaries.is_empty() {
+        CheckResult::warn(
+            name,
+            format!("{}, leftover canary files: {}", detail, status.canaries.join(", ")),
+            "an earlier deployment was interrupted, remove the files if no deployment is running",
+        )
+    } else if let (Some(closure), None) = (&status.pending_boot_closure, &target.standby_of) {
+        CheckResult::warn(
+            name,
+            format!("{}, {} is waiting for a reboot", detail, closure),
+            "reboot the node to run the closure deployed with `--boot`",
+        )
+    } else {
+        CheckResult::pass(name, detail)
+    };
+
+    (result, Some(status))
+}
+
+/// Whether the profile staged on the standby `standby` is the one its node `primary` runs, and
+/// otherwise for how long (as of `now`, in seconds since the epoch) it lags behind
+fn check_standby(
+    standby: &str,
+    primary: &str,
+    profile_name: &str,
+    standby_status: &crate::status::ProfileStatus,
+    primary_status: &crate::status::ProfileStatus,
+    now: u64,
+) -> CheckResult {
+    let name = format!("standby `{}.{}`", standby, profile_name);
+
+    let primary_closure = match &primary_status.current_closure {
+        Some(x) => x,
+        None => return CheckResult::pass(name, format!("`{}` isn't deployed yet", primary)),
+    };
+    if standby_status.current_closure.as_ref() == Some(primary_closure) {
+        return CheckResult::pass(name, format!("in lockstep with `{}`", primary));
+    }
+
+    let lag = match primary_status.current_date {
+        Some(date) => {
+            let minutes = now.saturating_sub(date) / 60;
+            format!(" for {}h {}m", minutes / 60, minutes % 60)
+        }
+        None => String::new(),
+    };
+    CheckResult::warn(
+        name,
+        format!(
+            "has {}, behind `{}` ({}){}",
+            standby_status.current_closure.as_deref().unwrap_or("nothing"),
+            primary,
+            primary_closure,
+            lag
+        ),
+        format!("deploy `{}` again to stage its profiles on the standby", primary),
+    )
+}
+
+fn collect_targets(
+    data: &data::Data,
+    flake: &DeployFlake<'_>,
+    cmd_overrides: &CmdOverrides,
+) -> (Vec<Target>, Vec<CheckResult>) {
+    let mut targets: Vec<Target> = Vec::new();
+    let mut problems = Vec::new();
+
+    let environment = match crate::select_environment(data, cmd_overrides) {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(CheckResult::fail(
+                "environment",
+                e.to_string(),
+                "pick one of the environments defined in `environments`",
+            ));
+            return (targets, problems);
+        }
+    };
+
+    let mut node_names: Vec<&String> = data.nodes.keys().collect();
+    node_names.sort();
+
+    for node_name in node_names {
+        if flake.node.as_ref().is_some_and(|n| n != node_name) {
+            continue;
+        }
+        let node = &data.nodes[node_name];
+
+        let mut profile_names: Vec<&String> = node.node_settings.profiles.keys().collect();
+        profile_names.sort();
+
+        for profile_name in profile_names {
+            if flake.profile.as_ref().is_some_and(|p| p != profile_name) {
+                continue;
+            }
+            let profile = &node.node_settings.profiles[profile_name];
+
+            let deploy_data = crate::make_deploy_data(
+                &data.generic_settings,
+                environment,
+                node,
+                node_name,
+                profile,
+                profile_name,
+                cmd_overrides,
+                false,
+                None,
+            );
+
+            let deploy_defs = match deploy_data.defs() {
+                Ok(x) => x,
+                Err(e) => {
+                    problems.push(CheckResult::fail(
+                        format!("settings for `{}.{}`", node_name, profile_name),
+                        e.to_string(),
+                        "set `sshUser` or `user` for this profile",
+                    ));
+                    continue;
+                }
+            };
+
+            let ssh_addr = format!("{}@{}", deploy_defs.ssh_user, deploy_data.hostname);
+
+            let target_profile = match deploy_data.get_profile_info() {
+                Ok(profile_info) => TargetProfile {
+                    profile_name: profile_name.clone(),
+                    closure: profile.profile_settings.path.clone(),
+                    profile_info,
+                },
+                Err(e) => {
+                    problems.push(CheckResult::fail(
+                        format!("settings for `{}.{}`", node_name, profile_name),
+                        e.to_string(),
+                        "set `sshUser` or `user` for this profile",
+                    ));
+                    continue;
+                }
+            };
+
+            if let Some(target) = targets
+                .iter_mut()
+                .find(|t| t.ssh_addr == ssh_addr && t.sudo == deploy_defs.sudo)
+            {
+                target.profiles.push(target_profile);
+                continue;
+            }
+
+            targets.push(Target {
+                node_name: node_name.clone(),
+                standby_of: node.node_settings.standby_of.clone(),
+                ssh_addr,
+                ssh_opts: deploy_data.merged_settings.ssh_opts.clone(),
+                ssh_wrapper: deploy_data.ssh_wrapper().map(<[String]>::to_vec),
+                sudo: deploy_defs.sudo,
+                interactive_sudo: deploy_data.merged_settings.interactive_sudo.unwrap_or(false),
+                temp_path: deploy_data
+                    .merged_settings
+                    .temp_path
+                    .as_deref()
+                    .unwrap_or_else(|| Path::new("/tmp"))
+                    .display()
+                    .to_string(),
+                profiles: vec![target_profile],
+            });
+        }
+    }
+
+    (targets, problems)
+}
+
+fn print_report(results: &[CheckResult]) {
+    for result in results {
+        let mark = match result.status {
+            CheckStatus::Pass => "✅",
+            CheckStatus::Warn => "⚠️",
+            CheckStatus::Fail => "❌",
+        };
+        println!("{} {}: {}", mark, result.name, result.detail);
+        if let Some(hint) = &result.hint {
+            println!("     hint: {}", hint);
+        }
+    }
+
+    let count = |s| results.iter().filter(|r| r.status == s).count();
+    println!(
+        "\n{} passed, {} warning(s), {} failed",
+        count(CheckStatus::Pass),
+        count(CheckStatus::Warn),
+        count(CheckStatus::Fail)
+    );
+}
+
+/// Checks the local and remote prerequisites for deploying `flakes` and prints a report
+pub async fn run_doctor(
+    flakes: &[DeployFlake<'_>],
+    cmd_overrides: &CmdOverrides,
+    extra_build_args: &[String],
+    skip_remote: bool,
+) -> Result<(), DoctorError> {
+    info!("Checking the deployment environment");
+
+    let mut results = vec![
+        check_nix_version().await,
+        check_flake_support().await,
+        check_ssh().await,
+    ];
+    results.extend(check_credentials().await);
+
+    let supports_flakes = cli::test_flake_support().await.unwrap_or(false);
+
+    for flake in flakes {
+        let (result, data) = check_flake(supports_flakes, flake, cmd_overrides, extra_build_args).await;
+        results.push(result);
+
+        let data = match data {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let (targets, problems) = collect_targets(&data, flake, cmd_overrides);
+        results.extend(problems);
+
+        if skip_remote {
+            continue;
+        }
+
+        let mut statuses = Vec::new();
+        for target in &targets {
+            let (target_results, target_statuses) = check_target(target).await;
+            results.extend(target_results);
+            statuses.extend(
+                target_statuses
+                    .into_iter()
+                    .map(|(profile, status)| (target.node_name.as_str(), profile, status)),
+            );
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        for target in &targets {
+            let primary = match &target.standby_of {
+                Some(x) => x,
+                None => continue,
+            };
+            let find = |node: &str, profile: &str| {
+                statuses
+                    .iter()
+                    .find(|(n, p, _)| *n == node && p == profile)
+                    .map(|(_, _, status)| status)
+            };
+            for profile in &target.profiles {
+                let name = &profile.profile_name;
+                if let (Some(standby_status), Some(primary_status)) =
+                    (find(&target.node_name, name), find(primary, name))
+                {
+                    results.push(check_standby(
+                        &target.node_name,
+                        primary,
+                        name,
+                        standby_status,
+                        primary_status,
+                        now,
+                    ));
+                }
+            }
+        }
+    }
+
+    print_report(&results);
+
+    match results
+        .iter()
+        .filter(|r| r.status == CheckStatus::Fail)
+        .count()
+    {
+        0 => Ok(()),
+        n => Err(DoctorError::ChecksFailed(n)),
+    }
+}
+
+#[test]
+fn test_activate_probe_command() {
+    assert_eq!(
+        activate_probe_command("/nix/store/abcd-hello", Some("sudo -u hello")),
+        "test -x '/nix/store/abcd-hello/activate-rs' || exit 100; sudo -u hello '/nix/store/abcd-hello/activate-rs' --help > /dev/null"
+    );
+    assert_eq!(
+        activate_probe_command("/nix/store/abcd-hello", None),
+        "test -x '/nix/store/abcd-hello/activate-rs' || exit 100; '/nix/store/abcd-hello/activate-rs' --help > /dev/null"
+    );
+}
+
+#[test]
+fn test_check_standby() {
+    let status = |closure: Option<&str>, date| crate::status::ProfileStatus {
+        version: "0.1.0".to_string(),
+        profile_path: "/nix/var/nix/profiles/system".to_string(),
+        current_generation: Some(7),
+        current_closure: closure.map(str::to_string),
+        current_date: date,
+        pending_boot_closure: None,
+        canaries: Vec::new(),
+        log_tail: Vec::new(),
+        read_only_profile_dir: None,
+    };
+    let primary = status(Some("/nix/store/bbbb-system"), Some(1_000_000));
+
+    let result = check_standby(
+        "db1-standby",
+        "db1",
+        "system",
+        &status(Some("/nix/store/bbbb-system"), Some(1_000_100)),
+        &primary,
+        1_010_000,
+    );
+    assert_eq!(result.status, CheckStatus::Pass);
+    assert_eq!(result.detail, "in lockstep with `db1`");
+
+    let result = check_standby(
+        "db1-standby",
+        "db1",
+        "system",
+        &status(Some("/nix/store/aaaa-system"), Some(900_000)),
+        &primary,
+        1_000_000 + 2 * 3600 + 5 * 60,
+    );
+    assert_eq!(result.status, CheckStatus::Warn);
+    assert_eq!(result.name, "standby `db1-standby.system`");
+    assert_eq!(
+        result.detail,
+        "has /nix/store/aaaa-system, behind `db1` (/nix/store/bbbb-system) for 2h 5m"
+    );
+}
@@ -0,0 +1,197 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The report an activation script can write during `--dry-activate`, of what activating would
+//! do.
+//!
+//! `activate-rs` passes the path to write it to as `DRY_ACTIVATE_REPORT`. After the script ran,
+//! the report is validated (a malformed one fails the dry activation) and printed on a line of
+//! its own, starting with [`REPORT_MARKER`], from which the deploying side reads it back. A
+//! report is a JSON object with the optional fields `units` (lists of the units that would be
+//! `restart`ed, `reload`ed, `stop`ped and `start`ed), `migrations` and `warnings` (lists of
+//! descriptions); any other field is an error.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::units::UnitChanges;
+
+/// What the line carrying the report in the output of `activate-rs` starts with
+pub const REPORT_MARKER: &str = "deploy-rs dry-activate report: ";
+
+/// The name of the report in the temporary directory of the activation
+const REPORT_NAME: &str = "dry-activate-report.json";
+
+#[derive(Error, Debug)]
+pub enum DryActivateReportError {
+    #[error("Failed to read the dry activation report {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("The dry activation report {0} is malformed: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("The dry activation report {0} is invalid: {1}")]
+    Invalid(PathBuf, String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ReportUnits {
+    #[serde(default)]
+    pub restart: Vec<String>,
+    #[serde(default)]
+    pub reload: Vec<String>,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub start: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DryActivateReport {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub units: Option<ReportUnits>,
+    #[serde(default)]
+    pub migrations: Vec<String>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+/// Where the activation script in `temp_path` writes its report
+pub fn report_path(temp_path: &Path) -> PathBuf {
+    temp_path.join(REPORT_NAME)
+}
+
+impl DryActivateReport {
+    /// Loads and validates the report at `path`, if the activation script wrote one
+    pub fn load(path: &Path) -> Result<Option<Self>, DryActivateReportError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(DryActivateReportError::Read(path.to_path_buf(), e)),
+        };
+        let report: DryActivateReport = serde_json::from_str(&content)
+            .map_err(|e| DryActivateReportError::Parse(path.to_path_buf(), e))?;
+        report
+            .validate()
+            .map_err(|e| DryActivateReportError::Invalid(path.to_path_buf(), e))?;
+
+        Ok(Some(report))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(units) = &self.units {
+            for unit in units
+                .restart
+                .iter()
+                .chain(&units.reload)
+                .chain(&units.stop)
+                .chain(&units.start)
+            {
+                if unit.is_empty() || unit.contains(char::is_whitespace) {
+                    return Err(format!("`{}` isn't a unit name", unit));
+                }
+            }
+        }
+        for (field, entries) in [("migrations", &self.migrations), ("warnings", &self.warnings)] {
+            if entries.iter().any(|entry| entry.trim().is_empty()) {
+                return Err(format!("{} has an empty entry", field));
+            }
+        }
+        Ok(())
+    }
+
+    /// The line carrying the report to the deploying side
+    pub fn to_line(&self) -> String {
+        // Serializing plain strings and lists can't fail
+        format!("{}{}", REPORT_MARKER, serde_json::to_string(self).unwrap_or_default())
+    }
+
+    /// The report in the output of `activate-rs`, if it printed one
+    pub fn from_output(output: &str) -> Option<Result<Self, serde_json::Error>> {
+        output
+            .lines()
+            .find_map(|line| line.trim_end().strip_prefix(REPORT_MARKER))
+            .map(serde_json::from_str)
+    }
+
+    /// The unit changes reported, if any were
+    pub fn unit_changes(&self) -> Option<UnitChanges> {
+        self.units.as_ref().map(|units| UnitChanges {
+            stopped: units.stop.clone(),
+            restarted: units.restart.clone(),
+            reloaded: units.reload.clone(),
+            started: units.start.clone(),
+        })
+    }
+}
+
+impl fmt::Display for DryActivateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(units) = self.unit_changes() {
+            parts.push(format!("would have {}", units));
+        }
+        if !self.migrations.is_empty() {
+            parts.push(format!("would run migrations {}", self.migrations.join(", ")));
+        }
+        if !self.warnings.is_empty() {
+            parts.push(format!("{} warning(s)", self.warnings.len()));
+        }
+        match parts.is_empty() {
+            true => f.write_str("nothing reported"),
+            false => f.write_str(&parts.join("; ")),
+        }
+    }
+}
+
+#[test]
+fn test_dry_activate_report() {
+    let dir = std::env::temp_dir().join(format!("deployrsdryactivate{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = report_path(&dir);
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(DryActivateReport::load(&path).unwrap(), None);
+
+    std::fs::write(
+        &path,
+        r#"{ "units": { "restart": ["nginx.service"], "start": ["backup.timer"] },
+             "migrations": ["postgresql 15 -> 16"], "warnings": ["disk 91% full"] }"#,
+    )
+    .unwrap();
+    let report = DryActivateReport::load(&path).unwrap().unwrap();
+    assert_eq!(
+        report.to_string(),
+        "would have restarted nginx.service; started backup.timer; would run migrations postgresql 15 -> 16; 1 warning(s)"
+    );
+
+    let output = format!("activating...\n{}\ndone\n", report.to_line());
+    assert_eq!(
+        DryActivateReport::from_output(&output).unwrap().unwrap(),
+        report
+    );
+    assert!(DryActivateReport::from_output("activating...\n").is_none());
+
+    std::fs::write(&path, r#"{ "unit": [] }"#).unwrap();
+    assert!(matches!(
+        DryActivateReport::load(&path),
+        Err(DryActivateReportError::Parse(..))
+    ));
+    std::fs::write(&path, r#"{ "units": { "restart": ["nginx service"] } }"#).unwrap();
+    assert!(matches!(
+        DryActivateReport::load(&path),
+        Err(DryActivateReportError::Invalid(..))
+    ));
+    std::fs::write(&path, r#"{ "warnings": [" "] }"#).unwrap();
+    assert!(matches!(
+        DryActivateReport::load(&path),
+        Err(DryActivateReportError::Invalid(..))
+    ));
+
+    assert_eq!(DryActivateReport::default().to_string(), "nothing reported");
+    let _ = std::fs::remove_dir_all(dir);
+}
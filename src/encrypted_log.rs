@@ -0,0 +1,143 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Log files encrypted to age recipients (`--log-recipient`), for `--log-dir` on shared machines.
+//!
+//! Log records are piped into `age` as they are written, so the logs never touch the disk in the
+//! clear; `deploy decrypt-logs` reads them back with an identity of one of the recipients.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+
+use flexi_logger::writers::LogWriter;
+use flexi_logger::{DeferredNow, FormatFunction};
+use log::Record;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncryptedLogError {
+    #[error("Failed to run age (is it installed?): {0}")]
+    Age(std::io::Error),
+    #[error("Decrypting {0} with age resulted in a bad exit code: {1:?}")]
+    AgeExit(PathBuf, Option<i32>),
+}
+
+/// Writes log records to a file in a log directory through `age --encrypt`
+pub struct AgeLogWriter {
+    stdin: Mutex<Option<ChildStdin>>,
+    child: Mutex<Child>,
+    format: FormatFunction,
+}
+
+/// The file logs of this run are written to in `dir`, named like flexi_logger names log files
+pub fn log_file(dir: &Path, discriminant: Option<&str>) -> PathBuf {
+    let program = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "deploy".to_string());
+    let timestamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S");
+
+    let name = match discriminant {
+        Some(discriminant) => format!("{}_{}_{}.log.age", program, timestamp, discriminant),
+        None => format!("{}_{}.log.age", program, timestamp),
+    };
+    dir.join(name)
+}
+
+impl AgeLogWriter {
+    /// Starts `age` encrypting to `recipients` into `path`
+    pub fn start(path: &Path, recipients: &[String]) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut command = Command::new("age");
+        command.arg("--encrypt");
+        for recipient in recipients {
+            command.arg("--recipient").arg(recipient);
+        }
+        // In its own process group, so that interrupting the deployment doesn't cut off the end of
+        // the log: age finishes the file once deploy-rs exits and closes the pipe
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command
+            .arg("--output")
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        Ok(AgeLogWriter {
+            stdin: Mutex::new(child.stdin.take()),
+            child: Mutex::new(child),
+            format: flexi_logger::default_format,
+        })
+    }
+}
+
+impl LogWriter for AgeLogWriter {
+    fn write(&self, now: &mut DeferredNow, record: &Record) -> std::io::Result<()> {
+        let mut stdin = self.stdin.lock().unwrap_or_else(|e| e.into_inner());
+        match stdin.as_mut() {
+            Some(stdin) => {
+                (self.format)(stdin, now, record)?;
+                stdin.write_all(b"\n")
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        match self.stdin.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn max_log_level(&self) -> log::LevelFilter {
+        log::LevelFilter::Trace
+    }
+
+    fn format(&mut self, format: FormatFunction) {
+        self.format = format;
+    }
+
+    fn shutdown(&self) {
+        self.stdin.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let _ = self.child.lock().unwrap_or_else(|e| e.into_inner()).wait();
+    }
+}
+
+/// Decrypts the log file `path` with the age identity file `identity`, printing it to stdout
+pub fn decrypt(path: &Path, identity: &Path) -> Result<(), EncryptedLogError> {
+    let status = Command::new("age")
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(identity)
+        .arg(path)
+        .status()
+        .map_err(EncryptedLogError::Age)?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(EncryptedLogError::AgeExit(path.to_path_buf(), a)),
+    }
+}
+
+#[test]
+fn test_log_file() {
+    let path = log_file(Path::new("/var/log/deploy"), Some("activate"));
+    let name = path.file_name().unwrap().to_string_lossy();
+
+    assert_eq!(path.parent(), Some(Path::new("/var/log/deploy")));
+    assert!(name.ends_with("_activate.log.age"));
+    // <program>_YYYY-MM-DD_HH-MM-SS_activate.log.age
+    let timestamp = name
+        .trim_end_matches("_activate.log.age")
+        .rsplitn(3, '_')
+        .take(2)
+        .collect::<Vec<_>>();
+    assert_eq!(timestamp[0].len(), "HH-MM-SS".len());
+    assert_eq!(timestamp[1].len(), "YYYY-MM-DD".len());
+}
@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The stream of things happening to each node and profile during a deployment.
+//!
+//! Events are emitted from anywhere with [`emit`] (much like log records) and consumed by a
+//! single renderer task started with [`EventStream::start`], which decides how they are shown.
+
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Child;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::render::Renderer;
+use crate::resources::ResourceUsage;
+use crate::units::UnitChanges;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Build,
+    Push,
+    Activate,
+    Revoke,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Build => "build",
+            Phase::Push => "push",
+            Phase::Activate => "activate",
+            Phase::Revoke => "revoke",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    Started(Phase),
+    Finished(Phase),
+    Failed(Phase, String),
+    /// A line printed by a command run for the node (nix, ssh or the activation script)
+    Output(String),
+    /// A command about to be run for the node
+    Command(String),
+    /// What is going to be deployed to the profile, as TOML
+    Planned(String),
+    /// The systemd units the activation of the profile restarted, reloaded, stopped or started
+    Units(UnitChanges),
+    /// The resources a command of the phase used, see [`crate::resources`]
+    Usage(Phase, ResourceUsage),
+    /// The activation of the profile was confirmed (with magic rollback)
+    Confirmed,
+    /// Bytes of the closure of the profile copied to the node so far, sent every second while
+    /// pushing it and once each `nix copy` is done
+    Pushed(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub node: String,
+    pub profile: String,
+    pub kind: EventKind,
+}
+
+static SENDER: Mutex<Option<mpsc::UnboundedSender<Event>>> = Mutex::new(None);
+
+/// Sends an event to the renderer, does nothing if no event stream is running
+pub fn emit(node: &str, profile: &str, kind: EventKind) {
+    let sender = SENDER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(sender) = sender.as_ref() {
+        let _ = sender.send(Event {
+            node: node.to_string(),
+            profile: profile.to_string(),
+            kind,
+        });
+    }
+}
+
+pub struct EventStream {
+    renderer_task: JoinHandle<Box<dyn Renderer>>,
+}
+
+impl EventStream {
+    /// Starts rendering all emitted events with `renderer`
+    pub fn start(mut renderer: Box<dyn Renderer>) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Event>();
+        *SENDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(sender);
+
+        let renderer_task = tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                renderer.render(&event);
+            }
+            renderer.finish();
+            renderer
+        });
+
+        EventStream { renderer_task }
+    }
+
+    /// Stops accepting events and waits until all emitted events are rendered
+    pub async fn finish(self) {
+        SENDER.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let _ = self.renderer_task.await;
+    }
+}
+
+pub trait SpawnWithEvents {
+    /// Like `spawn`, but emits the command as a command event for `node` and `profile` first
+    fn spawn_with_events(&mut self, node: &str, profile: &str) -> std::io::Result<Child>;
+}
+
+impl SpawnWithEvents for tokio::process::Command {
+    fn spawn_with_events(&mut self, node: &str, profile: &str) -> std::io::Result<Child> {
+        // The `Debug` of tokio's `Command` wraps the one of std's, which shows the command line
+        let debug = format!("{:?}", self);
+        let command = debug
+            .strip_prefix("Command { std: ")
+            .and_then(|c| c.rsplit_once(", kill_on_drop"))
+            .map_or(debug.clone(), |(c, _)| c.to_string());
+
+        emit(node, profile, EventKind::Command(command));
+        crate::trace::spawn(self)
+    }
+}
+
+async fn read_lines<R: AsyncRead + Unpin>(
+    reader: Option<R>,
+    node: &str,
+    profile: &str,
+) -> Result<Vec<u8>, std::io::Error> {
+    let mut collected = Vec::new();
+
+    if let Some(reader) = reader {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+
+        while reader.read_until(b'\n', &mut line).await? > 0 {
+            let text = String::from_utf8_lossy(&line);
+            emit(
+                node,
+                profile,
+                EventKind::Output(text.trim_end_matches(['\n', '\r']).to_string()),
+            );
+            collected.append(&mut line);
+        }
+    }
+
+    Ok(collected)
+}
+
+/// Like `Child::wait_with_output`, but emits every line the child prints (on piped stdout or
+/// stderr) as an output event for `node` and `profile` while it is running
+pub async fn wait_with_output_events(
+    mut child: Child,
+    node: &str,
+    profile: &str,
+) -> Result<Output, std::io::Error> {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let pid = child.id();
+
+    let (stdout, stderr, status): (Vec<u8>, Vec<u8>, ExitStatus) = tokio::try_join!(
+        read_lines(stdout, node, profile),
+        read_lines(stderr, node, profile),
+        async {
+            let status = child.wait().await;
+            crate::trace::child_exited(pid, &status);
+            status
+        }
+    )?;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
@@ -0,0 +1,239 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Facts about nodes gathered during a run, for the `requires` of later profiles.
+//!
+//! A profile declares the facts it needs as environment variables for its activation, e.g.
+//! `requires.DATABASE_HOST = "db.address"`. The facts of a node are gathered once one of its
+//! profiles has been activated, so the referenced node has to be deployed earlier in the same
+//! run. Available facts are `<node>.hostname`, `<node>.address` (the first address the hostname
+//! resolves to on the deploying machine) and `<node>.<profile>.path` (the closure activated for
+//! that profile).
+
+use std::collections::HashMap;
+
+use log::debug;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FactsError {
+    #[error("`{0}` is not a valid environment variable name")]
+    InvalidName(String),
+    #[error("Malformed fact `{0}`, expected `<node>.hostname`, `<node>.address` or `<node>.<profile>.path`")]
+    Malformed(String),
+    #[error("Fact `{0}` needs node `{1}` to be deployed earlier in this run")]
+    NotGathered(String, String),
+    #[error("Fact `{0}` needs profile `{2}` of node `{1}` to be deployed earlier in this run")]
+    ProfileNotGathered(String, String, String),
+    #[error("Failed to resolve an address for `{0}`")]
+    Unresolved(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Fact<'a> {
+    Hostname(&'a str),
+    Address(&'a str),
+    Path(&'a str, &'a str),
+}
+
+impl<'a> Fact<'a> {
+    fn parse(reference: &'a str) -> Result<Self, FactsError> {
+        let parts: Vec<&str> = reference.split('.').collect();
+
+        match parts.as_slice() {
+            [node, "hostname"] if !node.is_empty() => Ok(Fact::Hostname(node)),
+            [node, "address"] if !node.is_empty() => Ok(Fact::Address(node)),
+            [node, profile, "path"] if !node.is_empty() && !profile.is_empty() => {
+                Ok(Fact::Path(node, profile))
+            }
+            _ => Err(FactsError::Malformed(reference.to_string())),
+        }
+    }
+
+    fn node(&self) -> &'a str {
+        match self {
+            Fact::Hostname(node) | Fact::Address(node) | Fact::Path(node, _) => node,
+        }
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Checks that all `requires` are well-formed and only reference profiles in `earlier`, the
+/// `(node, profile)` pairs activated before the requiring profile
+pub fn check_requires(
+    requires: &HashMap<String, String>,
+    earlier: &[(&str, &str)],
+) -> Result<(), FactsError> {
+    for (name, reference) in requires {
+        if !is_valid_name(name) {
+            return Err(FactsError::InvalidName(name.clone()));
+        }
+
+        let fact = Fact::parse(reference)?;
+        let node = fact.node();
+
+        if !earlier.iter().any(|(n, _)| *n == node) {
+            return Err(FactsError::NotGathered(reference.clone(), node.to_string()));
+        }
+        if let Fact::Path(_, profile) = fact {
+            if !earlier.contains(&(node, profile)) {
+                return Err(FactsError::ProfileNotGathered(
+                    reference.clone(),
+                    node.to_string(),
+                    profile.to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct NodeFacts {
+    hostname: String,
+    address: Option<String>,
+    paths: HashMap<String, String>,
+}
+
+/// The facts gathered so far in this run
+#[derive(Debug, Default)]
+pub struct Facts {
+    nodes: HashMap<String, NodeFacts>,
+}
+
+impl Facts {
+    /// Records the facts of `node` after `closure` was activated for `profile`
+    pub async fn gather(&mut self, node: &str, hostname: &str, profile: &str, closure: &str) {
+        if !self.nodes.contains_key(node) {
+            let address = match tokio::net::lookup_host((hostname, 0)).await {
+                Ok(mut addresses) => addresses.next().map(|a| a.ip().to_string()),
+                Err(e) => {
+                    debug!("Failed to resolve {}: {}", hostname, e);
+                    None
+                }
+            };
+
+            self.nodes.insert(
+                node.to_string(),
+                NodeFacts {
+                    hostname: hostname.to_string(),
+                    address,
+                    paths: HashMap::new(),
+                },
+            );
+        }
+
+        if let Some(facts) = self.nodes.get_mut(node) {
+            facts.paths.insert(profile.to_string(), closure.to_string());
+        }
+    }
+
+    /// The environment variables for `requires`, sorted by name
+    pub fn resolve(
+        &self,
+        requires: &HashMap<String, String>,
+    ) -> Result<Vec<(String, String)>, FactsError> {
+        let mut env = requires
+            .iter()
+            .map(|(name, reference)| {
+                if !is_valid_name(name) {
+                    return Err(FactsError::InvalidName(name.clone()));
+                }
+
+                let fact = Fact::parse(reference)?;
+                let node = self.nodes.get(fact.node()).ok_or_else(|| {
+                    FactsError::NotGathered(reference.clone(), fact.node().to_string())
+                })?;
+
+                let value = match fact {
+                    Fact::Hostname(_) => node.hostname.clone(),
+                    Fact::Address(_) => node
+                        .address
+                        .clone()
+                        .ok_or_else(|| FactsError::Unresolved(node.hostname.clone()))?,
+                    Fact::Path(node_name, profile) => {
+                        node.paths.get(profile).cloned().ok_or_else(|| {
+                            FactsError::ProfileNotGathered(
+                                reference.clone(),
+                                node_name.to_string(),
+                                profile.to_string(),
+                            )
+                        })?
+                    }
+                };
+
+                Ok((name.clone(), value))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        env.sort();
+        Ok(env)
+    }
+}
+
+#[tokio::test]
+async fn test_facts() {
+    let requires = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+
+    assert_eq!(
+        Fact::parse("db.system.path"),
+        Ok(Fact::Path("db", "system"))
+    );
+    assert!(matches!(
+        Fact::parse("db.ip"),
+        Err(FactsError::Malformed(_))
+    ));
+
+    let earlier = [("db", "system")];
+    assert_eq!(
+        check_requires(&requires(&[("DB_HOST", "db.hostname")]), &earlier),
+        Ok(())
+    );
+    assert_eq!(
+        check_requires(&requires(&[("DB-HOST", "db.hostname")]), &earlier),
+        Err(FactsError::InvalidName("DB-HOST".to_string()))
+    );
+    assert_eq!(
+        check_requires(&requires(&[("CACHE", "cache.address")]), &earlier),
+        Err(FactsError::NotGathered(
+            "cache.address".to_string(),
+            "cache".to_string()
+        ))
+    );
+    assert!(matches!(
+        check_requires(&requires(&[("DB_APP", "db.app.path")]), &earlier),
+        Err(FactsError::ProfileNotGathered(..))
+    ));
+
+    let mut facts = Facts::default();
+    facts
+        .gather("db", "localhost", "system", "/nix/store/aaaa-system")
+        .await;
+
+    assert_eq!(
+        facts.resolve(&requires(&[
+            ("DB_SYSTEM", "db.system.path"),
+            ("DB_HOST", "db.hostname")
+        ])),
+        Ok(vec![
+            ("DB_HOST".to_string(), "localhost".to_string()),
+            (
+                "DB_SYSTEM".to_string(),
+                "/nix/store/aaaa-system".to_string()
+            )
+        ])
+    );
+}
@@ -0,0 +1,360 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Importing the nodes of a Colmena hive, a morph network or a NixOps network (`deploy import`).
+//!
+//! The network file is evaluated with `nix-instantiate`, reading only the `deployment` options of
+//! each node (and of `defaults`) that have a counterpart in deploy-rs: the target host, user and
+//! port, the tags and whether to build on the target. Modules that are functions are called with
+//! `name` and empty `nodes`, and options depending on anything else (`config`, `pkgs`, ...) are
+//! left out, as is everything else in the modules. The result is a skeleton of the `deploy`
+//! output of a flake, or the same as JSON; the NixOS configurations of the nodes still need to be
+//! turned into `nixosConfigurations` by hand.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::trace;
+
+pub const FOREIGN_TOOLS: &[&str] = &["colmena", "morph", "nixops"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForeignTool {
+    Colmena,
+    Morph,
+    Nixops,
+}
+
+impl FromStr for ForeignTool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "colmena" => Ok(ForeignTool::Colmena),
+            "morph" => Ok(ForeignTool::Morph),
+            "nixops" => Ok(ForeignTool::Nixops),
+            _ => Err(format!(
+                "unknown tool `{}`, expected one of {}",
+                s,
+                FOREIGN_TOOLS.join(", ")
+            )),
+        }
+    }
+}
+
+impl ForeignTool {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ForeignTool::Colmena => "colmena",
+            ForeignTool::Morph => "morph",
+            ForeignTool::Nixops => "nixops",
+        }
+    }
+
+    /// The attributes of the network file that aren't nodes
+    fn reserved(self) -> &'static [&'static str] {
+        match self {
+            ForeignTool::Colmena => &["meta", "defaults"],
+            ForeignTool::Morph => &["network"],
+            ForeignTool::Nixops => &["network", "defaults", "resources", "require"],
+        }
+    }
+
+    /// The user the tool connects as when a node doesn't set `deployment.targetUser`, if it
+    /// isn't the local user
+    fn default_user(self) -> Option<&'static str> {
+        match self {
+            ForeignTool::Colmena | ForeignTool::Nixops => Some("root"),
+            ForeignTool::Morph => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("Failed to find {0}: {1}")]
+    Path(PathBuf, std::io::Error),
+    #[error("Failed to evaluate the network with nix-instantiate: {0}")]
+    Eval(std::io::Error),
+    #[error("Evaluating the network resulted in a bad exit code: {0:?}")]
+    EvalExit(Option<i32>),
+    #[error("Failed to parse the evaluated network: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// The settings of a node in the network file, as far as deploy-rs has a counterpart for them
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedNode {
+    pub target_host: Option<String>,
+    pub target_user: Option<String>,
+    pub target_port: Option<u16>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub build_on_target: Option<bool>,
+    /// The system of the node (`nixpkgs.system` or `nixpkgs.hostPlatform`), if set outright
+    pub system: Option<String>,
+}
+
+/// The Nix expression reading the nodes of the network in `file`
+pub fn build_eval_expr(tool: ForeignTool, file: &Path) -> String {
+    let reserved: Vec<String> = tool
+        .reserved()
+        .iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect();
+
+    format!(
+        r#"let
+  file = import {file};
+  network = if builtins.isFunction file then file {{ }} else file;
+  notEvaluated = throw "not evaluated by deploy import";
+  tryValue = value: let result = builtins.tryEval (builtins.deepSeq value value); in if result.success then result.value else null;
+  module = name: m:
+    if builtins.isFunction m
+    then m (builtins.mapAttrs (arg: _: {{ inherit name; nodes = {{ }}; }}.${{arg}} or notEvaluated) (builtins.functionArgs m))
+    else m;
+  deployment = m: option: tryValue ((m.deployment or {{ }}).${{option}} or null);
+  defaults = module "defaults" (network.defaults or {{ }});
+  node = name:
+    let
+      m = module name network.${{name}};
+      option = option: let value = deployment m option; in if value != null then value else deployment defaults option;
+      platform = tryValue (m.nixpkgs.hostPlatform or m.nixpkgs.system or null);
+    in {{
+      targetHost = option "targetHost";
+      targetUser = option "targetUser";
+      targetPort = option "targetPort";
+      tags = let tags = option "tags"; in if tags == null then [ ] else tags;
+      buildOnTarget = option "buildOnTarget";
+      system = if builtins.isString platform then platform else if builtins.isAttrs platform then platform.system or null else null;
+    }};
+  names = builtins.filter (name: !(builtins.elem name [ {reserved} ])) (builtins.attrNames network);
+in builtins.listToAttrs (map (name: {{ inherit name; value = node name; }}) names)"#,
+        file = nix_string(&file.to_string_lossy()),
+        reserved = reserved.join(" ")
+    )
+}
+
+/// Evaluates the nodes of the network in `file`
+pub async fn evaluate(
+    tool: ForeignTool,
+    file: &Path,
+) -> Result<BTreeMap<String, ImportedNode>, ImportError> {
+    // `import` of a relative path would be relative to the expression
+    let file = file
+        .canonicalize()
+        .map_err(|e| ImportError::Path(file.to_path_buf(), e))?;
+
+    let output = trace::output(
+        Command::new("nix-instantiate")
+            .arg("--eval")
+            .arg("--strict")
+            .arg("--json")
+            .arg("-E")
+            .arg(build_eval_expr(tool, &file))
+            .stderr(std::process::Stdio::inherit()),
+    )
+    .await
+    .map_err(ImportError::Eval)?;
+
+    match output.status.code() {
+        Some(0) => Ok(serde_json::from_slice(&output.stdout)?),
+        a => Err(ImportError::EvalExit(a)),
+    }
+}
+
+/// `s` as a Nix string
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+            .replace('\n', "\\n")
+    )
+}
+
+/// `name` as a Nix attribute name, quoted unless it is a plain identifier
+fn nix_attr(name: &str) -> String {
+    let plain = name
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-'".contains(c));
+    match plain {
+        true => name.to_string(),
+        false => nix_string(name),
+    }
+}
+
+/// The deploy-rs settings of `node` (named `name`), by name, as Nix values and as JSON
+fn node_settings(
+    tool: ForeignTool,
+    name: &str,
+    node: &ImportedNode,
+) -> Vec<(&'static str, String, serde_json::Value)> {
+    let mut settings = Vec::new();
+    // Nodes without a target host are reached by their name
+    let hostname = node.target_host.as_deref().unwrap_or(name);
+    settings.push(("hostname", nix_string(hostname), hostname.into()));
+    if let Some(user) = node.target_user.as_deref().or_else(|| tool.default_user()) {
+        settings.push(("sshUser", nix_string(user), user.into()));
+    }
+    if let Some(port) = node.target_port {
+        let port = port.to_string();
+        settings.push((
+            "sshOpts",
+            format!("[ \"-p\" {} ]", nix_string(&port)),
+            serde_json::json!(["-p", port]),
+        ));
+    }
+    if !node.tags.is_empty() {
+        let tags: Vec<String> = node.tags.iter().map(|tag| nix_string(tag)).collect();
+        settings.push((
+            "tags",
+            format!("[ {} ]", tags.join(" ")),
+            node.tags.clone().into(),
+        ));
+    }
+    if let Some(build_on_target) = node.build_on_target {
+        settings.push((
+            "remoteBuild",
+            build_on_target.to_string(),
+            build_on_target.into(),
+        ));
+    }
+    settings
+}
+
+/// The `deploy` output of a flake with `nodes`, imported from `file` of `tool`
+pub fn to_nix(tool: ForeignTool, file: &Path, nodes: &BTreeMap<String, ImportedNode>) -> String {
+    let mut nix = format!(
+        "# Imported from {} ({}) by `deploy import`. The NixOS configurations of the nodes still\n\
+         # need to become `nixosConfigurations` of the flake, with the modules of the nodes.\n\
+         {{\n  nodes = {{\n",
+        tool.as_str(),
+        file.display()
+    );
+
+    for (name, node) in nodes {
+        nix.push_str(&format!("    {} = {{\n", nix_attr(name)));
+        for (setting, value, _) in node_settings(tool, name, node) {
+            nix.push_str(&format!("      {} = {};\n", setting, value));
+        }
+        nix.push_str(&format!(
+            "      profiles.system = {{\n        user = \"root\";\n        path = deploy-rs.lib.{}.activate.nixos self.nixosConfigurations.{};\n      }};\n    }};\n",
+            node.system.as_deref().unwrap_or("x86_64-linux"),
+            nix_attr(name)
+        ));
+    }
+
+    nix.push_str("  };\n}\n");
+    nix
+}
+
+/// The settings of `nodes` as JSON, in the format of the `deploy` output (without the paths of
+/// the profiles)
+pub fn to_json(tool: ForeignTool, nodes: &BTreeMap<String, ImportedNode>) -> serde_json::Value {
+    let nodes: serde_json::Map<String, serde_json::Value> = nodes
+        .iter()
+        .map(|(name, node)| {
+            let mut settings: serde_json::Map<String, serde_json::Value> =
+                node_settings(tool, name, node)
+                    .into_iter()
+                    .map(|(setting, _, value)| (setting.to_string(), value))
+                    .collect();
+            if let Some(system) = &node.system {
+                settings.insert("system".to_string(), system.clone().into());
+            }
+            settings.insert(
+                "profiles".to_string(),
+                serde_json::json!({ "system": { "user": "root" } }),
+            );
+            (name.clone(), settings.into())
+        })
+        .collect();
+
+    serde_json::json!({ "nodes": nodes })
+}
+
+#[test]
+fn test_import() {
+    assert_eq!("morph".parse::<ForeignTool>(), Ok(ForeignTool::Morph));
+    assert!("nixops2".parse::<ForeignTool>().is_err());
+    assert!(build_eval_expr(ForeignTool::Colmena, Path::new("/src/hive.nix"))
+        .contains("file = import \"/src/hive.nix\";"));
+    assert!(build_eval_expr(ForeignTool::Colmena, Path::new("/src/hive.nix"))
+        .contains("[ \"meta\" \"defaults\" ]"));
+
+    let nodes: BTreeMap<String, ImportedNode> = serde_json::from_value(serde_json::json!({
+        "web1": {
+            "targetHost": "10.0.0.1",
+            "targetUser": null,
+            "targetPort": 2222,
+            "tags": ["web", "prod"],
+            "buildOnTarget": true,
+            "system": "aarch64-linux",
+        },
+        "db.internal": { "targetHost": null, "tags": [] },
+    }))
+    .unwrap();
+
+    assert_eq!(
+        to_nix(ForeignTool::Colmena, Path::new("./hive.nix"), &nodes),
+        r#"# Imported from colmena (./hive.nix) by `deploy import`. The NixOS configurations of the nodes still
+# need to become `nixosConfigurations` of the flake, with the modules of the nodes.
+{
+  nodes = {
+    "db.internal" = {
+      hostname = "db.internal";
+      sshUser = "root";
+      profiles.system = {
+        user = "root";
+        path = deploy-rs.lib.x86_64-linux.activate.nixos self.nixosConfigurations."db.internal";
+      };
+    };
+    web1 = {
+      hostname = "10.0.0.1";
+      sshUser = "root";
+      sshOpts = [ "-p" "2222" ];
+      tags = [ "web" "prod" ];
+      remoteBuild = true;
+      profiles.system = {
+        user = "root";
+        path = deploy-rs.lib.aarch64-linux.activate.nixos self.nixosConfigurations.web1;
+      };
+    };
+  };
+}
+"#
+    );
+
+    assert_eq!(
+        to_json(ForeignTool::Morph, &nodes),
+        serde_json::json!({
+            "nodes": {
+                "db.internal": {
+                    "hostname": "db.internal",
+                    "profiles": { "system": { "user": "root" } },
+                },
+                "web1": {
+                    "hostname": "10.0.0.1",
+                    "sshOpts": ["-p", "2222"],
+                    "tags": ["web", "prod"],
+                    "remoteBuild": true,
+                    "system": "aarch64-linux",
+                    "profiles": { "system": { "user": "root" } },
+                },
+            },
+        })
+    );
+}
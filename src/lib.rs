@@ -6,18 +6,381 @@
 
 use rnix::{types::*, SyntaxKind::*};
 
-use merge::Merge;
-
 use thiserror::Error;
 
 use flexi_logger::*;
 
 use std::path::{Path, PathBuf};
 
+/// Prefix of the per-run directories created under `tempPath`
+pub const RUN_DIR_PREFIX: &str = "deploy-rs-run-";
+
+/// Random identifier of this run (a version 4 UUID), separating its temporary files from those of
+/// concurrent deploys
+pub fn run_id() -> &'static str {
+    static RUN_ID: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+    RUN_ID.get_or_init(|| {
+        let mut bytes = [0u8; 16];
+        if getrandom::getrandom(&mut bytes).is_err() {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default();
+            bytes = (nanos ^ ((std::process::id() as u128) << 96)).to_le_bytes();
+        }
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    })
+}
+
+/// Creates a new directory under the temporary directory only the current user can access, named
+/// `prefix` followed by random characters (like `mkdtemp`). A directory which already exists is
+/// never reused, as another user could have made it
+pub fn create_private_temp_dir(prefix: &str) -> std::io::Result<PathBuf> {
+    create_private_dir(&std::env::temp_dir(), prefix)
+}
+
+/// [`create_private_temp_dir`] in `parent`
+pub fn create_private_dir(parent: &Path, prefix: &str) -> std::io::Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    for _ in 0..16 {
+        let mut bytes = [0u8; 8];
+        getrandom::getrandom(&mut bytes).map_err(std::io::Error::other)?;
+        let suffix: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let dir = parent.join(format!("{}{}", prefix, suffix));
+
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+        // The owner of `/proc/self` is the effective user of the process
+        let metadata = std::fs::symlink_metadata(&dir)?;
+        if !metadata.is_dir() || metadata.uid() != std::fs::metadata("/proc/self")?.uid() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} isn't a directory of ours", dir.display()),
+            ));
+        }
+        return Ok(dir);
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("Failed to find a name for a new {}* directory", prefix),
+    ))
+}
+
+/// Whether `hostname` refers to the deploying machine
+pub fn is_local_host(hostname: &str) -> bool {
+    matches!(hostname, "localhost" | "127.0.0.1" | "::1")
+        || hostname.eq_ignore_ascii_case(&whoami::hostname())
+}
+
+/// The name of the user running deploy-rs, from `$USER` or `$LOGNAME`, or else the passwd entry
+/// of the process owner; containers often have neither
+pub fn local_username() -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let from_env = ["USER", "LOGNAME"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|user| !user.is_empty());
+    if from_env.is_some() {
+        return from_env;
+    }
+
+    // The owner of `/proc/self` is the effective user of the process
+    let uid = std::fs::metadata("/proc/self").ok()?.uid().to_string();
+    std::fs::read_to_string("/etc/passwd")
+        .ok()?
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[2] == uid)
+        .map(|fields| fields[0].to_string())
+        .filter(|user| !user.is_empty())
+}
+
+/// The directory for the temporary files of run `run_id` under `temp_path`
+pub fn make_run_temp_path(temp_path: &Path, run_id: &str) -> PathBuf {
+    temp_path.join(format!("{}{}", RUN_DIR_PREFIX, run_id))
+}
+
+/// Whether `path` is a directory made by [`make_run_temp_path`], which may be removed with all
+/// its contents after the activation
+pub fn is_run_temp_path(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with(RUN_DIR_PREFIX))
+}
+
+/// Where `activate-rs` logs to on the node without `--log-dir`, so the log of an activation
+/// outlives the SSH session it ran in
+pub const ACTIVATION_LOG_DIR: &str = "/var/log/deploy-rs";
+
+/// Where `activate-rs` logs to without `--log-dir` when it can't write to [`ACTIVATION_LOG_DIR`]
+/// (e.g. for profiles of other users), under the temporary path of the node
+pub fn fallback_activation_log_dir(temp_path: &Path) -> PathBuf {
+    let temp_path = match is_run_temp_path(temp_path) {
+        true => temp_path.parent().unwrap_or(temp_path),
+        false => temp_path,
+    };
+    temp_path.join("deploy-rs-logs")
+}
+
+/// The first of `dirs` which exists (or can be created) and can be written to
+pub fn first_writable_dir(dirs: &[PathBuf]) -> Option<&Path> {
+    dirs.iter()
+        .find(|dir| {
+            let probe = dir.join(format!(".deploy-rs-probe-{}", std::process::id()));
+            let writable = std::fs::create_dir_all(dir).is_ok() && std::fs::write(&probe, "").is_ok();
+            let _ = std::fs::remove_file(probe);
+            writable
+        })
+        .map(PathBuf::as_path)
+}
+
+#[test]
+fn test_tag_filter() {
+    let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+
+    let filter: TagFilter = "web+prod, db".parse().unwrap();
+    assert!(filter.matches(&tags(&["prod", "web", "eu"])));
+    assert!(filter.matches(&tags(&["db"])));
+    assert!(!filter.matches(&tags(&["web", "staging"])));
+    assert!(!filter.matches(&[]));
+    assert_eq!(filter.to_string(), "web+prod,db");
+    assert_eq!(filter.tags(), tags(&["db", "prod", "web"]));
+
+    assert!("web,".parse::<TagFilter>().is_err());
+    assert!("web++prod".parse::<TagFilter>().is_err());
+}
+
+#[test]
+fn test_is_local_host() {
+    assert!(is_local_host("localhost"));
+    assert!(is_local_host("::1"));
+    assert!(is_local_host(&whoami::hostname().to_uppercase()));
+    assert!(!is_local_host("web1.example.com"));
+}
+
+#[test]
+fn test_run_temp_path() {
+    let id = run_id();
+    assert_eq!(id.len(), 36);
+    assert_eq!(&id[14..15], "4");
+    assert_eq!(id, run_id());
+
+    let path = make_run_temp_path(Path::new("/tmp"), id);
+    assert_eq!(path, PathBuf::from(format!("/tmp/deploy-rs-run-{}", id)));
+    assert!(is_run_temp_path(&path));
+    assert!(!is_run_temp_path(Path::new("/tmp")));
+
+    assert_eq!(fallback_activation_log_dir(&path), Path::new("/tmp/deploy-rs-logs"));
+    assert_eq!(fallback_activation_log_dir(Path::new("/var/tmp")), Path::new("/var/tmp/deploy-rs-logs"));
+
+    let dir = std::env::temp_dir().join(format!("deployrslogs{}", std::process::id()));
+    let dirs = vec![PathBuf::from("/proc/deploy-rs"), dir.clone()];
+    assert_eq!(first_writable_dir(&dirs), Some(dir.as_path()));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn test_create_private_temp_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let a = create_private_temp_dir("deploy-rs-test-").unwrap();
+    let b = create_private_temp_dir("deploy-rs-test-").unwrap();
+    assert_ne!(a, b);
+    assert!(a.file_name().unwrap().to_string_lossy().starts_with("deploy-rs-test-"));
+    assert_eq!(std::fs::metadata(&a).unwrap().permissions().mode() & 0o777, 0o700);
+    let _ = std::fs::remove_dir(a);
+    let _ = std::fs::remove_dir(b);
+}
+
+/// The hash of the store path `closure`, which is its file name up to the first dash
+fn closure_hash(closure: &str) -> String {
+    let name = Path::new(closure)
+        .file_name()
+        .map_or_else(|| closure.into(), |n| n.to_string_lossy());
+    name.split('-').next().unwrap_or_default().to_string()
+}
+
 pub fn make_lock_path(temp_path: &Path, closure: &str) -> PathBuf {
-    let lock_hash =
-        &closure["/nix/store/".len()..closure.find('-').unwrap_or_else(|| closure.len())];
-    temp_path.join(format!("deploy-rs-canary-{}", lock_hash))
+    temp_path.join(format!("deploy-rs-canary-{}", closure_hash(closure)))
+}
+
+/// The file the deployer touches while it is alive, for activations confirmed with heartbeats
+/// (`heartbeatTimeout`)
+pub fn make_heartbeat_path(temp_path: &Path, closure: &str) -> PathBuf {
+    temp_path.join(format!("deploy-rs-heartbeat-{}", closure_hash(closure)))
+}
+
+/// Printed by `wait` once the canary exists, followed by the seconds left to confirm the
+/// activation (counted on the node from the creation of the canary)
+pub const CONFIRM_REMAINING_PREFIX: &str = "deploy-rs-confirm-remaining: ";
+
+/// The seconds left to confirm the activation, from the output of `wait`
+pub fn parse_confirm_remaining(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .rev()
+        .find_map(|l| l.trim().strip_prefix(CONFIRM_REMAINING_PREFIX))
+        .and_then(|s| s.parse().ok())
+}
+
+#[test]
+fn test_parse_confirm_remaining() {
+    assert_eq!(
+        parse_confirm_remaining("something\ndeploy-rs-confirm-remaining: 27\n"),
+        Some(27)
+    );
+    assert_eq!(parse_confirm_remaining("⭐ Found canary file\n"), None);
+}
+
+/// How far the activation waiting for confirmation with the canary file got
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CanaryState {
+    Created,
+    Confirmed,
+    RolledBack,
+}
+
+impl CanaryState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanaryState::Created => "created",
+            CanaryState::Confirmed => "confirmed",
+            CanaryState::RolledBack => "rolled-back",
+        }
+    }
+}
+
+/// Where the activation records the [`CanaryState`], next to the directory of the run (which is
+/// removed when the activation ends) so that `wait` attaching after a fast activation already
+/// finished still learns how it went
+pub fn make_canary_state_path(temp_path: &Path, closure: &str) -> PathBuf {
+    let lock_path = make_lock_path(temp_path, closure);
+    let mut path = temp_path.as_os_str().to_owned();
+    if let Some(name) = lock_path.file_name() {
+        path.push(".");
+        path.push(name);
+    }
+    path.push(".state");
+    PathBuf::from(path)
+}
+
+pub fn read_canary_state(path: &Path) -> Option<CanaryState> {
+    match std::fs::read_to_string(path).ok()?.trim() {
+        "created" => Some(CanaryState::Created),
+        "confirmed" => Some(CanaryState::Confirmed),
+        "rolled-back" => Some(CanaryState::RolledBack),
+        _ => None,
+    }
+}
+
+/// Records `state` at `path`, which is in a directory shared with other users: the file is created
+/// anew for [`CanaryState::Created`] and otherwise only written if it is still ours, and symbolic
+/// links are never followed, so that it can't be used to overwrite another file
+pub fn write_canary_state(path: &Path, state: CanaryState) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+    let mut options = std::fs::OpenOptions::new();
+    options
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW);
+    if state == CanaryState::Created {
+        options.create_new(true);
+    }
+    let mut file = options.open(path)?;
+
+    // The owner of `/proc/self` is the effective user of the process
+    let metadata = file.metadata()?;
+    if !metadata.is_file()
+        || metadata.nlink() != 1
+        || metadata.uid() != std::fs::metadata("/proc/self")?.uid()
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} isn't a file of ours", path.display()),
+        ));
+    }
+    file.set_len(0)?;
+    file.write_all(state.as_str().as_bytes())
+}
+
+/// Removes the canary states in `dir` older than a day, left behind by activations `wait` had
+/// already attached to
+pub fn remove_stale_canary_states(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !(name.starts_with(RUN_DIR_PREFIX) && name.ends_with(".state")) {
+            continue;
+        }
+        let stale = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > std::time::Duration::from_secs(24 * 60 * 60));
+        if stale {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+#[test]
+fn test_canary_state() {
+    let dir = std::env::temp_dir().join(format!(
+        "deploy-rs-canary-state-test-{}",
+        std::process::id()
+    ));
+    let temp_path = make_run_temp_path(&dir, "1234");
+    std::fs::create_dir_all(&temp_path).unwrap();
+
+    let path = make_canary_state_path(&temp_path, "/nix/store/abcd-hello");
+    assert_eq!(
+        path,
+        dir.join("deploy-rs-run-1234.deploy-rs-canary-abcd.state")
+    );
+    assert_eq!(read_canary_state(&path), None);
+    write_canary_state(&path, CanaryState::Created).unwrap();
+    write_canary_state(&path, CanaryState::Confirmed).unwrap();
+    assert_eq!(read_canary_state(&path), Some(CanaryState::Confirmed));
+
+    // A state left by someone else isn't reused, nor is a link followed
+    assert!(write_canary_state(&path, CanaryState::Created).is_err());
+    let link = dir.join("deploy-rs-run-5678.deploy-rs-canary-abcd.state");
+    std::os::unix::fs::symlink(&path, &link).unwrap();
+    assert!(write_canary_state(&link, CanaryState::RolledBack).is_err());
+    assert_eq!(read_canary_state(&path), Some(CanaryState::Confirmed));
+
+    // Fresh states are kept
+    remove_stale_canary_states(&dir);
+    assert!(path.exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
 
 const fn make_emoji(level: log::Level) -> &'static str {
@@ -42,7 +405,7 @@ pub fn logger_formatter_activate(
         "⭐ {} [activate] [{}] {}",
         make_emoji(level),
         style(level, level.to_string()),
-        record.args()
+        redact::redact(&record.args().to_string())
     )
 }
 
@@ -58,7 +421,7 @@ pub fn logger_formatter_wait(
         "👀 {} [wait] [{}] {}",
         make_emoji(level),
         style(level, level.to_string()),
-        record.args()
+        redact::redact(&record.args().to_string())
     )
 }
 
@@ -74,7 +437,7 @@ pub fn logger_formatter_revoke(
         "↩️ {} [revoke] [{}] {}",
         make_emoji(level),
         style(level, level.to_string()),
-        record.args()
+        redact::redact(&record.args().to_string())
     )
 }
 
@@ -90,7 +453,22 @@ pub fn logger_formatter_deploy(
         "🚀 {} [deploy] [{}] {}",
         make_emoji(level),
         style(level, level.to_string()),
-        record.args()
+        redact::redact(&record.args().to_string())
+    )
+}
+
+/// Format for log files, the same as `flexi_logger::default_format` but with secrets redacted
+pub fn logger_formatter_file(
+    w: &mut dyn std::io::Write,
+    _now: &mut DeferredNow,
+    record: &Record,
+) -> Result<(), std::io::Error> {
+    write!(
+        w,
+        "{} [{}] {}",
+        record.level(),
+        record.module_path().unwrap_or("<unnamed>"),
+        redact::redact(&record.args().to_string())
     )
 }
 
@@ -101,11 +479,115 @@ pub enum LoggerType {
     Revoke,
 }
 
+#[derive(Error, Debug)]
+pub enum LogFilterError {
+    #[error("Unknown log level `{0}`, expected one of off, error, warn, info, debug or trace")]
+    UnknownLevel(String),
+    #[error("Malformed log filter directive `{0}`, expected `module=level` or `level`")]
+    Malformed(String),
+}
+
+fn check_log_level(level: &str) -> Result<(), LogFilterError> {
+    match level {
+        "off" | "error" | "warn" | "info" | "debug" | "trace" => Ok(()),
+        _ => Err(LogFilterError::UnknownLevel(level.to_string())),
+    }
+}
+
+/// Builds a `flexi_logger` specification from the verbosity level and a list of per-module
+/// directives such as `push=debug,deploy=info`.
+///
+/// Bare module names refer to deploy-rs modules (`push` becomes `deploy::push`), paths containing
+/// `::` are passed through as they are, and a bare level replaces the default level.
+pub fn make_log_spec(verbosity: u8, log_filter: Option<&str>) -> Result<String, LogFilterError> {
+    let mut default_level = match verbosity {
+        0 => "info",
+        1 | 2 => "debug",
+        _ => "trace",
+    }
+    .to_string();
+
+    let mut directives = Vec::new();
+
+    if verbosity == 2 {
+        directives.push("deploy=trace".to_string());
+    }
+
+    for directive in log_filter
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        match directive.split('=').collect::<Vec<_>>()[..] {
+            [level] => {
+                check_log_level(level)?;
+                default_level = level.to_string();
+            }
+            [module, level] if !module.is_empty() => {
+                check_log_level(level)?;
+                if module.contains("::") {
+                    directives.push(format!("{}={}", module, level));
+                } else {
+                    directives.push(format!("deploy::{}={}", module, level));
+                }
+            }
+            _ => return Err(LogFilterError::Malformed(directive.to_string())),
+        }
+    }
+
+    Ok(std::iter::once(default_level)
+        .chain(directives)
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+#[test]
+fn test_make_log_spec() {
+    assert_eq!(make_log_spec(0, None).unwrap(), "info");
+    assert_eq!(make_log_spec(1, None).unwrap(), "debug");
+    assert_eq!(make_log_spec(2, None).unwrap(), "debug, deploy=trace");
+    assert_eq!(make_log_spec(3, None).unwrap(), "trace");
+    assert_eq!(
+        make_log_spec(0, Some("push=debug,deploy=info")).unwrap(),
+        "info, deploy::push=debug, deploy::deploy=info"
+    );
+    assert_eq!(
+        make_log_spec(1, Some("warn, notify::inotify=trace")).unwrap(),
+        "warn, notify::inotify=trace"
+    );
+    assert!(matches!(
+        make_log_spec(0, Some("push=loud")),
+        Err(LogFilterError::UnknownLevel(_))
+    ));
+    assert!(matches!(
+        make_log_spec(0, Some("push=debug=info")),
+        Err(LogFilterError::Malformed(_))
+    ));
+}
+
+#[derive(Error, Debug)]
+pub enum InitLoggerError {
+    #[error("{0}")]
+    LogFilter(#[from] LogFilterError),
+    #[error("{0}")]
+    Logger(#[from] FlexiLoggerError),
+    #[error("Failed to start age to encrypt the log file: {0}")]
+    Encrypt(std::io::Error),
+}
+
+/// Initializes logging to stderr and, if `log_dir` is set, to log files.
+///
+/// With `quiet` set, only errors are printed to stderr, log files are written as usual. With
+/// `log_recipients`, the log file is encrypted to them (see [`encrypted_log`]).
 pub fn init_logger(
-    debug_logs: bool,
+    verbosity: u8,
+    quiet: bool,
+    log_filter: Option<&str>,
     log_dir: Option<&str>,
+    log_recipients: &[String],
     logger_type: &LoggerType,
-) -> Result<(), FlexiLoggerError> {
+) -> Result<(), InitLoggerError> {
     let logger_formatter = match &logger_type {
         LoggerType::Deploy => logger_formatter_deploy,
         LoggerType::Activate => logger_formatter_activate,
@@ -114,52 +596,151 @@ pub fn init_logger(
     };
 
     if let Some(log_dir) = log_dir {
-        let mut logger = Logger::with_env_or_str("debug")
+        // Log files always get at least debug logs, the verbosity only affects stderr
+        let mut logger = Logger::with_env_or_str(make_log_spec(verbosity.max(1), log_filter)?)
             .log_to_file()
+            .format_for_files(logger_formatter_file)
             .format_for_stderr(logger_formatter)
             .set_palette("196;208;51;7;8".to_string())
             .directory(log_dir)
-            .duplicate_to_stderr(match debug_logs {
-                true => Duplicate::Debug,
-                false => Duplicate::Info,
+            // Processes started in the same second (e.g. parallel activations) share a file
+            .append()
+            .duplicate_to_stderr(match (quiet, verbosity) {
+                (true, _) => Duplicate::Error,
+                (false, 0) => Duplicate::Info,
+                (false, 1) => Duplicate::Debug,
+                (false, _) => Duplicate::Trace,
             })
             .print_message();
 
-        match logger_type {
-            LoggerType::Activate => logger = logger.discriminant("activate"),
-            LoggerType::Wait => logger = logger.discriminant("wait"),
-            LoggerType::Revoke => logger = logger.discriminant("revoke"),
-            LoggerType::Deploy => (),
+        let discriminant = match logger_type {
+            LoggerType::Activate => Some("activate"),
+            LoggerType::Wait => Some("wait"),
+            LoggerType::Revoke => Some("revoke"),
+            LoggerType::Deploy => None,
+        };
+        if let Some(discriminant) = discriminant {
+            logger = logger.discriminant(discriminant);
+        }
+
+        if !log_recipients.is_empty() {
+            let path = encrypted_log::log_file(Path::new(log_dir), discriminant);
+            let writer = encrypted_log::AgeLogWriter::start(&path, log_recipients)
+                .map_err(InitLoggerError::Encrypt)?;
+            logger = logger
+                .log_target(LogTarget::Writer(Box::new(writer)))
+                .format_for_writer(logger_formatter_file);
         }
 
         logger.start()?;
     } else {
-        Logger::with_env_or_str(match debug_logs {
-            true => "debug",
-            false => "info",
-        })
-        .log_target(LogTarget::StdErr)
-        .format(logger_formatter)
-        .set_palette("196;208;51;7;8".to_string())
-        .start()?;
+        let log_spec = match quiet {
+            true => "error".to_string(),
+            false => make_log_spec(verbosity, log_filter)?,
+        };
+
+        Logger::with_env_or_str(log_spec)
+            .log_target(LogTarget::StdErr)
+            .format(logger_formatter)
+            .set_palette("196;208;51;7;8".to_string())
+            .start()?;
     }
 
     Ok(())
 }
 
+pub mod approval;
+pub mod bundle;
 pub mod cli;
 pub mod data;
+pub mod dependencies;
 pub mod deploy;
+pub mod doctor;
+pub mod dry_activate;
+pub mod encrypted_log;
+pub mod events;
+pub mod facts;
+pub mod import;
+pub mod keys;
+pub mod locks;
+pub mod logs;
+pub mod maintenance;
+pub mod manifest;
+pub mod migrations;
+pub mod multiplex;
+pub mod native_ssh;
+pub mod notifications;
+pub mod orchestrator;
+pub mod pending_confirm;
+pub mod plan_diff;
+pub mod provenance;
 pub mod push;
+pub mod push_strategy;
+pub mod redact;
+pub mod render;
+pub mod resources;
+pub mod restrictions;
+pub mod rollback;
+pub mod run_state;
+pub mod schedule;
+pub mod secret_resolver;
+pub mod security;
+pub mod settings;
+pub mod severity;
+pub mod simulate;
+pub mod snapshot;
+pub mod ssh_config;
+pub mod ssh_wrapper;
+pub mod state;
+pub mod status;
+pub mod suggest;
+pub mod sudo;
+pub mod summary;
+pub mod trace;
+pub mod tunnel;
+pub mod units;
+pub mod vars;
+pub mod watch;
 
-#[derive(Debug)]
+/// Where the output of child processes (nix, ssh) should go.
+///
+/// Normally it is passed through to our own stdout/stderr, but in quiet mode it gets captured
+/// so it can be logged with `log_child_output` instead.
+pub fn child_stdio(quiet: bool) -> std::process::Stdio {
+    match quiet {
+        true => std::process::Stdio::piped(),
+        false => std::process::Stdio::inherit(),
+    }
+}
+
+/// Logs the captured output of a child process, as errors if it failed and as debug logs otherwise
+pub fn log_child_output(name: &str, output: &std::process::Output) {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    for line in stdout.lines().chain(stderr.lines()) {
+        if output.status.success() {
+            log::debug!("[{}] {}", name, line);
+        } else {
+            log::error!("[{}] {}", name, line);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct CmdOverrides {
     pub ssh_user: Option<String>,
     pub profile_user: Option<String>,
-    pub ssh_opts: Option<String>,
-    pub fast_connection: Option<bool>,
+    pub ssh_opts: Option<Vec<String>>,
+    /// Reach the nodes through this SSH destination, overriding `sshJumpHost`
+    pub jump_host: Option<String>,
+    /// SSH options from the environment (see [`settings::env_ssh_opts`])
+    pub env_ssh_opts: Vec<String>,
+    pub fast_connection: Option<data::FastConnection>,
     pub auto_rollback: Option<bool>,
     pub hostname: Option<String>,
+    /// Don't check (or remember) the host key of `hostname`, e.g. a rescue system
+    pub skip_host_key_check: bool,
     pub magic_rollback: Option<bool>,
     pub temp_path: Option<PathBuf>,
     pub confirm_timeout: Option<u16>,
@@ -168,6 +749,27 @@ pub struct CmdOverrides {
     pub interactive_sudo: Option<bool>,
     pub dry_activate: bool,
     pub remote_build: bool,
+    pub quiet: bool,
+    pub strict: bool,
+    pub override_restrictions: bool,
+    pub max_connections: usize,
+    pub max_builds: usize,
+    pub environment: Option<String>,
+    pub skip_if_unchanged: bool,
+    /// Only deploy to nodes with a profile whose closure changed since it was last deployed
+    pub changed_only: bool,
+    /// Deploy to nodes under maintenance as well
+    pub include_maintenance: bool,
+    /// Activate this many nodes at once, overriding `rollout.maxUnavailable`
+    pub max_unavailable: Option<usize>,
+    /// Print the settings the command line overrides for each node before deploying
+    pub explain_overrides: bool,
+    /// Fail instead of prompting for anything (passwords, host keys or confirmation)
+    pub non_interactive: bool,
+    pub label: Option<String>,
+    pub push_only: bool,
+    /// Fail once all dry activations are done if any of them reported warnings
+    pub fail_on_dry_activate_warnings: bool,
 }
 
 #[derive(PartialEq, Debug)]
@@ -175,16 +777,71 @@ pub struct DeployFlake<'a> {
     pub repo: &'a str,
     pub node: Option<String>,
     pub profile: Option<String>,
+    /// Only the profiles of the node targeting this container (`node/container`)
+    pub container: Option<String>,
 }
 
-#[derive(Error, Debug)]
+/// The part of a flake reference an error was found in, rendered with the offending part underlined
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlakeSpan {
+    pub flake: String,
+    /// Byte offset of the start of the offending part
+    pub start: usize,
+    /// Byte offset of the end of the offending part
+    pub end: usize,
+}
+
+impl std::fmt::Display for FlakeSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let indent = self.flake[..self.start].chars().count();
+        let width = self.flake[self.start..self.end].chars().count().max(1);
+
+        writeln!(f, "  {}", self.flake)?;
+        write!(f, "  {}{}", " ".repeat(indent), "^".repeat(width))
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
 pub enum ParseFlakeError {
-    #[error("The given path was too long, did you mean to put something in quotes?")]
-    PathTooLong,
-    #[error("Unrecognized node or token encountered")]
-    Unrecognized,
+    #[error("The given path was too long, did you mean to put something in quotes?\n{0}")]
+    PathTooLong(FlakeSpan),
+    #[error("Unrecognized node or token `{token}` encountered\n{span}")]
+    Unrecognized { token: String, span: FlakeSpan },
+}
+/// Splits the container off `node/container[.profile]`, returning the fragment without it, and the
+/// container with where it was cut out
+fn split_container(fragment: &str) -> (String, Option<(usize, String)>) {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in fragment.char_indices() {
+        match c {
+            '\\' if in_string => escaped = !escaped,
+            '"' if !escaped => in_string = !in_string,
+            '/' if !in_string => {
+                let rest = &fragment[i + 1..];
+                let end = rest.find('.').unwrap_or(rest.len());
+                let container = &rest[..end];
+
+                if container.is_empty()
+                    || !container
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    break;
+                }
+
+                let stripped = format!("{}{}", &fragment[..i], &rest[end..]);
+                return (stripped, Some((i, container.to_string())));
+            }
+            _ => escaped = false,
+        }
+    }
+
+    (fragment.to_string(), None)
 }
-pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
+
+pub fn parse_flake(flake: &str) -> Result<DeployFlake<'_>, ParseFlakeError> {
     let flake_fragment_start = flake.find('#');
     let (repo, maybe_fragment) = match flake_fragment_start {
         Some(s) => (&flake[..s], Some(&flake[s + 1..])),
@@ -193,9 +850,41 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
 
     let mut node: Option<String> = None;
     let mut profile: Option<String> = None;
+    let mut container: Option<String> = None;
 
-    if let Some(fragment) = maybe_fragment {
-        let ast = rnix::parse(fragment);
+    if let Some(original_fragment) = maybe_fragment {
+        let fragment_offset = flake.len() - original_fragment.len();
+        let (fragment, cut) = split_container(original_fragment);
+        // Positions in the fragment after the container need to be moved past it again
+        let (cut_at, cut_len) = match cut {
+            Some((at, name)) => {
+                let len = name.len() + 1;
+                container = Some(name);
+                (at, len)
+            }
+            None => (usize::MAX, 0),
+        };
+        let position = |pos: usize| match pos >= cut_at {
+            true => fragment_offset + pos + cut_len,
+            false => fragment_offset + pos,
+        };
+        let span_of = |entry: &rnix::SyntaxElement| {
+            let range = entry.text_range();
+            FlakeSpan {
+                flake: flake.to_string(),
+                start: position(range.start().to_usize()),
+                end: position(range.end().to_usize()),
+            }
+        };
+        let unrecognized = |entry: &rnix::SyntaxElement| ParseFlakeError::Unrecognized {
+            token: match entry {
+                rnix::NodeOrToken::Node(n) => n.text().to_string(),
+                rnix::NodeOrToken::Token(t) => t.text().to_string(),
+            },
+            span: span_of(entry),
+        };
+
+        let ast = rnix::parse(&fragment);
 
         let first_child = match ast.root().node().first_child() {
             Some(x) => x,
@@ -204,6 +893,7 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
                     repo,
                     node: None,
                     profile: None,
+                    container,
                 })
             }
         };
@@ -217,21 +907,38 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
                     None
                 }
                 (TOKEN_DOT, true) => {
-                    return Err(ParseFlakeError::PathTooLong);
+                    // Underline everything from the superfluous dot on
+                    let mut span = span_of(&entry);
+                    span.end = flake.len();
+                    return Err(ParseFlakeError::PathTooLong(span));
+                }
+                (NODE_SELECT, _) => {
+                    // `a.b.c` parses as `(a.b).c`, underline everything after `a.b`
+                    let mut span = span_of(&entry);
+                    span.start = span.end;
+                    span.end = flake.len();
+                    return Err(ParseFlakeError::PathTooLong(span));
                 }
                 (NODE_IDENT, _) => Some(entry.into_node().unwrap().text().to_string()),
                 (TOKEN_IDENT, _) => Some(entry.into_token().unwrap().text().to_string()),
                 (NODE_STRING, _) => {
-                    let c = entry
-                        .into_node()
-                        .unwrap()
-                        .children_with_tokens()
-                        .nth(1)
-                        .unwrap();
-
-                    Some(c.into_token().unwrap().text().to_string())
+                    let mut content = String::new();
+
+                    for part in entry.as_node().unwrap().children_with_tokens() {
+                        match part.kind() {
+                            TOKEN_STRING_START | TOKEN_STRING_END => (),
+                            TOKEN_STRING_CONTENT => content.push_str(&rnix::value::unescape(
+                                part.as_token().unwrap().text(),
+                                false,
+                            )),
+                            // Interpolations can't be evaluated here
+                            _ => return Err(unrecognized(&part)),
+                        }
+                    }
+
+                    Some(content)
                 }
-                _ => return Err(ParseFlakeError::Unrecognized),
+                _ => return Err(unrecognized(&entry)),
             };
 
             if !node_over {
@@ -246,6 +953,7 @@ pub fn parse_flake(flake: &str) -> Result<DeployFlake, ParseFlakeError> {
         repo,
         node,
         profile,
+        container,
     })
 }
 
@@ -257,6 +965,7 @@ fn test_parse_flake() {
             repo: "../deploy/examples/system",
             node: None,
             profile: None,
+            container: None,
         }
     );
 
@@ -266,6 +975,7 @@ fn test_parse_flake() {
             repo: "../deploy/examples/system",
             node: None,
             profile: None,
+            container: None,
         }
     );
 
@@ -275,6 +985,7 @@ fn test_parse_flake() {
             repo: "../deploy/examples/system",
             node: Some("computer".to_string()),
             profile: Some("something.nix".to_string()),
+            container: None,
         }
     );
 
@@ -284,6 +995,7 @@ fn test_parse_flake() {
             repo: "../deploy/examples/system",
             node: Some("example.com".to_string()),
             profile: Some("system".to_string()),
+            container: None,
         }
     );
 
@@ -292,7 +1004,8 @@ fn test_parse_flake() {
         DeployFlake {
             repo: "../deploy/examples/system",
             node: Some("example".to_string()),
-            profile: None
+            profile: None,
+            container: None,
         }
     );
 
@@ -301,7 +1014,8 @@ fn test_parse_flake() {
         DeployFlake {
             repo: "../deploy/examples/system",
             node: Some("example".to_string()),
-            profile: Some("system".to_string())
+            profile: Some("system".to_string()),
+            container: None,
         }
     );
 
@@ -311,8 +1025,67 @@ fn test_parse_flake() {
             repo: "../deploy/examples/system",
             node: None,
             profile: None,
+            container: None,
         }
     );
+
+    assert_eq!(
+        parse_flake(r#".#"my \"quoted\" node".system"#).unwrap(),
+        DeployFlake {
+            repo: ".",
+            node: Some("my \"quoted\" node".to_string()),
+            profile: Some("system".to_string()),
+            container: None,
+        }
+    );
+
+    assert_eq!(
+        parse_flake(".#example.system.extra"),
+        Err(ParseFlakeError::PathTooLong(FlakeSpan {
+            flake: ".#example.system.extra".to_string(),
+            start: 16,
+            end: 22,
+        }))
+    );
+
+    let err = parse_flake(".#example+system").unwrap_err();
+    assert_eq!(
+        err,
+        ParseFlakeError::Unrecognized {
+            token: "+".to_string(),
+            span: FlakeSpan {
+                flake: ".#example+system".to_string(),
+                start: 9,
+                end: 10,
+            },
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Unrecognized node or token `+` encountered\n  .#example+system\n           ^"
+    );
+
+    assert_eq!(
+        parse_flake(".#host/app-1.system").unwrap(),
+        DeployFlake {
+            repo: ".",
+            node: Some("host".to_string()),
+            profile: Some("system".to_string()),
+            container: Some("app-1".to_string()),
+        }
+    );
+    assert_eq!(parse_flake(".#host/app").unwrap().container.as_deref(), Some("app"));
+    // Slashes in quoted names are part of the name
+    assert_eq!(parse_flake(r#".#"a/b".system"#).unwrap().container, None);
+    // Errors after the container point at the original flake
+    assert_eq!(
+        parse_flake(".#host/app.system.extra"),
+        Err(ParseFlakeError::PathTooLong(FlakeSpan {
+            flake: ".#host/app.system.extra".to_string(),
+            start: 17,
+            end: 23,
+        }))
+    );
 }
 
 #[derive(Debug, Clone)]
@@ -321,6 +1094,8 @@ pub struct DeployData<'a> {
     pub node: &'a data::Node,
     pub profile_name: &'a str,
     pub profile: &'a data::Profile,
+    /// The hostname to connect to, after applying the environment and `--hostname`
+    pub hostname: &'a str,
 
     pub cmd_overrides: &'a CmdOverrides,
 
@@ -330,12 +1105,58 @@ pub struct DeployData<'a> {
     pub log_dir: Option<&'a str>,
 }
 
+/// Where the user deploy-rs connects as came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SshUserSource {
+    /// `--ssh-user`
+    Flag,
+    /// `sshUser` (or `bootstrapSshUser`) in the deployment data
+    Settings,
+    /// The `DEPLOY_SSH_USER` environment variable
+    Env,
+    /// A `User` in `~/.ssh/config` for the hostname
+    SshConfig,
+    /// The user running deploy-rs
+    Local,
+}
+
+impl std::fmt::Display for SshUserSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SshUserSource::Flag => "--ssh-user",
+            SshUserSource::Settings => "sshUser",
+            SshUserSource::Env => "DEPLOY_SSH_USER",
+            SshUserSource::SshConfig => "~/.ssh/config",
+            SshUserSource::Local => "local user",
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct DeployDefs {
     pub ssh_user: String,
+    pub ssh_user_source: SshUserSource,
     pub profile_user: String,
     pub sudo: Option<String>,
     pub sudo_password: Option<String>,
+    /// Signature of the closure from the manifest passed with `--manifest`
+    pub manifest_signature: Option<String>,
+    /// The directory of this run under `tempPath`
+    pub temp_path: PathBuf,
+    /// Label for the new generation, from `--label` or `git describe`
+    pub label: Option<String>,
+    /// The node is the deploying machine (and `sshUser` the current user), so nothing is copied
+    /// and commands are run locally instead of over SSH
+    pub local: bool,
+    /// Runs a shell command (its last argument) as the profile user in the container the profile
+    /// targets, from the node
+    pub container_command: Option<String>,
+    /// The node runs single-user Nix, without a daemon, so its store is written to directly
+    pub single_user_store: bool,
+    /// sudo on the node requires a terminal (`requiretty`), so one is allocated for the commands
+    /// run there
+    pub sudo_tty: bool,
 }
 enum ProfileInfo {
     ProfilePath {
@@ -351,27 +1172,113 @@ enum ProfileInfo {
 pub enum DeployDataDefsError {
     #[error("Neither `user` nor `sshUser` are set for profile {0} of node {1}")]
     NoProfileUser(String, String),
+    #[error("No SSH user for node {0}: set `sshUser`, pass `--ssh-user` or set DEPLOY_SSH_USER (the local user is unknown, e.g. in a container without a passwd entry)")]
+    NoSshUser(String),
 }
 
 impl<'a> DeployData<'a> {
+    /// The SSH options to connect to the node with: its `sshOpts`, and those multiplexing the
+    /// connections with `sshMultiplexing` (see [`multiplex`])
+    pub fn ssh_opts(&self) -> Vec<String> {
+        let mut ssh_opts = self.merged_settings.ssh_opts.clone();
+        if self.merged_settings.ssh_multiplexing == Some(true) {
+            ssh_opts.extend(multiplex::ssh_opts());
+        }
+        ssh_opts
+    }
+
+    /// The `sshWrapper` of the profile, if any (see [`ssh_wrapper`])
+    pub fn ssh_wrapper(&self) -> Option<&[String]> {
+        self.merged_settings
+            .ssh_wrapper
+            .as_deref()
+            .filter(|wrapper| !wrapper.is_empty())
+    }
+
+    /// The command running `ssh` (through the `sshWrapper`) to the node, without arguments yet
+    pub fn ssh_command(&self) -> tokio::process::Command {
+        ssh_wrapper::command(self.ssh_wrapper())
+    }
+
+    /// Connects as `ssh_user` instead of `sshUser` (e.g. `bootstrapSshUser`), without changing
+    /// the user the profile is deployed for
+    pub fn use_ssh_user(&mut self, ssh_user: String) {
+        if self.merged_settings.user.is_none() {
+            self.merged_settings.user = self.merged_settings.ssh_user.clone();
+        }
+        self.merged_settings.ssh_user = Some(ssh_user);
+    }
+
+    /// Deploys the profile for `owner`, who owns the single-user (daemon-less) Nix store of the
+    /// node, as nobody else can write to it
+    pub fn use_single_user_store(&mut self, owner: String) {
+        self.merged_settings.user = Some(owner);
+    }
+
+    /// The user to connect as and where it came from: `--ssh-user`, `sshUser`, `DEPLOY_SSH_USER`,
+    /// the `User` of the hostname in `~/.ssh/config` and the local user, in that order
+    pub fn resolve_ssh_user(&self) -> Result<(String, SshUserSource), DeployDataDefsError> {
+        if let Some(ref user) = self.merged_settings.ssh_user {
+            let source = match self.cmd_overrides.ssh_user {
+                Some(ref flag) if flag == user => SshUserSource::Flag,
+                _ => SshUserSource::Settings,
+            };
+            return Ok((user.clone(), source));
+        }
+
+        if let Some(user) = std::env::var("DEPLOY_SSH_USER").ok().filter(|u| !u.is_empty()) {
+            return Ok((user, SshUserSource::Env));
+        }
+
+        if let Some(user) = ssh_config::load(self.hostname).and_then(|(_, config)| config.user) {
+            return Ok((user, SshUserSource::SshConfig));
+        }
+
+        match local_username() {
+            Some(user) => Ok((user, SshUserSource::Local)),
+            None => Err(DeployDataDefsError::NoSshUser(self.node_name.to_owned())),
+        }
+    }
+
     pub fn defs(&'a self) -> Result<DeployDefs, DeployDataDefsError> {
-        let ssh_user = match self.merged_settings.ssh_user {
-            Some(ref u) => u.clone(),
-            None => whoami::username(),
-        };
+        let (ssh_user, ssh_user_source) = self.resolve_ssh_user()?;
 
         let profile_user = self.get_profile_user()?;
 
-        let sudo: Option<String> = match self.merged_settings.user {
-            Some(ref user) if user != &ssh_user => Some(format!("{} {}", self.get_sudo(), user)),
+        let sudo: Option<String> = match (&self.merged_settings.user, &self.profile.profile_settings.container) {
+            // `machinectl shell` switches to the profile user in the container
+            (_, Some(_)) => None,
+            (Some(ref user), None) if user != &ssh_user => Some(format!("{} {}", self.get_sudo(), user)),
             _ => None,
         };
 
+        let container_command = self.profile.profile_settings.container.as_ref().map(|container| {
+            let machinectl = format!("machinectl shell -q {}@{} /bin/sh -c", profile_user, container);
+            match ssh_user.as_str() {
+                "root" => machinectl,
+                _ => format!("{} root {}", self.get_sudo(), machinectl),
+            }
+        });
+
         Ok(DeployDefs {
-            ssh_user,
             profile_user,
             sudo,
             sudo_password: None,
+            manifest_signature: None,
+            temp_path: make_run_temp_path(
+                self.merged_settings
+                    .temp_path
+                    .as_deref()
+                    .unwrap_or_else(|| Path::new("/tmp")),
+                run_id(),
+            ),
+            label: None,
+            local: is_local_host(self.hostname) && Some(&ssh_user) == local_username().as_ref(),
+            container_command,
+            single_user_store: false,
+            sudo_tty: false,
+            ssh_user,
+            ssh_user_source,
         })
     }
 
@@ -409,8 +1316,135 @@ impl<'a> DeployData<'a> {
     }
 }
 
-pub fn make_deploy_data<'a, 's>(
-    top_settings: &'s data::GenericSettings,
+#[derive(Error, Debug)]
+pub enum EnvironmentError {
+    #[error("No environment named `{0}` was found{1}")]
+    NotFound(String, String),
+}
+
+#[derive(Error, Debug)]
+pub enum CloneError {
+    #[error("`deploy clone` needs the template node in the flake reference, e.g. `.#node`")]
+    NoTemplate,
+    #[error("No node named `{0}` was found{1}")]
+    NodeNotFound(String, String),
+    #[error("No profile named `{0}` was found on node `{1}`{2}")]
+    ProfileNotFound(String, String, String),
+}
+
+/// Replaces the nodes of `data` with a copy of the node `flake` refers to for each of `hosts`
+/// (`deploy clone`), with only the profile it refers to, if any. The copies are named
+/// `<node>@<host>`, and `flake` is changed to deploy all of them.
+pub fn clone_node(
+    flake: &mut DeployFlake<'_>,
+    data: &mut data::Data,
+    hosts: &[String],
+) -> Result<(), CloneError> {
+    let template_name = flake.node.take().ok_or(CloneError::NoTemplate)?;
+    let mut template = match data.nodes.remove(&template_name) {
+        Some(node) => node,
+        None => {
+            let names: Vec<&str> = data.nodes.keys().map(String::as_str).collect();
+            let hint = suggest::not_found_hint(&template_name, "node", &names);
+            return Err(CloneError::NodeNotFound(template_name, hint));
+        }
+    };
+
+    if let Some(profile_name) = flake.profile.take() {
+        let profile = match template.node_settings.profiles.remove(&profile_name) {
+            Some(profile) => profile,
+            None => {
+                let names: Vec<&str> = template.node_settings.profiles.keys().map(String::as_str).collect();
+                let hint = suggest::not_found_hint(&profile_name, "profile", &names);
+                return Err(CloneError::ProfileNotFound(profile_name, template_name, hint));
+            }
+        };
+        template.node_settings.profiles = std::iter::once((profile_name, profile)).collect();
+        template.node_settings.profiles_order.clear();
+    }
+
+    data.nodes = hosts
+        .iter()
+        .map(|host| {
+            let mut node = template.clone();
+            node.node_settings.hostname = host.clone();
+            (format!("{}@{}", template_name, host), node)
+        })
+        .collect();
+
+    Ok(())
+}
+
+/// The nodes selected by their `tags` (`--tags`): those with all the tags of any of the
+/// alternatives, e.g. `web+prod,db` for the nodes tagged both `web` and `prod`, and those tagged `db`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagFilter(Vec<Vec<String>>);
+
+impl std::str::FromStr for TagFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let alternatives: Vec<Vec<String>> = s
+            .split(',')
+            .map(|alternative| alternative.split('+').map(|t| t.trim().to_string()).collect())
+            .collect();
+        if alternatives.iter().flatten().any(String::is_empty) {
+            return Err(format!("invalid tags `{}`, expected e.g. `web,db` or `web+prod`", s));
+        }
+        Ok(TagFilter(alternatives))
+    }
+}
+
+impl std::fmt::Display for TagFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alternatives: Vec<String> = self.0.iter().map(|tags| tags.join("+")).collect();
+        write!(f, "{}", alternatives.join(","))
+    }
+}
+
+impl TagFilter {
+    pub fn matches(&self, tags: &[String]) -> bool {
+        self.0
+            .iter()
+            .any(|alternative| alternative.iter().all(|t| tags.contains(t)))
+    }
+
+    /// Every tag named, for `nodesExpr`
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.0.iter().flatten().cloned().collect();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    }
+}
+
+/// Looks up the environment selected with `--env`
+pub fn select_environment<'a>(
+    data: &'a data::Data,
+    cmd_overrides: &CmdOverrides,
+) -> Result<Option<&'a data::Environment>, EnvironmentError> {
+    let name = match &cmd_overrides.environment {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    match data.environments.get(name) {
+        Some(environment) => Ok(Some(environment)),
+        None => {
+            let names: Vec<&str> = data.environments.keys().map(String::as_str).collect();
+            Err(EnvironmentError::NotFound(
+                name.clone(),
+                suggest::not_found_hint(name, "environment", &names),
+            ))
+        }
+    }
+}
+
+/// Merges the settings of all layers (see [`settings`])
+#[allow(clippy::too_many_arguments)]
+pub fn make_deploy_data<'a>(
+    top_settings: &data::GenericSettings,
+    environment: Option<&'a data::Environment>,
     node: &'a data::Node,
     node_name: &'a str,
     profile: &'a data::Profile,
@@ -419,50 +1453,235 @@ pub fn make_deploy_data<'a, 's>(
     debug_logs: bool,
     log_dir: Option<&'a str>,
 ) -> DeployData<'a> {
-    let mut merged_settings = profile.generic_settings.clone();
-    merged_settings.merge(node.generic_settings.clone());
-    merged_settings.merge(top_settings.clone());
-
-    // build all machines remotely when the command line flag is set
-    if cmd_overrides.remote_build {
-        merged_settings.remote_build = Some(cmd_overrides.remote_build);
-    }
-    if cmd_overrides.ssh_user.is_some() {
-        merged_settings.ssh_user = cmd_overrides.ssh_user.clone();
-    }
-    if cmd_overrides.profile_user.is_some() {
-        merged_settings.user = cmd_overrides.profile_user.clone();
-    }
-    if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
-        merged_settings.ssh_opts = ssh_opts.split(' ').map(|x| x.to_owned()).collect();
-    }
-    if let Some(fast_connection) = cmd_overrides.fast_connection {
-        merged_settings.fast_connection = Some(fast_connection);
-    }
-    if let Some(auto_rollback) = cmd_overrides.auto_rollback {
-        merged_settings.auto_rollback = Some(auto_rollback);
-    }
-    if let Some(magic_rollback) = cmd_overrides.magic_rollback {
-        merged_settings.magic_rollback = Some(magic_rollback);
-    }
-    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
-        merged_settings.confirm_timeout = Some(confirm_timeout);
-    }
-    if let Some(activation_timeout) = cmd_overrides.activation_timeout {
-        merged_settings.activation_timeout = Some(activation_timeout);
-    }
-    if let Some(interactive_sudo) = cmd_overrides.interactive_sudo {
-        merged_settings.interactive_sudo = Some(interactive_sudo);
-    }
+    let (hostname, merged_settings) = settings::merge(
+        top_settings,
+        environment,
+        node,
+        node_name,
+        profile,
+        cmd_overrides,
+    );
+    log::debug!(
+        "SSH options for profile `{}` of node `{}`: {:?}",
+        profile_name, node_name, merged_settings.ssh_opts
+    );
 
     DeployData {
         node_name,
         node,
         profile_name,
         profile,
+        hostname,
         cmd_overrides,
         merged_settings,
         debug_logs,
         log_dir,
     }
 }
+
+#[test]
+fn test_make_deploy_data_environment() {
+    let data: data::Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "sshOpts": ["-p", "22"],
+                "profiles": { "system": { "path": "/nix/store/blah-system", "user": "root" } },
+            },
+        },
+        "environments": {
+            "staging": {
+                "sshUser": "staging",
+                "nodes": { "web1": { "hostname": "web1.staging.example.com", "sshOpts": ["-A"] } },
+            },
+        },
+    }))
+    .unwrap();
+
+    let mut cmd_overrides = CmdOverrides::default();
+
+    fn make(data: &data::Data, cmd_overrides: &CmdOverrides) -> (String, Option<String>, Vec<String>) {
+        let node = &data.nodes["web1"];
+        let deploy_data = make_deploy_data(
+            &data.generic_settings,
+            select_environment(data, cmd_overrides).unwrap(),
+            node,
+            "web1",
+            &node.node_settings.profiles["system"],
+            "system",
+            cmd_overrides,
+            false,
+            None,
+        );
+        (
+            deploy_data.hostname.to_string(),
+            deploy_data.merged_settings.ssh_user,
+            deploy_data.merged_settings.ssh_opts,
+        )
+    }
+
+    assert_eq!(
+        make(&data, &cmd_overrides),
+        ("web1.example.com".to_string(), Some("deploy".to_string()), vec!["-p".to_string(), "22".to_string()])
+    );
+
+    cmd_overrides.environment = Some("staging".to_string());
+    assert_eq!(
+        make(&data, &cmd_overrides),
+        (
+            "web1.staging.example.com".to_string(),
+            Some("staging".to_string()),
+            vec!["-A".to_string(), "-p".to_string(), "22".to_string()]
+        )
+    );
+
+    // A rescue system booted at another address, whose host key isn't known
+    cmd_overrides.hostname = Some("10.0.0.99".to_string());
+    cmd_overrides.skip_host_key_check = true;
+    assert_eq!(
+        make(&data, &cmd_overrides),
+        (
+            "10.0.0.99".to_string(),
+            Some("staging".to_string()),
+            ["-A", "-p", "22", "-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null"]
+                .iter()
+                .map(|x| x.to_string())
+                .collect()
+        )
+    );
+
+    cmd_overrides.environment = Some("prod".to_string());
+    assert!(matches!(
+        select_environment(&data, &cmd_overrides),
+        Err(EnvironmentError::NotFound(_, _))
+    ));
+}
+
+#[test]
+fn test_clone_node() {
+    let mut data: data::Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "nodes": {
+            "template": {
+                "hostname": "template.example.com",
+                "profilesOrder": ["system", "hello"],
+                "profiles": {
+                    "system": { "path": "/nix/store/blah-system" },
+                    "hello": { "path": "/nix/store/blah-hello" },
+                },
+            },
+            "db": { "hostname": "db.example.com", "profiles": {} },
+        },
+    }))
+    .unwrap();
+    let hosts = vec!["10.0.0.1".to_string(), "10.0.0.2".to_string()];
+
+    let mut flake = parse_flake(".#template.hello").unwrap();
+    clone_node(&mut flake, &mut data, &hosts).unwrap();
+    assert_eq!((flake.node, flake.profile), (None, None));
+
+    let mut nodes: Vec<(&str, &str)> = data
+        .nodes
+        .iter()
+        .map(|(name, node)| (name.as_str(), node.node_settings.hostname.as_str()))
+        .collect();
+    nodes.sort();
+    assert_eq!(nodes, [("template@10.0.0.1", "10.0.0.1"), ("template@10.0.0.2", "10.0.0.2")]);
+    let clone = &data.nodes["template@10.0.0.1"].node_settings;
+    assert_eq!(clone.profiles.keys().collect::<Vec<_>>(), ["hello"]);
+    assert!(clone.profiles_order.is_empty());
+
+    let mut flake = parse_flake(".").unwrap();
+    assert!(matches!(clone_node(&mut flake, &mut data, &hosts), Err(CloneError::NoTemplate)));
+    let mut flake = parse_flake(".#db").unwrap();
+    assert!(matches!(
+        clone_node(&mut flake, &mut data, &hosts),
+        Err(CloneError::NodeNotFound(node, _)) if node == "db"
+    ));
+}
+
+#[test]
+fn test_use_ssh_user() {
+    let data: data::Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "bootstrapSshUser": "root",
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "profiles": { "hello": { "path": "/nix/store/blah-hello" } },
+            },
+        },
+    }))
+    .unwrap();
+
+    let cmd_overrides = CmdOverrides::default();
+    let node = &data.nodes["web1"];
+    let mut deploy_data = make_deploy_data(
+        &data.generic_settings,
+        None,
+        node,
+        "web1",
+        &node.node_settings.profiles["hello"],
+        "hello",
+        &cmd_overrides,
+        false,
+        None,
+    );
+    assert_eq!(deploy_data.merged_settings.bootstrap_ssh_user.as_deref(), Some("root"));
+
+    deploy_data.use_ssh_user("root".to_string());
+    let defs = deploy_data.defs().unwrap();
+    assert_eq!(defs.ssh_user, "root");
+    assert_eq!(defs.ssh_user_source, SshUserSource::Settings);
+    // The profile is still deployed for the regular SSH user
+    assert_eq!(defs.profile_user, "deploy");
+    assert_eq!(defs.sudo.as_deref(), Some("sudo -u deploy"));
+
+    // Nix commands run as the owner of a single-user store, without sudo
+    deploy_data.use_ssh_user("alice".to_string());
+    deploy_data.use_single_user_store("alice".to_string());
+    let defs = deploy_data.defs().unwrap();
+    assert_eq!(defs.profile_user, "alice");
+    assert_eq!(defs.sudo, None);
+}
+
+#[test]
+fn test_resolve_ssh_user() {
+    let data: data::Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "profiles": { "hello": { "path": "/nix/store/blah-hello" } },
+            },
+        },
+    }))
+    .unwrap();
+    let node = &data.nodes["web1"];
+    let resolve = |cmd_overrides: &CmdOverrides| {
+        make_deploy_data(
+            &data.generic_settings,
+            None,
+            node,
+            "web1",
+            &node.node_settings.profiles["hello"],
+            "hello",
+            cmd_overrides,
+            false,
+            None,
+        )
+        .resolve_ssh_user()
+        .unwrap()
+    };
+
+    assert_eq!(
+        resolve(&CmdOverrides::default()),
+        ("deploy".to_string(), SshUserSource::Settings)
+    );
+    let cmd_overrides = CmdOverrides {
+        ssh_user: Some("ci".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(resolve(&cmd_overrides), ("ci".to_string(), SshUserSource::Flag));
+}
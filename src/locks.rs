@@ -0,0 +1,628 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Locks on nodes shared between everyone deploying them (`lock`), so that two operators can't
+//! deploy the same node at the same time.
+//!
+//! Before anything is deployed, a lock is taken for every node with `lock` set, at its `url`:
+//! `ssh://[user@]host/dir` keeps the locks as files in `dir` on a shared host, `s3://bucket/prefix`
+//! as objects in an S3 bucket (with `aws`, relying on conditional writes) and
+//! `etcd://host:port/prefix` (or `etcd+https://`) as keys in etcd (with `etcdctl`). Each lock
+//! (`<node>.lock`) holds when it was taken and by whom. A node someone else holds the lock of isn't
+//! deployed, unless their lock is older than `ttl` seconds; it is then replaced only if it is
+//! still the one read (with an etcd transaction, an S3 `If-Match` or under `flock` on the shared
+//! host), so that two deployers can't both take it. The locks are released once the deployment
+//! is done; `deploy locks list` shows the locks held and `deploy locks steal` removes locks left
+//! behind.
+
+use std::fmt;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::deploy::shell_quote;
+use crate::severity::Severity;
+use crate::trace;
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("Invalid lock URL `{0}`, expected ssh://host/dir, s3://bucket/prefix or etcd://host:port/prefix")]
+    Url(String),
+    #[error("Failed to run `{0}` for the lock: {1}")]
+    Run(String, std::io::Error),
+    #[error("`{0}` for the lock resulted in a bad exit code: {1:?}")]
+    Exit(String, Option<i32>),
+    #[error("Node `{0}` is locked by {1}; wait for their deployment, or run `deploy locks steal` if it is stale")]
+    Held(String, LockHolder),
+}
+
+/// Where the locks are kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum LockBackend {
+    Ssh { host: String, dir: String },
+    S3 { bucket: String, prefix: String },
+    Etcd { endpoints: String, prefix: String },
+}
+
+/// Who took a lock, and when
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockHolder {
+    /// When the lock was taken, in seconds since the Unix epoch
+    pub since: i64,
+    /// `user@host/pid` of the deployment holding the lock
+    pub holder: String,
+}
+
+impl LockHolder {
+    /// The holder for this deployment
+    pub fn current(now: i64) -> Self {
+        // Kept to characters that need no quoting in any backend
+        let holder: String = format!(
+            "{}@{}/{}",
+            whoami::username(),
+            whoami::hostname(),
+            std::process::id()
+        )
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || "@/._-".contains(*c))
+        .collect();
+        LockHolder { since: now, holder }
+    }
+
+    pub fn is_expired(&self, ttl: Option<u64>, now: i64) -> bool {
+        ttl.is_some_and(|ttl| now - self.since > ttl as i64)
+    }
+
+    fn to_line(&self) -> String {
+        format!("{} {}", self.since, self.holder)
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let (since, holder) = s.trim().split_once(' ')?;
+        Some(LockHolder {
+            since: since.parse().ok()?,
+            holder: holder.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (since {})",
+            self.holder,
+            crate::schedule::format_at(self.since)
+        )
+    }
+}
+
+impl FromStr for LockBackend {
+    type Err = LockError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || LockError::Url(s.to_string());
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, path.trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+
+        match scheme {
+            "ssh" if !path.is_empty() => Ok(LockBackend::Ssh {
+                host: authority.to_string(),
+                dir: format!("/{}", path),
+            }),
+            "s3" => Ok(LockBackend::S3 {
+                bucket: authority.to_string(),
+                prefix: path.to_string(),
+            }),
+            "etcd" | "etcd+http" => Ok(LockBackend::Etcd {
+                endpoints: format!("http://{}", authority),
+                prefix: format!("/{}", path),
+            }),
+            "etcd+https" => Ok(LockBackend::Etcd {
+                endpoints: format!("https://{}", authority),
+                prefix: format!("/{}", path),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A command run for a lock, with what to write to its stdin
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockCommand {
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+}
+
+fn command(args: &[&str], stdin: Option<String>) -> LockCommand {
+    LockCommand {
+        args: args.iter().map(|x| x.to_string()).collect(),
+        stdin,
+    }
+}
+
+impl LockBackend {
+    /// Where the lock of `node` is kept
+    fn key(&self, node: &str) -> String {
+        let prefix = match self {
+            LockBackend::Ssh { dir: prefix, .. }
+            | LockBackend::S3 { prefix, .. }
+            | LockBackend::Etcd { prefix, .. } => prefix,
+        };
+        match prefix.as_str() {
+            "" => format!("{}.lock", node),
+            prefix => format!("{}/{}.lock", prefix.trim_end_matches('/'), node),
+        }
+    }
+
+    /// Creates the lock of `node` holding `content`, only if there is none; etcd prints `FAILURE`
+    /// instead of failing when there is one
+    pub fn create_command(&self, node: &str, content: &str) -> LockCommand {
+        let key = self.key(node);
+        match self {
+            LockBackend::Ssh { host, dir } => command(
+                &[
+                    "ssh",
+                    host,
+                    &format!(
+                        "mkdir -p {} && (set -C && cat > {})",
+                        shell_quote(dir),
+                        shell_quote(&key)
+                    ),
+                ],
+                Some(content.to_string()),
+            ),
+            LockBackend::S3 { bucket, .. } => command(
+                &[
+                    "aws",
+                    "s3api",
+                    "put-object",
+                    "--bucket",
+                    bucket,
+                    "--key",
+                    &key,
+                    "--if-none-match",
+                    "*",
+                    "--body",
+                    "/dev/stdin",
+                ],
+                Some(content.to_string()),
+            ),
+            LockBackend::Etcd { endpoints, .. } => command(
+                &["etcdctl", "--endpoints", endpoints, "txn"],
+                Some(format!(
+                    "create(\"{0}\") = \"0\"\n\nput {0} \"{1}\"\n\n",
+                    key, content
+                )),
+            ),
+        }
+    }
+
+    /// Replaces the lock of `node` by one holding `content`, only if it still holds `old` (for S3,
+    /// if it still has the `etag` it had when it was read holding `old`); etcd prints `FAILURE`
+    /// instead of failing when it doesn't. Over SSH, replacements are serialized with `flock`
+    pub fn replace_command(&self, node: &str, old: &str, content: &str, etag: &str) -> LockCommand {
+        let key = self.key(node);
+        match self {
+            LockBackend::Ssh { host, dir } => command(
+                &[
+                    "ssh",
+                    host,
+                    &format!(
+                        "exec 9>>{} && flock 9 && [ \"$(cat {})\" = {} ] && cat > {}",
+                        shell_quote(&format!("{}/.replacing", dir)),
+                        shell_quote(&key),
+                        shell_quote(old),
+                        shell_quote(&key)
+                    ),
+                ],
+                Some(content.to_string()),
+            ),
+            LockBackend::S3 { bucket, .. } => command(
+                &[
+                    "aws",
+                    "s3api",
+                    "put-object",
+                    "--bucket",
+                    bucket,
+                    "--key",
+                    &key,
+                    "--if-match",
+                    etag,
+                    "--body",
+                    "/dev/stdin",
+                ],
+                Some(content.to_string()),
+            ),
+            LockBackend::Etcd { endpoints, .. } => command(
+                &["etcdctl", "--endpoints", endpoints, "txn"],
+                Some(format!(
+                    "value(\"{0}\") = \"{1}\"\n\nput {0} \"{2}\"\n\n",
+                    key, old, content
+                )),
+            ),
+        }
+    }
+
+    /// Prints the metadata of the lock of `node` in S3, with its `ETag`
+    fn head_command(&self, node: &str) -> Option<LockCommand> {
+        match self {
+            LockBackend::S3 { bucket, .. } => Some(command(
+                &[
+                    "aws",
+                    "s3api",
+                    "head-object",
+                    "--bucket",
+                    bucket,
+                    "--key",
+                    &self.key(node),
+                ],
+                None,
+            )),
+            _ => None,
+        }
+    }
+
+    /// Prints the lock of `node`, failing (or printing nothing) if there is none
+    pub fn read_command(&self, node: &str) -> LockCommand {
+        let key = self.key(node);
+        match self {
+            LockBackend::Ssh { host, .. } => {
+                command(&["ssh", host, &format!("cat {}", shell_quote(&key))], None)
+            }
+            LockBackend::S3 { bucket, .. } => command(
+                &["aws", "s3", "cp", &format!("s3://{}/{}", bucket, key), "-"],
+                None,
+            ),
+            LockBackend::Etcd { endpoints, .. } => command(
+                &[
+                    "etcdctl",
+                    "--endpoints",
+                    endpoints,
+                    "get",
+                    &key,
+                    "--print-value-only",
+                ],
+                None,
+            ),
+        }
+    }
+
+    pub fn remove_command(&self, node: &str) -> LockCommand {
+        let key = self.key(node);
+        match self {
+            LockBackend::Ssh { host, .. } => command(
+                &["ssh", host, &format!("rm -f {}", shell_quote(&key))],
+                None,
+            ),
+            LockBackend::S3 { bucket, .. } => command(
+                &["aws", "s3", "rm", &format!("s3://{}/{}", bucket, key)],
+                None,
+            ),
+            LockBackend::Etcd { endpoints, .. } => {
+                command(&["etcdctl", "--endpoints", endpoints, "del", &key], None)
+            }
+        }
+    }
+
+    /// Prints the locks there are, with [`LockBackend::nodes`] making out their nodes
+    pub fn list_command(&self) -> LockCommand {
+        match self {
+            LockBackend::Ssh { host, dir } => command(
+                &[
+                    "ssh",
+                    host,
+                    &format!("ls -1 {} 2>/dev/null || true", shell_quote(dir)),
+                ],
+                None,
+            ),
+            LockBackend::S3 { bucket, prefix } => command(
+                &[
+                    "aws",
+                    "s3",
+                    "ls",
+                    &match prefix.as_str() {
+                        "" => format!("s3://{}/", bucket),
+                        prefix => format!("s3://{}/{}/", bucket, prefix),
+                    },
+                ],
+                None,
+            ),
+            LockBackend::Etcd { endpoints, prefix } => command(
+                &[
+                    "etcdctl",
+                    "--endpoints",
+                    endpoints,
+                    "get",
+                    &format!("{}/", prefix.trim_end_matches('/')),
+                    "--prefix",
+                    "--keys-only",
+                ],
+                None,
+            ),
+        }
+    }
+
+    /// The nodes locked according to the output of [`LockBackend::list_command`]
+    pub fn nodes(&self, output: &str) -> Vec<String> {
+        output
+            .lines()
+            // The name is the last column of `aws s3 ls`, and after the prefix for etcd
+            .filter_map(|line| line.split_whitespace().last())
+            .filter_map(|name| name.rsplit('/').next()?.strip_suffix(".lock"))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// Runs `command`, giving its exit code and stdout
+async fn run(command: &LockCommand) -> Result<(Option<i32>, String), LockError> {
+    let name = command.args.join(" ");
+    debug!("Running {} for the lock", name);
+
+    let mut child = trace::spawn(
+        Command::new(&command.args[0])
+            .args(&command.args[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(|e| LockError::Run(name.clone(), e))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(input) = &command.stdin {
+            let _ = stdin.write_all(input.as_bytes()).await;
+        }
+    }
+
+    let output = trace::wait_with_output(child)
+        .await
+        .map_err(|e| LockError::Run(name.clone(), e))?;
+    crate::log_child_output(&name, &output);
+
+    Ok((
+        output.status.code(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+/// The holder of the lock of `node`, if it is locked
+pub async fn holder(backend: &LockBackend, node: &str) -> Result<Option<LockHolder>, LockError> {
+    Ok(match run(&backend.read_command(node)).await? {
+        (Some(0), output) => LockHolder::parse(&output),
+        _ => None,
+    })
+}
+
+/// Locks `node` for `holder`, replacing a lock older than `ttl` seconds
+pub async fn acquire(
+    backend: &LockBackend,
+    node: &str,
+    holder: &LockHolder,
+    ttl: Option<u64>,
+) -> Result<(), LockError> {
+    let create = backend.create_command(node, &holder.to_line());
+
+    for retry in [false, true] {
+        let (code, output) = run(&create).await?;
+        if code == Some(0) && !output.starts_with("FAILURE") {
+            return Ok(());
+        }
+
+        match self::holder(backend, node).await? {
+            Some(other) if other.is_expired(ttl, holder.since) && !retry => {
+                warn!("Replacing the expired lock of node `{}` by {}", node, other);
+                if replace(backend, node, &other, holder).await? {
+                    return Ok(());
+                }
+                // Someone else replaced it first
+            }
+            Some(other) => return Err(LockError::Held(node.to_string(), other)),
+            None => return Err(LockError::Exit(create.args.join(" "), code)),
+        }
+    }
+
+    unreachable!("the second attempt always returns")
+}
+
+/// Replaces the lock of `node` held by `old` by one for `holder`, unless it changed in the meantime
+/// (e.g. someone else replaced it first), in which case it is left as it is
+async fn replace(
+    backend: &LockBackend,
+    node: &str,
+    old: &LockHolder,
+    holder: &LockHolder,
+) -> Result<bool, LockError> {
+    // The ETag is read before the lock, so the lock is only written if it didn't change since
+    let mut etag = String::new();
+    if let Some(head) = backend.head_command(node) {
+        let output = match run(&head).await? {
+            (Some(0), output) => output,
+            _ => return Ok(false),
+        };
+        let metadata: serde_json::Value = serde_json::from_str(&output).unwrap_or_default();
+        etag = match metadata["ETag"].as_str() {
+            Some(etag) => etag.to_string(),
+            None => return Ok(false),
+        };
+        if self::holder(backend, node).await?.as_ref() != Some(old) {
+            return Ok(false);
+        }
+    }
+
+    let replace = backend.replace_command(node, &old.to_line(), &holder.to_line(), &etag);
+    let (code, output) = run(&replace).await?;
+    Ok(code == Some(0) && !output.starts_with("FAILURE"))
+}
+
+/// Removes the lock of `node`, whoever holds it
+pub async fn remove(backend: &LockBackend, node: &str) -> Result<(), LockError> {
+    let command = backend.remove_command(node);
+    match run(&command).await? {
+        (Some(0), _) => Ok(()),
+        (code, _) => Err(LockError::Exit(command.args.join(" "), code)),
+    }
+}
+
+/// Releases the lock of `node` if `holder` still holds it
+pub async fn release(
+    backend: &LockBackend,
+    node: &str,
+    holder: &LockHolder,
+) -> Result<(), LockError> {
+    match self::holder(backend, node).await? {
+        Some(current) if &current == holder => remove(backend, node).await,
+        _ => {
+            warn!("The lock of node `{}` was taken over, leaving it", node);
+            Ok(())
+        }
+    }
+}
+
+/// The locks held at `backend`, by node
+pub async fn list(backend: &LockBackend) -> Result<Vec<(String, LockHolder)>, LockError> {
+    let command = backend.list_command();
+    let output = match run(&command).await? {
+        (Some(0), output) => output,
+        (code, _) => return Err(LockError::Exit(command.args.join(" "), code)),
+    };
+
+    let mut locks = Vec::new();
+    for node in backend.nodes(&output) {
+        // Released in the meantime otherwise
+        if let Some(holder) = holder(backend, &node).await? {
+            locks.push((node, holder));
+        }
+    }
+    Ok(locks)
+}
+
+/// The locks a deployment took, to release once it is done
+pub struct Locks {
+    pub holder: LockHolder,
+    held: Vec<(String, LockBackend)>,
+}
+
+impl Locks {
+    pub fn new(now: i64) -> Self {
+        Locks {
+            holder: LockHolder::current(now),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn holds(&self, node: &str) -> bool {
+        self.held.iter().any(|(n, _)| n == node)
+    }
+
+    /// Locks `node` at `backend` (see [`acquire`])
+    pub async fn take(
+        &mut self,
+        backend: LockBackend,
+        node: &str,
+        ttl: Option<u64>,
+    ) -> Result<(), LockError> {
+        acquire(&backend, node, &self.holder, ttl).await?;
+        self.held.push((node.to_string(), backend));
+        Ok(())
+    }
+
+    /// Releases every lock taken, handling the errors of those that can't be with `severity`; the
+    /// others are released even if one of them fails
+    pub async fn release_all(self, severity: Severity) -> Result<(), LockError> {
+        let mut result = Ok(());
+        for (node, backend) in &self.held {
+            let released = release(backend, node, &self.holder).await;
+            if let Err(e) = severity.check(&format!("Releasing the lock of node `{}`", node), released) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn test_locks() {
+    assert_eq!(
+        "ssh://locks@shared.example.com/var/lib/deploy-locks/"
+            .parse::<LockBackend>()
+            .unwrap(),
+        LockBackend::Ssh {
+            host: "locks@shared.example.com".to_string(),
+            dir: "/var/lib/deploy-locks".to_string(),
+        }
+    );
+    assert_eq!(
+        "etcd+https://etcd.example.com:2379/deploy"
+            .parse::<LockBackend>()
+            .unwrap(),
+        LockBackend::Etcd {
+            endpoints: "https://etcd.example.com:2379".to_string(),
+            prefix: "/deploy".to_string(),
+        }
+    );
+    assert!("ssh://shared.example.com".parse::<LockBackend>().is_err());
+    assert!("ftp://example.com/locks".parse::<LockBackend>().is_err());
+
+    let s3: LockBackend = "s3://ops-bucket/deploy-locks".parse().unwrap();
+    assert_eq!(
+        s3.create_command("web1", "1700000000 alice@laptop/42").args.join(" "),
+        "aws s3api put-object --bucket ops-bucket --key deploy-locks/web1.lock --if-none-match * --body /dev/stdin"
+    );
+    assert_eq!(
+        s3.nodes(
+            "2024-01-01 12:00:00         26 web1.lock\n2024-01-01 12:00:00          4 notes.txt\n"
+        ),
+        vec!["web1"]
+    );
+
+    let etcd: LockBackend = "etcd://127.0.0.1:2379/deploy".parse().unwrap();
+    assert_eq!(
+        etcd.create_command("web1", "1700000000 alice@laptop/42").stdin.unwrap(),
+        "create(\"/deploy/web1.lock\") = \"0\"\n\nput /deploy/web1.lock \"1700000000 alice@laptop/42\"\n\n"
+    );
+    assert_eq!(
+        etcd.nodes("/deploy/web1.lock\n\n/deploy/db1.lock\n"),
+        vec!["web1", "db1"]
+    );
+
+    let ssh: LockBackend = "ssh://shared/var/lib/deploy-locks".parse().unwrap();
+    assert_eq!(
+        ssh.create_command("web1", "").args[2],
+        "mkdir -p '/var/lib/deploy-locks' && (set -C && cat > '/var/lib/deploy-locks/web1.lock')"
+    );
+
+    // Expired locks are only replaced if they are still the one read
+    let (old, new) = ("1700000000 alice@laptop/42", "1700090000 bob@desktop/7");
+    assert_eq!(
+        ssh.replace_command("web1", old, new, "").args[2],
+        "exec 9>>'/var/lib/deploy-locks/.replacing' && flock 9 && [ \"$(cat '/var/lib/deploy-locks/web1.lock')\" = '1700000000 alice@laptop/42' ] && cat > '/var/lib/deploy-locks/web1.lock'"
+    );
+    assert_eq!(
+        s3.replace_command("web1", old, new, "\"abc\"").args.join(" "),
+        "aws s3api put-object --bucket ops-bucket --key deploy-locks/web1.lock --if-match \"abc\" --body /dev/stdin"
+    );
+    assert_eq!(
+        etcd.replace_command("web1", old, new, "").stdin.unwrap(),
+        "value(\"/deploy/web1.lock\") = \"1700000000 alice@laptop/42\"\n\nput /deploy/web1.lock \"1700090000 bob@desktop/7\"\n\n"
+    );
+
+    let holder = LockHolder::parse("1700000000 alice@laptop/42\n").unwrap();
+    assert_eq!(holder.holder, "alice@laptop/42");
+    assert_eq!(LockHolder::parse(&holder.to_line()), Some(holder.clone()));
+    assert!(!holder.is_expired(None, 1800000000));
+    assert!(!holder.is_expired(Some(3600), 1700003600));
+    assert!(holder.is_expired(Some(3600), 1700003601));
+    assert!(LockHolder::current(0).holder.contains('@'));
+}
@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Fetching the activation logs `activate-rs` leaves on a node (`deploy logs`).
+//!
+//! Without `--log-dir`, `activate-rs` logs to [`ACTIVATION_LOG_DIR`] or, if it can't write there,
+//! to `deploy-rs-logs` under the temporary path of the node, in a file per activation named after
+//! the time it started. The newest of these (or the one before it) is printed on the node and
+//! read over SSH.
+
+use std::path::Path;
+
+use log::debug;
+use thiserror::Error;
+
+use crate::deploy::{node_command, shell_quote};
+use crate::trace;
+use crate::{DeployData, DeployDefs, ACTIVATION_LOG_DIR};
+
+#[derive(Error, Debug)]
+pub enum LogsError {
+    #[error("Failed to fetch the activation log over SSH: {0}")]
+    Ssh(std::io::Error),
+    #[error("Node {0} has no {1}activation log in {2}")]
+    NotFound(String, &'static str, String),
+}
+
+/// The directories `activate-rs` may have logged to on the node, `log_dir` (`--log-dir`) first
+pub fn log_dirs(deploy_data: &DeployData<'_>, log_dir: Option<&str>) -> Vec<String> {
+    let temp_path = deploy_data
+        .merged_settings
+        .temp_path
+        .as_deref()
+        .unwrap_or_else(|| Path::new("/tmp"));
+
+    let mut dirs: Vec<String> = log_dir.into_iter().map(str::to_string).collect();
+    dirs.push(ACTIVATION_LOG_DIR.to_string());
+    dirs.push(crate::fallback_activation_log_dir(temp_path).display().to_string());
+    dirs
+}
+
+/// A command printing the path of the `nth` newest activation log in `dirs` (the newest being
+/// the first) and then its contents
+fn build_fetch_command(dirs: &[String], nth: usize) -> String {
+    let globs: Vec<String> = dirs
+        .iter()
+        .map(|dir| format!("{}/*_activate_*.log", shell_quote(dir)))
+        .collect();
+    format!(
+        "log=$(ls -1t {} 2>/dev/null | sed -n {}p) && [ -n \"$log\" ] && echo \"$log\" && cat \"$log\"",
+        globs.join(" "),
+        nth
+    )
+}
+
+/// The path and contents of the newest activation log of the node, or of the one before it
+pub async fn fetch(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    log_dir: Option<&str>,
+    previous: bool,
+) -> Result<(String, String), LogsError> {
+    let dirs = log_dirs(deploy_data, log_dir);
+    let fetch_command = build_fetch_command(&dirs, if previous { 2 } else { 1 });
+    debug!("Constructed log fetching command: {}", fetch_command);
+
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(fetch_command)
+            .stdin(std::process::Stdio::null()),
+    )
+    .await
+    .map_err(LogsError::Ssh)?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match (output.status.success(), stdout.split_once('\n')) {
+        (true, Some((path, content))) => Ok((path.to_string(), content.to_string())),
+        _ => Err(LogsError::NotFound(
+            deploy_data.node_name.to_string(),
+            if previous { "previous " } else { "" },
+            dirs.join(", "),
+        )),
+    }
+}
+
+#[tokio::test]
+async fn test_fetch_logs() {
+    let dir = std::env::temp_dir().join(format!("deployrsfetchlogs{}", std::process::id()));
+    let fallback = dir.join("deploy-rs-logs");
+    std::fs::create_dir_all(&fallback).unwrap();
+
+    let dirs = vec![
+        "/nonexistent/deploy-rs".to_string(),
+        fallback.display().to_string(),
+    ];
+    assert_eq!(
+        build_fetch_command(&dirs[..1], 2),
+        "log=$(ls -1t '/nonexistent/deploy-rs'/*_activate_*.log 2>/dev/null | sed -n 2p) && [ -n \"$log\" ] && echo \"$log\" && cat \"$log\""
+    );
+
+    let older = fallback.join("activate-rs_activate_2024-05-01_10-00-00.log");
+    let newer = fallback.join("activate-rs_activate_2024-05-02_10-00-00.log");
+    std::fs::write(&older, "activated 1111\n").unwrap();
+    std::fs::write(fallback.join("activate-rs_wait_2024-05-02_10-00-00.log"), "waited\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    std::fs::write(&newer, "activated 2222\nrolled back\n").unwrap();
+
+    let dirs = &dirs;
+    let fetch = |nth| async move {
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(build_fetch_command(dirs, nth))
+            .output()
+            .await
+            .unwrap();
+        (output.status.success(), String::from_utf8(output.stdout).unwrap())
+    };
+    assert_eq!(
+        fetch(1).await,
+        (true, format!("{}\nactivated 2222\nrolled back\n", newer.display()))
+    );
+    assert_eq!(
+        fetch(2).await,
+        (true, format!("{}\nactivated 1111\n", older.display()))
+    );
+    assert!(!fetch(3).await.0);
+
+    let _ = std::fs::remove_dir_all(dir);
+}
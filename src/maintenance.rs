@@ -0,0 +1,167 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Nodes under maintenance, which deployments skip unless `--include-maintenance` is given.
+//!
+//! A node is under maintenance when the flake says so (`maintenance = true;`) or when it was
+//! marked with `deploy maintenance set`, which records it in `maintenance.json` in the state
+//! directory, optionally until a given time. Marks that ran out are ignored and dropped the next
+//! time the file is written.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const MAINTENANCE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceMark {
+    /// When the node was marked, in seconds since the Unix epoch
+    pub since: i64,
+    /// When the mark runs out, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Maintenance {
+    pub version: u32,
+    /// The marks, by node name
+    pub nodes: BTreeMap<String, MaintenanceMark>,
+}
+
+#[derive(Error, Debug)]
+pub enum MaintenanceError {
+    #[error("Failed to read the maintenance marks from {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the maintenance marks: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported maintenance marks version {0} (expected {})", MAINTENANCE_VERSION)]
+    Version(u32),
+    #[error("Failed to write the maintenance marks to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("Node `{0}` isn't marked as under maintenance")]
+    NotMarked(String),
+}
+
+pub fn maintenance_path() -> PathBuf {
+    crate::state::path("maintenance.json")
+}
+
+impl MaintenanceMark {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+}
+
+impl std::fmt::Display for MaintenanceMark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "since {}", crate::schedule::format_at(self.since))?;
+        if let Some(until) = self.until {
+            write!(f, ", until {}", crate::schedule::format_at(until))?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl Maintenance {
+    /// Loads the marks at `path`, none if there is no file yet
+    pub fn load(path: &Path) -> Result<Self, MaintenanceError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Maintenance {
+                    version: MAINTENANCE_VERSION,
+                    nodes: BTreeMap::new(),
+                })
+            }
+            Err(e) => return Err(MaintenanceError::Read(path.to_path_buf(), e)),
+        };
+
+        let maintenance: Maintenance = serde_json::from_str(&content)?;
+        if maintenance.version != MAINTENANCE_VERSION {
+            return Err(MaintenanceError::Version(maintenance.version));
+        }
+
+        Ok(maintenance)
+    }
+
+    /// Writes the marks to `path`, without those that ran out before `now`
+    pub fn save(&mut self, path: &Path, now: i64) -> Result<(), MaintenanceError> {
+        self.nodes.retain(|_, mark| mark.is_active(now));
+
+        // Serializing plain strings and numbers can't fail
+        crate::state::write(path, &serde_json::to_string_pretty(self).unwrap_or_default())
+            .map_err(|e| MaintenanceError::Write(path.to_path_buf(), e))
+    }
+
+    /// Marks `node` as under maintenance from `now`, replacing an earlier mark
+    pub fn set(&mut self, node: &str, now: i64, until: Option<i64>, reason: Option<String>) {
+        self.nodes.insert(
+            node.to_string(),
+            MaintenanceMark {
+                since: now,
+                until,
+                reason,
+            },
+        );
+    }
+
+    pub fn clear(&mut self, node: &str) -> Result<MaintenanceMark, MaintenanceError> {
+        self.nodes
+            .remove(node)
+            .ok_or_else(|| MaintenanceError::NotMarked(node.to_string()))
+    }
+
+    /// The mark of `node`, if it is under maintenance at `now`
+    pub fn active(&self, node: &str, now: i64) -> Option<&MaintenanceMark> {
+        self.nodes.get(node).filter(|mark| mark.is_active(now))
+    }
+}
+
+#[test]
+fn test_maintenance() {
+    let path = std::env::temp_dir().join(format!("deployrsmaintenance{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut maintenance = Maintenance::load(&path).unwrap();
+    assert!(maintenance.nodes.is_empty());
+
+    maintenance.set("web1", 1000, None, Some("disk swap".to_string()));
+    maintenance.set("db1", 1000, Some(2000), None);
+    assert!(maintenance.active("web1", 5000).is_some());
+    assert!(maintenance.active("db1", 1500).is_some());
+    assert!(maintenance.active("db1", 2000).is_none());
+    assert!(maintenance.active("web2", 1500).is_none());
+
+    maintenance.save(&path, 1500).unwrap();
+    assert_eq!(Maintenance::load(&path).unwrap(), maintenance);
+    // Ran out in the meantime
+    maintenance.save(&path, 2500).unwrap();
+    assert_eq!(
+        Maintenance::load(&path).unwrap().nodes.keys().collect::<Vec<_>>(),
+        vec!["web1"]
+    );
+
+    assert!(maintenance.clear("web1").is_ok());
+    assert!(matches!(
+        maintenance.clear("web1"),
+        Err(MaintenanceError::NotMarked(_))
+    ));
+
+    std::fs::write(&path, r#"{ "version": 2, "nodes": {} }"#).unwrap();
+    assert!(matches!(
+        Maintenance::load(&path),
+        Err(MaintenanceError::Version(2))
+    ));
+    let _ = std::fs::remove_file(path);
+}
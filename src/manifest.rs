@@ -0,0 +1,238 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Deploy manifests, recording which closure is going to be deployed to each profile.
+//!
+//! `deploy plan --sign <key>` signs the closure of every profile with an SSH key (using
+//! `ssh-keygen -Y sign`). When deploying with `--manifest`, the signatures are passed on to the
+//! activation, which refuses closures without a valid signature from one of the keys in
+//! [`ALLOWED_SIGNERS_PATH`] on the target if `requireSignedManifest` is set.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::trace;
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Keeps signatures for manifests apart from other uses of the same SSH key
+pub const SIGNATURE_NAMESPACE: &str = "deploy-rs-manifest";
+
+/// The `allowed_signers` file (see `ssh-keygen(1)`) signatures are checked against on the target
+pub const ALLOWED_SIGNERS_PATH: &str = "/etc/deploy-rs/allowed_signers";
+
+const ARMOR_BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const ARMOR_END: &str = "-----END SSH SIGNATURE-----";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    pub node: String,
+    pub profile: String,
+    pub hostname: String,
+    pub path: String,
+    /// Signature of `path`, without the armor lines and line breaks of `ssh-keygen -Y sign`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub profiles: Vec<ManifestEntry>,
+}
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("Failed to read manifest: {0}")]
+    Read(std::io::Error),
+    #[error("Failed to parse manifest: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported manifest version {0} (expected {})", MANIFEST_VERSION)]
+    Version(u32),
+    #[error("Failed to run ssh-keygen: {0}")]
+    SshKeygen(std::io::Error),
+    #[error("Signing with ssh-keygen failed: {0}")]
+    Sign(String),
+    #[error("Failed to write signature to {0}: {1}")]
+    WriteSignature(String, std::io::Error),
+    #[error("The signature of {0} isn't from any key in {}", ALLOWED_SIGNERS_PATH)]
+    UnknownSigner(String),
+    #[error("The signature of {0} is invalid: {1}")]
+    InvalidSignature(String, String),
+}
+
+impl Manifest {
+    pub fn new(profiles: Vec<ManifestEntry>) -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            profiles,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        let manifest: Manifest =
+            serde_json::from_str(&std::fs::read_to_string(path).map_err(ManifestError::Read)?)?;
+
+        if manifest.version != MANIFEST_VERSION {
+            return Err(ManifestError::Version(manifest.version));
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn entry(&self, node: &str, profile: &str) -> Option<&ManifestEntry> {
+        self.profiles
+            .iter()
+            .find(|e| e.node == node && e.profile == profile)
+    }
+}
+
+fn dearmor(armored: &str) -> String {
+    armored
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && *l != ARMOR_BEGIN && *l != ARMOR_END)
+        .collect()
+}
+
+fn armor(signature: &str) -> String {
+    let mut armored = format!("{}\n", ARMOR_BEGIN);
+    for chunk in signature.as_bytes().chunks(70) {
+        armored.push_str(&String::from_utf8_lossy(chunk));
+        armored.push('\n');
+    }
+    armored.push_str(ARMOR_END);
+    armored.push('\n');
+    armored
+}
+
+#[test]
+fn test_armor() {
+    let signature = "U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgVhmlEw0YuoEC6OF/6Dbk6Rt1PpQ0nnYBmcWHH\
+                     KsuVnUAAAASZGVwbG95LXJzLW1hbmlmZXN0";
+
+    let armored = armor(signature);
+    assert!(armored.starts_with("-----BEGIN SSH SIGNATURE-----\nU1NIU0lHAAAAAQ"));
+    assert!(armored.lines().all(|l| l.len() <= 70));
+    assert_eq!(dearmor(&armored), signature);
+}
+
+async fn run_ssh_keygen(args: &[&str], stdin: &str) -> Result<std::process::Output, ManifestError> {
+    debug!("Running ssh-keygen {}", args.join(" "));
+
+    let mut child = trace::spawn(
+        Command::new("ssh-keygen")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(ManifestError::SshKeygen)?;
+
+    let mut child_stdin = child.stdin.take().unwrap();
+    child_stdin
+        .write_all(stdin.as_bytes())
+        .await
+        .map_err(ManifestError::SshKeygen)?;
+    drop(child_stdin);
+
+    trace::wait_with_output(child)
+        .await
+        .map_err(ManifestError::SshKeygen)
+}
+
+/// Signs `closure` with the SSH key at `key` (or its public key, if the private key is in the agent)
+pub async fn sign(key: &Path, closure: &str) -> Result<String, ManifestError> {
+    let output = run_ssh_keygen(
+        &[
+            "-Y",
+            "sign",
+            "-f",
+            &key.to_string_lossy(),
+            "-n",
+            SIGNATURE_NAMESPACE,
+        ],
+        closure,
+    )
+    .await?;
+
+    match output.status.success() {
+        true => Ok(dearmor(&String::from_utf8_lossy(&output.stdout))),
+        false => Err(ManifestError::Sign(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )),
+    }
+}
+
+/// Checks that `signature` is a signature of `closure` by one of the keys in `allowed_signers`,
+/// `temp_path` is used to store the signature for `ssh-keygen`
+pub async fn verify(
+    allowed_signers: &Path,
+    closure: &str,
+    signature: &str,
+    temp_path: &Path,
+) -> Result<(), ManifestError> {
+    let signature_path = temp_path.join(format!(
+        "deploy-rs-manifest-{}.sig",
+        closure.trim_start_matches("/nix/store/").replace('/', "-")
+    ));
+    std::fs::write(&signature_path, armor(signature))
+        .map_err(|e| ManifestError::WriteSignature(signature_path.display().to_string(), e))?;
+
+    let signature_file = signature_path.to_string_lossy().to_string();
+    let allowed_signers = allowed_signers.to_string_lossy().to_string();
+
+    let result = async {
+        let principals = run_ssh_keygen(
+            &["-Y", "find-principals", "-s", &signature_file, "-f", &allowed_signers],
+            "",
+        )
+        .await?;
+        let principals = String::from_utf8_lossy(&principals.stdout);
+        let principal = match principals.lines().next() {
+            Some(p) if !p.trim().is_empty() => p.trim().to_string(),
+            _ => return Err(ManifestError::UnknownSigner(closure.to_string())),
+        };
+
+        let output = run_ssh_keygen(
+            &[
+                "-Y",
+                "verify",
+                "-f",
+                &allowed_signers,
+                "-I",
+                &principal,
+                "-n",
+                SIGNATURE_NAMESPACE,
+                "-s",
+                &signature_file,
+            ],
+            closure,
+        )
+        .await?;
+
+        match output.status.success() {
+            true => {
+                debug!("Manifest signature of {} by {} is valid", closure, principal);
+                Ok(())
+            }
+            false => Err(ManifestError::InvalidSignature(
+                closure.to_string(),
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            )),
+        }
+    }
+    .await;
+
+    let _ = std::fs::remove_file(&signature_path);
+
+    result
+}
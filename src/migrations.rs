@@ -0,0 +1,184 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Migrations run on one node of a group before any profile of the group is activated
+//! (`migrations`).
+//!
+//! Profiles with the same `migrations.group` (by default, those with the same name) form a group.
+//! Its leader is the node named in `migrations.leader` if that node is being deployed, otherwise
+//! the node whose profile comes first in the activation order. Right before the first profile of
+//! the group is activated, once the closure of the leader is on it, the command of the leader's
+//! profile is run there through the profile's sudo, in the new closure with it as `$PROFILE`. The
+//! group isn't activated anywhere unless the command succeeds, and a failure fails the deployment
+//! like a failed activation. Dry activations and `--boot` don't run migrations.
+
+use std::process::Stdio;
+
+use log::{debug, info};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::data::Migrations;
+use crate::deploy::{in_container, node_command, shell_quote};
+use crate::trace;
+use crate::{DeployData, DeployDefs};
+
+#[derive(Error, Debug)]
+pub enum MigrationsError {
+    #[error("Failed to run the migrations over SSH: {0}")]
+    Ssh(std::io::Error),
+    #[error("The migrations resulted in a bad exit code: {0:?}")]
+    Exit(Option<i32>),
+}
+
+/// Profiles running the same migrations, of which only the leader runs them
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationGroup {
+    pub name: String,
+    /// The index of the leader's profile among the profiles
+    pub leader: usize,
+    /// The indices of all profiles of the group, in activation order
+    pub members: Vec<usize>,
+}
+
+/// The migration groups of `profiles` (node name, profile name and `migrations` of each profile,
+/// in activation order)
+pub fn plan(profiles: &[(&str, &str, Option<&Migrations>)]) -> Vec<MigrationGroup> {
+    let mut groups: Vec<MigrationGroup> = Vec::new();
+
+    for (i, (_, profile_name, migrations)) in profiles.iter().enumerate() {
+        let migrations = match migrations {
+            Some(x) => x,
+            None => continue,
+        };
+        let name = migrations.group.as_deref().unwrap_or(profile_name);
+        match groups.iter_mut().find(|group| group.name == name) {
+            Some(group) => group.members.push(i),
+            None => groups.push(MigrationGroup {
+                name: name.to_string(),
+                leader: i,
+                members: vec![i],
+            }),
+        }
+    }
+
+    for group in &mut groups {
+        let designated = group.members.iter().find_map(|&i| {
+            let leader = profiles[i].2.and_then(|m| m.leader.as_deref())?;
+            group
+                .members
+                .iter()
+                .copied()
+                .find(|&j| profiles[j].0 == leader)
+        });
+        if let Some(leader) = designated {
+            group.leader = leader;
+        }
+    }
+
+    groups
+}
+
+/// The command running `command` in `closure`, through `sudo` if given
+pub fn build_migrations_command(sudo: Option<&str>, closure: &str, command: &str) -> String {
+    let command = format!(
+        "cd {} && env PROFILE={} sh -c {}",
+        shell_quote(closure),
+        shell_quote(closure),
+        shell_quote(command)
+    );
+    match sudo {
+        Some(sudo) => format!("{} sh -c {}", sudo, shell_quote(&command)),
+        None => command,
+    }
+}
+
+/// Runs the migrations of the profile on its node
+pub async fn run(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    migrations: &Migrations,
+) -> Result<(), MigrationsError> {
+    info!(
+        "Running the migrations of profile `{}` on node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let command = build_migrations_command(
+        deploy_defs.sudo.as_deref(),
+        &deploy_data.profile.profile_settings.path,
+        &migrations.command,
+    );
+    debug!("Constructed migrations command: {}", command);
+
+    let mut child = trace::spawn(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, command))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(MigrationsError::Ssh)?;
+
+    if let (Some(mut stdin), Some(password)) = (child.stdin.take(), &deploy_defs.sudo_password) {
+        let _ = stdin.write_all(format!("{}\n", password).as_bytes()).await;
+    }
+
+    let output = trace::wait_with_output(child)
+        .await
+        .map_err(MigrationsError::Ssh)?;
+    crate::log_child_output(&format!("migrations {}", deploy_data.node_name), &output);
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        a => Err(MigrationsError::Exit(a)),
+    }
+}
+
+#[test]
+fn test_migrations() {
+    let migrations = |group: Option<&str>, leader: Option<&str>| Migrations {
+        command: "bin/migrate".to_string(),
+        group: group.map(str::to_string),
+        leader: leader.map(str::to_string),
+    };
+    let (shared, led, other) = (
+        migrations(None, None),
+        migrations(None, Some("web2")),
+        migrations(Some("db"), Some("web9")),
+    );
+
+    assert_eq!(
+        plan(&[
+            ("web1", "app", Some(&shared)),
+            ("web1", "system", None),
+            ("web2", "app", Some(&led)),
+            ("web3", "app", Some(&shared)),
+            ("web1", "worker", Some(&other)),
+            ("web3", "cron", Some(&other)),
+        ]),
+        vec![
+            MigrationGroup {
+                name: "app".to_string(),
+                leader: 2,
+                members: vec![0, 2, 3],
+            },
+            // The designated leader isn't being deployed
+            MigrationGroup {
+                name: "db".to_string(),
+                leader: 4,
+                members: vec![4, 5],
+            },
+        ]
+    );
+
+    assert_eq!(
+        build_migrations_command(Some("sudo -u app"), "/nix/store/abc-app", "bin/migrate --up"),
+        "sudo -u app sh -c 'cd '\\''/nix/store/abc-app'\\'' && env PROFILE='\\''/nix/store/abc-app'\\'' sh -c '\\''bin/migrate --up'\\'''"
+    );
+    assert_eq!(
+        build_migrations_command(None, "/nix/store/abc-app", "bin/migrate"),
+        "cd '/nix/store/abc-app' && env PROFILE='/nix/store/abc-app' sh -c 'bin/migrate'"
+    );
+}
@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! SSH connection multiplexing managed by deploy-rs (`sshMultiplexing`).
+//!
+//! Deploying a profile opens several SSH sessions to its node (checks, `nix copy`, activating,
+//! waiting, confirming), each with its own handshake, which adds up on slow links. With
+//! multiplexing, the first session to a node becomes a control master (`ControlMaster=auto`) and
+//! the following ones, `nix copy` included, reuse its connection. The control sockets are kept in
+//! a new directory with a random name per deploy-rs process, only accessible by the user (so
+//! nobody else can bind the predictable `%C` socket names in it), and the masters are
+//! told to exit (and the directory removed) once the deployment is done; a master left behind by
+//! a crash exits by itself after being idle for [`CONTROL_PERSIST`] seconds.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use tokio::process::Command;
+
+use crate::trace;
+
+/// How long a control master stays around without sessions, in seconds
+pub const CONTROL_PERSIST: u32 = 60;
+
+/// Longest path of a control socket most systems can bind to, with room for the `%C` hash
+const MAX_DIR_LEN: usize = 60;
+
+/// The name of control socket directories, followed by random characters
+const DIR_PREFIX: &str = "deploy-rs-ssh-";
+
+/// The directory of the control sockets of this process, once created
+static CONTROL_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Where the directory of the control sockets is created
+pub fn control_parent() -> PathBuf {
+    let temp_dir = std::env::temp_dir();
+    // Unix sockets can't have long paths, as in the temporary directories of macOS
+    match temp_dir.as_os_str().len() + DIR_PREFIX.len() + 16 < MAX_DIR_LEN {
+        true => temp_dir,
+        false => PathBuf::from("/tmp"),
+    }
+}
+
+/// The SSH options multiplexing connections through control sockets in `dir`
+pub fn control_opts(dir: &Path) -> Vec<String> {
+    vec![
+        "-o".to_string(),
+        "ControlMaster=auto".to_string(),
+        "-o".to_string(),
+        // `%C` is a hash of the local and remote host, port and user
+        format!("ControlPath={}/%C", dir.display()),
+        "-o".to_string(),
+        format!("ControlPersist={}", CONTROL_PERSIST),
+    ]
+}
+
+/// The SSH options multiplexing connections, creating the directory of the control sockets if
+/// needed (a new one only the user can access, see [`crate::create_private_dir`]), or none if it
+/// can't be created
+pub fn ssh_opts() -> Vec<String> {
+    let mut control_dir = CONTROL_DIR.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(dir) = &*control_dir {
+        return control_opts(dir);
+    }
+
+    let parent = control_parent();
+    match crate::create_private_dir(&parent, DIR_PREFIX) {
+        Ok(dir) => {
+            let opts = control_opts(&dir);
+            *control_dir = Some(dir);
+            opts
+        }
+        Err(e) => {
+            warn!(
+                "Failed to create a directory in {} for SSH control sockets, not multiplexing: {}",
+                parent.display(),
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Tells all control masters of this process to exit, and removes their directory
+pub async fn close_all() {
+    let dir = match CONTROL_DIR.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        Some(dir) => dir,
+        // Nothing was multiplexed
+        None => return,
+    };
+    let sockets = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+
+    for socket in sockets {
+        debug!("Closing the SSH control master of {}", socket.display());
+        // The destination is required, but the socket decides where the command goes
+        let closed = trace::output(
+            Command::new("ssh")
+                .arg("-S")
+                .arg(&socket)
+                .arg("-O")
+                .arg("exit")
+                .arg("deploy-rs")
+                .stdin(Stdio::null())
+                .stderr(Stdio::piped()),
+        )
+        .await;
+        match closed {
+            Ok(output) if output.status.success() => (),
+            Ok(output) => debug!(
+                "Failed to close the SSH control master of {}: {}",
+                socket.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => debug!(
+                "Failed to close the SSH control master of {}: {}",
+                socket.display(),
+                e
+            ),
+        }
+    }
+
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        debug!("Failed to remove {}: {}", dir.display(), e);
+    }
+}
+
+#[test]
+fn test_control_opts() {
+    assert_eq!(
+        control_opts(Path::new("/tmp/deploy-rs-ssh-42")),
+        vec![
+            "-o",
+            "ControlMaster=auto",
+            "-o",
+            "ControlPath=/tmp/deploy-rs-ssh-42/%C",
+            "-o",
+            "ControlPersist=60"
+        ]
+    );
+    assert!(control_parent().as_os_str().len() + DIR_PREFIX.len() + 16 < MAX_DIR_LEN);
+}
@@ -0,0 +1,656 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The native SSH transport (`sshTransport = "native"`), running the commands of deploying a
+//! profile (activation, waiting for it, confirming and revoking it) through libssh2 instead of
+//! the `ssh` binary.
+//!
+//! One session is kept open per destination and shared by all commands run there, each in its
+//! own channel. The `HostName`, `Port` and `IdentityFile` of the node in `~/.ssh/config` apply
+//! as with `ssh` (its `User` is already part of resolving the SSH user). Of the `sshOpts`, only
+//! `-p`, `-i` and the `Port`, `IdentityFile`, `StrictHostKeyChecking` and `UserKnownHostsFile`
+//! options are understood, and a few only tuning the connection (like `-v` or `ConnectTimeout`)
+//! are ignored. Nodes with any other option (e.g. `ProxyJump` or `ProxyCommand`, also from
+//! `~/.ssh/config`), a jump host or `sshWrapper`, or security key (`ed25519-sk`, `ecdsa-sk`) or
+//! certificate identities are reached with the `ssh` binary instead, rather than connecting in
+//! another way than `ssh` would. Logging in is tried with the SSH agent, then with the identity
+//! files (by default `~/.ssh/id_ed25519`, `~/.ssh/id_ecdsa` and `~/.ssh/id_rsa`), and the host key
+//! has to be in `~/.ssh/known_hosts` unless `StrictHostKeyChecking=no`. Pushing with `nix copy`
+//! and the other commands run on nodes still use the `ssh` binary. Needs deploy-rs to be built
+//! with the `native-ssh` feature.
+
+use std::path::PathBuf;
+use std::process::Output;
+
+use log::debug;
+use thiserror::Error;
+
+use crate::ssh_config::HostConfig;
+
+#[derive(Error, Debug)]
+pub enum NativeSshError {
+    #[error("deploy-rs was built without the `native-ssh` feature, set `sshTransport` to \"external\"")]
+    Unsupported,
+    #[error("The native SSH transport doesn't support the SSH option `{0}`")]
+    UnsupportedOption(String),
+    #[error("The native SSH transport doesn't support {1} identities ({0})")]
+    UnsupportedKey(PathBuf, &'static str),
+    #[error("The native SSH transport can't connect through jump host `{0}`")]
+    JumpHost(String),
+    #[error("The native SSH transport can't run ssh through the `sshWrapper` {0:?}")]
+    Wrapper(Vec<String>),
+    #[error("Failed to connect to {0}: {1}")]
+    Connect(String, std::io::Error),
+    #[cfg(feature = "native-ssh")]
+    #[error("SSH session with {0} failed: {1}")]
+    Session(String, ssh2::Error),
+    #[error("The host key of {0} is not in {1}")]
+    UnknownHostKey(String, String),
+    #[error("The host key of {0} doesn't match the one in {1}, it may have been replaced")]
+    ChangedHostKey(String, String),
+    #[error("Failed to log in to {0} with the SSH agent or any of {1:?}")]
+    Auth(String, Vec<PathBuf>),
+    #[error("Failed to run the command: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The native SSH task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+impl From<NativeSshError> for std::io::Error {
+    fn from(e: NativeSshError) -> Self {
+        match e {
+            NativeSshError::Io(e) => e,
+            e => std::io::Error::other(e),
+        }
+    }
+}
+
+/// Where commands are run, and how to log in there
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Tried after the SSH agent, the default keys if empty
+    pub identity_files: Vec<PathBuf>,
+    /// `~/.ssh/known_hosts` if not set
+    pub known_hosts: Option<PathBuf>,
+    pub strict_host_key_checking: bool,
+}
+
+/// The options of `ssh` taking an argument
+const OPTIONS_WITH_ARGUMENT: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// The flags of `ssh` only tuning the connection, which the native transport can do without
+const IGNORED_FLAGS: &str = "4CqTtv";
+
+/// The `-o` options only tuning the connection (or multiplexing it), in lowercase
+const IGNORED_OPTIONS: [&str; 9] = [
+    "batchmode",
+    "compression",
+    "connecttimeout",
+    "controlmaster",
+    "controlpath",
+    "controlpersist",
+    "loglevel",
+    "serveralivecountmax",
+    "serveraliveinterval",
+];
+
+/// The identity files `ssh` uses by default which the native transport can't
+const DEFAULT_SECURITY_KEYS: [&str; 2] = ["id_ecdsa_sk", "id_ed25519_sk"];
+
+impl Destination {
+    /// `user` at `host`, with the options of `ssh_opts`, which have to be ones the native
+    /// transport understands or can ignore, and then those of `config` (the stanzas of
+    /// `~/.ssh/config` matching `host`)
+    pub fn new(
+        user: &str,
+        host: &str,
+        ssh_opts: &[String],
+        config: &HostConfig,
+    ) -> Result<Self, NativeSshError> {
+        let mut destination = Destination {
+            user: user.to_string(),
+            host: match &config.hostname {
+                Some(hostname) => hostname.replace("%h", host),
+                None => host.to_string(),
+            },
+            port: 22,
+            identity_files: Vec::new(),
+            known_hosts: None,
+            strict_host_key_checking: true,
+        };
+        let mut port = None;
+
+        let mut opts = ssh_opts.iter();
+        while let Some(opt) = opts.next() {
+            let flag = match opt.strip_prefix('-').and_then(|o| o.chars().next()) {
+                Some(flag) => flag,
+                None => return Err(NativeSshError::UnsupportedOption(opt.clone())),
+            };
+            let value = match &opt[1 + flag.len_utf8()..] {
+                "" if OPTIONS_WITH_ARGUMENT.contains(flag) => opts.next().cloned().unwrap_or_default(),
+                value => value.to_string(),
+            };
+            match flag {
+                'p' => destination.set_option("Port", &value, &mut port)?,
+                'i' => destination.set_option("IdentityFile", &value, &mut port)?,
+                'o' => match value.split_once(['=', ' ']) {
+                    Some((key, value)) => destination.set_option(key, value.trim(), &mut port)?,
+                    None => return Err(NativeSshError::UnsupportedOption(format!("-o {}", value))),
+                },
+                flag if IGNORED_FLAGS.contains(flag) => {
+                    debug!("Ignoring SSH option `-{}` with the native transport", flag)
+                }
+                _ => return Err(NativeSshError::UnsupportedOption(opt.clone())),
+            }
+        }
+
+        // As with `ssh`, the command line takes precedence, and identity files add up
+        destination.port = port.or(config.port).unwrap_or(22);
+        destination
+            .identity_files
+            .extend(config.identity_files.iter().map(|path| expand_home(path)));
+
+        Ok(destination)
+    }
+
+    fn set_option(&mut self, key: &str, value: &str, port: &mut Option<u16>) -> Result<(), NativeSshError> {
+        let unsupported = || NativeSshError::UnsupportedOption(format!("{}={}", key, value));
+        match key.to_lowercase().as_str() {
+            "port" => *port = Some(value.parse().map_err(|_| unsupported())?),
+            "identityfile" => self.identity_files.push(expand_home(value)),
+            "userknownhostsfile" => self.known_hosts = Some(expand_home(value)),
+            "stricthostkeychecking" => self.strict_host_key_checking = value != "no",
+            key if IGNORED_OPTIONS.contains(&key) => {
+                debug!("Ignoring SSH option `{}` with the native transport", key)
+            }
+            _ => return Err(unsupported()),
+        }
+        Ok(())
+    }
+
+    /// Checks that `ssh` wouldn't log in with identities the native transport can't use: security
+    /// keys or certificates, given with `-i` or found where `ssh` looks for them by default (in
+    /// `ssh_dir`)
+    pub fn check_identities(&self, ssh_dir: &std::path::Path) -> Result<(), NativeSshError> {
+        let identity_files = match self.identity_files.is_empty() {
+            true => {
+                if let Some(path) = DEFAULT_SECURITY_KEYS
+                    .iter()
+                    .map(|name| ssh_dir.join(name))
+                    .find(|path| path.exists())
+                {
+                    return Err(NativeSshError::UnsupportedKey(path, "security key"));
+                }
+                ["id_ed25519", "id_ecdsa", "id_rsa"]
+                    .iter()
+                    .map(|name| ssh_dir.join(name))
+                    .collect()
+            }
+            false => self.identity_files.clone(),
+        };
+
+        for path in identity_files {
+            let with_suffix = |suffix: &str| {
+                let mut name = path.clone().into_os_string();
+                name.push(suffix);
+                PathBuf::from(name)
+            };
+            // `ssh` offers `<identity>-cert.pub` along with the identity
+            if with_suffix("-cert.pub").exists() {
+                return Err(NativeSshError::UnsupportedKey(path, "certificate"));
+            }
+            let public_key = std::fs::read_to_string(with_suffix(".pub")).unwrap_or_default();
+            let key_type = public_key.split_whitespace().next().unwrap_or_default();
+            if key_type.starts_with("sk-") {
+                return Err(NativeSshError::UnsupportedKey(path, "security key"));
+            }
+            if key_type.contains("-cert-") {
+                return Err(NativeSshError::UnsupportedKey(path, "certificate"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The key sessions are reused by
+    fn key(&self) -> String {
+        format!("{}@{}:{}", self.user, self.host, self.port)
+    }
+}
+
+/// Where the commands deploying the profile are run with the native transport, or why it would
+/// connect to the node differently than `ssh` does, in which case `ssh` is used instead
+pub fn destination(
+    deploy_data: &crate::DeployData<'_>,
+    deploy_defs: &crate::DeployDefs,
+) -> Result<Destination, NativeSshError> {
+    // `sshJumpHost` and the nodes guests run on are given as ProxyJump, which would be refused
+    // below, but not as clearly
+    let jump_host = deploy_data
+        .merged_settings
+        .ssh_jump_host
+        .as_ref()
+        .or(deploy_data.node.node_settings.jump_host.as_ref());
+    if let Some(jump_host) = jump_host {
+        return Err(NativeSshError::JumpHost(jump_host.clone()));
+    }
+    if let Some(wrapper) = deploy_data.ssh_wrapper() {
+        return Err(NativeSshError::Wrapper(wrapper.to_vec()));
+    }
+    let config = crate::ssh_config::load(deploy_data.hostname)
+        .map(|(_, config)| config)
+        .unwrap_or_default();
+    if let Some(proxy_jump) = &config.proxy_jump {
+        return Err(NativeSshError::UnsupportedOption(format!(
+            "ProxyJump={} (from ~/.ssh/config)",
+            proxy_jump
+        )));
+    }
+    let destination = Destination::new(
+        &deploy_defs.ssh_user,
+        deploy_data.hostname,
+        &deploy_data.merged_settings.ssh_opts,
+        &config,
+    )?;
+    destination.check_identities(&expand_home("~/.ssh"))?;
+    Ok(destination)
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Runs `command` at `destination`, writing `stdin` to it, and emits it and every line it prints
+/// as events for `node` and `profile`
+pub async fn output(
+    destination: Destination,
+    command: String,
+    stdin: Option<String>,
+    node: String,
+    profile: String,
+) -> Result<Output, NativeSshError> {
+    crate::events::emit(
+        &node,
+        &profile,
+        crate::events::EventKind::Command(format!("ssh {} {:?}", destination.key(), command)),
+    );
+    run(destination, command, stdin, node, profile).await
+}
+
+#[cfg(not(feature = "native-ssh"))]
+async fn run(
+    _destination: Destination,
+    _command: String,
+    _stdin: Option<String>,
+    _node: String,
+    _profile: String,
+) -> Result<Output, NativeSshError> {
+    Err(NativeSshError::Unsupported)
+}
+
+#[cfg(feature = "native-ssh")]
+async fn run(
+    destination: Destination,
+    command: String,
+    stdin: Option<String>,
+    node: String,
+    profile: String,
+) -> Result<Output, NativeSshError> {
+    tokio::task::spawn_blocking(move || {
+        let session = session::get(&destination)?;
+        let channel = match session::exec(&session, &command) {
+            Ok(channel) => channel,
+            // A reused session may have been closed by the node in the meantime
+            Err(e) => {
+                debug!("Reconnecting to {} after: {}", destination.key(), e);
+                session::forget(&destination);
+                session::exec(&session::get(&destination)?, &command)
+                    .map_err(|e| NativeSshError::Session(destination.key(), e))?
+            }
+        };
+        session::communicate(channel, stdin, &node, &profile)
+    })
+    .await?
+}
+
+#[cfg(feature = "native-ssh")]
+mod session {
+    use std::collections::BTreeMap;
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use log::debug;
+    use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, Session};
+
+    use super::{Destination, NativeSshError};
+    use crate::events::{emit, EventKind};
+
+    /// The open sessions, by destination
+    static SESSIONS: Mutex<BTreeMap<String, Session>> = Mutex::new(BTreeMap::new());
+
+    /// How long to wait before polling a channel again when it has nothing to read
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+    /// The open session to `destination`, connecting if there is none
+    pub fn get(destination: &Destination) -> Result<Session, NativeSshError> {
+        let key = destination.key();
+        if let Some(session) = SESSIONS.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            debug!("Reusing the SSH session with {}", key);
+            return Ok(session.clone());
+        }
+
+        let session = connect(destination)?;
+        SESSIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key, session.clone());
+        Ok(session)
+    }
+
+    pub fn forget(destination: &Destination) {
+        SESSIONS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&destination.key());
+    }
+
+    fn connect(destination: &Destination) -> Result<Session, NativeSshError> {
+        let key = destination.key();
+        debug!("Connecting to {} with the native SSH transport", key);
+        let session_error = |e| NativeSshError::Session(key.clone(), e);
+
+        let host = destination.host.trim_start_matches('[').trim_end_matches(']');
+        let tcp = TcpStream::connect((host, destination.port))
+            .map_err(|e| NativeSshError::Connect(key.clone(), e))?;
+        let mut session = Session::new().map_err(session_error)?;
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(session_error)?;
+
+        check_host_key(&session, destination)?;
+
+        if session.userauth_agent(&destination.user).is_err() || !session.authenticated() {
+            let identity_files = match destination.identity_files.is_empty() {
+                true => ["id_ed25519", "id_ecdsa", "id_rsa"]
+                    .iter()
+                    .map(|name| super::expand_home(&format!("~/.ssh/{}", name)))
+                    .filter(|path| path.exists())
+                    .collect(),
+                false => destination.identity_files.clone(),
+            };
+            let authenticated = identity_files.iter().any(|path| {
+                session
+                    .userauth_pubkey_file(&destination.user, None, path, None)
+                    .is_ok()
+            });
+            if !authenticated {
+                return Err(NativeSshError::Auth(key, identity_files));
+            }
+        }
+
+        // Commands run at the same time (like activating and waiting for it) each poll their
+        // own channel of the session
+        session.set_blocking(false);
+        Ok(session)
+    }
+
+    fn check_host_key(session: &Session, destination: &Destination) -> Result<(), NativeSshError> {
+        let key = destination.key();
+        if !destination.strict_host_key_checking {
+            return Ok(());
+        }
+
+        let path = destination
+            .known_hosts
+            .clone()
+            .unwrap_or_else(|| super::expand_home("~/.ssh/known_hosts"));
+        let mut known_hosts = session
+            .known_hosts()
+            .map_err(|e| NativeSshError::Session(key.clone(), e))?;
+        // A missing file just knows no hosts
+        let _ = known_hosts.read_file(&path, KnownHostFileKind::OpenSSH);
+        let host_key = session.host_key().map(|(host_key, _)| host_key).unwrap_or_default();
+
+        match known_hosts.check_port(&destination.host, destination.port, host_key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(NativeSshError::ChangedHostKey(key, path.display().to_string())),
+            CheckResult::NotFound | CheckResult::Failure => {
+                Err(NativeSshError::UnknownHostKey(key, path.display().to_string()))
+            }
+        }
+    }
+
+    /// Runs `f` until it doesn't fail because the non-blocking session would block
+    fn retry<T>(mut f: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+        loop {
+            match f() {
+                // LIBSSH2_ERROR_EAGAIN
+                Err(e) if e.code() == ErrorCode::Session(-37) => std::thread::sleep(POLL_INTERVAL),
+                result => return result,
+            }
+        }
+    }
+
+    /// A channel of `session` running `command`
+    pub fn exec(session: &Session, command: &str) -> Result<Channel, ssh2::Error> {
+        let mut channel = retry(|| session.channel_session())?;
+        retry(|| channel.exec(command))?;
+        Ok(channel)
+    }
+
+    /// Reads from `reader` into `collected` until it would block, emitting each complete line
+    /// read, and returns whether the end of it was reached
+    fn read_available(
+        reader: &mut impl Read,
+        collected: &mut Vec<u8>,
+        node: &str,
+        profile: &str,
+    ) -> std::io::Result<bool> {
+        let mut buf = [0; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => {
+                    let line_start = collected.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                    if line_start < collected.len() {
+                        let line = String::from_utf8_lossy(&collected[line_start..]);
+                        emit(node, profile, EventKind::Output(line.trim_end_matches('\r').to_string()));
+                    }
+                    return Ok(true);
+                }
+                Ok(n) => {
+                    let line_start = collected.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                    collected.extend_from_slice(&buf[..n]);
+                    let mut lines = collected[line_start..].split(|&b| b == b'\n').peekable();
+                    while let Some(line) = lines.next() {
+                        // The last part isn't a complete line yet
+                        if lines.peek().is_some() {
+                            let line = String::from_utf8_lossy(line);
+                            emit(node, profile, EventKind::Output(line.trim_end_matches('\r').to_string()));
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes `stdin` to the command of `channel` and collects its output until it exits
+    pub fn communicate(
+        mut channel: Channel,
+        stdin: Option<String>,
+        node: &str,
+        profile: &str,
+    ) -> Result<Output, NativeSshError> {
+        let mut input = stdin.unwrap_or_default().into_bytes();
+        while !input.is_empty() {
+            match channel.write(&input) {
+                Ok(n) => input.drain(..n).for_each(drop),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        retry(|| channel.send_eof()).map_err(std::io::Error::from)?;
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let (mut stdout_done, mut stderr_done) = (false, false);
+        while !(stdout_done && stderr_done) {
+            if !stdout_done {
+                stdout_done = read_available(&mut channel, &mut stdout, node, profile)?;
+            }
+            if !stderr_done {
+                stderr_done = read_available(&mut channel.stderr(), &mut stderr, node, profile)?;
+            }
+            if !(stdout_done && stderr_done) {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+        retry(|| channel.wait_close()).map_err(std::io::Error::from)?;
+
+        let status = match retry(|| channel.exit_signal()).ok().and_then(|s| s.exit_signal) {
+            Some(signal) => ExitStatus::from_raw(signal_number(&signal)),
+            None => ExitStatus::from_raw(
+                retry(|| channel.exit_status()).map_err(std::io::Error::from)? << 8,
+            ),
+        };
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// The number of the signal named `name` (without `SIG`), `SIGKILL` if it isn't known
+    fn signal_number(name: &str) -> i32 {
+        match name {
+            "HUP" => 1,
+            "INT" => 2,
+            "QUIT" => 3,
+            "ABRT" => 6,
+            "SEGV" => 11,
+            "PIPE" => 13,
+            "TERM" => 15,
+            _ => 9,
+        }
+    }
+}
+
+#[test]
+fn test_destination() {
+    let opts = |opts: &[&str]| opts.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        Destination::new("deploy", "web1", &[], &HostConfig::default()).unwrap(),
+        Destination {
+            user: "deploy".to_string(),
+            host: "web1".to_string(),
+            port: 22,
+            identity_files: Vec::new(),
+            known_hosts: None,
+            strict_host_key_checking: true,
+        }
+    );
+
+    let destination = Destination::new(
+        "root",
+        "10.0.0.1",
+        &opts(&[
+            "-p",
+            "2222",
+            "-v",
+            "-i/etc/deploy-key",
+            "-o",
+            "StrictHostKeyChecking=no",
+            "-oUserKnownHostsFile=/dev/null",
+            "-o",
+            "Compression yes",
+        ]),
+        &HostConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(destination.port, 2222);
+    assert_eq!(destination.identity_files, vec![PathBuf::from("/etc/deploy-key")]);
+    assert_eq!(destination.known_hosts, Some(PathBuf::from("/dev/null")));
+    assert!(!destination.strict_host_key_checking);
+    assert_eq!(destination.key(), "root@10.0.0.1:2222");
+
+    assert_eq!(Destination::new("root", "web1", &opts(&["-o", "Port=2121"]), &HostConfig::default()).unwrap().port, 2121);
+
+    // The stanzas of ~/.ssh/config apply after the command line
+    let config = crate::ssh_config::resolve(
+        "Host web*\n  HostName %h.internal\n  Port 2222\n  IdentityFile /etc/web-key\n",
+        "web1",
+    );
+    let destination = Destination::new("root", "web1", &[], &config).unwrap();
+    assert_eq!(destination.key(), "root@web1.internal:2222");
+    assert_eq!(destination.identity_files, vec![PathBuf::from("/etc/web-key")]);
+    let destination = Destination::new("root", "web1", &opts(&["-p", "22", "-i", "/etc/deploy-key"]), &config).unwrap();
+    assert_eq!(destination.port, 22);
+    assert_eq!(
+        destination.identity_files,
+        vec![PathBuf::from("/etc/deploy-key"), PathBuf::from("/etc/web-key")]
+    );
+
+    // Options changing how (or where) ssh connects are refused rather than ignored
+    for refused in [
+        &["-J", "bastion"][..],
+        &["-oProxyJump=admin@bastion"],
+        &["-o", "ProxyCommand=ssh -W %h:%p bastion"],
+        &["-A"],
+        &["-o", "CertificateFile=~/.ssh/id_ed25519-cert.pub"],
+        &["-p", "ssh"],
+    ] {
+        assert!(
+            matches!(
+                Destination::new("root", "web1", &opts(refused), &HostConfig::default()),
+                Err(NativeSshError::UnsupportedOption(_))
+            ),
+            "{:?}",
+            refused
+        );
+    }
+}
+
+#[test]
+fn test_check_identities() {
+    let dir = std::env::temp_dir().join(format!("deploy-rs-test-identities-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let destination = |identity_files: &[&str]| Destination {
+        identity_files: identity_files.iter().map(|name| dir.join(name)).collect(),
+        ..Destination::new("root", "web1", &[], &HostConfig::default()).unwrap()
+    };
+
+    std::fs::write(dir.join("id_ed25519.pub"), "ssh-ed25519 AAAA deploy@laptop\n").unwrap();
+    std::fs::write(dir.join("yubikey.pub"), "sk-ssh-ed25519@openssh.com AAAA deploy@laptop\n").unwrap();
+    std::fs::write(dir.join("signed"), "").unwrap();
+    std::fs::write(dir.join("signed-cert.pub"), "ssh-ed25519-cert-v01@openssh.com AAAA\n").unwrap();
+
+    assert!(destination(&[]).check_identities(&dir).is_ok());
+    assert!(destination(&["id_ed25519"]).check_identities(&dir).is_ok());
+    assert!(matches!(
+        destination(&["yubikey"]).check_identities(&dir),
+        Err(NativeSshError::UnsupportedKey(_, "security key"))
+    ));
+    assert!(matches!(
+        destination(&["signed"]).check_identities(&dir),
+        Err(NativeSshError::UnsupportedKey(_, "certificate"))
+    ));
+
+    // ssh would try a security key of the default ones
+    std::fs::write(dir.join("id_ed25519_sk"), "").unwrap();
+    assert!(matches!(
+        destination(&[]).check_identities(&dir),
+        Err(NativeSshError::UnsupportedKey(_, "security key"))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,374 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Webhook notifications of how the deployment went (`notifications`), aggregated so deploying to
+//! a hundred nodes doesn't send a hundred messages.
+//!
+//! The outcome of each profile (activated, or failed in some phase) is collected as the events
+//! come in, and sent as a single message once the aggregation `window` is over (by a thread
+//! waiting for it, so the message doesn't wait for the next event): a message at most every
+//! `window` seconds, listing at most `batchSize` profiles each. Whatever is left (everything,
+//! without a window) is sent when the deployment is done. Messages are rendered from a `template`
+//! and POSTed with `curl` as `{"text": message}`, which Slack, Mattermost and most chat webhooks
+//! accept. Dry activations send nothing. Messages that can't be sent are reported once the
+//! deployment is done, as non-critical errors.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::events::{Event, EventKind, Phase};
+use crate::render::Renderer;
+
+/// The template of messages unless `template` is set
+pub const DEFAULT_TEMPLATE: &str = "deploy-rs: {succeeded} succeeded, {failed} failed\n{results}";
+
+/// How long sending a message may take, in seconds
+const SEND_TIMEOUT: u32 = 30;
+
+#[derive(Error, Debug)]
+pub enum NotificationError {
+    #[error("Failed to run curl to send a notification: {0}")]
+    Curl(std::io::Error),
+    #[error("Failed to send a notification: {0}")]
+    Send(String),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Notifications {
+    /// The webhook messages are POSTed to
+    pub url: String,
+    /// Seconds outcomes are collected for before they are sent, at most one message per window;
+    /// everything is sent once the deployment is done without one
+    pub window: Option<u64>,
+    /// Most profiles listed in one message, the others are sent in the following ones
+    #[serde(rename(deserialize = "batchSize"))]
+    pub batch_size: Option<usize>,
+    /// The message, with `{succeeded}`, `{failed}` and `{total}` replaced by the counts of
+    /// profiles and `{results}` by a line per profile
+    pub template: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Succeeded,
+    Failed(Phase, String),
+}
+
+/// A profile and how its deployment ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileResult {
+    pub node: String,
+    pub profile: String,
+    pub outcome: Outcome,
+}
+
+impl ProfileResult {
+    fn line(&self) -> String {
+        match &self.outcome {
+            Outcome::Succeeded => format!("✓ {}.{}", self.node, self.profile),
+            Outcome::Failed(phase, reason) => {
+                format!(
+                    "✗ {}.{} ({} failed: {})",
+                    self.node, self.profile, phase, reason
+                )
+            }
+        }
+    }
+}
+
+/// The message reporting `results` with `template`
+pub fn render_message(template: &str, results: &[ProfileResult]) -> String {
+    let succeeded = results
+        .iter()
+        .filter(|r| r.outcome == Outcome::Succeeded)
+        .count();
+    let lines: Vec<String> = results.iter().map(ProfileResult::line).collect();
+    template
+        .replace("{succeeded}", &succeeded.to_string())
+        .replace("{failed}", &(results.len() - succeeded).to_string())
+        .replace("{total}", &results.len().to_string())
+        .replace("{results}", &lines.join("\n"))
+}
+
+/// The outcomes not sent yet, shared with the thread sending them when the window is over
+#[derive(Default)]
+struct State {
+    pending: Vec<ProfileResult>,
+    first_pending: Option<Instant>,
+    last_sent: Option<Instant>,
+    finished: bool,
+    /// Why messages couldn't be sent
+    errors: Vec<NotificationError>,
+}
+
+impl State {
+    /// When the pending outcomes are due: a `window` after the first of them came in, and at
+    /// least a `window` after the last message
+    fn deadline(&self, window: Duration) -> Option<Instant> {
+        let collected = self.first_pending? + window;
+        Some(match self.last_sent {
+            Some(last) => collected.max(last + window),
+            None => collected,
+        })
+    }
+
+    /// Takes the next message to send: the oldest `batchSize` pending outcomes
+    fn next_message(&mut self, notifications: &Notifications) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let batch_size = notifications.batch_size.unwrap_or(usize::MAX).max(1);
+        let batch: Vec<ProfileResult> = self
+            .pending
+            .drain(..batch_size.min(self.pending.len()))
+            .collect();
+        let template = notifications
+            .template
+            .as_deref()
+            .unwrap_or(DEFAULT_TEMPLATE);
+        Some(render_message(template, &batch))
+    }
+}
+
+/// The errors sending the messages of a [`NotificationRenderer`], kept after it is handed over
+pub struct NotificationErrors(Arc<(Mutex<State>, Condvar)>);
+
+impl NotificationErrors {
+    /// Takes the errors so far, all of them once the renderer is finished
+    pub fn take(&self) -> Vec<NotificationError> {
+        let (lock, _) = &*self.0;
+        std::mem::take(&mut lock.lock().unwrap_or_else(|e| e.into_inner()).errors)
+    }
+}
+
+/// Sends the outcomes of the profiles of `nodes` to the `notifications` webhook, see the module
+/// docs
+pub struct NotificationRenderer {
+    notifications: Notifications,
+    nodes: Vec<String>,
+    state: Arc<(Mutex<State>, Condvar)>,
+    /// Sends the messages due while the deployment goes on, with a `window`
+    sender: Option<std::thread::JoinHandle<()>>,
+}
+
+impl NotificationRenderer {
+    pub fn new(notifications: Notifications, nodes: Vec<String>) -> Self {
+        let state = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let sender = notifications.window.map(|window| {
+            let notifications = notifications.clone();
+            let state = state.clone();
+            std::thread::spawn(move || {
+                send_when_due(&notifications, Duration::from_secs(window), &state)
+            })
+        });
+        NotificationRenderer {
+            notifications,
+            nodes,
+            state,
+            sender,
+        }
+    }
+
+    pub fn errors(&self) -> NotificationErrors {
+        NotificationErrors(self.state.clone())
+    }
+}
+
+/// Sends the pending outcomes whenever they are due, until the deployment is finished
+fn send_when_due(notifications: &Notifications, window: Duration, state: &(Mutex<State>, Condvar)) {
+    let (lock, changed) = state;
+    let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+    while !state.finished {
+        let now = Instant::now();
+        state = match state.deadline(window) {
+            None => changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            Some(deadline) if deadline > now => {
+                changed
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0
+            }
+            Some(_) => {
+                let message = state.next_message(notifications);
+                state.last_sent = Some(now);
+                state.first_pending = Some(now).filter(|_| !state.pending.is_empty());
+                drop(state);
+                let sent = message.map_or(Ok(()), |m| send_message(&notifications.url, &m));
+                let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+                state.errors.extend(sent.err());
+                state
+            }
+        };
+    }
+}
+
+impl Renderer for NotificationRenderer {
+    fn render(&mut self, event: &Event) {
+        let outcome = match &event.kind {
+            EventKind::Finished(Phase::Activate) => Outcome::Succeeded,
+            EventKind::Failed(phase, reason) => Outcome::Failed(*phase, reason.clone()),
+            _ => return,
+        };
+        if !self.nodes.contains(&event.node) {
+            return;
+        }
+
+        let (lock, changed) = &*self.state;
+        let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        state.pending.push(ProfileResult {
+            node: event.node.clone(),
+            profile: event.profile.clone(),
+            outcome,
+        });
+        state.first_pending.get_or_insert_with(Instant::now);
+        changed.notify_one();
+    }
+
+    fn finish(&mut self) {
+        let (lock, changed) = &*self.state;
+        lock.lock().unwrap_or_else(|e| e.into_inner()).finished = true;
+        changed.notify_one();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.join();
+        }
+
+        let mut state = lock.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(message) = state.next_message(&self.notifications) {
+            if let Err(e) = send_message(&self.notifications.url, &message) {
+                state.errors.push(e);
+            }
+        }
+    }
+}
+
+/// Quotes `value` for a curl config file
+fn curl_config_quote(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The curl config POSTing `message` to the webhook at `url`, given on stdin so the URL (which
+/// usually holds a secret) isn't in the command line others can see
+fn curl_config(url: &str, message: &str) -> String {
+    let body = serde_json::json!({ "text": message }).to_string();
+    format!(
+        "url = {}\ndata-binary = {}\n",
+        curl_config_quote(url),
+        curl_config_quote(&body)
+    )
+}
+
+/// POSTs `message` to the webhook at `url`
+fn send_message(url: &str, message: &str) -> Result<(), NotificationError> {
+    debug!("Sending a notification");
+
+    let mut command = Command::new("curl");
+    command
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--max-time")
+        .arg(SEND_TIMEOUT.to_string())
+        .arg("--header")
+        .arg("Content-Type: application/json")
+        .arg("--output")
+        .arg("/dev/null")
+        .arg("--config")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped());
+    let id = crate::trace::started(&command);
+    let output = command.spawn().and_then(|mut child| {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(curl_config(url, message).as_bytes())?;
+        }
+        child.wait_with_output()
+    });
+    crate::trace::exited(
+        id,
+        &output
+            .as_ref()
+            .map(|o| o.status)
+            .map_err(|e| std::io::Error::new(e.kind(), e.to_string())),
+    );
+    match output {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(NotificationError::Send(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        )),
+        Err(e) => Err(NotificationError::Curl(e)),
+    }
+}
+
+#[test]
+fn test_notifications() {
+    let result = |node: &str, outcome| ProfileResult {
+        node: node.to_string(),
+        profile: "system".to_string(),
+        outcome,
+    };
+    let results = vec![
+        result("web1", Outcome::Succeeded),
+        result(
+            "web2",
+            Outcome::Failed(Phase::Activate, "exit code 1".to_string()),
+        ),
+        result("web3", Outcome::Succeeded),
+    ];
+    assert_eq!(
+        render_message(DEFAULT_TEMPLATE, &results),
+        "deploy-rs: 2 succeeded, 1 failed\n✓ web1.system\n✗ web2.system (activate failed: exit code 1)\n✓ web3.system"
+    );
+    assert_eq!(
+        render_message("{failed}/{total} failed", &results),
+        "1/3 failed"
+    );
+
+    let notifications = Notifications {
+        url: "https://hooks.example.com/deploy".to_string(),
+        window: Some(60),
+        batch_size: Some(2),
+        template: Some("{total}".to_string()),
+    };
+    let window = Duration::from_secs(60);
+    let start = Instant::now();
+    let mut state = State {
+        pending: results,
+        first_pending: Some(start),
+        ..State::default()
+    };
+    assert_eq!(state.deadline(window), Some(start + window));
+    state.last_sent = Some(start + Duration::from_secs(30));
+    assert_eq!(
+        state.deadline(window),
+        Some(start + Duration::from_secs(90))
+    );
+
+    // Batches of at most `batchSize` profiles, oldest first
+    assert_eq!(state.next_message(&notifications).as_deref(), Some("2"));
+    assert_eq!(state.next_message(&notifications).as_deref(), Some("1"));
+    assert_eq!(state.next_message(&notifications), None);
+
+    assert_eq!(
+        curl_config("https://hooks.example.com/T0/B0/s3cr3t", "\"ok\"\n✓ web1"),
+        "url = \"https://hooks.example.com/T0/B0/s3cr3t\"\ndata-binary = \"{\\\"text\\\":\\\"\\\\\\\"ok\\\\\\\"\\\\n✓ web1\\\"}\"\n"
+    );
+}
@@ -0,0 +1,330 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Scheduling of the work done on the nodes.
+//!
+//! Everything opening SSH sessions (or `nix copy` connections) to a node goes through
+//! [`Orchestrator::connect`], which caps the number of simultaneous sessions across all nodes
+//! (`--max-connections`, so bastions don't start refusing them because of `MaxStartups` or
+//! fail2ban) and only lets one operation at a time work on each node. Pushes to nodes in the same
+//! `uplinkGroup` additionally run one at a time, so nodes behind the same thin link don't compete
+//! for it. Consecutive `parallel` profiles of a node are activated together, under one permit
+//! (see [`parallel_batch`]), and with a `rollout`, the profiles of several nodes are activated in
+//! waves (see [`rollout_wave`]).
+//!
+//! Deployments are pipelined across nodes: each closure is pushed to its nodes as soon as it is
+//! built (see [`build_groups`]), while the next ones build, and a profile is activated as soon as
+//! it is pushed and the profiles before it are activated, instead of the whole fleet going through
+//! each phase together.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+use tokio::sync::{OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+
+use crate::events::{emit, EventKind, Phase};
+use crate::push::{PushProfileData, PushProfileError};
+
+/// Held while an operation is connected to a node
+pub struct ConnectionPermit {
+    _node: OwnedMutexGuard<()>,
+    _sessions: OwnedSemaphorePermit,
+}
+
+pub struct Orchestrator {
+    max_connections: usize,
+    connections: Arc<Semaphore>,
+    nodes: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    uplink_groups: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// The bytes copied of each pushed profile, by node and profile
+    pushed: Mutex<HashMap<(String, String), u64>>,
+}
+
+fn lock_for(
+    locks: &Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    key: &str,
+) -> Arc<tokio::sync::Mutex<()>> {
+    locks
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry(key.to_string())
+        .or_default()
+        .clone()
+}
+
+impl Orchestrator {
+    pub fn new(max_connections: usize) -> Self {
+        let max_connections = max_connections.max(1);
+
+        Orchestrator {
+            max_connections,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            nodes: Mutex::new(HashMap::new()),
+            uplink_groups: Mutex::new(HashMap::new()),
+            pushed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until `node` is free and `sessions` more sessions fit in the budget
+    ///
+    /// An operation needing more sessions than the whole budget gets all of it.
+    pub async fn connect(&self, node: &str, sessions: usize) -> ConnectionPermit {
+        let node_lock = lock_for(&self.nodes, node);
+
+        debug!("[{}] Waiting for {} connection(s)", node, sessions);
+
+        let node_guard = node_lock.lock_owned().await;
+        let sessions = self
+            .connections
+            .clone()
+            .acquire_many_owned(sessions.clamp(1, self.max_connections) as u32)
+            .await
+            // The semaphore is never closed
+            .unwrap();
+
+        ConnectionPermit {
+            _node: node_guard,
+            _sessions: sessions,
+        }
+    }
+
+    /// Pushes a profile, once its node (and uplink group) is free and the budget allows
+    pub async fn push(&self, data: PushProfileData<'_>) -> Result<(), PushProfileError> {
+        let (node_name, profile_name) = (data.deploy_data.node_name, data.deploy_data.profile_name);
+
+        // Taken before connecting, so waiting for the uplink doesn't hold back other nodes
+        let _uplink = match &data.deploy_data.node.node_settings.uplink_group {
+            Some(group) => {
+                debug!("[{}] Waiting for uplink group {}", node_name, group);
+                Some(lock_for(&self.uplink_groups, group).lock_owned().await)
+            }
+            None => None,
+        };
+        let _permit = self.connect(node_name, 1).await;
+
+        emit(node_name, profile_name, EventKind::Started(Phase::Push));
+        match crate::push::push_profile(data).await {
+            Ok(bytes) => {
+                self.pushed
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert((node_name.to_string(), profile_name.to_string()), bytes);
+                emit(node_name, profile_name, EventKind::Finished(Phase::Push));
+                Ok(())
+            }
+            Err(e) => {
+                emit(
+                    node_name,
+                    profile_name,
+                    EventKind::Failed(Phase::Push, e.to_string()),
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// The bytes copied of each profile pushed so far, by node and profile (0 if they aren't
+    /// known, see [`crate::push::push_profile`])
+    pub fn pushed(&self) -> HashMap<(String, String), u64> {
+        self.pushed.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// A profile waiting to be activated, see [`parallel_batch`]
+pub struct PendingActivation<'a> {
+    pub node: &'a str,
+    pub parallel: bool,
+    pub requires: &'a HashMap<String, String>,
+}
+
+/// The number of `pending` profiles (in activation order) to activate together: the first one,
+/// and if it is `parallel`, the `parallel` ones of the same node following it, up to `max`. All of
+/// them may only require facts of the profiles in `activated`, not of each other.
+pub fn parallel_batch(
+    pending: &[PendingActivation<'_>],
+    activated: &[(&str, &str)],
+    max: usize,
+) -> usize {
+    let first = match pending.first() {
+        Some(x) => x,
+        None => return 0,
+    };
+    if !first.parallel {
+        return 1;
+    }
+
+    pending
+        .iter()
+        .take(max)
+        .take_while(|p| {
+            p.node == first.node
+                && p.parallel
+                && crate::facts::check_requires(p.requires, activated).is_ok()
+        })
+        .count()
+        .max(1)
+}
+
+/// The number of `pending` profiles (in activation order) to activate as one wave of a rolling
+/// deployment: those of the next (up to) `max_unavailable` nodes, as long as the profiles of each
+/// node follow each other. The first node always makes it in, the others only if their profiles
+/// require facts of the profiles in `activated` alone, not of each other.
+pub fn rollout_wave(
+    pending: &[PendingActivation<'_>],
+    activated: &[(&str, &str)],
+    max_unavailable: usize,
+) -> usize {
+    let mut nodes: Vec<&str> = Vec::new();
+    let mut end = 0;
+
+    while end < pending.len() && nodes.len() < max_unavailable.max(1) {
+        let node = pending[end].node;
+        if nodes.contains(&node) {
+            break;
+        }
+        let profiles = pending[end..]
+            .iter()
+            .take_while(|p| p.node == node)
+            .count();
+        let independent = pending[end..end + profiles]
+            .iter()
+            .all(|p| crate::facts::check_requires(p.requires, activated).is_ok());
+        if !independent && !nodes.is_empty() {
+            break;
+        }
+
+        nodes.push(node);
+        end += profiles;
+    }
+
+    end
+}
+
+/// The profiles (indices into `closures`, in activation order) to build each closure for, in the
+/// order to build them in: a closure built locally is built once for all profiles using it, a
+/// closure built remotely (`true`) once for each profile, on its node.
+pub fn build_groups(closures: &[(&str, bool)]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut local: HashMap<&str, usize> = HashMap::new();
+
+    for (i, (path, remote_build)) in closures.iter().enumerate() {
+        if *remote_build {
+            groups.push(vec![i]);
+            continue;
+        }
+        match local.get(path) {
+            Some(&group) => groups[group].push(i),
+            None => {
+                local.insert(path, groups.len());
+                groups.push(vec![i]);
+            }
+        }
+    }
+
+    groups
+}
+
+#[test]
+fn test_parallel_batch() {
+    let none = HashMap::new();
+    let mut needs_api = HashMap::new();
+    needs_api.insert("API".to_string(), "app.api.path".to_string());
+
+    let pending = |profiles: &[(&'static str, bool)], requires| -> Vec<PendingActivation<'_>> {
+        profiles
+            .iter()
+            .map(|(profile, parallel)| PendingActivation {
+                node: "app",
+                parallel: *parallel,
+                requires: match *profile == "worker" {
+                    true => requires,
+                    false => &none,
+                },
+            })
+            .collect()
+    };
+
+    let profiles = [("api", true), ("worker", true), ("web", true), ("system", false)];
+    assert_eq!(parallel_batch(&pending(&profiles, &none), &[], 8), 3);
+    assert_eq!(parallel_batch(&pending(&profiles, &none), &[], 2), 2);
+    assert_eq!(parallel_batch(&pending(&profiles[3..], &none), &[], 8), 1);
+    // The worker needs the api activated first
+    assert_eq!(parallel_batch(&pending(&profiles, &needs_api), &[], 8), 1);
+    assert_eq!(
+        parallel_batch(&pending(&profiles[1..], &needs_api), &[("app", "api")], 8),
+        2
+    );
+}
+
+#[test]
+fn test_rollout_wave() {
+    let none = HashMap::new();
+    let mut needs_db = HashMap::new();
+    needs_db.insert("DB".to_string(), "db1.postgres.path".to_string());
+
+    let pending: Vec<PendingActivation<'_>> = [
+        ("web1", &none),
+        ("web1", &none),
+        ("web2", &none),
+        ("web3", &needs_db),
+        ("web4", &none),
+        ("web1", &none),
+    ]
+    .iter()
+    .map(|(node, requires)| PendingActivation {
+        node,
+        parallel: false,
+        requires,
+    })
+    .collect();
+
+    assert_eq!(rollout_wave(&pending, &[], 2), 3);
+    // web3 needs db1 activated first
+    assert_eq!(rollout_wave(&pending, &[], 8), 3);
+    assert_eq!(rollout_wave(&pending, &[("db1", "postgres")], 8), 5);
+    assert_eq!(rollout_wave(&pending[3..], &[], 8), 3);
+    assert_eq!(rollout_wave(&pending, &[], 0), 2);
+    assert_eq!(rollout_wave(&[], &[], 2), 0);
+}
+
+#[test]
+fn test_build_groups() {
+    let closures = [
+        ("/nix/store/aaaa-system", false),
+        ("/nix/store/bbbb-system", false),
+        ("/nix/store/aaaa-system", false),
+        ("/nix/store/aaaa-system", true),
+        ("/nix/store/cccc-system", true),
+        ("/nix/store/bbbb-system", false),
+    ];
+    assert_eq!(
+        build_groups(&closures),
+        vec![vec![0, 2], vec![1, 5], vec![3], vec![4]]
+    );
+    assert!(build_groups(&[]).is_empty());
+}
+
+#[tokio::test]
+async fn test_connection_budget() {
+    use std::time::Duration;
+
+    let orchestrator = Orchestrator::new(2);
+
+    let web1 = orchestrator.connect("web1", 1).await;
+    let _web2 = orchestrator.connect("web2", 1).await;
+
+    // The budget is used up
+    assert!(tokio::time::timeout(Duration::from_millis(50), orchestrator.connect("web3", 1))
+        .await
+        .is_err());
+
+    drop(web1);
+
+    // web1 is free again, but only one operation may work on a node at a time
+    let _web1 = orchestrator.connect("web1", 1).await;
+    assert!(tokio::time::timeout(Duration::from_millis(50), orchestrator.connect("web1", 1))
+        .await
+        .is_err());
+}
@@ -0,0 +1,237 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Confirmations of magic rollback activations still outstanding, so that they can be completed
+//! with `deploy confirm --resume <run id>` if deploy-rs panics or is killed while a node waits.
+//!
+//! The command confirming a profile is recorded in `pending-confirmations/<run id>/` in the state
+//! directory before the activation is started, updated with the deadline of the confirmation once
+//! the node reports it, and removed only after the confirmation succeeded or the activation
+//! failed. Each record is a file of its own, written atomically, so a crash at any point leaves
+//! either no activation waiting or a record to confirm it with.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::trace;
+
+#[derive(Error, Debug)]
+pub enum PendingConfirmError {
+    #[error("Failed to read the pending confirmations of run {0} from {1}: {2}")]
+    Read(String, PathBuf, std::io::Error),
+    #[error("Failed to parse the pending confirmation {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("Run {0} has no pending confirmations (in {1})")]
+    NothingPending(String, PathBuf),
+    #[error("Failed to run the confirmation of profile {1} of node {0}: {2}")]
+    Run(String, String, std::io::Error),
+    #[error("Confirming profile {1} of node {0} resulted in a bad exit code (it may have been rolled back already): {2:?}")]
+    Exit(String, String, Option<i32>),
+}
+
+/// The directory holding the pending confirmations of run `run_id`
+pub fn run_dir(run_id: &str) -> PathBuf {
+    crate::state::path("pending-confirmations").join(run_id)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingConfirmation {
+    pub node: String,
+    pub profile: String,
+    /// The program and arguments removing the canary file of the activation on the node
+    pub command: Vec<String>,
+    /// Whether the command reads the sudo password from stdin
+    pub interactive_sudo: bool,
+    /// When the node rolls back without a confirmation, in seconds since the epoch, once known
+    pub deadline: Option<u64>,
+}
+
+impl PendingConfirmation {
+    pub fn new(node: &str, profile: &str, command: Vec<String>, interactive_sudo: bool) -> Self {
+        PendingConfirmation {
+            node: node.to_string(),
+            profile: profile.to_string(),
+            command,
+            interactive_sudo,
+            deadline: None,
+        }
+    }
+
+    fn path(&self, run_id: &str) -> PathBuf {
+        run_dir(run_id).join(format!("{}.{}.json", self.node, self.profile))
+    }
+
+    /// Records the confirmation as pending in run `run_id`, until the returned guard is dropped
+    pub fn save(self, run_id: &str) -> PendingGuard {
+        let guard = PendingGuard {
+            pending: self,
+            run_id: run_id.to_string(),
+            keep: false,
+        };
+        guard.write();
+        guard
+    }
+
+    /// Loads the pending confirmations of run `run_id`
+    pub fn load_run(run_id: &str) -> Result<Vec<Self>, PendingConfirmError> {
+        let dir = run_dir(run_id);
+        let read_dir = match std::fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(PendingConfirmError::NothingPending(run_id.to_string(), dir))
+            }
+            Err(e) => return Err(PendingConfirmError::Read(run_id.to_string(), dir, e)),
+        };
+
+        let mut pending = Vec::new();
+        for entry in read_dir {
+            let path = entry
+                .map_err(|e| PendingConfirmError::Read(run_id.to_string(), dir.clone(), e))?
+                .path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            let content = std::fs::read_to_string(&path)
+                .map_err(|e| PendingConfirmError::Read(run_id.to_string(), path.clone(), e))?;
+            pending.push(
+                serde_json::from_str(&content).map_err(|e| PendingConfirmError::Parse(path, e))?,
+            );
+        }
+
+        if pending.is_empty() {
+            return Err(PendingConfirmError::NothingPending(run_id.to_string(), dir));
+        }
+        Ok(pending)
+    }
+
+    /// The seconds left until the node rolls back, if the deadline is known
+    pub fn remaining(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.deadline.map(|deadline| deadline.saturating_sub(now))
+    }
+
+    /// Runs the confirmation, piping in `sudo_password` if it reads one
+    pub async fn confirm(&self, sudo_password: Option<&str>) -> Result<(), PendingConfirmError> {
+        let run_error = |e| PendingConfirmError::Run(self.node.clone(), self.profile.clone(), e);
+
+        let mut child = trace::spawn(
+            Command::new(&self.command[0])
+                .args(&self.command[1..])
+                .stdin(std::process::Stdio::piped()),
+        )
+        .map_err(run_error)?;
+        if let (Some(stdin), Some(password)) = (child.stdin.as_mut(), sudo_password) {
+            stdin
+                .write_all(format!("{}\n", password).as_bytes())
+                .await
+                .map_err(run_error)?;
+        }
+        drop(child.stdin.take());
+
+        let status = trace::wait(&mut child).await.map_err(run_error)?;
+        match status.code() {
+            Some(0) => Ok(()),
+            a => Err(PendingConfirmError::Exit(
+                self.node.clone(),
+                self.profile.clone(),
+                a,
+            )),
+        }
+    }
+
+    /// Removes the record of the confirmation from run `run_id`, and the run's directory once empty
+    pub fn remove(&self, run_id: &str) {
+        let path = self.path(run_id);
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove the pending confirmation {}: {}", path.display(), e);
+            }
+        }
+        let _ = std::fs::remove_dir(run_dir(run_id));
+    }
+}
+
+/// Keeps a confirmation recorded as pending: dropping it removes the record, unless the
+/// confirmation failed (see [`PendingGuard::keep`]) or the thread is panicking
+pub struct PendingGuard {
+    pending: PendingConfirmation,
+    run_id: String,
+    keep: bool,
+}
+
+impl PendingGuard {
+    fn write(&self) {
+        let path = self.pending.path(&self.run_id);
+        let result = serde_json::to_string_pretty(&self.pending)
+            .map_err(std::io::Error::other)
+            .and_then(|content| crate::state::write(&path, &content));
+        match result {
+            Ok(()) => debug!("Recorded the pending confirmation in {}", path.display()),
+            Err(e) => warn!(
+                "Failed to record the pending confirmation in {}, it can't be resumed: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Records when the node rolls back without a confirmation
+    pub fn set_deadline(&mut self, remaining: u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.pending.deadline = Some(now + remaining);
+        self.write();
+    }
+
+    /// Keeps the record once dropped, for `deploy confirm --resume`
+    pub fn keep(&mut self) {
+        self.keep = true;
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if self.keep || std::thread::panicking() {
+            return;
+        }
+        self.pending.remove(&self.run_id);
+    }
+}
+
+#[test]
+fn test_pending_confirmation() {
+    let dir = std::env::temp_dir().join(format!("deployrspending{}", std::process::id()));
+    crate::state::set_dir(dir.clone());
+
+    let command = vec!["sh".to_string(), "-c".to_string(), "true".to_string()];
+    let pending = PendingConfirmation::new("web1", "system", command, false);
+
+    let mut guard = pending.clone().save("run1");
+    guard.set_deadline(30);
+    let loaded = PendingConfirmation::load_run("run1").unwrap();
+    assert_eq!(loaded.len(), 1);
+    assert!((29..=30).contains(&loaded[0].remaining().unwrap()));
+
+    // A failed confirmation stays pending
+    guard.keep();
+    drop(guard);
+    assert!(PendingConfirmation::load_run("run1").is_ok());
+
+    pending.remove("run1");
+    assert!(matches!(
+        PendingConfirmation::load_run("run1"),
+        Err(PendingConfirmError::NothingPending(..))
+    ));
+    let _ = std::fs::remove_dir_all(dir);
+}
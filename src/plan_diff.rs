@@ -0,0 +1,271 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Comparing a plan with the one of an earlier deployment (`deploy plan --against`).
+//!
+//! Every deployment records what it was going to deploy in its state (see
+//! [`crate::run_state`]): the closure, hostname and the settings that decide how each profile is
+//! deployed. A new plan is compared with the one of the last deployment, or of any run still in
+//! the deployment history, to catch nodes, profiles or settings that changed by accident before
+//! anything is deployed.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::data::{FastConnection, GenericSettings};
+use crate::run_state::{RunState, RUN_STATE_VERSION};
+use crate::DeployData;
+
+#[derive(Error, Debug)]
+pub enum PlanDiffError {
+    #[error("Failed to read the deployment to compare with from {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the deployment to compare with: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported state version {0} (expected {})", RUN_STATE_VERSION)]
+    Version(u32),
+    #[error("Run {0} didn't record its plan (it was made by an older deploy-rs)")]
+    NoPlan(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedProfile {
+    pub node: String,
+    pub profile: String,
+    pub hostname: String,
+    pub path: String,
+    /// The settings the profile is deployed with, by name, leaving out unset ones
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+impl PlannedProfile {
+    pub fn new(deploy_data: &DeployData<'_>) -> Self {
+        PlannedProfile {
+            node: deploy_data.node_name.to_string(),
+            profile: deploy_data.profile_name.to_string(),
+            hostname: deploy_data.hostname.to_string(),
+            path: deploy_data.profile.profile_settings.path.clone(),
+            settings: compared_settings(&deploy_data.merged_settings),
+        }
+    }
+}
+
+/// The settings changes to which are worth pointing out, by their name in the deployment data
+fn compared_settings(settings: &GenericSettings) -> BTreeMap<String, String> {
+    let mut compared = BTreeMap::new();
+    let mut add = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            compared.insert(name.to_string(), value);
+        }
+    };
+
+    add("sshUser", settings.ssh_user.clone());
+    add("user", settings.user.clone());
+    add("sudo", settings.sudo.clone());
+    add(
+        "sshOpts",
+        Some(crate::settings::join_ssh_opts(&settings.ssh_opts)).filter(|opts| !opts.is_empty()),
+    );
+    add(
+        "fastConnection",
+        settings.fast_connection.map(|fast_connection| {
+            match fast_connection {
+                FastConnection::Fast => "true",
+                FastConnection::Slow => "false",
+                FastConnection::Auto => "auto",
+            }
+            .to_string()
+        }),
+    );
+    add("autoRollback", settings.auto_rollback.map(|x| x.to_string()));
+    add("magicRollback", settings.magic_rollback.map(|x| x.to_string()));
+    add("confirmTimeout", settings.confirm_timeout.map(|x| x.to_string()));
+    add("activationTimeout", settings.activation_timeout.map(|x| x.to_string()));
+    add("remoteBuild", settings.remote_build.map(|x| x.to_string()));
+    add("interactiveSudo", settings.interactive_sudo.map(|x| x.to_string()));
+    add("profileEngine", settings.profile_engine.map(|x| x.as_str().to_string()));
+    add(
+        "pushStrategy",
+        settings.push_strategy.map(|x| format!("{:?}", x).to_lowercase()),
+    );
+    for (option, value) in &settings.nix_options {
+        add(&format!("nixOptions.{}", option), Some(value.clone()));
+    }
+
+    compared
+}
+
+/// The plan recorded by the last deployment (`last`) or by run `against` in the history
+pub fn load(against: &str) -> Result<Vec<PlannedProfile>, PlanDiffError> {
+    let path = match against {
+        "last" => crate::run_state::state_path(),
+        run_id => crate::state::history_dir().join(format!("{}.json", run_id)),
+    };
+    let state: RunState = serde_json::from_str(
+        &std::fs::read_to_string(&path).map_err(|e| PlanDiffError::Read(path, e))?,
+    )?;
+
+    if state.version != RUN_STATE_VERSION {
+        return Err(PlanDiffError::Version(state.version));
+    }
+    if state.plan.is_empty() {
+        return Err(PlanDiffError::NoPlan(state.run_id));
+    }
+
+    Ok(state.plan)
+}
+
+fn or_unset(value: Option<&String>) -> &str {
+    value.map_or("unset", String::as_str)
+}
+
+/// The differences between the plans `old` and `new`, a line each
+pub fn diff(old: &[PlannedProfile], new: &[PlannedProfile]) -> Vec<String> {
+    let nodes = |plan: &[PlannedProfile]| -> BTreeMap<String, Vec<PlannedProfile>> {
+        let mut nodes: BTreeMap<String, Vec<PlannedProfile>> = BTreeMap::new();
+        for profile in plan {
+            nodes.entry(profile.node.clone()).or_default().push(profile.clone());
+        }
+        nodes
+    };
+    let (old_nodes, new_nodes) = (nodes(old), nodes(new));
+
+    let mut changes = Vec::new();
+    for node in old_nodes.keys().filter(|node| !new_nodes.contains_key(*node)) {
+        changes.push(format!("node {} removed", node));
+    }
+    for (node, new_profiles) in &new_nodes {
+        let old_profiles = match old_nodes.get(node) {
+            Some(x) => x,
+            None => {
+                changes.push(format!("node {} added", node));
+                continue;
+            }
+        };
+
+        let (old_hostname, new_hostname) = (&old_profiles[0].hostname, &new_profiles[0].hostname);
+        if old_hostname != new_hostname {
+            changes.push(format!(
+                "hostname changed for {}: {} -> {}",
+                node, old_hostname, new_hostname
+            ));
+        }
+
+        for old_profile in old_profiles {
+            if !new_profiles.iter().any(|p| p.profile == old_profile.profile) {
+                changes.push(format!("profile {} of node {} removed", old_profile.profile, node));
+            }
+        }
+        for new_profile in new_profiles {
+            let old_profile = match old_profiles.iter().find(|p| p.profile == new_profile.profile) {
+                Some(x) => x,
+                None => {
+                    changes.push(format!("profile {} of node {} added", new_profile.profile, node));
+                    continue;
+                }
+            };
+
+            if old_profile.path != new_profile.path {
+                changes.push(format!(
+                    "closure changed for profile {} of node {}: {} -> {}",
+                    new_profile.profile, node, old_profile.path, new_profile.path
+                ));
+            }
+
+            let mut names: Vec<&String> = old_profile
+                .settings
+                .keys()
+                .chain(new_profile.settings.keys())
+                .collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let (old_value, new_value) =
+                    (old_profile.settings.get(name), new_profile.settings.get(name));
+                if old_value != new_value {
+                    changes.push(format!(
+                        "{} changed for profile {} of node {}: {} -> {}",
+                        name,
+                        new_profile.profile,
+                        node,
+                        or_unset(old_value),
+                        or_unset(new_value)
+                    ));
+                }
+            }
+        }
+    }
+
+    changes
+}
+
+#[test]
+fn test_plan_diff() {
+    use crate::data::Data;
+
+    let data: Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "magicRollback": false,
+                "nixOptions": { "max-jobs": 4 },
+                "profiles": { "system": { "path": "/nix/store/aaaa-system" } },
+            },
+        },
+    }))
+    .unwrap();
+    let cmd_overrides = crate::CmdOverrides::default();
+    let node = &data.nodes["web1"];
+    let deploy_data = crate::make_deploy_data(
+        &data.generic_settings,
+        None,
+        node,
+        "web1",
+        &node.node_settings.profiles["system"],
+        "system",
+        &cmd_overrides,
+        false,
+        None,
+    );
+    let web1 = PlannedProfile::new(&deploy_data);
+    assert_eq!(web1.hostname, "web1.example.com");
+    assert_eq!(
+        web1.settings.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>(),
+        vec!["magicRollback=false", "nixOptions.max-jobs=4", "sshUser=deploy"]
+    );
+
+    let profile = |node: &str, profile: &str| PlannedProfile {
+        node: node.to_string(),
+        profile: profile.to_string(),
+        hostname: format!("{}.example.com", node),
+        ..web1.clone()
+    };
+    let old = vec![web1.clone(), profile("db1", "system")];
+    assert!(diff(&old, &old).is_empty());
+
+    let mut changed_web1 = web1.clone();
+    changed_web1.path = "/nix/store/bbbb-system".to_string();
+    changed_web1.hostname = "10.0.0.1".to_string();
+    changed_web1.settings.insert("sshUser".to_string(), "admin".to_string());
+    changed_web1.settings.remove("magicRollback");
+    let new = vec![changed_web1, profile("web1", "app"), profile("db2", "system")];
+
+    assert_eq!(
+        diff(&old, &new),
+        vec![
+            "node db1 removed",
+            "node db2 added",
+            "hostname changed for web1: web1.example.com -> 10.0.0.1",
+            "closure changed for profile system of node web1: /nix/store/aaaa-system -> /nix/store/bbbb-system",
+            "magicRollback changed for profile system of node web1: false -> unset",
+            "sshUser changed for profile system of node web1: deploy -> admin",
+            "profile app of node web1 added",
+        ]
+    );
+}
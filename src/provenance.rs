@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Provenance of closures (`allowedSigners`): proof that every path of a closure was signed by a
+//! trusted cache, i.e. that nothing in it was built on the deploying machine.
+//!
+//! Once a closure is built and before it is pushed, the signatures of all its paths are verified
+//! with `nix store verify --recursive --sigs-needed 1` (in the node's store for remote builds),
+//! trusting only the public keys in `allowedSigners` (`name:key`, as in `trusted-public-keys`).
+//! The profile isn't deployed if any path has no valid signature by one of them.
+
+use std::process::Stdio;
+
+use log::{debug, info};
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::trace;
+use crate::{DeployData, DeployDefs};
+
+/// How many unsigned paths are listed in the error
+const LISTED_PATHS: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum ProvenanceError {
+    #[error("`{0}` in allowedSigners isn't a public key, expected `name:key` as in trusted-public-keys")]
+    NotAKey(String),
+    #[error("Failed to run `nix store verify`: {0}")]
+    Verify(std::io::Error),
+    #[error("`nix store verify` resulted in a bad exit code: {0:?}")]
+    VerifyExit(Option<i32>),
+    #[error("{}", describe_unsigned(.0, .1, .2))]
+    Unsigned(Vec<String>, String, Vec<String>),
+}
+
+fn describe_unsigned(unsigned: &[String], closure: &str, allowed_signers: &[String]) -> String {
+    let mut listed = unsigned[..unsigned.len().min(LISTED_PATHS)].join(", ");
+    if unsigned.len() > LISTED_PATHS {
+        listed.push_str(", ...");
+    }
+    let names: Vec<&str> = allowed_signers
+        .iter()
+        .map(|key| key.split_once(':').map_or(key.as_str(), |(name, _)| name))
+        .collect();
+    format!(
+        "{} of the closure {} {} not signed by any of the allowed signers ({}): {}",
+        match unsigned.len() {
+            1 => "1 path".to_string(),
+            n => format!("{} paths", n),
+        },
+        closure,
+        if unsigned.len() == 1 { "is" } else { "are" },
+        names.join(", "),
+        listed
+    )
+}
+
+/// The paths `nix store verify` reports as lacking a valid signature by a trusted key in its
+/// `stderr`
+pub fn untrusted_paths(stderr: &str) -> Vec<String> {
+    stderr
+        .lines()
+        .filter_map(|line| {
+            let path = line.trim().strip_prefix("path '")?;
+            let (path, rest) = path.split_once('\'')?;
+            match rest.trim() {
+                "is untrusted" => Some(path.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Checks that the whole closure of the profile is signed by one of its `allowedSigners`
+pub async fn check(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    allowed_signers: &[String],
+) -> Result<(), ProvenanceError> {
+    if let Some(key) = allowed_signers.iter().find(|key| !key.contains(':')) {
+        return Err(ProvenanceError::NotAKey(key.clone()));
+    }
+
+    let closure = &deploy_data.profile.profile_settings.path;
+    info!(
+        "Checking the signatures of the closure of profile `{}` for node `{}`",
+        deploy_data.profile_name, deploy_data.node_name
+    );
+
+    let mut command = Command::new("nix");
+    command
+        .arg("--extra-experimental-features")
+        .arg("nix-command")
+        .arg("store")
+        .arg("verify")
+        .arg("--recursive")
+        .arg("--sigs-needed")
+        .arg("1")
+        // Replaces the keys trusted by the configuration of Nix, rather than adding to them
+        .arg("--trusted-public-keys")
+        .arg(allowed_signers.join(" "))
+        .arg(closure);
+    // Remotely built closures are only in the store of the node
+    if deploy_data.merged_settings.remote_build.unwrap_or(false) {
+        crate::ssh_wrapper::wrap(&mut command, deploy_data.ssh_wrapper())
+            .map_err(ProvenanceError::Verify)?;
+        command
+            .arg("--store")
+            .arg(format!("ssh-ng://{}@{}", deploy_defs.ssh_user, deploy_data.hostname))
+            .env(
+                "NIX_SSHOPTS",
+                crate::settings::join_ssh_opts(&deploy_data.ssh_opts()),
+            );
+    }
+
+    let output = trace::output(command.stdin(Stdio::null()).stderr(Stdio::piped()))
+        .await
+        .map_err(ProvenanceError::Verify)?;
+    crate::log_child_output("nix store verify", &output);
+    if output.status.success() {
+        debug!("The closure {} is signed by allowed signers", closure);
+        return Ok(());
+    }
+
+    let unsigned = untrusted_paths(&String::from_utf8_lossy(&output.stderr));
+    if unsigned.is_empty() {
+        return Err(ProvenanceError::VerifyExit(output.status.code()));
+    }
+    Err(ProvenanceError::Unsigned(
+        unsigned,
+        closure.clone(),
+        allowed_signers.to_vec(),
+    ))
+}
+
+#[test]
+fn test_untrusted_paths() {
+    let allowed = vec![
+        "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY=".to_string(),
+        "cache.example.com-1:eQ==".to_string(),
+    ];
+
+    let stderr = "path '/nix/store/bbbb-app' is untrusted\n\
+        warning: something else\n\
+        path '/nix/store/cccc-system' was modified! expected hash 'sha256:aaaa', got 'sha256:bbbb'\n\
+        path '/nix/store/dddd-config' is untrusted\n\
+        2 paths are untrusted\n";
+    assert_eq!(
+        untrusted_paths(stderr),
+        vec!["/nix/store/bbbb-app", "/nix/store/dddd-config"]
+    );
+    assert!(untrusted_paths("").is_empty());
+
+    let error = ProvenanceError::Unsigned(
+        (0..7).map(|i| format!("/nix/store/{}-p", i)).collect(),
+        "/nix/store/eeee-system".to_string(),
+        allowed,
+    );
+    assert_eq!(
+        error.to_string(),
+        "7 paths of the closure /nix/store/eeee-system are not signed by any of the allowed signers (cache.nixos.org-1, cache.example.com-1): /nix/store/0-p, /nix/store/1-p, /nix/store/2-p, /nix/store/3-p, /nix/store/4-p, ..."
+    );
+}
@@ -2,13 +2,102 @@
 //
 // SPDX-License-Identifier: MPL-2.0
 
-use log::{debug, info};
-use std::collections::HashMap;
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
 
+use crate::data::{FastConnection, PushStrategy};
+use crate::deploy::shell_quote;
+use crate::events::{emit, wait_with_output_events, EventKind, Phase, SpawnWithEvents};
+use crate::resources::{ResourceUsage, UsageMonitor};
+use crate::severity::ExitCategory;
+use crate::trace;
+
+/// A common reason for Nix failing to build or copy a closure, recognized from what it printed
+#[derive(Debug, Clone, PartialEq)]
+pub enum NixFailure {
+    SignatureRejected,
+    UntrustedUser,
+    OutOfSpace,
+    NetworkReset,
+    UnknownFlag(String),
+}
+
+impl NixFailure {
+    /// The failure Nix reported in `stderr`, if it is one of the recognized ones
+    pub fn recognize(stderr: &str) -> Option<Self> {
+        // Warnings (like ignored restricted settings) don't explain the failure
+        for line in stderr.lines().filter(|l| !l.trim_start().starts_with("warning:")) {
+            let line = line.to_lowercase();
+            if let Some(i) = line
+                .find("unrecognised flag '")
+                .or_else(|| line.find("unrecognized flag '"))
+            {
+                let flag = line[i..].split('\'').nth(1).unwrap_or_default();
+                return Some(NixFailure::UnknownFlag(flag.to_string()));
+            }
+            if line.contains("lacks a signature by a trusted key")
+                || line.contains("lacks a valid signature")
+            {
+                return Some(NixFailure::SignatureRejected);
+            }
+            if line.contains("not a trusted user") || line.contains("you are not privileged") {
+                return Some(NixFailure::UntrustedUser);
+            }
+            if line.contains("no space left on device") {
+                return Some(NixFailure::OutOfSpace);
+            }
+            if line.contains("connection reset by peer")
+                || line.contains("broken pipe")
+                || line.contains("connection closed by")
+            {
+                return Some(NixFailure::NetworkReset);
+            }
+        }
+        None
+    }
+
+    /// A one-line suggestion for fixing the failure
+    pub fn hint(&self) -> String {
+        match self {
+            NixFailure::SignatureRejected => "sign the closure with a key in trusted-public-keys of the node (see `LOCAL_KEY`), add the SSH user to its trusted-users or set pushStrategy = \"sftp\"".to_string(),
+            NixFailure::UntrustedUser => "add the SSH user to trusted-users in nix.conf of the node or set pushStrategy = \"sftp\", which imports the closure as root".to_string(),
+            NixFailure::OutOfSpace => "free up space on the node, e.g. with `nix-collect-garbage --delete-older-than 30d`".to_string(),
+            NixFailure::NetworkReset => "check the connection to the node and deploy again, `--retry-failed` skips what already succeeded".to_string(),
+            NixFailure::UnknownFlag(flag) => format!("`{}` isn't supported by this version of Nix, update it or remove the flag", flag),
+        }
+    }
+
+    /// The kind of problem the failure is, for the exit code of deploy
+    pub fn category(&self) -> ExitCategory {
+        match self {
+            NixFailure::SignatureRejected
+            | NixFailure::UntrustedUser
+            | NixFailure::UnknownFlag(_) => ExitCategory::Configuration,
+            NixFailure::OutOfSpace => ExitCategory::Node,
+            NixFailure::NetworkReset => ExitCategory::Network,
+        }
+    }
+}
+
+impl std::fmt::Display for NixFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixFailure::SignatureRejected => write!(f, "the node rejected the signatures of the closure"),
+            NixFailure::UntrustedUser => write!(f, "the SSH user isn't trusted by Nix on the node"),
+            NixFailure::OutOfSpace => write!(f, "no space left on the device"),
+            NixFailure::NetworkReset => write!(f, "the connection was reset"),
+            NixFailure::UnknownFlag(flag) => write!(f, "Nix doesn't know the flag `{}`", flag),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum PushProfileError {
     #[error("Failed to run Nix show-derivation command: {0}")]
@@ -25,6 +114,8 @@ pub enum PushProfileError {
     Build(std::io::Error),
     #[error("Nix build command resulted in a bad exit code: {0:?}")]
     BuildExit(Option<i32>),
+    #[error("Nix build command failed, {0}\nHint: {hint}", hint = .0.hint())]
+    BuildFailure(NixFailure),
     #[error(
         "Activation script deploy-rs-activate does not exist in profile.\n\
              Did you forget to use deploy-rs#lib.<...>.activate.<...> on your profile path?"
@@ -41,11 +132,56 @@ pub enum PushProfileError {
     Copy(std::io::Error),
     #[error("Nix copy command resulted in a bad exit code: {0:?}")]
     CopyExit(Option<i32>),
+    #[error("Nix copy command failed, {0}\nHint: {hint}", hint = .0.hint())]
+    CopyFailure(NixFailure),
+    #[error("Nix copy command made no progress for {0}s, giving up after {1} attempt(s)")]
+    CopyStalled(u16, u16),
     #[error("The remote building option is not supported when using legacy nix")]
     RemoteBuildWithLegacyNix,
 
     #[error("Failed to run Nix path-info command: {0}")]
     PathInfo(std::io::Error),
+    #[error("Failed to run the Nix command adding the result link: {0}")]
+    AddRoot(std::io::Error),
+    #[error("The Nix command adding the result link resulted in a bad exit code: {0:?}")]
+    AddRootExit(Option<i32>),
+
+    #[error("{0}")]
+    Strategy(#[from] crate::push_strategy::PushStrategyError),
+}
+
+impl PushProfileError {
+    /// The kind of problem the error is, for the exit code of deploy
+    pub fn category(&self) -> ExitCategory {
+        match self {
+            PushProfileError::BuildFailure(failure) | PushProfileError::CopyFailure(failure) => {
+                failure.category()
+            }
+            PushProfileError::CopyStalled(..) => ExitCategory::Network,
+            _ => ExitCategory::Failure,
+        }
+    }
+}
+
+fn emit_usage(node: &str, profile: &str, phase: Phase, usage: ResourceUsage) {
+    debug!("[{}] Resources used by the {} of profile {}: {}", node, phase, profile, usage);
+    emit(node, profile, EventKind::Usage(phase, usage));
+}
+
+/// The error for Nix build exiting with `code`, explained if possible from its `stderr`
+fn build_exit_error(code: Option<i32>, stderr: &[u8]) -> PushProfileError {
+    match NixFailure::recognize(&String::from_utf8_lossy(stderr)) {
+        Some(failure) => PushProfileError::BuildFailure(failure),
+        None => PushProfileError::BuildExit(code),
+    }
+}
+
+/// The error for Nix copy exiting with `code`, explained if possible from its `stderr`
+fn copy_exit_error(code: Option<i32>, stderr: &[u8]) -> PushProfileError {
+    match NixFailure::recognize(&String::from_utf8_lossy(stderr)) {
+        Some(failure) => PushProfileError::CopyFailure(failure),
+        None => PushProfileError::CopyExit(code),
+    }
 }
 
 pub struct PushProfileData<'a> {
@@ -59,6 +195,40 @@ pub struct PushProfileData<'a> {
     pub extra_build_args: &'a [String],
 }
 
+fn result_link(data: &PushProfileData<'_>) -> String {
+    format!(
+        "{}/{}/{}",
+        data.result_path.unwrap_or("./.deploy-gc"),
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name
+    )
+}
+
+/// Adds the result link `--keep-result` asks for to a closure already built for another profile
+pub async fn add_result_link(data: &PushProfileData<'_>) -> Result<(), PushProfileError> {
+    let link = result_link(data);
+    if let Some(parent) = Path::new(&link).parent() {
+        std::fs::create_dir_all(parent).map_err(PushProfileError::AddRoot)?;
+    }
+
+    let status = trace::status(
+        Command::new("nix-store")
+            .arg("--add-root")
+            .arg(&link)
+            .arg("--indirect")
+            .arg("--realise")
+            .arg(&data.deploy_data.profile.profile_settings.path)
+            .stdout(Stdio::null()),
+    )
+    .await
+    .map_err(PushProfileError::AddRoot)?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(PushProfileError::AddRootExit(a)),
+    }
+}
+
 pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name: &str) -> Result<(), PushProfileError> {
     info!(
         "Building profile `{}` for node `{}`",
@@ -78,30 +248,38 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
     };
 
     match (data.keep_result, data.supports_flakes) {
-        (true, _) => {
-            let result_path = data.result_path.unwrap_or("./.deploy-gc");
-
-            build_command.arg("--out-link").arg(format!(
-                "{}/{}/{}",
-                result_path, data.deploy_data.node_name, data.deploy_data.profile_name
-            ))
-        }
+        (true, _) => build_command.arg("--out-link").arg(result_link(data)),
         (false, false) => build_command.arg("--no-out-link"),
         (false, true) => build_command.arg("--no-link"),
     };
 
     build_command.args(data.extra_build_args);
 
-    let build_exit_status = build_command
+    let build_child = build_command
         // Logging should be in stderr, this just stops the store path from printing for no reason
         .stdout(Stdio::null())
-        .status()
-        .await
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn_with_events(data.deploy_data.node_name, data.deploy_data.profile_name)
         .map_err(PushProfileError::Build)?;
+    let monitor = UsageMonitor::start(build_child.id());
+    let build_output = wait_with_output_events(
+        build_child,
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+    )
+    .await
+    .map_err(PushProfileError::Build)?;
+    emit_usage(
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+        Phase::Build,
+        monitor.finish(0),
+    );
 
-    match build_exit_status.code() {
+    match build_output.status.code() {
         Some(0) => (),
-        a => return Err(PushProfileError::BuildExit(a)),
+        a => return Err(build_exit_error(a, &build_output.stderr)),
     };
 
     if !Path::new(
@@ -134,17 +312,26 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
-        let sign_exit_status = Command::new("nix")
+        let sign_child = Command::new("nix")
             .arg("sign-paths")
             .arg("-r")
             .arg("-k")
             .arg(local_key)
             .arg(&data.deploy_data.profile.profile_settings.path)
-            .status()
-            .await
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn_with_events(data.deploy_data.node_name, data.deploy_data.profile_name)
             .map_err(PushProfileError::Sign)?;
+        let sign_output = wait_with_output_events(
+            sign_child,
+            data.deploy_data.node_name,
+            data.deploy_data.profile_name,
+        )
+        .await
+        .map_err(PushProfileError::Sign)?;
 
-        match sign_exit_status.code() {
+        match sign_output.status.code() {
             Some(0) => (),
             a => return Err(PushProfileError::SignExit(a)),
         };
@@ -152,58 +339,223 @@ pub async fn build_profile_locally(data: &PushProfileData<'_>, derivation_name:
     Ok(())
 }
 
+/// How much data is sent to measure the throughput of a connection for `fastConnection = "auto"`
+const PROBE_SIZE: usize = 1 << 20;
+
+/// Throughput (in bytes per second) from which copying whole closures is assumed to be faster
+/// than the node substituting paths itself
+const FAST_THROUGHPUT: f64 = 10_000_000.0;
+
+/// Whether the connection to each node measured so far is fast, by node name
+static MEASURED_CONNECTIONS: Mutex<BTreeMap<String, bool>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionProbe {
+    /// How long it took to connect and run a command
+    pub round_trip: Duration,
+    /// Bytes per second, after connecting
+    pub throughput: f64,
+}
+
+impl ConnectionProbe {
+    pub fn is_fast(&self) -> bool {
+        self.throughput >= FAST_THROUGHPUT
+    }
+}
+
+/// Data that doesn't compress, so ssh compression doesn't skew the measurement
+fn probe_data() -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..PROBE_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Measures the round trip of running a command on the node and the throughput of sending data to it
+pub async fn probe_connection(data: &PushProfileData<'_>) -> Result<ConnectionProbe, std::io::Error> {
+    let ssh_addr = format!("{}@{}", data.deploy_defs.ssh_user, data.deploy_data.hostname);
+    let ssh = |command: &str| {
+        let mut ssh_command = data.deploy_data.ssh_command();
+        ssh_command
+            .arg(&ssh_addr)
+            .args(data.deploy_data.ssh_opts())
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        ssh_command
+    };
+    let check = |status: std::process::ExitStatus| match status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!("ssh exited with {}", status))),
+    };
+
+    let start = Instant::now();
+    check(trace::status(&mut ssh("true")).await?)?;
+    let round_trip = start.elapsed();
+
+    let start = Instant::now();
+    let mut child = trace::spawn(&mut ssh("cat > /dev/null"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&probe_data()).await?;
+    }
+    check(trace::wait(&mut child).await?)?;
+    let transfer = start
+        .elapsed()
+        .saturating_sub(round_trip)
+        .max(Duration::from_millis(1));
+
+    Ok(ConnectionProbe {
+        round_trip,
+        throughput: PROBE_SIZE as f64 / transfer.as_secs_f64(),
+    })
+}
+
+/// Whether the connection to the node is fast, measuring it once per node for `"auto"`
+async fn is_fast_connection(data: &PushProfileData<'_>) -> bool {
+    let node_name = data.deploy_data.node_name;
+
+    match data.deploy_data.merged_settings.fast_connection {
+        Some(FastConnection::Fast) => return true,
+        Some(FastConnection::Slow) | None => return false,
+        Some(FastConnection::Auto) => (),
+    }
+
+    let measured = MEASURED_CONNECTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(node_name)
+        .copied();
+    if let Some(fast) = measured {
+        return fast;
+    }
+
+    let fast = match probe_connection(data).await {
+        Ok(probe) => {
+            info!(
+                "Connection to node `{}`: {:.0} ms round trip, {:.1} MB/s, {}",
+                node_name,
+                probe.round_trip.as_secs_f64() * 1000.0,
+                probe.throughput / 1_000_000.0,
+                match probe.is_fast() {
+                    true => "copying whole closures",
+                    false => "letting the node substitute and compressing the rest",
+                }
+            );
+            probe.is_fast()
+        }
+        Err(e) => {
+            warn!(
+                "Failed to measure the connection to node `{}`, treating it as slow: {}",
+                node_name, e
+            );
+            false
+        }
+    };
+
+    MEASURED_CONNECTIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(node_name.to_string(), fast);
+    fast
+}
+
+/// `--option` arguments for the `nixOptions` of the node, for the nix invocations touching it
+pub(crate) fn nix_option_args(data: &PushProfileData<'_>) -> Vec<String> {
+    data.deploy_data
+        .merged_settings
+        .nix_options
+        .iter()
+        .flat_map(|(name, value)| vec!["--option".to_string(), name.clone(), value.clone()])
+        .collect()
+}
+
 pub async fn build_profile_remotely(data: &PushProfileData<'_>, derivation_name: &str) -> Result<(), PushProfileError> {
     info!(
         "Building profile `{}` for node `{}` on remote host",
         data.deploy_data.profile_name, data.deploy_data.node_name
     );
 
-    // TODO: this should probably be handled more nicely during 'data' construction
-    let hostname = match data.deploy_data.cmd_overrides.hostname {
-        Some(ref x) => x,
-        None => &data.deploy_data.node.node_settings.hostname,
-    };
-    let store_address = format!("ssh-ng://{}@{}", data.deploy_defs.ssh_user, hostname);
+    let hostname = data.deploy_data.hostname;
+    let mut store_address = format!("ssh-ng://{}@{}", data.deploy_defs.ssh_user, hostname);
+    if data.deploy_defs.single_user_store {
+        store_address.push_str("?remote-store=local");
+    }
 
-    let ssh_opts_str = data.deploy_data.merged_settings.ssh_opts.join(" ");
+    let ssh_opts_str = crate::settings::join_ssh_opts(&data.deploy_data.ssh_opts());
 
 
     // copy the derivation to remote host so it can be built there
-    let copy_command_status = Command::new("nix").arg("copy")
+    let mut copy_command = Command::new("nix");
+    crate::ssh_wrapper::wrap(&mut copy_command, data.deploy_data.ssh_wrapper())
+        .map_err(PushProfileError::Copy)?;
+    let copy_command_child = copy_command.arg("copy")
         .arg("-s")  // fetch dependencies from substitures, not localhost
         .arg("--to").arg(&store_address)
         .arg("--derivation").arg(derivation_name)
+        .args(nix_option_args(data))
         .env("NIX_SSHOPTS", ssh_opts_str.clone())
         .stdout(Stdio::null())
-        .status()
-        .await
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn_with_events(data.deploy_data.node_name, data.deploy_data.profile_name)
         .map_err(PushProfileError::Copy)?;
+    let copy_command_output = wait_with_output_events(
+        copy_command_child,
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+    )
+    .await
+    .map_err(PushProfileError::Copy)?;
 
-    match copy_command_status.code() {
+    match copy_command_output.status.code() {
         Some(0) => (),
-        a => return Err(PushProfileError::CopyExit(a)),
+        a => return Err(copy_exit_error(a, &copy_command_output.stderr)),
     };
 
     let mut build_command = Command::new("nix");
+    crate::ssh_wrapper::wrap(&mut build_command, data.deploy_data.ssh_wrapper())
+        .map_err(PushProfileError::Build)?;
     build_command
         .arg("build").arg(derivation_name)
         .arg("--eval-store").arg("auto")
         .arg("--store").arg(&store_address)
         .args(data.extra_build_args)
+        .args(nix_option_args(data))
         .env("NIX_SSHOPTS", ssh_opts_str.clone());
 
     debug!("build command: {:?}", build_command);
 
-    let build_exit_status = build_command
+    let build_child = build_command
         // Logging should be in stderr, this just stops the store path from printing for no reason
         .stdout(Stdio::null())
-        .status()
-        .await
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn_with_events(data.deploy_data.node_name, data.deploy_data.profile_name)
         .map_err(PushProfileError::Build)?;
+    let monitor = UsageMonitor::start(build_child.id());
+    let build_output = wait_with_output_events(
+        build_child,
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+    )
+    .await
+    .map_err(PushProfileError::Build)?;
+    emit_usage(
+        data.deploy_data.node_name,
+        data.deploy_data.profile_name,
+        Phase::Build,
+        monitor.finish(0),
+    );
 
-    match build_exit_status.code() {
+    match build_output.status.code() {
         Some(0) => (),
-        a => return Err(PushProfileError::BuildExit(a)),
+        a => return Err(build_exit_error(a, &build_output.stderr)),
     };
 
 
@@ -223,8 +575,7 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         .arg("show-derivation")
         .arg(&data.deploy_data.profile.profile_settings.path);
 
-    let show_derivation_output = show_derivation_command
-        .output()
+    let show_derivation_output = trace::output(&mut show_derivation_command)
         .await
         .map_err(PushProfileError::ShowDerivation)?;
 
@@ -252,12 +603,14 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         deriver.to_owned()
     };
 
-    let path_info_output = Command::new("nix")
-        .arg("--experimental-features").arg("nix-command")
-        .arg("path-info")
-        .arg(&deriver)
-        .output().await
-        .map_err(PushProfileError::PathInfo)?;
+    let path_info_output = trace::output(
+        Command::new("nix")
+            .arg("--experimental-features").arg("nix-command")
+            .arg("path-info")
+            .arg(deriver),
+    )
+    .await
+    .map_err(PushProfileError::PathInfo)?;
 
     let deriver = if std::str::from_utf8(&path_info_output.stdout).map(|s| s.trim()) == Ok(deriver) {
         // In this case we're on 2.15.0 or newer, because 'nix path-infonix path-info <...>.drv'
@@ -274,42 +627,325 @@ pub async fn build_profile(data: PushProfileData<'_>) -> Result<(), PushProfileE
         // 'error: path '...' is not valid'.
         deriver
     };
-    if data.deploy_data.merged_settings.remote_build.unwrap_or(false) {
+    if data.deploy_data.merged_settings.remote_build.unwrap_or(false) && !data.deploy_defs.local {
         if !data.supports_flakes {
             return Err(PushProfileError::RemoteBuildWithLegacyNix)
         }
 
-        build_profile_remotely(&data, &deriver).await?;
+        build_profile_remotely(&data, deriver).await?;
     } else {
-        build_profile_locally(&data, &deriver).await?;
+        build_profile_locally(&data, deriver).await?;
+    }
+
+    Ok(())
+}
+
+/// A line of Nix's structured log (`--log-format internal-json`)
+#[derive(Debug, PartialEq)]
+enum NixLogLine {
+    /// A message Nix would have printed at the default verbosity
+    Message(String),
+    /// Bytes (or paths) done of an activity, by its id, reported as it goes
+    Progress(u64, u64),
+    /// The start of copying a path, whose progress is in bytes, by the id of the activity
+    CopyStarted(u64),
+    /// Anything else Nix reports, which still shows it is doing something
+    Activity,
+}
+
+fn parse_nix_log_line(line: &str) -> NixLogLine {
+    let json = match line.strip_prefix("@nix ") {
+        Some(json) => json,
+        // Printed by something other than Nix's logger, e.g. ssh
+        None => return NixLogLine::Message(line.to_string()),
+    };
+    let value: serde_json::Value = match serde_json::from_str(json) {
+        Ok(x) => x,
+        Err(_) => return NixLogLine::Activity,
+    };
+
+    match value["action"].as_str() {
+        // Levels above 3 (info) are only shown with `-v`
+        Some("msg") if value["level"].as_u64().is_some_and(|l| l <= 3) => {
+            NixLogLine::Message(value["msg"].as_str().unwrap_or_default().to_string())
+        }
+        // Result type 105 is the progress of an activity: done, expected, running, failed
+        Some("result") if value["type"].as_u64() == Some(105) => {
+            NixLogLine::Progress(
+                value["id"].as_u64().unwrap_or(0),
+                value["fields"][0].as_u64().unwrap_or(0),
+            )
+        }
+        // Activity type 100 is copying a path
+        Some("start") if value["type"].as_u64() == Some(100) => {
+            NixLogLine::CopyStarted(value["id"].as_u64().unwrap_or(0))
+        }
+        _ => NixLogLine::Activity,
+    }
+}
+
+enum CopyOutcome {
+    /// With the messages Nix printed
+    Exited(Option<i32>, Vec<String>),
+    Stalled,
+}
+
+/// Runs `copy_command` (a `nix copy` with `--log-format internal-json`), killing it once Nix
+/// reports nothing for `stall_timeout`. The bytes it copies are added to `pushed` (those copied
+/// of the profile by earlier attempts), and sent as push events while it runs.
+async fn copy_until_stalled(
+    copy_command: &mut Command,
+    data: &PushProfileData<'_>,
+    stall_timeout: Duration,
+    pushed: &mut u64,
+) -> Result<CopyOutcome, PushProfileError> {
+    let (node_name, profile_name) = (data.deploy_data.node_name, data.deploy_data.profile_name);
+
+    let mut copy_child = copy_command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn_with_events(node_name, profile_name)
+        .map_err(PushProfileError::Copy)?;
+    let monitor = UsageMonitor::start(copy_child.id());
+    let mut lines = BufReader::new(copy_child.stderr.take().unwrap()).lines();
+
+    let mut done = 0;
+    // Bytes copied so far of each path, by the id of its activity
+    let mut copied: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut messages = Vec::new();
+    let mut last_pushed_event = Instant::now();
+    loop {
+        match tokio::time::timeout(stall_timeout, lines.next_line()).await {
+            Err(_) => {
+                warn!(
+                    "Copying profile `{}` to node `{}` made no progress for {}s (after {} done), killing it",
+                    profile_name,
+                    node_name,
+                    stall_timeout.as_secs(),
+                    done
+                );
+                let _ = copy_child.kill().await;
+                *pushed += copied.values().sum::<u64>();
+                emit(node_name, profile_name, EventKind::Pushed(*pushed));
+                emit_usage(node_name, profile_name, Phase::Push, monitor.finish(copied.values().sum()));
+                return Ok(CopyOutcome::Stalled);
+            }
+            Ok(Err(e)) => return Err(PushProfileError::Copy(e)),
+            Ok(Ok(None)) => break,
+            Ok(Ok(Some(line))) => match parse_nix_log_line(&line) {
+                NixLogLine::Message(msg) => {
+                    messages.push(msg.clone());
+                    emit(node_name, profile_name, EventKind::Output(msg))
+                }
+                NixLogLine::Progress(id, x) => {
+                    done = done.max(x);
+                    if let Some(bytes) = copied.get_mut(&id) {
+                        *bytes = x;
+                        if last_pushed_event.elapsed() >= Duration::from_secs(1) {
+                            last_pushed_event = Instant::now();
+                            let so_far = *pushed + copied.values().sum::<u64>();
+                            emit(node_name, profile_name, EventKind::Pushed(so_far));
+                        }
+                    }
+                }
+                NixLogLine::CopyStarted(id) => {
+                    copied.insert(id, 0);
+                }
+                NixLogLine::Activity => (),
+            },
+        }
     }
 
+    let status = trace::wait(&mut copy_child).await.map_err(PushProfileError::Copy)?;
+    *pushed += copied.values().sum::<u64>();
+    emit(node_name, profile_name, EventKind::Pushed(*pushed));
+    emit_usage(node_name, profile_name, Phase::Push, monitor.finish(copied.values().sum()));
+    Ok(CopyOutcome::Exited(status.code(), messages))
+}
+
+#[test]
+fn test_parse_nix_log_line() {
+    assert_eq!(
+        parse_nix_log_line(r#"@nix {"action":"msg","level":0,"msg":"error: cannot connect"}"#),
+        NixLogLine::Message("error: cannot connect".to_string())
+    );
+    assert_eq!(
+        parse_nix_log_line(r#"@nix {"action":"msg","level":5,"msg":"using cached data"}"#),
+        NixLogLine::Activity
+    );
+    assert_eq!(
+        parse_nix_log_line(
+            r#"@nix {"action":"result","fields":[4096,65536,1,0],"id":12,"type":105}"#
+        ),
+        NixLogLine::Progress(12, 4096)
+    );
+    assert_eq!(
+        parse_nix_log_line(r#"@nix {"action":"start","id":12,"level":4,"type":100}"#),
+        NixLogLine::CopyStarted(12)
+    );
+    assert_eq!(
+        parse_nix_log_line(r#"@nix {"action":"start","id":11,"level":4,"type":103}"#),
+        NixLogLine::Activity
+    );
+    assert_eq!(
+        parse_nix_log_line("ssh: connect to host web1 port 22: Connection refused"),
+        NixLogLine::Message("ssh: connect to host web1 port 22: Connection refused".to_string())
+    );
+}
+
+/// Whether each closure is on each relay host yet, by relay and closure, locked while copying it
+/// there so that the nodes sharing it wait for a single copy
+#[allow(clippy::type_complexity)]
+static RELAYED: Mutex<BTreeMap<(String, String), Arc<tokio::sync::Mutex<bool>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Copies the closure of `data` to `relay`, unless it is there already
+async fn copy_to_relay(data: &PushProfileData<'_>, relay: &str) -> Result<(), PushProfileError> {
+    let path = &data.deploy_data.profile.profile_settings.path;
+    let lock = RELAYED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .entry((relay.to_string(), path.clone()))
+        .or_default()
+        .clone();
+    let mut relayed = lock.lock().await;
+    if *relayed {
+        debug!("The closure {} is on relay `{}` already", path, relay);
+        return Ok(());
+    }
+
+    info!(
+        "Copying profile `{}` of node `{}` to relay `{}`",
+        data.deploy_data.profile_name, data.deploy_data.node_name, relay
+    );
+
+    let mut copy_command = Command::new("nix");
+    copy_command.arg("copy");
+    if !data.check_sigs {
+        copy_command.arg("--no-check-sigs");
+    }
+    copy_command
+        .arg("--log-format")
+        .arg("internal-json")
+        .arg("--to")
+        .arg(format!("ssh://{}", relay))
+        .arg(path)
+        .args(nix_option_args(data));
+
+    let stall_timeout = data.deploy_data.merged_settings.push_stall_timeout.unwrap_or(600);
+    match copy_until_stalled(&mut copy_command, data, Duration::from_secs(stall_timeout as u64), &mut 0).await? {
+        CopyOutcome::Exited(Some(0), _) => (),
+        CopyOutcome::Exited(a, messages) => return Err(copy_exit_error(a, messages.join("\n").as_bytes())),
+        CopyOutcome::Stalled => return Err(PushProfileError::CopyStalled(stall_timeout, 1)),
+    }
+
+    *relayed = true;
     Ok(())
 }
 
-pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileError> {
-    let ssh_opts_str = data
+/// The command run on the relay host to copy `path` on to the store at `store_address`, with
+/// the SSH options of the profile
+fn build_relay_copy_command(
+    store_address: &str,
+    ssh_opts: &[String],
+    check_sigs: bool,
+    path: &str,
+) -> String {
+    format!(
+        "NIX_SSHOPTS={} nix --extra-experimental-features nix-command copy{} --to {} {}",
+        shell_quote(&crate::settings::join_ssh_opts(ssh_opts)),
+        if check_sigs { "" } else { " --no-check-sigs" },
+        shell_quote(store_address),
+        shell_quote(path)
+    )
+}
+
+/// Copies the closure of `data` to `relay` (once for all nodes) and from there to the node
+async fn push_through_relay(data: &PushProfileData<'_>, relay: &str) -> Result<(), PushProfileError> {
+    copy_to_relay(data, relay).await?;
+
+    let (node_name, profile_name) = (data.deploy_data.node_name, data.deploy_data.profile_name);
+    info!(
+        "Copying profile `{}` to node `{}` from relay `{}`",
+        profile_name, node_name, relay
+    );
+
+    let relay_copy_command = build_relay_copy_command(
+        &format!("ssh://{}@{}", data.deploy_defs.ssh_user, data.deploy_data.hostname),
+        &data.deploy_data.merged_settings.ssh_opts,
+        data.check_sigs,
+        &data.deploy_data.profile.profile_settings.path,
+    );
+    debug!("Constructed relay copy command: {}", relay_copy_command);
+
+    // Forwarding the agent lets the relay log in to the node as we do
+    let child = data
         .deploy_data
-        .merged_settings
-        .ssh_opts
-        // This should provide some extra safety, but it also breaks for some reason, oh well
-        // .iter()
-        // .map(|x| format!("'{}'", x))
-        // .collect::<Vec<String>>()
-        .join(" ");
+        .ssh_command()
+        .arg("-A")
+        .arg(relay)
+        .arg(relay_copy_command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn_with_events(node_name, profile_name)
+        .map_err(PushProfileError::Copy)?;
+    let output = wait_with_output_events(child, node_name, profile_name)
+        .await
+        .map_err(PushProfileError::Copy)?;
+
+    match output.status.code() {
+        Some(0) => Ok(()),
+        a => Err(copy_exit_error(a, &output.stderr)),
+    }
+}
+
+/// Copies the closure of the profile to its node, returning the bytes copied (0 if they aren't
+/// known, e.g. for a node built on remotely or pushed to with another strategy)
+pub async fn push_profile(data: PushProfileData<'_>) -> Result<u64, PushProfileError> {
+    let ssh_opts_str = crate::settings::join_ssh_opts(&data.deploy_data.ssh_opts());
+
+    if data.deploy_defs.local {
+        info!(
+            "Node `{}` is this machine, not copying profile `{}`",
+            data.deploy_data.node_name, data.deploy_data.profile_name
+        );
+        return Ok(0);
+    }
 
     // remote building guarantees that the resulting derivation is stored on the target system
     // no need to copy after building
     if !data.deploy_data.merged_settings.remote_build.unwrap_or(false) {
+        match data.deploy_data.merged_settings.push_strategy.unwrap_or_default() {
+            PushStrategy::Copy => (),
+            strategy => {
+                info!(
+                    "Pushing profile `{}` to node `{}` with the {:?} strategy",
+                    data.deploy_data.profile_name, data.deploy_data.node_name, strategy
+                );
+                crate::push_strategy::push(&data, strategy).await?;
+                return Ok(0);
+            }
+        }
+
+        if let Some(relay) = &data.deploy_data.merged_settings.relay_host {
+            push_through_relay(&data, relay).await?;
+            return Ok(0);
+        }
+
         info!(
             "Copying profile `{}` to node `{}`",
             data.deploy_data.profile_name, data.deploy_data.node_name
         );
 
+        let fast_connection = is_fast_connection(&data).await;
+
         let mut copy_command = Command::new("nix");
         copy_command.arg("copy");
 
-        if data.deploy_data.merged_settings.fast_connection != Some(true) {
+        if !fast_connection {
             copy_command.arg("--substitute-on-destination");
         }
 
@@ -317,25 +953,138 @@ pub async fn push_profile(data: PushProfileData<'_>) -> Result<(), PushProfileEr
             copy_command.arg("--no-check-sigs");
         }
 
-        let hostname = match data.deploy_data.cmd_overrides.hostname {
-            Some(ref x) => x,
-            None => &data.deploy_data.node.node_settings.hostname,
-        };
+        let hostname = data.deploy_data.hostname;
+        let mut store_address = format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname);
+        let mut ssh_opts_str = ssh_opts_str;
+        let node_settings = &data.deploy_data.node.node_settings;
+        if let (true, Some(jump_host)) = (node_settings.host_store, &node_settings.jump_host) {
+            info!(
+                "Copying to `{}` instead, node `{}` shares its store",
+                jump_host, data.deploy_data.node_name
+            );
+            store_address = format!("ssh://{}", jump_host);
+            ssh_opts_str = crate::settings::join_ssh_opts(&crate::data::without_guest_jump(
+                &data.deploy_data.ssh_opts(),
+                jump_host,
+            ));
+        }
+        let mut store_params = Vec::new();
+        // Only measured slow connections are compressed, so nothing changes for configured ones
+        if data.deploy_data.merged_settings.fast_connection == Some(FastConnection::Auto)
+            && !fast_connection
+        {
+            store_params.push("compress=true");
+        }
+        // The node writes to its store directly instead of trying to reach a daemon, as the owner
+        // of the store (who needn't be a trusted user)
+        if data.deploy_defs.single_user_store {
+            store_params.push("remote-store=local");
+        }
+        if !store_params.is_empty() {
+            store_address = format!("{}?{}", store_address, store_params.join("&"));
+        }
 
-        let copy_exit_status = copy_command
+        crate::ssh_wrapper::wrap(&mut copy_command, data.deploy_data.ssh_wrapper())
+            .map_err(PushProfileError::Copy)?;
+        copy_command
+            .arg("--log-format")
+            .arg("internal-json")
             .arg("--to")
-            .arg(format!("ssh://{}@{}", data.deploy_defs.ssh_user, hostname))
+            .arg(store_address)
             .arg(&data.deploy_data.profile.profile_settings.path)
-            .env("NIX_SSHOPTS", ssh_opts_str)
-            .status()
-            .await
-            .map_err(PushProfileError::Copy)?;
+            .args(nix_option_args(&data))
+            .env("NIX_SSHOPTS", ssh_opts_str);
 
-        match copy_exit_status.code() {
-            Some(0) => (),
-            a => return Err(PushProfileError::CopyExit(a)),
-        };
+        let stall_timeout = data.deploy_data.merged_settings.push_stall_timeout.unwrap_or(600);
+        let retries = data.deploy_data.merged_settings.push_stall_retries.unwrap_or(2) as u16;
+
+        let mut attempt: u16 = 0;
+        let mut pushed = 0;
+        loop {
+            attempt += 1;
+            match copy_until_stalled(
+                &mut copy_command,
+                &data,
+                Duration::from_secs(stall_timeout as u64),
+                &mut pushed,
+            )
+            .await?
+            {
+                CopyOutcome::Exited(Some(0), _) => break,
+                CopyOutcome::Exited(a, messages) => {
+                    return Err(copy_exit_error(a, messages.join("\n").as_bytes()))
+                }
+                CopyOutcome::Stalled if attempt > retries => {
+                    return Err(PushProfileError::CopyStalled(stall_timeout, attempt))
+                }
+                CopyOutcome::Stalled => info!(
+                    "Copying profile `{}` to node `{}` again (attempt {} of {})",
+                    data.deploy_data.profile_name,
+                    data.deploy_data.node_name,
+                    attempt + 1,
+                    retries + 1
+                ),
+            }
+        }
+
+        return Ok(pushed);
     }
 
-    Ok(())
+    Ok(0)
+}
+
+#[test]
+fn test_nix_failure() {
+    assert_eq!(
+        NixFailure::recognize("copying path '/nix/store/aaaa-foo'\nerror: cannot add path '/nix/store/aaaa-foo' because it lacks a signature by a trusted key"),
+        Some(NixFailure::SignatureRejected)
+    );
+    assert_eq!(
+        NixFailure::recognize("error: you are not privileged to build input-addressed derivations"),
+        Some(NixFailure::UntrustedUser)
+    );
+    assert_eq!(
+        NixFailure::recognize("error: writing to file: No space left on device"),
+        Some(NixFailure::OutOfSpace)
+    );
+    assert_eq!(
+        NixFailure::recognize("client_loop: send disconnect: Broken pipe\nerror: unexpected end-of-file"),
+        Some(NixFailure::NetworkReset)
+    );
+    assert_eq!(
+        NixFailure::recognize("error: unrecognised flag '--log-format'\nTry 'nix --help' for more information."),
+        Some(NixFailure::UnknownFlag("--log-format".to_string()))
+    );
+    // Only a warning, the actual failure isn't recognized
+    assert_eq!(
+        NixFailure::recognize("warning: ignoring the client-specified setting 'substituters', because it is a restricted setting and you are not a trusted user\nerror: build of '/nix/store/bbbb-bar.drv' failed"),
+        None
+    );
+
+    let error = copy_exit_error(Some(1), b"error: writing to file: No space left on device\n");
+    assert_eq!(error.category(), ExitCategory::Node);
+    assert_eq!(
+        error.to_string(),
+        "Nix copy command failed, no space left on the device\nHint: free up space on the node, e.g. with `nix-collect-garbage --delete-older-than 30d`"
+    );
+    let error = build_exit_error(Some(1), b"error: build of '/nix/store/bbbb-bar.drv' failed\n");
+    assert!(matches!(error, PushProfileError::BuildExit(Some(1))));
+    assert_eq!(error.category(), ExitCategory::Failure);
+}
+
+#[test]
+fn test_build_relay_copy_command() {
+    assert_eq!(
+        build_relay_copy_command(
+            "ssh://deploy@web1.example.com",
+            &["-p".to_string(), "2222".to_string()],
+            false,
+            "/nix/store/aaaa-system"
+        ),
+        "NIX_SSHOPTS='-p 2222' nix --extra-experimental-features nix-command copy --no-check-sigs --to 'ssh://deploy@web1.example.com' '/nix/store/aaaa-system'"
+    );
+    assert_eq!(
+        build_relay_copy_command("ssh://root@10.0.0.1", &[], true, "/nix/store/aaaa-system"),
+        "NIX_SSHOPTS='' nix --extra-experimental-features nix-command copy --to 'ssh://root@10.0.0.1' '/nix/store/aaaa-system'"
+    );
 }
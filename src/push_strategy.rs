@@ -0,0 +1,341 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Pushing closures to nodes whose Nix daemon doesn't accept writes through an SSH store
+//! (`pushStrategy`).
+//!
+//! With `"serve"`, the local store is served by `nix-serve`, which the node reaches through a
+//! reverse SSH tunnel (see [`crate::tunnel`]) to copy the closure from with `nix copy --from`. With `"sftp"`, the paths of
+//! the closure missing on the node are exported (`nix-store --export`), uploaded with `sftp` and
+//! imported with `nix-store --import`. Either way the Nix commands on the node run with the `sudo`
+//! of the profile, as the activation does.
+
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::data::PushStrategy;
+use crate::deploy::shell_quote;
+use crate::push::PushProfileData;
+use crate::trace;
+
+/// How long `nix-serve` gets to start listening
+const SERVE_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum PushStrategyError {
+    #[error("Failed to find a free local port for nix-serve: {0}")]
+    Port(std::io::Error),
+    #[error("Failed to run nix-serve (is it installed?): {0}")]
+    Serve(std::io::Error),
+    #[error("nix-serve exited before the node could copy from it: {0:?}")]
+    ServeExit(Option<i32>),
+    #[error("nix-serve didn't start listening within {}s", SERVE_START_TIMEOUT.as_secs())]
+    ServeTimeout,
+    #[error("Failed to run ssh: {0}")]
+    Ssh(std::io::Error),
+    #[error("{0}")]
+    Tunnel(#[from] crate::tunnel::TunnelError),
+    #[error("Copying from the tunnelled nix-serve on the node resulted in a bad exit code: {0:?}")]
+    PullExit(Option<i32>),
+    #[error("Failed to run nix-store: {0}")]
+    NixStore(std::io::Error),
+    #[error("nix-store --{0} resulted in a bad exit code: {1:?}")]
+    NixStoreExit(&'static str, Option<i32>),
+    #[error("The sftp push strategy can't check signatures (`nix-store --import` doesn't), drop --checksigs or use another one")]
+    SftpCheckSigs,
+    #[error("Failed to run sftp: {0}")]
+    Sftp(std::io::Error),
+    #[error("Uploading the closure with sftp resulted in a bad exit code: {0:?}")]
+    SftpExit(Option<i32>),
+    #[error("Failed to write the exported closure to {0}: {1}")]
+    Write(std::path::PathBuf, std::io::Error),
+}
+
+/// Pushes the profile of `data` to its node with `strategy`
+pub async fn push(
+    data: &PushProfileData<'_>,
+    strategy: PushStrategy,
+) -> Result<(), PushStrategyError> {
+    match strategy {
+        PushStrategy::Copy => unreachable!("`nix copy --to` is run by push_profile"),
+        PushStrategy::Serve => push_by_serve(data).await,
+        PushStrategy::Sftp => push_by_sftp(data).await,
+    }
+}
+
+fn ssh_address(data: &PushProfileData<'_>) -> String {
+    format!(
+        "{}@{}",
+        data.deploy_defs.ssh_user, data.deploy_data.hostname
+    )
+}
+
+/// `command` run with the `sudo` of the profile, if any
+fn with_sudo(data: &PushProfileData<'_>, command: String) -> String {
+    match &data.deploy_defs.sudo {
+        Some(sudo) => format!("{} {}", sudo, command),
+        None => command,
+    }
+}
+
+fn ssh_command(data: &PushProfileData<'_>) -> Command {
+    let mut command = data.deploy_data.ssh_command();
+    command.args(data.deploy_data.ssh_opts());
+    command
+}
+
+async fn push_by_serve(data: &PushProfileData<'_>) -> Result<(), PushStrategyError> {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|l| l.local_addr())
+        .map_err(PushStrategyError::Port)?
+        .port();
+
+    info!(
+        "Serving the local store on port {} for node `{}` to copy profile `{}` from",
+        port, data.deploy_data.node_name, data.deploy_data.profile_name
+    );
+
+    let mut serve = trace::spawn(
+        Command::new("nix-serve")
+            .arg("--listen")
+            .arg(format!("127.0.0.1:{}", port))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true),
+    )
+    .map_err(PushStrategyError::Serve)?;
+
+    let started = Instant::now();
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        if let Some(status) = serve.try_wait().map_err(PushStrategyError::Serve)? {
+            return Err(PushStrategyError::ServeExit(status.code()));
+        }
+        if started.elapsed() > SERVE_START_TIMEOUT {
+            return Err(PushStrategyError::ServeTimeout);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let tunnel = crate::tunnel::open(data.deploy_data, data.deploy_defs, port).await?;
+
+    let mut pull_command = format!(
+        "nix --extra-experimental-features nix-command copy --from http://127.0.0.1:{} {}",
+        tunnel.remote_port,
+        shell_quote(&data.deploy_data.profile.profile_settings.path)
+    );
+    if !data.check_sigs {
+        pull_command += " --no-check-sigs";
+    }
+    for arg in crate::push::nix_option_args(data) {
+        pull_command += " ";
+        pull_command += &shell_quote(&arg);
+    }
+    let pull_command = with_sudo(data, pull_command);
+
+    debug!(
+        "Copying from the tunnelled nix-serve on the node: {}",
+        pull_command
+    );
+
+    let status = trace::status(ssh_command(data).arg(ssh_address(data)).arg(pull_command))
+        .await
+        .map_err(PushStrategyError::Ssh)?;
+
+    tunnel.close().await;
+    let _ = serve.kill().await;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(PushStrategyError::PullExit(a)),
+    }
+}
+
+/// The options of `ssh_opts` in the form `sftp` takes them, which only differs in the port
+fn sftp_opts(ssh_opts: &[String]) -> Vec<String> {
+    ssh_opts
+        .iter()
+        .map(|opt| match opt.strip_prefix("-p") {
+            Some(port) => format!("-P{}", port),
+            None => opt.clone(),
+        })
+        .collect()
+}
+
+async fn push_by_sftp(data: &PushProfileData<'_>) -> Result<(), PushStrategyError> {
+    if data.check_sigs {
+        return Err(PushStrategyError::SftpCheckSigs);
+    }
+
+    let path = &data.deploy_data.profile.profile_settings.path;
+
+    let requisites = trace::output(
+        Command::new("nix-store")
+            .arg("--query")
+            .arg("--requisites")
+            .arg(path)
+            .stderr(Stdio::inherit()),
+    )
+    .await
+    .map_err(PushStrategyError::NixStore)?;
+    match requisites.status.code() {
+        Some(0) => (),
+        a => return Err(PushStrategyError::NixStoreExit("query", a)),
+    };
+
+    // Only the paths the node doesn't have yet are uploaded; they are piped in, the closure may
+    // not fit on a command line
+    let mut check_child = trace::spawn(
+        ssh_command(data)
+            .arg(ssh_address(data))
+            .arg("xargs nix-store --check-validity --print-invalid")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped()),
+    )
+    .map_err(PushStrategyError::Ssh)?;
+    if let Some(mut stdin) = check_child.stdin.take() {
+        stdin
+            .write_all(&requisites.stdout)
+            .await
+            .map_err(PushStrategyError::Ssh)?;
+    }
+    let check = trace::wait_with_output(check_child)
+        .await
+        .map_err(PushStrategyError::Ssh)?;
+    match check.status.code() {
+        Some(0) => (),
+        a => return Err(PushStrategyError::NixStoreExit("check-validity", a)),
+    };
+
+    let missing: Vec<String> = String::from_utf8_lossy(&check.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    if missing.is_empty() {
+        info!(
+            "Node `{}` already has the closure of profile `{}`",
+            data.deploy_data.node_name, data.deploy_data.profile_name
+        );
+        return Ok(());
+    }
+
+    let name = format!(
+        "{}-{}.closure",
+        data.deploy_defs
+            .temp_path
+            .file_name()
+            .map_or("deploy-rs-push".into(), |n| n.to_string_lossy()),
+        data.deploy_data.profile_name
+    );
+    let local_file = std::env::temp_dir().join(&name);
+    let remote_file = format!("/tmp/{}", name);
+
+    info!(
+        "Uploading {} path(s) of profile `{}` to node `{}` with sftp",
+        missing.len(),
+        data.deploy_data.profile_name,
+        data.deploy_data.node_name
+    );
+
+    let result = upload_and_import(data, &missing, &local_file, &remote_file).await;
+    let _ = std::fs::remove_file(&local_file);
+    result
+}
+
+async fn upload_and_import(
+    data: &PushProfileData<'_>,
+    paths: &[String],
+    local_file: &std::path::Path,
+    remote_file: &str,
+) -> Result<(), PushStrategyError> {
+    let file = std::fs::File::create(local_file)
+        .map_err(|e| PushStrategyError::Write(local_file.to_path_buf(), e))?;
+    let export = trace::status(
+        Command::new("nix-store")
+            .arg("--export")
+            .args(paths)
+            .stdout(file),
+    )
+    .await
+    .map_err(PushStrategyError::NixStore)?;
+    match export.code() {
+        Some(0) => (),
+        a => return Err(PushStrategyError::NixStoreExit("export", a)),
+    };
+
+    let mut sftp_command = Command::new("sftp");
+    // sftp only takes a program to run as ssh, without arguments
+    if let Some(wrapper) = data.deploy_data.ssh_wrapper() {
+        sftp_command
+            .arg("-S")
+            .arg(crate::ssh_wrapper::shim(wrapper).map_err(PushStrategyError::Sftp)?);
+    }
+    let mut sftp_child = trace::spawn(
+        sftp_command
+            .arg("-b")
+            .arg("-")
+            .args(sftp_opts(&data.deploy_data.ssh_opts()))
+            .arg(ssh_address(data))
+            .stdin(Stdio::piped()),
+    )
+    .map_err(PushStrategyError::Sftp)?;
+    if let Some(mut stdin) = sftp_child.stdin.take() {
+        stdin
+            .write_all(format!("put \"{}\" \"{}\"\n", local_file.display(), remote_file).as_bytes())
+            .await
+            .map_err(PushStrategyError::Sftp)?;
+    }
+    match trace::wait(&mut sftp_child)
+        .await
+        .map_err(PushStrategyError::Sftp)?
+        .code()
+    {
+        Some(0) => (),
+        a => return Err(PushStrategyError::SftpExit(a)),
+    };
+
+    let remote_file = shell_quote(remote_file);
+    let import_command = format!(
+        "{} < {1}; status=$?; rm -f {1}; exit $status",
+        with_sudo(data, "nix-store --import".to_string()),
+        remote_file
+    );
+
+    debug!(
+        "Importing the uploaded closure on the node: {}",
+        import_command
+    );
+
+    let import = trace::status(
+        ssh_command(data)
+            .arg(ssh_address(data))
+            .arg(import_command)
+            .stdout(Stdio::null()),
+    )
+    .await
+    .map_err(PushStrategyError::Ssh)?;
+    match import.code() {
+        Some(0) => Ok(()),
+        a => Err(PushStrategyError::NixStoreExit("import", a)),
+    }
+}
+
+#[test]
+fn test_sftp_opts() {
+    let opts: Vec<String> = ["-p", "2222", "-i", "~/.ssh/deploy", "-p22", "-oPort=22"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(
+        sftp_opts(&opts),
+        vec!["-P", "2222", "-i", "~/.ssh/deploy", "-P22", "-oPort=22"]
+    );
+}
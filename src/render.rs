@@ -0,0 +1,623 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Renderers showing the event stream, one per `--output-format`.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use log::{debug, error};
+
+use crate::events::{Event, EventKind};
+use crate::redact::redact;
+use crate::resources::{format_bytes, ResourceUsage};
+
+/// Consumes the events of a deployment (see [`crate::events`]) and shows them
+pub trait Renderer: Send {
+    fn render(&mut self, event: &Event);
+
+    /// Called once all events are rendered
+    fn finish(&mut self) {}
+}
+
+/// Renders the events with several renderers, e.g. for reports next to the regular output
+pub struct Renderers(pub Vec<Box<dyn Renderer>>);
+
+impl Renderer for Renderers {
+    fn render(&mut self, event: &Event) {
+        for renderer in &mut self.0 {
+            renderer.render(event);
+        }
+    }
+
+    fn finish(&mut self) {
+        for renderer in &mut self.0 {
+            renderer.finish();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Output of each node prefixed with its colored name
+    Human,
+    /// Like `Human`, without colors
+    Plain,
+    /// One JSON object per event on stdout
+    Json,
+    /// Like `Plain`, with failures as GitHub Actions error annotations
+    Github,
+    /// Output is only shown for failed steps
+    Quiet,
+}
+
+pub const OUTPUT_FORMATS: &[&str] = &["human", "plain", "json", "github", "quiet"];
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "plain" => Ok(OutputFormat::Plain),
+            "json" => Ok(OutputFormat::Json),
+            "github" => Ok(OutputFormat::Github),
+            "quiet" => Ok(OutputFormat::Quiet),
+            _ => Err(format!(
+                "unknown output format `{}`, expected one of {}",
+                s,
+                OUTPUT_FORMATS.join(", ")
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// The format without `--output-format`, following `--plain` and `--quiet`
+    pub fn default_for(plain: bool, quiet: bool) -> Self {
+        match (quiet, plain) {
+            (true, _) => OutputFormat::Quiet,
+            (false, true) => OutputFormat::Plain,
+            (false, false) => OutputFormat::Human,
+        }
+    }
+
+    /// Makes the renderer of this format, `terminal` tells whether stderr is a terminal
+    pub fn renderer(self, terminal: bool) -> Box<dyn Renderer> {
+        match self {
+            OutputFormat::Human => Box::new(HostRenderer::new(!terminal, false)),
+            OutputFormat::Plain => Box::new(HostRenderer::new(true, false)),
+            OutputFormat::Quiet => Box::new(HostRenderer::new(!terminal, true)),
+            OutputFormat::Json => Box::new(JsonRenderer),
+            OutputFormat::Github => Box::new(GithubRenderer),
+        }
+    }
+}
+
+/// ANSI foreground colors nodes are assigned from (red is left out, it reads like an error)
+const NODE_COLORS: &[u8] = &[36, 32, 33, 35, 34, 96, 92, 93, 95, 94];
+
+/// Picks a color for `node` that stays the same across runs
+fn node_color(node: &str) -> u8 {
+    // FNV-1a, as the std hasher is randomly seeded
+    let hash = node.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    NODE_COLORS[(hash % NODE_COLORS.len() as u64) as usize]
+}
+
+#[test]
+fn test_node_color() {
+    assert_eq!(node_color("web1"), node_color("web1"));
+    assert!(NODE_COLORS.contains(&node_color("")));
+    assert_ne!(node_color("web1"), node_color("web2"));
+}
+
+/// Whether `line` is ssh asking to touch a security key (e.g. for `ed25519-sk` keys)
+fn is_user_presence_prompt(line: &str) -> bool {
+    line.starts_with("Confirm user presence for key") || line.starts_with("User presence confirmed")
+}
+
+#[test]
+fn test_is_user_presence_prompt() {
+    assert!(is_user_presence_prompt(
+        "Confirm user presence for key ED25519-SK SHA256:Qx1pJ8Yf0X4ph2jCN9lQ4WqVJgqJXtVxq0sxtgkLZqA"
+    ));
+    assert!(!is_user_presence_prompt(
+        "copying path '/nix/store/...' to 'ssh://web1'"
+    ));
+}
+
+/// Renders the output of every node prefixed with its name, colored per node unless `plain`.
+///
+/// In quiet mode the output is held back instead, and only logged (as errors) if the step
+/// producing it fails, or as debug logs (ending up in `--log-dir`) otherwise. Prompts to touch a
+/// security key are always shown, as ssh waits for them.
+pub struct HostRenderer {
+    plain: bool,
+    quiet: bool,
+    held_back: HashMap<(String, String), Vec<String>>,
+    /// The bytes pushed so far of each profile
+    pushed: HashMap<(String, String), u64>,
+}
+
+impl HostRenderer {
+    pub fn new(plain: bool, quiet: bool) -> Self {
+        HostRenderer {
+            plain,
+            quiet,
+            held_back: HashMap::new(),
+            pushed: HashMap::new(),
+        }
+    }
+
+    fn prefix(&self, node: &str) -> String {
+        match self.plain {
+            true => format!("[{}]", node),
+            false => format!("\x1b[{}m[{}]\x1b[0m", node_color(node), node),
+        }
+    }
+}
+
+impl Renderer for HostRenderer {
+    fn render(&mut self, event: &Event) {
+        let key = (event.node.clone(), event.profile.clone());
+
+        match &event.kind {
+            EventKind::Output(line) if is_user_presence_prompt(line) => {
+                eprintln!("{} 🔑 {}", self.prefix(&event.node), line)
+            }
+            EventKind::Output(line) if self.quiet => {
+                self.held_back.entry(key).or_default().push(line.clone())
+            }
+            EventKind::Output(line) => eprintln!("{} {}", self.prefix(&event.node), redact(line)),
+            EventKind::Finished(phase) => {
+                for line in self.held_back.remove(&key).unwrap_or_default() {
+                    debug!("[{}] {}", event.node, line);
+                }
+                match self.pushed.remove(&key) {
+                    Some(bytes) if *phase == crate::events::Phase::Push && !self.quiet => eprintln!(
+                        "{} Pushed profile `{}` ({})",
+                        self.prefix(&event.node),
+                        event.profile,
+                        format_bytes(bytes)
+                    ),
+                    _ => (),
+                }
+            }
+            EventKind::Failed(phase, _) => {
+                for line in self.held_back.remove(&key).unwrap_or_default() {
+                    error!("[{}] {}", event.node, line);
+                }
+                match self.pushed.remove(&key) {
+                    Some(bytes) if *phase == crate::events::Phase::Push && bytes > 0 => error!(
+                        "[{}] Pushing profile `{}` failed after copying {}",
+                        event.node,
+                        event.profile,
+                        format_bytes(bytes)
+                    ),
+                    _ => (),
+                }
+            }
+            EventKind::Pushed(bytes) => {
+                self.pushed.insert(key, *bytes);
+            }
+            EventKind::Command(command) => {
+                debug!("[{}] Running {}", event.node, redact(command))
+            }
+            EventKind::Started(_)
+            | EventKind::Planned(_)
+            | EventKind::Units(_)
+            | EventKind::Usage(_, _)
+            | EventKind::Confirmed => (),
+        }
+    }
+}
+
+/// Prints every event as a JSON object on its own line on stdout
+pub struct JsonRenderer;
+
+impl Renderer for JsonRenderer {
+    fn render(&mut self, event: &Event) {
+        let mut object = serde_json::json!({
+            "node": event.node,
+            "profile": event.profile,
+        });
+
+        let fields = match &event.kind {
+            EventKind::Started(phase) => {
+                serde_json::json!({ "event": "started", "phase": phase.to_string() })
+            }
+            EventKind::Finished(phase) => {
+                serde_json::json!({ "event": "finished", "phase": phase.to_string() })
+            }
+            EventKind::Failed(phase, message) => serde_json::json!({
+                "event": "failed",
+                "phase": phase.to_string(),
+                "message": redact(message),
+            }),
+            EventKind::Output(line) => {
+                serde_json::json!({ "event": "output", "line": redact(line) })
+            }
+            EventKind::Command(command) => {
+                serde_json::json!({ "event": "command", "command": redact(command) })
+            }
+            EventKind::Planned(plan) => {
+                serde_json::json!({ "event": "planned", "plan": redact(plan) })
+            }
+            EventKind::Units(units) => serde_json::json!({
+                "event": "units",
+                "restarted": units.restarted,
+                "reloaded": units.reloaded,
+                "stopped": units.stopped,
+                "started": units.started,
+            }),
+            EventKind::Confirmed => serde_json::json!({ "event": "confirmed" }),
+            EventKind::Pushed(bytes) => serde_json::json!({ "event": "pushed", "bytes": bytes }),
+            EventKind::Usage(phase, usage) => serde_json::json!({
+                "event": "usage",
+                "phase": phase.to_string(),
+                "cpuSeconds": usage.cpu_time.as_secs_f64(),
+                "peakRssBytes": usage.peak_rss,
+                "transferredBytes": usage.transferred,
+            }),
+        };
+        if let (Some(object), serde_json::Value::Object(fields)) = (object.as_object_mut(), fields)
+        {
+            object.extend(fields);
+        }
+
+        println!("{}", object);
+    }
+}
+
+/// Escapes `s` for the message of a GitHub Actions workflow command
+fn escape_workflow_command(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[test]
+fn test_escape_workflow_command() {
+    assert_eq!(
+        escape_workflow_command("100% failed\nexit code 1"),
+        "100%25 failed%0Aexit code 1"
+    );
+}
+
+/// Prints the output of every node prefixed with its name, and failures as error annotations
+/// (`::error::`) GitHub Actions shows on the workflow run
+pub struct GithubRenderer;
+
+impl Renderer for GithubRenderer {
+    fn render(&mut self, event: &Event) {
+        match &event.kind {
+            EventKind::Output(line) => eprintln!("[{}] {}", event.node, redact(line)),
+            EventKind::Started(phase) => {
+                eprintln!("[{}] Starting {} of {}", event.node, phase, event.profile)
+            }
+            EventKind::Finished(phase) => {
+                eprintln!("[{}] Finished {} of {}", event.node, phase, event.profile)
+            }
+            // Workflow commands are read from stdout
+            EventKind::Failed(phase, message) => println!(
+                "::error title={}.{} {} failed::{}",
+                event.node,
+                event.profile,
+                phase,
+                escape_workflow_command(&redact(message))
+            ),
+            EventKind::Units(units) => eprintln!("[{}] Activation {}", event.node, units),
+            EventKind::Confirmed => {
+                eprintln!("[{}] Confirmed activation of {}", event.node, event.profile)
+            }
+            EventKind::Command(_)
+            | EventKind::Planned(_)
+            | EventKind::Usage(_, _)
+            | EventKind::Pushed(_) => (),
+        }
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+struct TestCase {
+    node: String,
+    profile: String,
+    started: std::time::Instant,
+    finished: std::time::Instant,
+    failure: Option<String>,
+}
+
+/// Writes a JUnit XML report with a test case per profile (`--report-junit`), failed if any of
+/// its steps failed or it was rolled back
+pub struct JunitRenderer {
+    path: std::path::PathBuf,
+    cases: Vec<TestCase>,
+}
+
+impl JunitRenderer {
+    pub fn new(path: std::path::PathBuf) -> Self {
+        JunitRenderer {
+            path,
+            cases: Vec::new(),
+        }
+    }
+
+    fn to_xml(&self) -> String {
+        let seconds = |c: &TestCase| (c.finished - c.started).as_secs_f64();
+        let failures = self.cases.iter().filter(|c| c.failure.is_some()).count();
+        let total: f64 = self.cases.iter().map(seconds).sum();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites name=\"deploy-rs\" tests=\"{0}\" failures=\"{1}\" time=\"{2:.3}\">\n  <testsuite name=\"deploy-rs\" tests=\"{0}\" failures=\"{1}\" time=\"{2:.3}\">\n",
+            self.cases.len(),
+            failures,
+            total
+        ));
+        for case in &self.cases {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                escape_xml(&case.node),
+                escape_xml(&case.profile),
+                seconds(case)
+            ));
+            match &case.failure {
+                Some(message) => xml.push_str(&format!(
+                    ">\n      <failure message=\"{0}\">{0}</failure>\n    </testcase>\n",
+                    escape_xml(message)
+                )),
+                None => xml.push_str("/>\n"),
+            }
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+}
+
+impl Renderer for JunitRenderer {
+    fn render(&mut self, event: &Event) {
+        let now = std::time::Instant::now();
+
+        let case = match self
+            .cases
+            .iter_mut()
+            .find(|c| c.node == event.node && c.profile == event.profile)
+        {
+            Some(case) => case,
+            None => {
+                self.cases.push(TestCase {
+                    node: event.node.clone(),
+                    profile: event.profile.clone(),
+                    started: now,
+                    finished: now,
+                    failure: None,
+                });
+                self.cases.last_mut().unwrap()
+            }
+        };
+
+        case.finished = now;
+        match &event.kind {
+            EventKind::Failed(phase, message) => {
+                case.failure = Some(format!("{} failed: {}", phase, redact(message)))
+            }
+            EventKind::Finished(crate::events::Phase::Revoke) => {
+                case.failure = Some("rolled back after a later profile failed".to_string())
+            }
+            _ => (),
+        }
+    }
+
+    fn finish(&mut self) {
+        match std::fs::write(&self.path, self.to_xml()) {
+            Ok(()) => debug!("Wrote the JUnit report to {}", self.path.display()),
+            Err(e) => error!(
+                "Failed to write the JUnit report to {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_junit_report() {
+    use crate::events::Phase;
+
+    let mut renderer = JunitRenderer::new(std::path::PathBuf::from("/nonexistent"));
+    let event = |node: &str, kind| Event {
+        node: node.to_string(),
+        profile: "system".to_string(),
+        kind,
+    };
+
+    renderer.render(&event("web1", EventKind::Started(Phase::Push)));
+    renderer.render(&event("web1", EventKind::Finished(Phase::Push)));
+    renderer.render(&event("db<1>", EventKind::Started(Phase::Push)));
+    renderer.render(&event(
+        "db<1>",
+        EventKind::Failed(Phase::Push, "exit code \"1\"".to_string()),
+    ));
+
+    let xml = renderer.to_xml();
+    assert!(xml.contains("<testsuites name=\"deploy-rs\" tests=\"2\" failures=\"1\""));
+    assert!(xml.contains("<testcase classname=\"web1\" name=\"system\" time=\""));
+    assert!(xml.contains(
+        "<failure message=\"push failed: exit code &quot;1&quot;\">push failed: exit code &quot;1&quot;</failure>"
+    ));
+    assert!(xml.contains("classname=\"db&lt;1&gt;\""));
+}
+
+struct PhaseTiming {
+    node: String,
+    profile: String,
+    phase: crate::events::Phase,
+    started: std::time::Instant,
+    elapsed: Option<std::time::Duration>,
+    usage: Option<ResourceUsage>,
+}
+
+/// Prints how long each phase of each profile took once the deployment is done (`--timings`),
+/// with the resources its commands used
+#[derive(Default)]
+pub struct TimingsRenderer {
+    phases: Vec<PhaseTiming>,
+}
+
+impl TimingsRenderer {
+    fn phase(&mut self, event: &Event, phase: crate::events::Phase) -> &mut PhaseTiming {
+        let i = match self.phases.iter().rposition(|p| {
+            p.node == event.node && p.profile == event.profile && p.phase == phase
+        }) {
+            Some(i) => i,
+            None => {
+                self.phases.push(PhaseTiming {
+                    node: event.node.clone(),
+                    profile: event.profile.clone(),
+                    phase,
+                    started: std::time::Instant::now(),
+                    elapsed: None,
+                    usage: None,
+                });
+                self.phases.len() - 1
+            }
+        };
+        &mut self.phases[i]
+    }
+
+    fn to_table(&self) -> String {
+        let rows: Vec<[String; 7]> = self
+            .phases
+            .iter()
+            .map(|p| {
+                let usage = p.usage.unwrap_or_default();
+                let or_dash = |known: bool, value: String| if known { value } else { "-".to_string() };
+                [
+                    p.node.clone(),
+                    p.profile.clone(),
+                    p.phase.to_string(),
+                    or_dash(
+                        p.elapsed.is_some(),
+                        format!("{:.1}s", p.elapsed.unwrap_or_default().as_secs_f64()),
+                    ),
+                    or_dash(
+                        p.usage.is_some(),
+                        format!("{:.1}s", usage.cpu_time.as_secs_f64()),
+                    ),
+                    or_dash(p.usage.is_some(), format_bytes(usage.peak_rss)),
+                    or_dash(usage.transferred > 0, format_bytes(usage.transferred)),
+                ]
+            })
+            .collect();
+
+        let header = ["NODE", "PROFILE", "PHASE", "TIME", "CPU", "PEAK MEMORY", "TRANSFERRED"];
+        let widths: Vec<usize> = (0..header.len())
+            .map(|i| rows.iter().map(|r| r[i].len()).chain([header[i].len()]).max().unwrap_or_default())
+            .collect();
+        let line = |cells: Vec<&str>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            format!("{}\n", padded.join("  ").trim_end())
+        };
+
+        let mut table = line(header.to_vec());
+        for row in &rows {
+            table.push_str(&line(row.iter().map(String::as_str).collect()));
+        }
+        table
+    }
+}
+
+impl Renderer for TimingsRenderer {
+    fn render(&mut self, event: &Event) {
+        match &event.kind {
+            EventKind::Started(phase) => {
+                // A phase run again, e.g. a push retried, is timed from its first start
+                self.phase(event, *phase);
+            }
+            EventKind::Finished(phase) | EventKind::Failed(phase, _) => {
+                let timing = self.phase(event, *phase);
+                timing.elapsed = Some(timing.started.elapsed());
+            }
+            EventKind::Usage(phase, usage) => {
+                self.phase(event, *phase)
+                    .usage
+                    .get_or_insert_with(ResourceUsage::default)
+                    .add(usage);
+            }
+            _ => (),
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.phases.is_empty() {
+            eprint!("Timings:\n{}", self.to_table());
+        }
+    }
+}
+
+#[test]
+fn test_timings() {
+    use crate::events::Phase;
+    use std::time::Duration;
+
+    let mut renderer = TimingsRenderer::default();
+    let event = |node: &str, kind| Event {
+        node: node.to_string(),
+        profile: "system".to_string(),
+        kind,
+    };
+
+    renderer.render(&event("web1", EventKind::Started(Phase::Build)));
+    renderer.render(&event(
+        "web1",
+        EventKind::Usage(
+            Phase::Build,
+            ResourceUsage {
+                cpu_time: Duration::from_millis(12300),
+                peak_rss: 512 * 1024 * 1024,
+                transferred: 0,
+            },
+        ),
+    ));
+    renderer.render(&event("web1", EventKind::Finished(Phase::Build)));
+    renderer.render(&event("web1", EventKind::Started(Phase::Push)));
+    for transferred in [1024 * 1024, 2 * 1024 * 1024] {
+        renderer.render(&event(
+            "web1",
+            EventKind::Usage(
+                Phase::Push,
+                ResourceUsage {
+                    cpu_time: Duration::from_millis(500),
+                    peak_rss: 20 * 1024 * 1024,
+                    transferred,
+                },
+            ),
+        ));
+    }
+    renderer.render(&event("web1", EventKind::Failed(Phase::Push, "stalled".to_string())));
+    renderer.render(&event("db1", EventKind::Started(Phase::Activate)));
+
+    renderer.phases[0].elapsed = Some(Duration::from_millis(20000));
+    renderer.phases[1].elapsed = Some(Duration::from_millis(4500));
+    assert_eq!(
+        renderer.to_table(),
+        "NODE  PROFILE  PHASE     TIME   CPU    PEAK MEMORY  TRANSFERRED\n\
+         web1  system   build     20.0s  12.3s  512.0 MiB    -\n\
+         web1  system   push      4.5s   1.0s   20.0 MiB     3.0 MiB\n\
+         db1   system   activate  -      -      -            -\n"
+    );
+}
@@ -0,0 +1,215 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The resources used by the commands building and pushing each profile (`--timings`).
+//!
+//! The CPU time and resident memory of a command and everything it started are sampled from
+//! `/proc` while it runs, so nothing is measured without `/proc`, and processes started and
+//! reaped between two samples only count once their parent is sampled again. Builds run by the
+//! Nix daemon aren't children of deploy-rs and don't count. The bytes transferred are those
+//! `nix copy` reports copying.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+/// How often a running command is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The unit of the CPU times in `/proc/<pid>/stat` (`USER_HZ`, 100 on every Linux architecture)
+const CLOCK_TICKS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceUsage {
+    pub cpu_time: Duration,
+    /// The most memory the commands had resident at once, in bytes
+    pub peak_rss: u64,
+    /// Bytes copied to the node
+    pub transferred: u64,
+}
+
+impl ResourceUsage {
+    /// Adds the usage of a later command of the same phase
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.cpu_time += other.cpu_time;
+        self.peak_rss = self.peak_rss.max(other.peak_rss);
+        self.transferred += other.transferred;
+    }
+}
+
+/// `bytes` with a binary unit, e.g. `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1}s CPU, {} peak memory",
+            self.cpu_time.as_secs_f64(),
+            format_bytes(self.peak_rss)
+        )?;
+        if self.transferred > 0 {
+            write!(f, ", {} transferred", format_bytes(self.transferred))?;
+        }
+        Ok(())
+    }
+}
+
+/// The CPU time of a process and of its reaped children, from the contents of `/proc/<pid>/stat`
+fn parse_cpu_time(stat: &str) -> Option<Duration> {
+    // The command name in parentheses may contain spaces, the fields after it don't
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // utime, stime, cutime and cstime, fields 14 to 17 counting from the pid
+    let ticks = fields
+        .get(11..15)?
+        .iter()
+        .map(|field| field.parse::<u64>().ok())
+        .sum::<Option<u64>>()?;
+    Some(Duration::from_millis(ticks * 1000 / CLOCK_TICKS))
+}
+
+/// The resident memory of a process in bytes, from the contents of `/proc/<pid>/status`
+fn parse_rss(status: &str) -> Option<u64> {
+    let kib: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// `pid` and all processes started by it that are still running
+fn process_tree(pid: u32) -> Vec<u32> {
+    let mut tree = vec![pid];
+    let mut i = 0;
+    while i < tree.len() {
+        let tasks = std::fs::read_dir(format!("/proc/{}/task", tree[i]));
+        for task in tasks.into_iter().flatten().flatten() {
+            let children = std::fs::read_to_string(task.path().join("children")).unwrap_or_default();
+            tree.extend(children.split_whitespace().filter_map(|c| c.parse::<u32>().ok()));
+        }
+        i += 1;
+    }
+    tree
+}
+
+/// The CPU time and resident memory of `pid` and everything it started
+fn sample(pid: u32) -> (Duration, u64) {
+    process_tree(pid)
+        .into_iter()
+        .fold((Duration::ZERO, 0), |(cpu_time, rss), pid| {
+            let read = |file: &str| std::fs::read_to_string(format!("/proc/{}/{}", pid, file));
+            (
+                cpu_time + read("stat").ok().and_then(|s| parse_cpu_time(&s)).unwrap_or_default(),
+                rss + read("status").ok().and_then(|s| parse_rss(&s)).unwrap_or_default(),
+            )
+        })
+}
+
+/// Samples the resources used by a running command until finished
+pub struct UsageMonitor {
+    usage: Arc<Mutex<ResourceUsage>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl UsageMonitor {
+    /// Starts sampling the process `pid` (of a child just spawned), if it has one
+    pub fn start(pid: Option<u32>) -> Self {
+        let usage = Arc::new(Mutex::new(ResourceUsage::default()));
+
+        let task = pid.map(|pid| {
+            let usage = usage.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (cpu_time, rss) = sample(pid);
+                    {
+                        let mut usage = usage.lock().unwrap_or_else(|e| e.into_inner());
+                        // Exited processes take their CPU time with them until reaped
+                        usage.cpu_time = usage.cpu_time.max(cpu_time);
+                        usage.peak_rss = usage.peak_rss.max(rss);
+                    }
+                    tokio::time::sleep(SAMPLE_INTERVAL).await;
+                }
+            })
+        });
+
+        UsageMonitor { usage, task }
+    }
+
+    /// Stops sampling, once the command exited, and returns what it used
+    pub fn finish(mut self, transferred: u64) -> ResourceUsage {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        let mut usage = *self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        usage.transferred = transferred;
+        usage
+    }
+}
+
+impl Drop for UsageMonitor {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_usage_monitor() {
+    assert_eq!(
+        parse_cpu_time("1234 (nix copy) S 1 1234 1234 0 -1 4194560 900 0 0 0 150 25 10 5 20 0 1 0"),
+        Some(Duration::from_millis(1900))
+    );
+    assert_eq!(parse_cpu_time("1234 (nix"), None);
+    assert_eq!(
+        parse_rss("Name:\tnix\nVmHWM:\t  20480 kB\nVmRSS:\t  10240 kB\n"),
+        Some(10 * 1024 * 1024)
+    );
+    assert_eq!(format_bytes(512), "512 B");
+    assert_eq!(format_bytes(1536 * 1024 * 1024), "1.5 GiB");
+
+    let mut usage = ResourceUsage {
+        cpu_time: Duration::from_secs(2),
+        peak_rss: 100,
+        transferred: 0,
+    };
+    usage.add(&ResourceUsage {
+        cpu_time: Duration::from_millis(500),
+        peak_rss: 50,
+        transferred: 3 * 1024 * 1024,
+    });
+    assert_eq!(usage.to_string(), "2.5s CPU, 100 B peak memory, 3.0 MiB transferred");
+
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg("i=0; while [ $i -lt 20000 ]; do i=$((i + 1)); done; sleep 0.5")
+        .spawn()
+        .unwrap();
+    let monitor = UsageMonitor::start(child.id());
+    child.wait().await.unwrap();
+    let usage = monitor.finish(42);
+    assert_eq!(usage.transferred, 42);
+    if std::path::Path::new("/proc/self/stat").exists() {
+        assert!(usage.peak_rss > 0);
+    }
+}
@@ -0,0 +1,165 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Enforcement of the `allowedDeployers` setting.
+//!
+//! A profile with `allowedDeployers` set can only be deployed by one of the listed local users or
+//! by someone with one of the listed SSH keys loaded in their agent, unless
+//! `--override-restrictions` is passed, which is recorded in an audit log.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::trace;
+
+/// Who is running the deployment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    /// Fingerprints of the keys loaded in the SSH agent, e.g. `SHA256:...`
+    pub key_fingerprints: Vec<String>,
+}
+
+impl Identity {
+    /// The local user and the keys in their SSH agent (if one is running)
+    pub async fn current() -> Self {
+        let key_fingerprints = match trace::output(
+            Command::new("ssh-add")
+                .arg("-l")
+                .stdin(Stdio::null()),
+        )
+        .await
+        {
+            Ok(output) if output.status.success() => {
+                parse_fingerprints(&String::from_utf8_lossy(&output.stdout))
+            }
+            Ok(output) => {
+                debug!("No SSH agent keys to check restrictions against: {:?}", output.status.code());
+                Vec::new()
+            }
+            Err(e) => {
+                debug!("Failed to run `ssh-add -l` to check restrictions: {}", e);
+                Vec::new()
+            }
+        };
+
+        Identity {
+            user: crate::local_username().unwrap_or_default(),
+            key_fingerprints,
+        }
+    }
+
+    /// Whether any of `allowed_deployers` (user names or key fingerprints) matches this identity
+    pub fn is_allowed(&self, allowed_deployers: &[String]) -> bool {
+        allowed_deployers
+            .iter()
+            .any(|allowed| allowed == &self.user || self.key_fingerprints.contains(allowed))
+    }
+}
+
+/// Extracts the fingerprints from the output of `ssh-add -l` (`<bits> <fingerprint> <comment> (<type>)`)
+fn parse_fingerprints(ssh_add_output: &str) -> Vec<String> {
+    ssh_add_output
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Error, Debug)]
+pub enum RestrictionError {
+    #[error("User {0} is not allowed to deploy profile {1} of node {2} (allowed deployers: {3}), use --override-restrictions to deploy anyway")]
+    NotAllowed(String, String, String, String),
+    #[error("Failed to write the audit log entry for overriding restrictions to {0}: {1}")]
+    AuditLog(PathBuf, std::io::Error),
+}
+
+fn audit_log_path() -> PathBuf {
+    crate::state::path("audit.log")
+}
+
+/// Checks whether `identity` may deploy the profile, recording overrides in the audit log
+pub fn check(
+    identity: &Identity,
+    allowed_deployers: &[String],
+    node_name: &str,
+    profile_name: &str,
+    override_restrictions: bool,
+) -> Result<(), RestrictionError> {
+    if identity.is_allowed(allowed_deployers) {
+        return Ok(());
+    }
+
+    if !override_restrictions {
+        return Err(RestrictionError::NotAllowed(
+            identity.user.clone(),
+            profile_name.to_string(),
+            node_name.to_string(),
+            allowed_deployers.join(", "),
+        ));
+    }
+
+    let path = audit_log_path();
+    warn!(
+        "User {} is overriding the deployment restrictions of profile {} of node {}, recording this in {}",
+        identity.user,
+        profile_name,
+        node_name,
+        path.display()
+    );
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let entry = format!(
+        "{} user={} keys={} node={} profile={} allowed={} override-restrictions\n",
+        timestamp,
+        identity.user,
+        identity.key_fingerprints.join(","),
+        node_name,
+        profile_name,
+        allowed_deployers.join(",")
+    );
+
+    std::fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+        })
+        .and_then(|mut file| file.write_all(entry.as_bytes()))
+        .map_err(|e| RestrictionError::AuditLog(path, e))
+}
+
+#[test]
+fn test_is_allowed() {
+    let identity = Identity {
+        user: "alice".to_string(),
+        key_fingerprints: parse_fingerprints(
+            "256 SHA256:Qx1pJ8Yf0X4ph2jCN9lQ4WqVJgqJXtVxq0sxtgkLZqA alice@laptop (ED25519)\n\
+             3072 SHA256:7d1fuE5RkV4pBqB1Xa1MkdDhdyJ0kSX4oRj8u0xLr2E ci (RSA)\n",
+        ),
+    };
+
+    assert_eq!(
+        identity.key_fingerprints,
+        vec![
+            "SHA256:Qx1pJ8Yf0X4ph2jCN9lQ4WqVJgqJXtVxq0sxtgkLZqA",
+            "SHA256:7d1fuE5RkV4pBqB1Xa1MkdDhdyJ0kSX4oRj8u0xLr2E"
+        ]
+    );
+
+    assert!(identity.is_allowed(&["bob".to_string(), "alice".to_string()]));
+    assert!(identity.is_allowed(&["SHA256:7d1fuE5RkV4pBqB1Xa1MkdDhdyJ0kSX4oRj8u0xLr2E".to_string()]));
+    assert!(!identity.is_allowed(&["bob".to_string()]));
+    assert!(!identity.is_allowed(&[]));
+}
@@ -0,0 +1,255 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Rolling a profile back to one of its earlier generations from the deploying machine
+//! (`deploy rollback`).
+//!
+//! The generations are listed and switched to by the `activate-rs` of the closure the profile
+//! currently points to, as the closure in the flake may not be on the node (or may be the broken
+//! one). The closure diff is computed on the node, where both closures are.
+
+use log::debug;
+use thiserror::Error;
+
+use crate::deploy::{build_current_closure_command, handle_sudo_stdin, in_container, node_command, shell_quote};
+use crate::events::{wait_with_output_events, SpawnWithEvents};
+use crate::status::Generation;
+use crate::trace;
+use crate::{DeployData, DeployDataDefsError, DeployDefs, ProfileInfo};
+
+#[derive(Error, Debug)]
+pub enum RollbackError {
+    #[error("Deployment data invalid: {0}")]
+    InvalidDeployDataDefs(#[from] DeployDataDefsError),
+    #[error("Failed to list the generations of the profile over SSH: {0}")]
+    ListSsh(std::io::Error),
+    #[error("Listing the generations of the profile resulted in a bad exit code (is it deployed?): {0:?}")]
+    ListExit(Option<i32>),
+    #[error("Failed to parse the generations of the profile: {0}")]
+    ListParse(#[from] serde_json::Error),
+    #[error("Failed to diff the closures over SSH: {0}")]
+    DiffSsh(std::io::Error),
+    #[error("Failed to run the rollback over SSH: {0}")]
+    RollbackSsh(std::io::Error),
+    #[error("Rolling back over SSH resulted in a bad exit code: {0:?}")]
+    RollbackExit(Option<i32>),
+}
+
+fn profile_args(profile_info: &ProfileInfo) -> String {
+    match profile_info {
+        ProfileInfo::ProfilePath { profile_path } => {
+            format!("--profile-path {}", shell_quote(profile_path))
+        }
+        ProfileInfo::ProfileUserAndName {
+            profile_user,
+            profile_name,
+        } => format!(
+            "--profile-user {} --profile-name {}",
+            profile_user, profile_name
+        ),
+    }
+}
+
+/// Runs `activate-rs <args>` of the closure the profile currently points to
+fn build_current_activate_command(
+    profile_info: &ProfileInfo,
+    sudo: &Option<String>,
+    args: &str,
+) -> String {
+    let activate = match sudo {
+        Some(sudo_cmd) => format!("{} \"$current/activate-rs\"", sudo_cmd),
+        None => "\"$current/activate-rs\"".to_string(),
+    };
+    format!(
+        "current=$({}) && {} {} {}",
+        build_current_closure_command(profile_info),
+        activate,
+        args,
+        profile_args(profile_info)
+    )
+}
+
+/// The generations of the profile on the node, oldest first
+pub async fn list_generations(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+) -> Result<Vec<Generation>, RollbackError> {
+    // Profiles are readable by everyone
+    let list_command = build_current_activate_command(&deploy_data.get_profile_info()?, &None, "list");
+    debug!("Constructed list command: {}", list_command);
+
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, list_command))
+            .stdin(std::process::Stdio::null()),
+    )
+    .await
+    .map_err(RollbackError::ListSsh)?;
+    match output.status.code() {
+        Some(0) => (),
+        a => return Err(RollbackError::ListExit(a)),
+    };
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+/// The differences between the closures `from` and `to` on the node, as shown by
+/// `nix store diff-closures`
+pub async fn diff_closures(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    from: &str,
+    to: &str,
+) -> Result<String, RollbackError> {
+    let diff_command = format!(
+        "nix --extra-experimental-features nix-command store diff-closures {} {}",
+        shell_quote(from),
+        shell_quote(to)
+    );
+
+    let output = trace::output(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, diff_command))
+            .stdin(std::process::Stdio::null()),
+    )
+    .await
+    .map_err(RollbackError::DiffSsh)?;
+
+    let diff = String::from_utf8_lossy(&output.stdout).into_owned();
+    match (output.status.success(), diff.trim().is_empty()) {
+        (true, true) => Ok("no differences".to_string()),
+        (true, false) => Ok(diff),
+        (false, _) => Ok(format!(
+            "not available: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Switches the profile to `generation` and activates it
+pub async fn rollback(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    generation: u64,
+) -> Result<(), RollbackError> {
+    let mut args = format!("rollback --generation {}", generation);
+    if let Some(profile_engine) = deploy_data.merged_settings.profile_engine {
+        args = format!("{} --profile-engine {}", args, profile_engine.as_str());
+    }
+    // Activated the way deploying the profile activates it
+    let profile_settings = &deploy_data.profile.profile_settings;
+    if let Some(specialisation) = &profile_settings.specialisation {
+        args = format!("{} --specialisation {}", args, shell_quote(specialisation));
+    }
+    if let Some(working_dir) = &profile_settings.working_dir {
+        args = format!("{} --working-dir {}", args, shell_quote(working_dir));
+    }
+    if deploy_data.merged_settings.require_signed_manifest.unwrap_or(false) {
+        args = format!(
+            "{} --require-signed-manifest --temp-path {}",
+            args,
+            shell_quote(&deploy_defs.temp_path.to_string_lossy())
+        );
+    }
+    if let Some(signature) = &deploy_defs.manifest_signature {
+        args = format!("{} --manifest-signature {}", args, shell_quote(signature));
+    }
+    let rollback_command =
+        build_current_activate_command(&deploy_data.get_profile_info()?, &deploy_defs.sudo, &args);
+    debug!("Constructed rollback command: {}", rollback_command);
+
+    let mut child = node_command(deploy_data, deploy_defs)
+        .arg(in_container(deploy_defs, rollback_command))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn_with_events(deploy_data.node_name, deploy_data.profile_name)
+        .map_err(RollbackError::RollbackSsh)?;
+
+    if deploy_data.merged_settings.interactive_sudo.unwrap_or(false) {
+        handle_sudo_stdin(&mut child, deploy_defs)
+            .await
+            .map_err(RollbackError::RollbackSsh)?;
+    }
+
+    let output = wait_with_output_events(child, deploy_data.node_name, deploy_data.profile_name)
+        .await
+        .map_err(RollbackError::RollbackSsh)?;
+    match output.status.code() {
+        Some(0) => Ok(()),
+        a => Err(RollbackError::RollbackExit(a)),
+    }
+}
+
+/// A line describing `generation` in the list to choose from
+pub fn describe(generation: &Generation) -> String {
+    use chrono::TimeZone;
+
+    let date = generation
+        .date
+        .and_then(|date| chrono::Local.timestamp_opt(date as i64, 0).single())
+        .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "unknown date".to_string());
+
+    format!(
+        "{:>4}  {}  {}  {}{}",
+        generation.number,
+        date,
+        generation.label.as_deref().unwrap_or("-"),
+        generation.path,
+        if generation.current { "  (current)" } else { "" }
+    )
+}
+
+/// The generation chosen with `input` (its number), unless it is the current one
+pub fn parse_choice<'a>(input: &str, generations: &'a [Generation]) -> Option<&'a Generation> {
+    let number: u64 = input.trim().parse().ok()?;
+    generations
+        .iter()
+        .find(|g| g.number == number && !g.current)
+}
+
+#[test]
+fn test_rollback() {
+    let profile_info = ProfileInfo::ProfileUserAndName {
+        profile_user: "root".to_string(),
+        profile_name: "system".to_string(),
+    };
+    assert_eq!(
+        build_current_activate_command(
+            &profile_info,
+            &Some("sudo -u root".to_string()),
+            "rollback --generation 41"
+        ),
+        "current=$(readlink -f /nix/var/nix/profiles/system) && sudo -u root \"$current/activate-rs\" rollback --generation 41 --profile-user root --profile-name system"
+    );
+
+    let generations = vec![
+        Generation {
+            number: 41,
+            path: "/nix/store/aaaa-system".to_string(),
+            current: false,
+            label: Some("v1.2.0".to_string()),
+            date: None,
+        },
+        Generation {
+            number: 42,
+            path: "/nix/store/bbbb-system".to_string(),
+            current: true,
+            label: None,
+            date: None,
+        },
+    ];
+    assert_eq!(
+        describe(&generations[0]),
+        "  41  unknown date  v1.2.0  /nix/store/aaaa-system"
+    );
+    assert_eq!(
+        describe(&generations[1]),
+        "  42  unknown date  -  /nix/store/bbbb-system  (current)"
+    );
+    assert_eq!(parse_choice(" 41\n", &generations).map(|g| g.number), Some(41));
+    assert_eq!(parse_choice("42", &generations), None);
+    assert_eq!(parse_choice("v1.2.0", &generations), None);
+}
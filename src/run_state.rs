@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The state of the last deployment, for `deploy --retry-failed`.
+//!
+//! After every deployment, the targets and the profiles that weren't deployed (because they
+//! failed, were rolled back or weren't gotten to) are recorded with their closures. Retrying
+//! deploys just those profiles again, without building the ones whose closure is unchanged and
+//! still in the local store. A copy of each is kept in the deployment history (see
+//! [`crate::state`]), which also tells the closure each profile was last deployed with, for
+//! `deploy --changed-only`.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::summary::{Outcome, Summary};
+
+pub const RUN_STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedProfile {
+    pub node: String,
+    pub profile: String,
+    /// The closure that was going to be deployed
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RunState {
+    pub version: u32,
+    pub run_id: String,
+    /// The targets the deployment was run with
+    pub targets: Vec<String>,
+    pub failed: Vec<RecordedProfile>,
+    /// The profiles that were deployed, for `deploy --changed-only`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deployed: Vec<RecordedProfile>,
+    /// The address deployed to instead of the node's hostname (`--hostname`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_host: Option<String>,
+    /// Whether the host key of `target_host` went unchecked
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skip_host_key_check: bool,
+    /// Everything the deployment was going to deploy, for `deploy plan --against`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plan: Vec<crate::plan_diff::PlannedProfile>,
+}
+
+#[derive(Error, Debug)]
+pub enum RunStateError {
+    #[error("Failed to read the state of the last deployment from {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the state of the last deployment: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported state version {0} (expected {})", RUN_STATE_VERSION)]
+    Version(u32),
+    #[error("Failed to write the state of the deployment to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("The last deployment (run {0}) deployed all profiles, nothing to retry")]
+    NothingToRetry(String),
+}
+
+pub fn state_path() -> PathBuf {
+    crate::state::path("last-run.json")
+}
+
+impl RunState {
+    /// The state after a deployment of `targets`, with the closures deployed by profile in
+    /// `paths`
+    pub fn new(
+        targets: Vec<String>,
+        summary: &Summary,
+        paths: &[(String, String, String)],
+    ) -> Self {
+        let recorded = |outcome: fn(&Outcome) -> bool| {
+            summary
+                .entries
+                .iter()
+                .filter(|e| outcome(&e.outcome))
+                .filter_map(|e| {
+                    paths
+                        .iter()
+                        .find(|(node, profile, _)| *node == e.node && *profile == e.profile)
+                        .map(|(node, profile, path)| RecordedProfile {
+                            node: node.clone(),
+                            profile: profile.clone(),
+                            path: path.clone(),
+                        })
+                })
+                .collect()
+        };
+        let failed = recorded(|outcome| !matches!(outcome, Outcome::Succeeded | Outcome::Skipped));
+        let deployed = recorded(|outcome| matches!(outcome, Outcome::Succeeded));
+
+        RunState {
+            version: RUN_STATE_VERSION,
+            run_id: crate::run_id().to_string(),
+            targets,
+            failed,
+            deployed,
+            target_host: None,
+            skip_host_key_check: false,
+            plan: Vec::new(),
+        }
+    }
+
+    /// Loads the state of the last deployment, failing if all of its profiles were deployed
+    pub fn load() -> Result<Self, RunStateError> {
+        let path = state_path();
+        let state: RunState = serde_json::from_str(
+            &std::fs::read_to_string(&path).map_err(|e| RunStateError::Read(path, e))?,
+        )?;
+
+        if state.version != RUN_STATE_VERSION {
+            return Err(RunStateError::Version(state.version));
+        }
+        if state.failed.is_empty() {
+            return Err(RunStateError::NothingToRetry(state.run_id));
+        }
+
+        Ok(state)
+    }
+
+    /// Saves the state as the one of the last deployment, and in the deployment history
+    pub fn save(&self) -> Result<(), RunStateError> {
+        let content = serde_json::to_string_pretty(self)?;
+
+        for path in [
+            state_path(),
+            crate::state::history_dir().join(format!("{}.json", self.run_id)),
+        ] {
+            crate::state::write(&path, &content).map_err(|e| RunStateError::Write(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn entry(&self, node: &str, profile: &str) -> Option<&RecordedProfile> {
+        self.failed
+            .iter()
+            .find(|f| f.node == node && f.profile == profile)
+    }
+}
+
+/// The closure each profile was last deployed with by the runs in `states` (oldest first), by
+/// node and profile
+fn deployed_closures(states: &[RunState]) -> BTreeMap<(String, String), String> {
+    states
+        .iter()
+        .flat_map(|state| &state.deployed)
+        .map(|p| ((p.node.clone(), p.profile.clone()), p.path.clone()))
+        .collect()
+}
+
+/// The closure each profile was last deployed with according to the deployment history, by node
+/// and profile. Entries that can't be read, like those of older versions, are left out.
+pub fn last_deployed() -> BTreeMap<(String, String), String> {
+    let entries = match crate::state::history_entries() {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{}", e);
+            return BTreeMap::new();
+        }
+    };
+
+    let states: Vec<RunState> = entries
+        .iter()
+        .filter_map(|entry| std::fs::read_to_string(&entry.path).ok())
+        .filter_map(|content| serde_json::from_str::<RunState>(&content).ok())
+        .filter(|state| state.version == RUN_STATE_VERSION)
+        .collect();
+
+    deployed_closures(&states)
+}
+
+#[test]
+fn test_run_state() {
+    let mut summary = Summary::new();
+    summary.add("web1", "system");
+    summary.add("web2", "system");
+    summary.add("web3", "system");
+    summary.add("db", "system");
+    summary.set("web1", "system", Outcome::Succeeded, None);
+    summary.set("web2", "system", Outcome::Failed, None);
+    summary.set("web3", "system", Outcome::Skipped, None);
+
+    let paths: Vec<(String, String, String)> = ["web1", "web2", "web3", "db"]
+        .iter()
+        .map(|node| {
+            (
+                node.to_string(),
+                "system".to_string(),
+                format!("/nix/store/{}", node),
+            )
+        })
+        .collect();
+
+    let state = RunState::new(vec![".#".to_string()], &summary, &paths);
+    assert_eq!(
+        state.failed,
+        vec![
+            RecordedProfile {
+                node: "web2".to_string(),
+                profile: "system".to_string(),
+                path: "/nix/store/web2".to_string(),
+            },
+            RecordedProfile {
+                node: "db".to_string(),
+                profile: "system".to_string(),
+                path: "/nix/store/db".to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        state.entry("db", "system").map(|f| f.path.as_str()),
+        Some("/nix/store/db")
+    );
+    assert_eq!(state.entry("web1", "system"), None);
+    assert_eq!(
+        state.deployed,
+        vec![RecordedProfile {
+            node: "web1".to_string(),
+            profile: "system".to_string(),
+            path: "/nix/store/web1".to_string(),
+        }]
+    );
+
+    let mut later = state.clone();
+    later.deployed[0].path = "/nix/store/web1-new".to_string();
+    later.deployed.push(RecordedProfile {
+        node: "db".to_string(),
+        profile: "system".to_string(),
+        path: "/nix/store/db".to_string(),
+    });
+    let closures = deployed_closures(&[state.clone(), later]);
+    assert_eq!(
+        closures.get(&("web1".to_string(), "system".to_string())).map(String::as_str),
+        Some("/nix/store/web1-new")
+    );
+    assert_eq!(closures.len(), 2);
+
+    let json = serde_json::to_string(&state).unwrap();
+    assert!(json.contains("\"runId\""));
+    assert_eq!(serde_json::from_str::<RunState>(&json).unwrap(), state);
+}
@@ -0,0 +1,209 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Pushes scheduled for later, e.g. to copy big closures to the nodes off-peak.
+//!
+//! `deploy schedule push --at 03:00 <flake>` records a job, which `deploy schedule run` (meant to
+//! be kept running, e.g. as a systemd user service) runs as `deploy --push-only <flake>` once it
+//! is due. The profiles are then activated with a regular deployment later on, which doesn't
+//! need to copy anything anymore.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, NaiveDateTime, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const SCHEDULE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledPush {
+    pub id: String,
+    /// When to push, in seconds since the Unix epoch
+    pub at: i64,
+    pub targets: Vec<String>,
+    /// The directory `deploy schedule push` was run in, relative targets are resolved from there
+    pub working_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub version: u32,
+    pub jobs: Vec<ScheduledPush>,
+}
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("Invalid time `{0}`, expected `HH:MM` or `YYYY-MM-DD HH:MM`")]
+    InvalidTime(String),
+    #[error("The time `{0}` doesn't exist in the local time zone")]
+    NonexistentTime(String),
+    #[error("Failed to read the schedule from {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse the schedule: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Unsupported schedule version {0} (expected {})", SCHEDULE_VERSION)]
+    Version(u32),
+    #[error("Failed to write the schedule to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+    #[error("No scheduled push with id {0}")]
+    NotFound(String),
+}
+
+pub fn schedule_path() -> PathBuf {
+    crate::state::path("schedule.json")
+}
+
+/// Parses `at`, either a time of day (the next time it comes around after `now`) or a date and
+/// time, in the local time zone
+pub fn parse_at(at: &str, now: DateTime<Local>) -> Result<DateTime<Local>, ScheduleError> {
+    let naive = match NaiveTime::parse_from_str(at, "%H:%M") {
+        Ok(time) => {
+            let today = NaiveDateTime::new(now.naive_local().date(), time);
+            match today > now.naive_local() {
+                true => today,
+                false => today + chrono::Duration::days(1),
+            }
+        }
+        Err(_) => NaiveDateTime::parse_from_str(at, "%Y-%m-%d %H:%M")
+            .map_err(|_| ScheduleError::InvalidTime(at.to_string()))?,
+    };
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| ScheduleError::NonexistentTime(at.to_string()))
+}
+
+/// Formats a job's time for humans, in the local time zone
+pub fn format_at(at: i64) -> String {
+    match Local.timestamp_opt(at, 0).earliest() {
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => at.to_string(),
+    }
+}
+
+impl Schedule {
+    /// Loads the schedule at `path`, empty if there is none yet
+    pub fn load(path: &Path) -> Result<Self, ScheduleError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Schedule {
+                    version: SCHEDULE_VERSION,
+                    jobs: Vec::new(),
+                })
+            }
+            Err(e) => return Err(ScheduleError::Read(path.to_path_buf(), e)),
+        };
+
+        let schedule: Schedule = serde_json::from_str(&content)?;
+        if schedule.version != SCHEDULE_VERSION {
+            return Err(ScheduleError::Version(schedule.version));
+        }
+
+        Ok(schedule)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ScheduleError> {
+        let write = || {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, serde_json::to_string_pretty(self)?)
+        };
+
+        write().map_err(|e| ScheduleError::Write(path.to_path_buf(), e))
+    }
+
+    /// Adds a push of `targets` at `at`, returning its id
+    pub fn add(
+        &mut self,
+        at: DateTime<Local>,
+        targets: Vec<String>,
+        working_dir: PathBuf,
+    ) -> String {
+        let mut bytes = [0u8; 4];
+        if getrandom::getrandom(&mut bytes).is_err() {
+            bytes = (at.timestamp() as u32 ^ self.jobs.len() as u32).to_le_bytes();
+        }
+        let id: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        self.jobs.push(ScheduledPush {
+            id: id.clone(),
+            at: at.timestamp(),
+            targets,
+            working_dir,
+        });
+        self.jobs.sort_by_key(|j| j.at);
+
+        id
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<ScheduledPush, ScheduleError> {
+        match self.jobs.iter().position(|j| j.id == id) {
+            Some(i) => Ok(self.jobs.remove(i)),
+            None => Err(ScheduleError::NotFound(id.to_string())),
+        }
+    }
+
+    /// The job to run next, if any
+    pub fn next(&self) -> Option<&ScheduledPush> {
+        self.jobs.iter().min_by_key(|j| j.at)
+    }
+}
+
+#[test]
+fn test_schedule() {
+    let now = Local
+        .from_local_datetime(
+            &NaiveDateTime::parse_from_str("2021-06-01 12:00", "%Y-%m-%d %H:%M").unwrap(),
+        )
+        .earliest()
+        .unwrap();
+
+    let format = |t: DateTime<Local>| t.format("%Y-%m-%d %H:%M").to_string();
+    assert_eq!(format(parse_at("13:30", now).unwrap()), "2021-06-01 13:30");
+    assert_eq!(format(parse_at("03:00", now).unwrap()), "2021-06-02 03:00");
+    assert_eq!(
+        format(parse_at("2021-07-01 03:00", now).unwrap()),
+        "2021-07-01 03:00"
+    );
+    assert!(matches!(
+        parse_at("3am", now),
+        Err(ScheduleError::InvalidTime(_))
+    ));
+
+    let mut schedule = Schedule {
+        version: SCHEDULE_VERSION,
+        jobs: Vec::new(),
+    };
+    let later = schedule.add(
+        parse_at("03:00", now).unwrap(),
+        vec![".#web".to_string()],
+        PathBuf::from("/"),
+    );
+    let sooner = schedule.add(
+        parse_at("13:30", now).unwrap(),
+        vec![".#db".to_string()],
+        PathBuf::from("/"),
+    );
+    assert_ne!(later, sooner);
+    assert_eq!(
+        schedule.next().map(|j| j.id.as_str()),
+        Some(sooner.as_str())
+    );
+    assert_eq!(format_at(schedule.jobs[1].at), "2021-06-02 03:00");
+
+    assert_eq!(
+        schedule.remove(&sooner).unwrap().targets,
+        vec![".#db".to_string()]
+    );
+    assert!(matches!(
+        schedule.remove(&sooner),
+        Err(ScheduleError::NotFound(_))
+    ));
+    assert_eq!(schedule.next().map(|j| j.id.as_str()), Some(later.as_str()));
+}
@@ -0,0 +1,181 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Settings read from secret providers at deploy time, so that tokens don't have to live in the
+//! repository.
+//!
+//! Any string in the deployment data (but `sshUser`) may be given as `{ fromEnv = "VAR"; }`, read
+//! from the environment of deploy-rs, or as `{ fromCommand = "..."; }`, the output of a shell
+//! command run on the deploying machine (e.g. `pass show deploy/token`), without the trailing
+//! newline. They are resolved in the evaluated data before it is read, each command only once,
+//! and the values are masked in all output.
+
+use std::collections::HashMap;
+
+use log::debug;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::process::Command;
+
+use crate::trace;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SecretRef {
+    Env(String),
+    Command(String),
+}
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("`sshUser` can't be read from a secret provider (at {0})")]
+    SshUser(String),
+    #[error("Failed to read the secret at {0} from environment variable `{1}`: {2}")]
+    Env(String, String, std::env::VarError),
+    #[error("Failed to run `{1}` for the secret at {0}: {2}")]
+    Command(String, String, std::io::Error),
+    #[error("Running `{1}` for the secret at {0} resulted in a bad exit code: {2:?}")]
+    CommandExit(String, String, Option<i32>),
+    #[error("The output of `{1}` for the secret at {0} isn't valid UTF-8")]
+    CommandUtf8(String, String),
+}
+
+/// The secret `value` refers to, if it is a `{ fromEnv }` or `{ fromCommand }` attribute set
+fn secret_ref(value: &Value) -> Option<SecretRef> {
+    let object = value.as_object().filter(|o| o.len() == 1)?;
+    match object.iter().next()? {
+        (key, Value::String(var)) if key == "fromEnv" => Some(SecretRef::Env(var.clone())),
+        (key, Value::String(command)) if key == "fromCommand" => {
+            Some(SecretRef::Command(command.clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Collects the secrets in `value` (found at the JSON pointer `pointer`), with their pointers
+fn find_refs(
+    value: &Value,
+    pointer: String,
+    refs: &mut Vec<(String, SecretRef)>,
+) -> Result<(), SecretError> {
+    if let Some(secret) = secret_ref(value) {
+        if pointer.ends_with("/sshUser") {
+            return Err(SecretError::SshUser(pointer));
+        }
+        refs.push((pointer, secret));
+        return Ok(());
+    }
+
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                find_refs(value, format!("{}/{}", pointer, key), refs)?;
+            }
+        }
+        Value::Array(array) => {
+            for (i, value) in array.iter().enumerate() {
+                find_refs(value, format!("{}/{}", pointer, i), refs)?;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+async fn read_secret(pointer: &str, secret: &SecretRef) -> Result<String, SecretError> {
+    match secret {
+        SecretRef::Env(var) => std::env::var(var)
+            .map_err(|e| SecretError::Env(pointer.to_string(), var.clone(), e)),
+        SecretRef::Command(command) => {
+            debug!("Running `{}` for the secret at {}", command, pointer);
+            let output = trace::output(
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(std::process::Stdio::null())
+                    .stderr(std::process::Stdio::inherit()),
+            )
+            .await
+            .map_err(|e| SecretError::Command(pointer.to_string(), command.clone(), e))?;
+            match output.status.code() {
+                Some(0) => (),
+                a => {
+                    return Err(SecretError::CommandExit(
+                        pointer.to_string(),
+                        command.clone(),
+                        a,
+                    ))
+                }
+            };
+            let mut value = String::from_utf8(output.stdout)
+                .map_err(|_| SecretError::CommandUtf8(pointer.to_string(), command.clone()))?;
+            if value.ends_with('\n') {
+                value.pop();
+            }
+            Ok(value)
+        }
+    }
+}
+
+/// Replaces the secrets in the evaluated deployment data `data` with their values
+pub async fn resolve(data: &mut Value) -> Result<(), SecretError> {
+    let mut refs = Vec::new();
+    find_refs(data, String::new(), &mut refs)?;
+
+    let mut resolved: HashMap<SecretRef, String> = HashMap::new();
+    for (pointer, secret) in refs {
+        let value = match resolved.get(&secret) {
+            Some(value) => value.clone(),
+            None => {
+                let value = read_secret(&pointer, &secret).await?;
+                crate::redact::register_secret(&value);
+                resolved.insert(secret, value.clone());
+                value
+            }
+        };
+        if let Some(slot) = data.pointer_mut(&pointer) {
+            *slot = Value::String(value);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolve() {
+    std::env::set_var("DEPLOY_RS_TEST_SECRET_TOKEN", "s3cr3t-test-token");
+
+    let mut data = serde_json::json!({
+        "approvalCommand": { "fromEnv": "DEPLOY_RS_TEST_SECRET_TOKEN" },
+        "nodes": {
+            "web/1": {
+                "hostname": { "fromCommand": "echo secret-resolver.test" },
+                "sshOpts": ["-i", { "fromCommand": "printf /run/keys/test-deploy" }],
+                "profiles": { "system": { "path": "/nix/store/aaaa" } },
+            },
+        },
+    });
+    resolve(&mut data).await.unwrap();
+
+    assert_eq!(
+        data,
+        serde_json::json!({
+            "approvalCommand": "s3cr3t-test-token",
+            "nodes": {
+                "web/1": {
+                    "hostname": "secret-resolver.test",
+                    "sshOpts": ["-i", "/run/keys/test-deploy"],
+                    "profiles": { "system": { "path": "/nix/store/aaaa" } },
+                },
+            },
+        })
+    );
+
+    let mut data = serde_json::json!({ "nodes": { "web": { "sshUser": { "fromEnv": "USER" } } } });
+    assert!(matches!(
+        resolve(&mut data).await,
+        Err(SecretError::SshUser(pointer)) if pointer == "/nodes/web/sshUser"
+    ));
+}
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Activating on nodes enforcing SELinux or AppArmor (`securityModule`).
+//!
+//! Before the activation script runs, the new store paths are labelled with `restorecon` (on
+//! SELinux, where paths written by Nix otherwise keep the label of the store). Afterwards, the
+//! denials logged since the activation started (found with `ausearch`, or in the kernel log if
+//! auditd isn't running) are reported, and fail the activation with `failOnDenials`.
+
+use std::process::Stdio;
+
+use log::debug;
+use tokio::process::Command;
+
+use crate::data::SecurityModule;
+
+/// Labels the store paths of the closure of `closure` according to the SELinux policy, returning
+/// whether `restorecon` succeeded
+pub async fn restore_contexts(closure: &str) -> Result<bool, std::io::Error> {
+    let requisites = Command::new("nix-store")
+        .arg("--query")
+        .arg("--requisites")
+        .arg(closure)
+        .stderr(Stdio::inherit())
+        .output()
+        .await?;
+    if !requisites.status.success() {
+        return Ok(false);
+    }
+
+    let paths: Vec<String> = String::from_utf8_lossy(&requisites.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    debug!(
+        "Restoring the SELinux contexts of {} store path(s)",
+        paths.len()
+    );
+
+    let status = Command::new("restorecon")
+        .arg("-R")
+        .args(&paths)
+        .status()
+        .await?;
+    Ok(status.success())
+}
+
+/// The time of an audit record (`audit(<seconds>.<millis>:<serial>)`) in `line`, in seconds
+fn audit_time(line: &str) -> Option<u64> {
+    let start = line.find("audit(")? + "audit(".len();
+    line[start..].split('.').next()?.parse().ok()
+}
+
+fn is_denial(line: &str, module: SecurityModule) -> bool {
+    match module {
+        SecurityModule::Selinux => line.contains("avc:") && line.contains("denied"),
+        SecurityModule::Apparmor => line.contains("apparmor=\"DENIED\""),
+    }
+}
+
+/// The denials of `module` in `log` (audit records, one per line) logged at or after `since`
+pub fn parse_denials(log: &str, module: SecurityModule, since: u64) -> Vec<String> {
+    log.lines()
+        .filter(|line| is_denial(line, module))
+        .filter(|line| audit_time(line).is_some_and(|t| t >= since))
+        .map(|line| line.trim().to_string())
+        .collect()
+}
+
+/// The denials of `module` logged at or after `since` (in seconds since the epoch)
+pub async fn denials_since(module: SecurityModule, since: u64) -> Vec<String> {
+    let message_types = match module {
+        SecurityModule::Selinux => "AVC,USER_AVC",
+        SecurityModule::Apparmor => "AVC,APPARMOR_DENIED",
+    };
+    let ausearch = Command::new("ausearch")
+        .arg("--message")
+        .arg(message_types)
+        .arg("--start")
+        .arg("today")
+        .stderr(Stdio::null())
+        .output()
+        .await;
+
+    // `ausearch` exits with 1 if there is nothing to find
+    let log = match ausearch {
+        Ok(output) if output.status.code().is_some_and(|c| c <= 1) => output.stdout,
+        _ => {
+            debug!("ausearch isn't available, looking for denials in the kernel log");
+            match Command::new("journalctl")
+                .arg("--dmesg")
+                .arg("--output")
+                .arg("cat")
+                .arg("--since")
+                .arg(format!("@{}", since))
+                .stderr(Stdio::null())
+                .output()
+                .await
+            {
+                Ok(output) => output.stdout,
+                Err(_) => return Vec::new(),
+            }
+        }
+    };
+
+    parse_denials(&String::from_utf8_lossy(&log), module, since)
+}
+
+#[test]
+fn test_parse_denials() {
+    let log = r#"
+----
+time->Mon Oct 16 10:00:01 2023
+type=AVC msg=audit(1697450401.123:412): avc:  denied  { read } for  pid=1234 comm="nginx" name="nginx.conf" scontext=system_u:system_r:httpd_t:s0 tcontext=unconfined_u:object_r:default_t:s0 tclass=file permissive=0
+type=AVC msg=audit(1697450000.001:400): avc:  denied  { write } for  pid=99 comm="old" tclass=file
+type=AVC msg=audit(1697450402.000:413): avc:  granted  { setenforce } for  pid=1 comm="init"
+audit: type=1400 audit(1697450403.500:414): apparmor="DENIED" operation="open" profile="nginx" name="/nix/store/abcd-nginx/conf" pid=1234 comm="nginx"
+"#;
+
+    let selinux = parse_denials(log, SecurityModule::Selinux, 1697450400);
+    assert_eq!(selinux.len(), 1);
+    assert!(selinux[0].contains("comm=\"nginx\" name=\"nginx.conf\""));
+
+    let apparmor = parse_denials(log, SecurityModule::Apparmor, 1697450400);
+    assert_eq!(apparmor.len(), 1);
+    assert!(apparmor[0].contains("operation=\"open\""));
+
+    assert!(parse_denials(log, SecurityModule::Apparmor, 1697450404).is_empty());
+}
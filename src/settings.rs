@@ -0,0 +1,710 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! How the settings a profile is deployed with are merged from the layers they can be given in.
+//!
+//! From most to least important, these are: the command line, the node in the selected
+//! environment, the environment, the profile, the node and the top level of the deployment data.
+//! A setting is taken from the most important layer setting it, except for `sshOpts`, which are
+//! concatenated (most important first), and `nixOptions`, which are merged option by option. The
+//! hostname is the one given on the command line, by the node in the environment or by the node.
+//!
+//! SSH options in the environment of deploy-rs (`NIX_SSHOPTS`, then `SSH_OPTS`) come before the
+//! `sshOpts` of the deployment, unless `--ssh-opts` replaces them all, and are used for `ssh` and
+//! `nix copy` alike.
+
+use merge::Merge;
+
+use crate::data::{Environment, FastConnection, GenericSettings, Node, Profile, Rollout};
+use crate::CmdOverrides;
+
+/// The settings of the profile and the hostname of its node, before the command line overrides
+pub fn merge_layers<'a>(
+    top_settings: &GenericSettings,
+    environment: Option<&'a Environment>,
+    node: &'a Node,
+    node_name: &str,
+    profile: &Profile,
+) -> (&'a str, GenericSettings) {
+    let mut merged_settings = profile.generic_settings.clone();
+    merged_settings.merge(node.generic_settings.clone());
+    merged_settings.merge(top_settings.clone());
+
+    let mut hostname = node.node_settings.hostname.as_str();
+
+    if let Some(environment) = environment {
+        let mut environment_settings = environment.generic_settings.clone();
+
+        if let Some(environment_node) = environment.nodes.get(node_name) {
+            if let Some(ref x) = environment_node.hostname {
+                hostname = x;
+            }
+
+            let mut node_settings = environment_node.generic_settings.clone();
+            node_settings.merge(environment_settings);
+            environment_settings = node_settings;
+        }
+
+        environment_settings.merge(merged_settings);
+        merged_settings = environment_settings;
+    }
+
+    (hostname, merged_settings)
+}
+
+/// Applies the settings given on the command line over `merged_settings`
+pub fn apply_overrides(merged_settings: &mut GenericSettings, cmd_overrides: &CmdOverrides) {
+    // build all machines remotely when the command line flag is set
+    if cmd_overrides.remote_build {
+        merged_settings.remote_build = Some(cmd_overrides.remote_build);
+    }
+    if cmd_overrides.ssh_user.is_some() {
+        merged_settings.ssh_user = cmd_overrides.ssh_user.clone();
+    }
+    if cmd_overrides.profile_user.is_some() {
+        merged_settings.user = cmd_overrides.profile_user.clone();
+    }
+    if let Some(ref ssh_opts) = cmd_overrides.ssh_opts {
+        merged_settings.ssh_opts = ssh_opts.clone();
+    } else if !cmd_overrides.env_ssh_opts.is_empty() {
+        merged_settings.ssh_opts = [
+            cmd_overrides.env_ssh_opts.clone(),
+            std::mem::take(&mut merged_settings.ssh_opts),
+        ]
+        .concat();
+    }
+    if cmd_overrides.jump_host.is_some() {
+        merged_settings.ssh_jump_host = cmd_overrides.jump_host.clone();
+    }
+    if cmd_overrides.skip_host_key_check {
+        merged_settings.ssh_opts.extend(
+            ["-o", "StrictHostKeyChecking=no", "-o", "UserKnownHostsFile=/dev/null"]
+                .iter()
+                .map(|x| x.to_string()),
+        );
+    }
+    if cmd_overrides.non_interactive {
+        // ssh fails instead of asking for passwords, passphrases or unknown host keys
+        merged_settings
+            .ssh_opts
+            .extend(["-o", "BatchMode=yes"].iter().map(|x| x.to_string()));
+    }
+    if let Some(fast_connection) = cmd_overrides.fast_connection {
+        merged_settings.fast_connection = Some(fast_connection);
+    }
+    if let Some(auto_rollback) = cmd_overrides.auto_rollback {
+        merged_settings.auto_rollback = Some(auto_rollback);
+    }
+    if let Some(magic_rollback) = cmd_overrides.magic_rollback {
+        merged_settings.magic_rollback = Some(magic_rollback);
+    }
+    if let Some(confirm_timeout) = cmd_overrides.confirm_timeout {
+        merged_settings.confirm_timeout = Some(confirm_timeout);
+    }
+    if let Some(activation_timeout) = cmd_overrides.activation_timeout {
+        merged_settings.activation_timeout = Some(activation_timeout);
+    }
+    if let Some(interactive_sudo) = cmd_overrides.interactive_sudo {
+        merged_settings.interactive_sudo = Some(interactive_sudo);
+    }
+    if let Some(max_unavailable) = cmd_overrides.max_unavailable {
+        merged_settings.rollout = Some(Rollout { max_unavailable });
+    }
+}
+
+/// The SSH options given in the environment (looked up with `var`): `NIX_SSHOPTS`, then `SSH_OPTS`
+pub fn env_ssh_opts(var: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    ["NIX_SSHOPTS", "SSH_OPTS"]
+        .iter()
+        .filter_map(|name| var(name))
+        .flat_map(|opts| {
+            // Unbalanced quotes are taken literally, as older versions of Nix do
+            shell_words::split(&opts).unwrap_or_else(|_| {
+                opts.split_whitespace()
+                    .map(str::to_string)
+                    .collect()
+            })
+        })
+        .collect()
+}
+
+/// The SSH options given with each `--ssh-opts`, split like a shell would, e.g.
+/// `-o ProxyCommand="ssh -W %h:%p bastion"` into `-o` and `ProxyCommand=ssh -W %h:%p bastion`
+pub fn split_ssh_opts(given: &[String]) -> Result<Vec<String>, (String, shell_words::ParseError)> {
+    let mut ssh_opts = Vec::new();
+    for opts in given {
+        ssh_opts.extend(shell_words::split(opts).map_err(|e| (opts.clone(), e))?);
+    }
+    Ok(ssh_opts)
+}
+
+/// The SSH options as a single string, with options containing spaces quoted so they are split
+/// back into the same options (by a shell, or by Nix reading `NIX_SSHOPTS`). The others are left
+/// as they are, since older versions of Nix split `NIX_SSHOPTS` on whitespace only and would pass
+/// quotes on to ssh
+pub fn join_ssh_opts(ssh_opts: &[String]) -> String {
+    ssh_opts
+        .iter()
+        .map(|opt| match opt.contains(char::is_whitespace) {
+            true => shell_words::quote(opt),
+            false => opt.into(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The host and SSH port of a hostname giving one, as `host:2222` or `[2001:db8::1]:2222`
+pub fn split_port(hostname: &str) -> (&str, Option<u16>) {
+    let (host, port) = match hostname.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once("]:") {
+            Some(split) => split,
+            None => return (hostname, None),
+        },
+        // Bare IPv6 addresses have several colons
+        None if hostname.matches(':').count() == 1 => hostname.split_once(':').unwrap(),
+        None => return (hostname, None),
+    };
+    match port.parse() {
+        Ok(port) => (host, Some(port)),
+        Err(_) => (hostname, None),
+    }
+}
+
+/// The hostname of the node and the settings of the profile, merged from all layers
+pub fn merge<'a>(
+    top_settings: &GenericSettings,
+    environment: Option<&'a Environment>,
+    node: &'a Node,
+    node_name: &str,
+    profile: &Profile,
+    cmd_overrides: &'a CmdOverrides,
+) -> (&'a str, GenericSettings) {
+    let (mut hostname, mut merged_settings) =
+        merge_layers(top_settings, environment, node, node_name, profile);
+
+    if let Some(ref x) = cmd_overrides.hostname {
+        hostname = x;
+    }
+    apply_overrides(&mut merged_settings, cmd_overrides);
+    // Given to ssh and to `nix copy` through `NIX_SSHOPTS` alike, as store URLs can't have ports
+    let (host, port) = split_port(hostname);
+    hostname = host;
+    if let Some(port) = port.or(node.node_settings.ssh_port) {
+        merged_settings.ssh_opts.splice(0..0, ["-p".to_string(), port.to_string()]);
+    }
+    if let Some(ref jump_host) = merged_settings.ssh_jump_host {
+        crate::data::add_jump_host(&mut merged_settings.ssh_opts, jump_host);
+    }
+
+    (hostname, merged_settings)
+}
+
+/// A setting the command line gives another value than the deployment data does
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    /// Name of the setting in the deployment data
+    pub setting: &'static str,
+    /// The flag overriding it
+    pub flag: &'static str,
+    /// The value from the deployment data, if it sets one
+    pub from: Option<String>,
+    pub to: String,
+}
+
+impl Override {
+    /// One line describing the override, highlighted with ANSI colors if `color`
+    pub fn describe(&self, color: bool) -> String {
+        let from = self.from.as_deref().unwrap_or("(unset)");
+        match color {
+            true => format!(
+                "\x1b[1m{}\x1b[0m: \x1b[31m{}\x1b[0m -> \x1b[32m{}\x1b[0m ({})",
+                self.setting, from, self.to, self.flag
+            ),
+            false => format!("{}: {} -> {} ({})", self.setting, from, self.to, self.flag),
+        }
+    }
+}
+
+fn fast_connection_str(fast_connection: &FastConnection) -> String {
+    match fast_connection {
+        FastConnection::Fast => "true",
+        FastConnection::Slow => "false",
+        FastConnection::Auto => "auto",
+    }
+    .to_string()
+}
+
+/// The settings of the profile the command line changes, see `--explain-overrides`
+pub fn overrides(
+    top_settings: &GenericSettings,
+    environment: Option<&Environment>,
+    node: &Node,
+    node_name: &str,
+    profile: &Profile,
+    cmd_overrides: &CmdOverrides,
+) -> Vec<Override> {
+    let (hostname, merged_settings) =
+        merge_layers(top_settings, environment, node, node_name, profile);
+    let mut overridden = merged_settings.clone();
+    apply_overrides(&mut overridden, cmd_overrides);
+
+    let mut overrides = Vec::new();
+    let mut compare = |setting, flag, from: Option<String>, to: Option<String>| {
+        if let Some(to) = to {
+            if from.as_ref() != Some(&to) {
+                overrides.push(Override {
+                    setting,
+                    flag,
+                    from,
+                    to,
+                });
+            }
+        }
+    };
+
+    compare(
+        "hostname",
+        "--hostname",
+        Some(hostname.to_string()),
+        cmd_overrides.hostname.clone(),
+    );
+    compare(
+        "sshUser",
+        "--ssh-user",
+        merged_settings.ssh_user.clone(),
+        overridden.ssh_user.clone(),
+    );
+    compare(
+        "user",
+        "--profile-user",
+        merged_settings.user.clone(),
+        overridden.user.clone(),
+    );
+    compare(
+        "sshOpts",
+        match cmd_overrides.ssh_opts {
+            Some(_) => "--ssh-opts",
+            None if !cmd_overrides.env_ssh_opts.is_empty() => "NIX_SSHOPTS/SSH_OPTS",
+            None if cmd_overrides.skip_host_key_check => "--skip-host-key-check",
+            None => "--non-interactive",
+        },
+        Some(join_ssh_opts(&merged_settings.ssh_opts)).filter(|x| !x.is_empty()),
+        Some(join_ssh_opts(&overridden.ssh_opts)).filter(|x| !x.is_empty()),
+    );
+    compare(
+        "sshJumpHost",
+        "--jump-host",
+        merged_settings.ssh_jump_host.clone(),
+        overridden.ssh_jump_host.clone(),
+    );
+    compare(
+        "fastConnection",
+        "--fast-connection",
+        merged_settings.fast_connection.as_ref().map(fast_connection_str),
+        overridden.fast_connection.as_ref().map(fast_connection_str),
+    );
+    let settings = [
+        (
+            "remoteBuild",
+            "--remote-build",
+            merged_settings.remote_build,
+            overridden.remote_build,
+        ),
+        (
+            "autoRollback",
+            "--auto-rollback",
+            merged_settings.auto_rollback,
+            overridden.auto_rollback,
+        ),
+        (
+            "magicRollback",
+            "--magic-rollback",
+            merged_settings.magic_rollback,
+            overridden.magic_rollback,
+        ),
+        (
+            "interactiveSudo",
+            "--interactive-sudo",
+            merged_settings.interactive_sudo,
+            overridden.interactive_sudo,
+        ),
+    ];
+    for (setting, flag, from, to) in settings {
+        compare(setting, flag, from.map(|x| x.to_string()), to.map(|x| x.to_string()));
+    }
+    compare(
+        "rollout.maxUnavailable",
+        "--max-unavailable",
+        merged_settings.rollout.as_ref().map(|x| x.max_unavailable.to_string()),
+        overridden.rollout.as_ref().map(|x| x.max_unavailable.to_string()),
+    );
+    let settings = [
+        (
+            "confirmTimeout",
+            "--confirm-timeout",
+            merged_settings.confirm_timeout,
+            overridden.confirm_timeout,
+        ),
+        (
+            "activationTimeout",
+            "--activation-timeout",
+            merged_settings.activation_timeout,
+            overridden.activation_timeout,
+        ),
+    ];
+    for (setting, flag, from, to) in settings {
+        compare(setting, flag, from.map(|x| x.to_string()), to.map(|x| x.to_string()));
+    }
+
+    overrides
+}
+
+/// The overrides of `profiles` (node name, profile name and overrides, in order) labelled with the
+/// node they apply to, once per node, or with the node and profile if they differ between the
+/// profiles of the node
+pub fn label_overrides<'a>(
+    profiles: &'a [(&str, &str, Vec<Override>)],
+) -> Vec<(String, &'a Override)> {
+    let mut labelled = Vec::new();
+
+    for (i, (node_name, profile_name, overrides)) in profiles.iter().enumerate() {
+        let node_profiles = profiles.iter().filter(|(n, _, _)| n == node_name);
+        let first_of_node = profiles[..i].iter().all(|(n, _, _)| n != node_name);
+        for o in overrides {
+            match node_profiles.clone().all(|(_, _, x)| x.contains(o)) {
+                true if first_of_node => labelled.push((node_name.to_string(), o)),
+                true => (),
+                false => labelled.push((format!("{}.{}", node_name, profile_name), o)),
+            }
+        }
+    }
+
+    labelled
+}
+
+#[test]
+fn test_merge() {
+    use crate::data::Data;
+    use serde_json::{json, Value};
+
+    // From most to least important
+    const LAYERS: [&str; 6] = ["cli", "environment_node", "environment", "profile", "node", "top"];
+
+    // Every combination of layers (a bit per layer) sets `settings(layer)`, or `cmd_overrides`
+    // for the command line
+    fn merge_layered(
+        layers: u32,
+        settings: impl Fn(&str) -> Value,
+        cmd_overrides: impl Fn() -> CmdOverrides,
+    ) -> (String, GenericSettings) {
+        let with = |layer: usize, mut value: Value| {
+            if layers & (1 << layer) != 0 {
+                let settings = settings(LAYERS[layer]);
+                let object = value.as_object_mut().unwrap();
+                object.extend(settings.as_object().unwrap().clone());
+            }
+            value
+        };
+
+        let data = with(
+            5,
+            json!({
+                "nodes": {
+                    "web1": with(4, json!({
+                        "hostname": "web1.example.com",
+                        "profiles": { "system": with(3, json!({ "path": "/nix/store/blah-system" })) },
+                    })),
+                },
+                "environments": {
+                    "staging": with(2, json!({ "nodes": { "web1": with(1, json!({})) } })),
+                },
+            }),
+        );
+        let data: Data = serde_json::from_value(data).unwrap();
+
+        let cmd_overrides = match layers & 1 != 0 {
+            true => cmd_overrides(),
+            false => CmdOverrides::default(),
+        };
+        let node = &data.nodes["web1"];
+        let (hostname, settings) = merge(
+            &data.generic_settings,
+            Some(&data.environments["staging"]),
+            node,
+            "web1",
+            &node.node_settings.profiles["system"],
+            &cmd_overrides,
+        );
+        (hostname.to_string(), settings)
+    }
+
+    for layers in 0..(1 << LAYERS.len()) {
+        let set: Vec<&str> = (0..LAYERS.len())
+            .filter(|layer| layers & (1 << layer) != 0)
+            .map(|layer| LAYERS[layer])
+            .collect();
+        let in_data: Vec<&str> = set.iter().copied().filter(|l| *l != "cli").collect();
+
+        // A setting is taken from the most important layer setting it
+        let (_, settings) = merge_layered(
+            layers,
+            |layer| json!({ "sshUser": layer, "confirmTimeout": layer.len() }),
+            || CmdOverrides {
+                ssh_user: Some("cli".to_string()),
+                confirm_timeout: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(settings.ssh_user.as_deref(), set.first().copied(), "{:?}", set);
+        assert_eq!(
+            settings.confirm_timeout,
+            set.first().map(|layer| layer.len() as u16),
+            "{:?}",
+            set
+        );
+
+        // SSH options are concatenated, unless replaced with `--ssh-opts`, and Nix options are
+        // taken from the most important layer setting each of them
+        let (_, settings) = merge_layered(
+            layers,
+            |layer| {
+                let mut nix_options = serde_json::Map::new();
+                nix_options.insert("substituters".to_string(), json!(layer));
+                nix_options.insert(layer.to_string(), json!("true"));
+                json!({ "sshOpts": [format!("-o{}", layer)], "nixOptions": nix_options })
+            },
+            || CmdOverrides {
+                ssh_opts: Some(vec!["-ocli".to_string()]),
+                ..Default::default()
+            },
+        );
+        let expected_opts: Vec<String> = match set.first() {
+            Some(&"cli") => vec!["-ocli".to_string()],
+            _ => set.iter().map(|layer| format!("-o{}", layer)).collect(),
+        };
+        assert_eq!(settings.ssh_opts, expected_opts, "{:?}", set);
+        assert_eq!(
+            settings.nix_options.get("substituters").map(String::as_str),
+            in_data.first().copied(),
+            "{:?}",
+            set
+        );
+        for layer in &in_data {
+            assert_eq!(settings.nix_options.get(*layer).map(String::as_str), Some("true"));
+        }
+
+        // The hostname comes from the command line, the node in the environment or the node
+        let (hostname, _) = merge_layered(
+            layers,
+            |layer| match layer {
+                "environment_node" => json!({ "hostname": "web1.staging.example.com" }),
+                _ => json!({}),
+            },
+            || CmdOverrides {
+                hostname: Some("10.0.0.99".to_string()),
+                ..Default::default()
+            },
+        );
+        let expected = match set.first() {
+            Some(&"cli") => "10.0.0.99",
+            Some(&"environment_node") => "web1.staging.example.com",
+            _ => "web1.example.com",
+        };
+        assert_eq!(hostname, expected, "{:?}", set);
+    }
+}
+
+#[test]
+fn test_env_ssh_opts() {
+    let env = |name: &str| match name {
+        "NIX_SSHOPTS" => Some("-p 2222  -o Compression=yes".to_string()),
+        "SSH_OPTS" => Some("-A".to_string()),
+        _ => None,
+    };
+    let from_env = env_ssh_opts(env);
+    assert_eq!(from_env, vec!["-p", "2222", "-o", "Compression=yes", "-A"]);
+    assert!(env_ssh_opts(|_| None).is_empty());
+
+    let mut settings: GenericSettings =
+        serde_json::from_value(serde_json::json!({ "sshOpts": ["-i", "/etc/deploy-key"] })).unwrap();
+    let mut cmd_overrides = CmdOverrides {
+        env_ssh_opts: from_env,
+        skip_host_key_check: true,
+        ..Default::default()
+    };
+    let mut merged = settings.clone();
+    apply_overrides(&mut merged, &cmd_overrides);
+    assert_eq!(
+        merged.ssh_opts.join(" "),
+        "-p 2222 -o Compression=yes -A -i /etc/deploy-key -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null"
+    );
+
+    // `--ssh-opts` replaces them all
+    cmd_overrides.ssh_opts = Some(vec!["-p".to_string(), "22".to_string()]);
+    cmd_overrides.skip_host_key_check = false;
+    apply_overrides(&mut settings, &cmd_overrides);
+    assert_eq!(settings.ssh_opts, vec!["-p", "22"]);
+
+    // Quoted options are kept together, unbalanced quotes are taken literally
+    let from_env = env_ssh_opts(|name| match name {
+        "NIX_SSHOPTS" => Some("-o 'ProxyCommand=ssh -W %h:%p bastion'".to_string()),
+        "SSH_OPTS" => Some("-o Banner=\"".to_string()),
+        _ => None,
+    });
+    assert_eq!(
+        from_env,
+        vec!["-o", "ProxyCommand=ssh -W %h:%p bastion", "-o", "Banner=\""]
+    );
+}
+
+#[test]
+fn test_split_ssh_opts() {
+    let given = |opts: &[&str]| opts.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+
+    let ssh_opts = split_ssh_opts(&given(&[
+        "-o ProxyCommand=\"ssh -W %h:%p bastion\"",
+        "-p 2222",
+        "-i '/etc/deploy keys/id'",
+    ]))
+    .unwrap();
+    assert_eq!(
+        ssh_opts,
+        given(&[
+            "-o",
+            "ProxyCommand=ssh -W %h:%p bastion",
+            "-p",
+            "2222",
+            "-i",
+            "/etc/deploy keys/id"
+        ])
+    );
+    // Joined for `NIX_SSHOPTS`, they are split back into the same options
+    let joined = join_ssh_opts(&ssh_opts);
+    assert_eq!(
+        joined,
+        "-o 'ProxyCommand=ssh -W %h:%p bastion' -p 2222 -i '/etc/deploy keys/id'"
+    );
+    assert_eq!(split_ssh_opts(&[joined]).unwrap(), ssh_opts);
+    // Options without spaces aren't quoted, e.g. the one of --skip-host-key-check
+    assert_eq!(
+        join_ssh_opts(&given(&["-o", "StrictHostKeyChecking=no", "-oUserKnownHostsFile=/dev/null"])),
+        "-o StrictHostKeyChecking=no -oUserKnownHostsFile=/dev/null"
+    );
+
+    let (opts, _) = split_ssh_opts(&given(&["-p 22", "-o 'User=deploy"])).unwrap_err();
+    assert_eq!(opts, "-o 'User=deploy");
+}
+
+#[test]
+fn test_split_port() {
+    use crate::data::Data;
+
+    assert_eq!(split_port("web1.example.com:2222"), ("web1.example.com", Some(2222)));
+    assert_eq!(split_port("[2001:db8::1]:2222"), ("2001:db8::1", Some(2222)));
+    assert_eq!(split_port("2001:db8::1"), ("2001:db8::1", None));
+    assert_eq!(split_port("[2001:db8::1]"), ("[2001:db8::1]", None));
+    assert_eq!(split_port("web1.example.com:ssh"), ("web1.example.com:ssh", None));
+
+    let data: Data = serde_json::from_value(serde_json::json!({
+        "sshOpts": ["-A"],
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com:2222",
+                "profiles": { "system": { "path": "/nix/store/blah-system" } },
+            },
+            "web2": {
+                "hostname": "web2.example.com",
+                "sshPort": 2200,
+                "profiles": { "system": { "path": "/nix/store/blah-system" } },
+            },
+        },
+    }))
+    .unwrap();
+    let cmd_overrides = CmdOverrides::default();
+    let merged = |node_name: &str| {
+        let node = &data.nodes[node_name];
+        let (hostname, settings) = merge(
+            &data.generic_settings,
+            None,
+            node,
+            node_name,
+            &node.node_settings.profiles["system"],
+            &cmd_overrides,
+        );
+        (hostname.to_string(), settings.ssh_opts.join(" "))
+    };
+    // Given to ssh before the `sshOpts`
+    assert_eq!(merged("web1"), ("web1.example.com".to_string(), "-p 2222 -A".to_string()));
+    assert_eq!(merged("web2"), ("web2.example.com".to_string(), "-p 2200 -A".to_string()));
+}
+
+#[test]
+fn test_overrides() {
+    use crate::data::Data;
+
+    let data: Data = serde_json::from_value(serde_json::json!({
+        "sshUser": "deploy",
+        "nodes": {
+            "web1": {
+                "hostname": "web1.example.com",
+                "profiles": {
+                    "system": { "path": "/nix/store/blah-system", "user": "root" },
+                    "app": { "path": "/nix/store/blah-app", "user": "app", "confirmTimeout": 60 },
+                },
+            },
+        },
+    }))
+    .unwrap();
+    let cmd_overrides = CmdOverrides {
+        ssh_user: Some("admin".to_string()),
+        profile_user: Some("app".to_string()),
+        confirm_timeout: Some(60),
+        magic_rollback: Some(false),
+        ..Default::default()
+    };
+
+    let node = &data.nodes["web1"];
+    let profile_overrides = |profile: &str| {
+        overrides(
+            &data.generic_settings,
+            None,
+            node,
+            "web1",
+            &node.node_settings.profiles[profile],
+            &cmd_overrides,
+        )
+    };
+    let (system, app) = (profile_overrides("system"), profile_overrides("app"));
+
+    let ssh_user = Override {
+        setting: "sshUser",
+        flag: "--ssh-user",
+        from: Some("deploy".to_string()),
+        to: "admin".to_string(),
+    };
+    let magic_rollback = Override {
+        setting: "magicRollback",
+        flag: "--magic-rollback",
+        from: None,
+        to: "false".to_string(),
+    };
+    // Values the command line gives too aren't overridden
+    assert_eq!(app, vec![ssh_user, magic_rollback]);
+    assert_eq!(system.len(), 4);
+
+    let profiles = vec![("web1", "system", system), ("web1", "app", app)];
+    let labelled: Vec<(String, String)> = label_overrides(&profiles)
+        .into_iter()
+        .map(|(label, o)| (label, o.describe(false)))
+        .collect();
+    assert_eq!(
+        labelled,
+        vec![
+            ("web1".to_string(), "sshUser: deploy -> admin (--ssh-user)".to_string()),
+            ("web1.system".to_string(), "user: root -> app (--profile-user)".to_string()),
+            ("web1".to_string(), "magicRollback: (unset) -> false (--magic-rollback)".to_string()),
+            ("web1.system".to_string(), "confirmTimeout: (unset) -> 60 (--confirm-timeout)".to_string()),
+        ]
+    );
+}
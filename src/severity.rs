@@ -0,0 +1,102 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! How errors are handled depending on what they affect.
+//!
+//! Errors on the deploy path (building, pushing, activating) are always fatal, but an error in
+//! auxiliary functionality (printing the deployment plan, notifications, tags, ...) shouldn't
+//! abort a fleet deployment. Those go through [`Severity::check`], which only logs a warning
+//! unless `--strict` is used.
+//!
+//! Fatal errors that are recognized end deploy with the exit code of their [`ExitCategory`], so
+//! scripts can tell a problem with the configuration from one with the node or the network.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::warn;
+
+static IGNORED_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// Log the error and continue
+    Warning,
+    /// Abort with the error
+    Fatal,
+}
+
+impl Severity {
+    /// The severity of errors in functionality the deployment doesn't depend on
+    pub fn non_critical(strict: bool) -> Self {
+        match strict {
+            true => Severity::Fatal,
+            false => Severity::Warning,
+        }
+    }
+
+    /// Passes on the value of `result`. An error is returned as well if fatal, otherwise it is
+    /// logged as a warning (prefixed with `what` failed) and `None` is returned instead.
+    pub fn check<T, E: Display>(self, what: &str, result: Result<T, E>) -> Result<Option<T>, E> {
+        match (result, self) {
+            (Ok(value), _) => Ok(Some(value)),
+            (Err(err), Severity::Fatal) => Err(err),
+            (Err(err), Severity::Warning) => {
+                IGNORED_ERRORS.fetch_add(1, Ordering::Relaxed);
+                warn!("{} failed, continuing anyway: {}", what, err);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// What kind of problem made deploy fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitCategory {
+    /// Anything not recognized as one of the others
+    Failure,
+    /// The deployment or Nix is set up wrong, deploying again won't help
+    Configuration,
+    /// The node can't take the deployment, e.g. it is out of space
+    Node,
+    /// The connection to the node failed, deploying again may work
+    Network,
+}
+
+impl ExitCategory {
+    /// The exit code of deploy for the category
+    pub fn code(self) -> i32 {
+        match self {
+            ExitCategory::Failure => 1,
+            ExitCategory::Configuration => 2,
+            ExitCategory::Node => 3,
+            ExitCategory::Network => 4,
+        }
+    }
+}
+
+/// How many errors were downgraded to warnings so far
+pub fn ignored_errors() -> usize {
+    IGNORED_ERRORS.load(Ordering::Relaxed)
+}
+
+#[test]
+fn test_severity_check() {
+    let ok: Result<u8, String> = Ok(1);
+    assert_eq!(Severity::Fatal.check("ok", ok.clone()), Ok(Some(1)));
+    assert_eq!(Severity::Warning.check("ok", ok), Ok(Some(1)));
+
+    let err: Result<u8, String> = Err("unreachable endpoint".to_string());
+    assert_eq!(
+        Severity::Fatal.check("notifying", err.clone()),
+        Err("unreachable endpoint".to_string())
+    );
+
+    let before = ignored_errors();
+    assert_eq!(Severity::Warning.check("notifying", err), Ok(None));
+    assert!(ignored_errors() > before);
+
+    assert_eq!(Severity::non_critical(true), Severity::Fatal);
+    assert_eq!(Severity::non_critical(false), Severity::Warning);
+}
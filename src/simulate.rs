@@ -0,0 +1,207 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Synthetic deployments to many nodes (`--simulate-nodes`), measuring the overhead of deploy-rs
+//! itself rather than of Nix and SSH.
+//!
+//! Every node gets a push and an activation scheduled by the [`Orchestrator`] as in a real
+//! deployment, but the commands are run by a [`MockRunner`], which emits the events a command
+//! would and sleeps instead. The report compares the time taken with the time the connection
+//! budget allows at best, and counts the events rendered; `benches/orchestrator.rs` runs it for
+//! growing numbers of nodes.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+
+use crate::events::{emit, Event, EventKind, EventStream, Phase};
+use crate::orchestrator::Orchestrator;
+use crate::render::Renderer;
+
+/// How long each mocked command takes
+pub const COMMAND_TIME: Duration = Duration::from_millis(10);
+
+/// How many lines each mocked command prints
+pub const COMMAND_OUTPUT_LINES: usize = 20;
+
+/// Runs commands for the simulated nodes: emits them and their output, and takes
+/// [`COMMAND_TIME`]
+pub struct MockRunner {
+    pub time: Duration,
+    pub output_lines: usize,
+}
+
+impl Default for MockRunner {
+    fn default() -> Self {
+        MockRunner {
+            time: COMMAND_TIME,
+            output_lines: COMMAND_OUTPUT_LINES,
+        }
+    }
+}
+
+impl MockRunner {
+    pub async fn run(&self, node: &str, profile: &str, command: &str) {
+        emit(node, profile, EventKind::Command(command.to_string()));
+        for i in 0..self.output_lines {
+            emit(
+                node,
+                profile,
+                EventKind::Output(format!("{}: line {}", command, i)),
+            );
+        }
+        tokio::time::sleep(self.time).await;
+    }
+}
+
+/// Discards the events, for measuring without the cost of printing them
+pub struct NullRenderer;
+
+impl Renderer for NullRenderer {
+    fn render(&mut self, _event: &Event) {}
+}
+
+/// Counts the events passed on to another renderer
+struct CountingRenderer {
+    inner: Box<dyn Renderer>,
+    count: Arc<AtomicUsize>,
+}
+
+impl Renderer for CountingRenderer {
+    fn render(&mut self, event: &Event) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.inner.render(event);
+    }
+
+    fn finish(&mut self) {
+        self.inner.finish();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationReport {
+    pub nodes: usize,
+    pub max_connections: usize,
+    pub elapsed: Duration,
+    /// The least time the commands take with the connection budget
+    pub ideal: Duration,
+    pub events: usize,
+    /// The peak resident memory of the process, in KiB (only known on Linux)
+    pub peak_memory_kib: Option<u64>,
+}
+
+impl SimulationReport {
+    /// The time spent on anything but the commands themselves
+    pub fn overhead(&self) -> Duration {
+        self.elapsed.saturating_sub(self.ideal)
+    }
+
+    pub fn events_per_second(&self) -> f64 {
+        self.events as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} nodes with {} connections: {:.3}s ({:.3}s ideal, {:.3}s overhead), {} events ({:.0}/s)",
+            self.nodes,
+            self.max_connections,
+            self.elapsed.as_secs_f64(),
+            self.ideal.as_secs_f64(),
+            self.overhead().as_secs_f64(),
+            self.events,
+            self.events_per_second()
+        )?;
+        if let Some(peak) = self.peak_memory_kib {
+            write!(f, ", {} KiB peak memory", peak)?;
+        }
+        Ok(())
+    }
+}
+
+/// The peak resident memory of this process from `/proc/self/status`, in KiB
+fn peak_memory_kib() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Deploys to `nodes` simulated nodes with `max_connections` sessions at most, rendering the
+/// events with `renderer`
+pub async fn simulate(
+    nodes: usize,
+    max_connections: usize,
+    runner: &MockRunner,
+    renderer: Box<dyn Renderer>,
+) -> SimulationReport {
+    let max_connections = max_connections.max(1);
+    let count = Arc::new(AtomicUsize::new(0));
+    let event_stream = EventStream::start(Box::new(CountingRenderer {
+        inner: renderer,
+        count: count.clone(),
+    }));
+
+    let orchestrator = Orchestrator::new(max_connections);
+    let names: Vec<String> = (0..nodes).map(|i| format!("node{}", i)).collect();
+
+    let started = Instant::now();
+    join_all(names.iter().map(|node| {
+        let orchestrator = &orchestrator;
+        async move {
+            {
+                let _permit = orchestrator.connect(node, 1).await;
+                emit(node, "system", EventKind::Started(Phase::Push));
+                runner.run(node, "system", "nix copy").await;
+                emit(node, "system", EventKind::Finished(Phase::Push));
+            }
+            // Activating with magic rollback takes a second session for waiting
+            let _permit = orchestrator.connect(node, 2).await;
+            emit(node, "system", EventKind::Started(Phase::Activate));
+            runner.run(node, "system", "activate-rs activate").await;
+            emit(node, "system", EventKind::Finished(Phase::Activate));
+        }
+    }))
+    .await;
+    event_stream.finish().await;
+    let elapsed = started.elapsed();
+
+    // Each node pushes with one session, then activates with two, all sessions being busy the
+    // whole time at best
+    let sessions = 1 + 2.min(max_connections);
+    let ideal = runner.time * (nodes * sessions).div_ceil(max_connections) as u32;
+
+    SimulationReport {
+        nodes,
+        max_connections,
+        elapsed,
+        ideal,
+        events: count.load(Ordering::Relaxed),
+        peak_memory_kib: peak_memory_kib(),
+    }
+}
+
+#[tokio::test]
+async fn test_simulate() {
+    let runner = MockRunner {
+        time: Duration::from_millis(5),
+        output_lines: 3,
+    };
+    let report = simulate(8, 4, &runner, Box::new(NullRenderer)).await;
+
+    // Per node and phase: started, command, output, finished
+    assert!(report.events >= 8 * 2 * (1 + 1 + 3 + 1));
+    // 8 pushes with one session and 8 activations with two, 4 sessions at a time
+    assert_eq!(report.ideal, Duration::from_millis(5 * (2 + 4)));
+    assert!(report.elapsed >= report.ideal);
+}
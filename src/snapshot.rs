@@ -0,0 +1,377 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Filesystem snapshots taken on the node before activating (`snapshot`).
+//!
+//! `activate-rs` snapshots the configured ZFS datasets (atomically, with one `zfs snapshot`) or
+//! btrfs subvolumes before switching the profile, so the state a bad migration damaged can be
+//! restored along with the configuration: when the activation is rolled back because it failed
+//! (`autoRollback`) or wasn't confirmed (`magicRollback`), the snapshots are restored before the
+//! previous generation is re-activated. ZFS datasets are rolled back in place; btrfs subvolumes
+//! are moved aside (to `.<name>.<snapshot>.replaced` next to them, kept for inspection) and
+//! replaced with a writable snapshot of the snapshot, which fails for subvolumes that are mount
+//! points. Revoking an activation because another node failed doesn't restore anything.
+//!
+//! Snapshots are named `deploy-rs-<UTC time>`; those of btrfs subvolumes live next to the
+//! subvolume as `.<name>.deploy-rs-<UTC time>`. After a successful activation, all but the
+//! latest `keep` of them are deleted.
+
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::process::Command;
+
+/// What the names of the snapshots taken by deploy-rs start with
+pub const SNAPSHOT_PREFIX: &str = "deploy-rs-";
+
+/// How many snapshots of each dataset are kept by default
+pub const DEFAULT_KEEP: usize = 3;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotType {
+    Zfs,
+    Btrfs,
+}
+
+impl SnapshotType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotType::Zfs => "zfs",
+            SnapshotType::Btrfs => "btrfs",
+        }
+    }
+}
+
+impl std::str::FromStr for SnapshotType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zfs" => Ok(SnapshotType::Zfs),
+            "btrfs" => Ok(SnapshotType::Btrfs),
+            _ => Err(format!(
+                "invalid snapshot type `{}`, expected \"zfs\" or \"btrfs\"",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Failed to run `{0}`: {1}")]
+    Run(String, std::io::Error),
+    #[error("`{0}` resulted in a bad exit code: {1:?}")]
+    Exit(String, Option<i32>),
+    #[error("The btrfs subvolume {0} has no parent directory to keep its snapshots in")]
+    NoParent(String),
+    #[error("Failed to move the btrfs subvolume {0} aside: {1}")]
+    MoveAside(String, std::io::Error),
+    #[error("Failed to list the snapshots of {0}: {1}")]
+    List(String, std::io::Error),
+}
+
+/// A snapshot taken before activating
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub kind: SnapshotType,
+    /// The ZFS dataset or the path of the btrfs subvolume
+    pub dataset: String,
+    pub name: String,
+}
+
+/// The name of the snapshots taken at `time`
+pub fn snapshot_name(time: chrono::DateTime<chrono::Utc>) -> String {
+    format!("{}{}", SNAPSHOT_PREFIX, time.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Where the snapshot `name` of the btrfs subvolume `subvolume` is kept
+fn btrfs_snapshot_path(subvolume: &str, name: &str) -> Result<PathBuf, SnapshotError> {
+    let path = Path::new(subvolume);
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => {
+            Ok(parent.join(format!(".{}.{}", file_name.to_string_lossy(), name)))
+        }
+        _ => Err(SnapshotError::NoParent(subvolume.to_string())),
+    }
+}
+
+impl Snapshot {
+    /// The snapshot as the tool taking it refers to it
+    pub fn path(&self) -> Result<String, SnapshotError> {
+        match self.kind {
+            SnapshotType::Zfs => Ok(format!("{}@{}", self.dataset, self.name)),
+            SnapshotType::Btrfs => Ok(btrfs_snapshot_path(&self.dataset, &self.name)?
+                .to_string_lossy()
+                .to_string()),
+        }
+    }
+}
+
+/// The commands taking the snapshots
+fn take_commands(snapshots: &[Snapshot]) -> Result<Vec<Vec<String>>, SnapshotError> {
+    let mut commands = Vec::new();
+    let mut zfs = vec!["zfs".to_string(), "snapshot".to_string()];
+    for snapshot in snapshots {
+        match snapshot.kind {
+            SnapshotType::Zfs => zfs.push(snapshot.path()?),
+            SnapshotType::Btrfs => commands.push(vec![
+                "btrfs".to_string(),
+                "subvolume".to_string(),
+                "snapshot".to_string(),
+                "-r".to_string(),
+                snapshot.dataset.clone(),
+                snapshot.path()?,
+            ]),
+        }
+    }
+    if zfs.len() > 2 {
+        commands.insert(0, zfs);
+    }
+    Ok(commands)
+}
+
+/// Of the names of the snapshots of a dataset, oldest first, those to delete to keep `keep`
+fn prunable(names: &[String], keep: usize) -> &[String] {
+    &names[..names.len().saturating_sub(keep)]
+}
+
+async fn run(argv: &[String]) -> Result<(), SnapshotError> {
+    let command = argv.join(" ");
+    debug!("Running `{}`", command);
+    let status = Command::new(&argv[0])
+        .args(&argv[1..])
+        .status()
+        .await
+        .map_err(|e| SnapshotError::Run(command.clone(), e))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        a => Err(SnapshotError::Exit(command, a)),
+    }
+}
+
+/// Snapshots `datasets` as `name`
+pub async fn take(
+    kind: SnapshotType,
+    datasets: &[String],
+    name: &str,
+) -> Result<Vec<Snapshot>, SnapshotError> {
+    let snapshots: Vec<Snapshot> = datasets
+        .iter()
+        .map(|dataset| Snapshot {
+            kind,
+            dataset: dataset.clone(),
+            name: name.to_string(),
+        })
+        .collect();
+
+    for argv in take_commands(&snapshots)? {
+        run(&argv).await?;
+    }
+    info!(
+        "Took {} snapshot(s) {} of {}",
+        kind.as_str(),
+        name,
+        datasets.join(", ")
+    );
+
+    Ok(snapshots)
+}
+
+/// Restores `snapshot`, replacing the current state of its dataset
+async fn restore_one(snapshot: &Snapshot) -> Result<(), SnapshotError> {
+    match snapshot.kind {
+        SnapshotType::Zfs => {
+            run(&[
+                "zfs".to_string(),
+                "rollback".to_string(),
+                "-r".to_string(),
+                snapshot.path()?,
+            ])
+            .await
+        }
+        SnapshotType::Btrfs => {
+            let replaced =
+                btrfs_snapshot_path(&snapshot.dataset, &format!("{}.replaced", snapshot.name))?;
+            std::fs::rename(&snapshot.dataset, &replaced)
+                .map_err(|e| SnapshotError::MoveAside(snapshot.dataset.clone(), e))?;
+            info!(
+                "Moved the btrfs subvolume {} aside to {}",
+                snapshot.dataset,
+                replaced.display()
+            );
+            run(&[
+                "btrfs".to_string(),
+                "subvolume".to_string(),
+                "snapshot".to_string(),
+                snapshot.path()?,
+                snapshot.dataset.clone(),
+            ])
+            .await
+        }
+    }
+}
+
+/// Restores `snapshots`, logging (and going on after) the ones that failed, returning whether
+/// all were restored
+pub async fn restore(snapshots: &[Snapshot]) -> bool {
+    let mut restored = true;
+    for snapshot in snapshots {
+        info!(
+            "Restoring snapshot {} of {}",
+            snapshot.name, snapshot.dataset
+        );
+        if let Err(e) = restore_one(snapshot).await {
+            warn!(
+                "Failed to restore snapshot {} of {}: {}",
+                snapshot.name, snapshot.dataset, e
+            );
+            restored = false;
+        }
+    }
+    restored
+}
+
+/// The names of the snapshots deploy-rs took of `dataset`, oldest first
+async fn list(kind: SnapshotType, dataset: &str) -> Result<Vec<String>, SnapshotError> {
+    let mut names: Vec<String> = match kind {
+        SnapshotType::Zfs => {
+            let output = Command::new("zfs")
+                .args([
+                    "list", "-H", "-o", "name", "-t", "snapshot", "-d", "1", dataset,
+                ])
+                .output()
+                .await
+                .map_err(|e| SnapshotError::List(dataset.to_string(), e))?;
+            if !output.status.success() {
+                return Err(SnapshotError::Exit(
+                    format!("zfs list -t snapshot {}", dataset),
+                    output.status.code(),
+                ));
+            }
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.split_once('@'))
+                .map(|(_, name)| name.to_string())
+                .collect()
+        }
+        SnapshotType::Btrfs => {
+            // `<parent>/.<name>.`, followed by the name of the snapshot
+            let prefix = btrfs_snapshot_path(dataset, "")?;
+            let parent = prefix.parent().unwrap_or_else(|| Path::new("/"));
+            let prefix = prefix.file_name().unwrap_or_default().to_string_lossy();
+            std::fs::read_dir(parent)
+                .map_err(|e| SnapshotError::List(dataset.to_string(), e))?
+                .flatten()
+                .filter_map(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .strip_prefix(prefix.as_ref())
+                        .map(str::to_string)
+                })
+                .collect()
+        }
+    };
+
+    // The UTC times in the names sort like the times
+    names.retain(|name| name.starts_with(SNAPSHOT_PREFIX) && !name.ends_with(".replaced"));
+    names.sort();
+    Ok(names)
+}
+
+/// Deletes all but the latest `keep` snapshots deploy-rs took of `datasets`, logging failures
+pub async fn prune(kind: SnapshotType, datasets: &[String], keep: usize) {
+    for dataset in datasets {
+        let names = match list(kind, dataset).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to prune the snapshots of {}: {}", dataset, e);
+                continue;
+            }
+        };
+
+        for name in prunable(&names, keep) {
+            let snapshot = Snapshot {
+                kind,
+                dataset: dataset.clone(),
+                name: name.clone(),
+            };
+            let delete = match snapshot.path() {
+                Ok(path) => match kind {
+                    SnapshotType::Zfs => vec!["zfs".to_string(), "destroy".to_string(), path],
+                    SnapshotType::Btrfs => vec![
+                        "btrfs".to_string(),
+                        "subvolume".to_string(),
+                        "delete".to_string(),
+                        path,
+                    ],
+                },
+                Err(e) => {
+                    warn!("Failed to prune the snapshots of {}: {}", dataset, e);
+                    break;
+                }
+            };
+            match run(&delete).await {
+                Ok(()) => debug!("Deleted snapshot {} of {}", name, dataset),
+                Err(e) => warn!("Failed to delete snapshot {} of {}: {}", name, dataset, e),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_commands() {
+    use chrono::TimeZone;
+
+    let name = snapshot_name(chrono::Utc.ymd(2024, 3, 9).and_hms(14, 5, 0));
+    assert_eq!(name, "deploy-rs-20240309T140500Z");
+
+    let snapshot = |kind, dataset: &str| Snapshot {
+        kind,
+        dataset: dataset.to_string(),
+        name: name.clone(),
+    };
+    assert_eq!(
+        take_commands(&[
+            snapshot(SnapshotType::Zfs, "rpool/var"),
+            snapshot(SnapshotType::Zfs, "rpool/var/lib/postgresql"),
+        ])
+        .unwrap(),
+        vec![vec![
+            "zfs",
+            "snapshot",
+            "rpool/var@deploy-rs-20240309T140500Z",
+            "rpool/var/lib/postgresql@deploy-rs-20240309T140500Z",
+        ]]
+    );
+    assert_eq!(
+        take_commands(&[snapshot(SnapshotType::Btrfs, "/var/lib/postgresql")]).unwrap(),
+        vec![vec![
+            "btrfs",
+            "subvolume",
+            "snapshot",
+            "-r",
+            "/var/lib/postgresql",
+            "/var/lib/.postgresql.deploy-rs-20240309T140500Z",
+        ]]
+    );
+    assert!(matches!(
+        take_commands(&[snapshot(SnapshotType::Btrfs, "/")]),
+        Err(SnapshotError::NoParent(_))
+    ));
+
+    let names: Vec<String> = ["deploy-rs-20240101T000000Z", "deploy-rs-20240201T000000Z"]
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+    assert_eq!(prunable(&names, 1), &names[..1]);
+    assert!(prunable(&names, 3).is_empty());
+
+    assert_eq!("btrfs".parse::<SnapshotType>(), Ok(SnapshotType::Btrfs));
+    assert!("lvm".parse::<SnapshotType>().is_err());
+}
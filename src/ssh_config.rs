@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reading the per-host defaults of `~/.ssh/config`, the way `ssh` applies them.
+//!
+//! Deployments go through the system `ssh`, which applies the config itself; this is for
+//! connecting without it and for showing in debug logs which stanzas applied to a node. Only
+//! `Host` stanzas and the options deploy-rs cares about are understood; `Match` blocks and
+//! `Include` are skipped. As with `ssh`, the first value found for an option wins.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+    /// The line numbers and patterns of the `Host` lines that matched
+    pub matched: Vec<(usize, String)>,
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".ssh").join("config"))
+}
+
+/// Whether `host` matches the glob `pattern` (`*` and `?` wildcards)
+fn glob_match(pattern: &[u8], host: &[u8]) -> bool {
+    match (pattern.first(), host.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], host) || (!host.is_empty() && glob_match(pattern, &host[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &host[1..]),
+        (Some(p), Some(h)) if p.eq_ignore_ascii_case(h) => glob_match(&pattern[1..], &host[1..]),
+        _ => false,
+    }
+}
+
+/// Whether the patterns of a `Host` line match `host`: any pattern does and no negated one does
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated.as_bytes(), host.as_bytes()) => return false,
+            Some(_) => (),
+            None => matched |= glob_match(pattern.as_bytes(), host.as_bytes()),
+        }
+    }
+    matched
+}
+
+/// The settings `content` (in ssh_config format) has for `host`
+pub fn resolve(content: &str, host: &str) -> HostConfig {
+    let mut config = HostConfig::default();
+    // Options before the first `Host` apply to all hosts
+    let mut active = true;
+
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.find(|c: char| c.is_whitespace() || c == '=') {
+            Some(at) => (
+                &line[..at],
+                line[at..].trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+            ),
+            None => (line, ""),
+        };
+        let value = value.trim().trim_matches('"');
+
+        match key.to_ascii_lowercase().as_str() {
+            "host" => {
+                active = host_matches(value, host);
+                if active {
+                    config.matched.push((i + 1, value.to_string()));
+                }
+            }
+            "match" => active = false,
+            _ if !active => (),
+            "hostname" if config.hostname.is_none() => config.hostname = Some(value.to_string()),
+            "user" if config.user.is_none() => config.user = Some(value.to_string()),
+            "port" if config.port.is_none() => config.port = value.parse().ok(),
+            // Unlike the others, all identity files are tried
+            "identityfile" => config.identity_files.push(value.to_string()),
+            "proxyjump" if config.proxy_jump.is_none() => {
+                config.proxy_jump = Some(value.to_string())
+            }
+            _ => (),
+        }
+    }
+
+    config
+}
+
+/// The settings `~/.ssh/config` has for `host`, if there is such a file
+pub fn load(host: &str) -> Option<(PathBuf, HostConfig)> {
+    let path = config_path()?;
+    let content = std::fs::read_to_string(&path).ok()?;
+    let config = resolve(&content, host);
+    Some((path, config))
+}
+
+/// Describes which stanzas of the config at `path` applied to a host, for debug logs
+pub fn describe_matches(path: &Path, config: &HostConfig) -> String {
+    match config.matched.is_empty() {
+        true => format!("no `Host` stanza of {} matched", path.display()),
+        false => config
+            .matched
+            .iter()
+            .map(|(line, patterns)| format!("`Host {}` ({}:{})", patterns, path.display(), line))
+            .collect::<Vec<_>>()
+            .join(", "),
+    }
+}
+
+#[test]
+fn test_resolve() {
+    let content = r#"
+IdentityFile ~/.ssh/id_ed25519
+
+Host *.internal !bastion.internal
+    User deploy
+    ProxyJump bastion.internal
+    Port 2222
+
+Host web1.internal
+    HostName 10.0.0.5
+    User admin
+    IdentityFile=~/.ssh/web1
+
+Match user root
+    User nobody
+
+Host *
+    Port 22
+"#;
+
+    let web1 = resolve(content, "web1.internal");
+    assert_eq!(web1.hostname.as_deref(), Some("10.0.0.5"));
+    // The first value wins
+    assert_eq!(web1.user.as_deref(), Some("deploy"));
+    assert_eq!(web1.port, Some(2222));
+    assert_eq!(web1.proxy_jump.as_deref(), Some("bastion.internal"));
+    assert_eq!(
+        web1.identity_files,
+        vec!["~/.ssh/id_ed25519", "~/.ssh/web1"]
+    );
+    assert_eq!(
+        web1.matched.iter().map(|(l, _)| *l).collect::<Vec<_>>(),
+        vec![4, 9, 17]
+    );
+
+    let bastion = resolve(content, "bastion.internal");
+    assert_eq!(bastion.user, None);
+    assert_eq!(bastion.proxy_jump, None);
+    assert_eq!(bastion.port, Some(22));
+
+    assert!(host_matches("web?.example.com", "WEB1.example.com"));
+    assert!(!host_matches("web?.example.com", "web10.example.com"));
+    assert_eq!(
+        describe_matches(Path::new("/home/me/.ssh/config"), &bastion),
+        "`Host *` (/home/me/.ssh/config:17)"
+    );
+}
@@ -0,0 +1,157 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! SSH through access proxies (`sshWrapper`), e.g. Teleport's `tsh ssh` or
+//! `boundary connect ssh --target-id ... --`.
+//!
+//! The wrapper is run instead of `ssh`, with the arguments ssh would get appended. Nix and `sftp`
+//! start `ssh` themselves, so they are given a shim instead: an `ssh` script running the wrapper
+//! (with the original `PATH`, for wrappers starting `ssh` in turn), in a private directory with a
+//! random name created by the deploy-rs process, which is removed once the deployment is done.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::debug;
+use tokio::process::Command;
+
+/// The argv starting with what `ssh` is run as: `wrapper`, or `ssh` without one
+pub fn argv(wrapper: Option<&[String]>) -> Vec<String> {
+    match wrapper {
+        Some(wrapper) if !wrapper.is_empty() => wrapper.to_vec(),
+        _ => vec!["ssh".to_string()],
+    }
+}
+
+/// The command running `ssh`, or `wrapper` without arguments for ssh yet
+pub fn command(wrapper: Option<&[String]>) -> Command {
+    let argv = argv(wrapper);
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    command
+}
+
+/// The shims written by this process: their directory, and the shim of each wrapper
+struct Shims {
+    dir: Option<PathBuf>,
+    written: Vec<(Vec<String>, PathBuf)>,
+}
+
+static SHIMS: Mutex<Shims> = Mutex::new(Shims {
+    dir: None,
+    written: Vec::new(),
+});
+
+/// The script of the shim running `wrapper` with the arguments given to it, and `path` as `PATH`
+pub fn shim_script(wrapper: &[String], path: Option<&str>) -> String {
+    let path = match path {
+        Some(path) => format!("PATH={}\n", shell_words::quote(path)),
+        None => String::new(),
+    };
+    format!(
+        "#!/bin/sh\n{}exec {} \"$@\"\n",
+        path,
+        shell_words::join(wrapper)
+    )
+}
+
+/// An executable named `ssh` running `wrapper`. Shims are only ever reused by the process which
+/// wrote them, in a directory it just created for them (see [`crate::create_private_temp_dir`])
+pub fn shim(wrapper: &[String]) -> std::io::Result<PathBuf> {
+    use std::io::Write;
+    use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+    let mut shims = SHIMS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((_, path)) = shims.written.iter().find(|(w, _)| w == wrapper) {
+        return Ok(path.clone());
+    }
+
+    let root = match &shims.dir {
+        Some(dir) => dir.clone(),
+        None => {
+            let dir = crate::create_private_temp_dir("deploy-rs-ssh-wrapper-")?;
+            shims.dir = Some(dir.clone());
+            dir
+        }
+    };
+    // Each shim is named `ssh`, so each wrapper gets a directory of its own
+    let dir = root.join(shims.written.len().to_string());
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let path = dir.join("ssh");
+    let original_path = std::env::var("PATH").ok();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .open(&path)?
+        .write_all(shim_script(wrapper, original_path.as_deref()).as_bytes())?;
+    debug!("Wrote the SSH wrapper shim {}", path.display());
+
+    shims.written.push((wrapper.to_vec(), path.clone()));
+    Ok(path)
+}
+
+/// Makes `command` (e.g. `nix copy` to an `ssh://` store) start `ssh` through `wrapper`, by
+/// putting its shim first in the `PATH`
+pub fn wrap(command: &mut Command, wrapper: Option<&[String]>) -> std::io::Result<()> {
+    let wrapper = match wrapper {
+        Some(wrapper) if !wrapper.is_empty() => wrapper,
+        _ => return Ok(()),
+    };
+    let shim = shim(wrapper)?;
+    let mut paths = vec![shim.parent().unwrap().to_path_buf()];
+    paths.extend(std::env::split_paths(
+        &std::env::var_os("PATH").unwrap_or_default(),
+    ));
+    command.env(
+        "PATH",
+        std::env::join_paths(paths).map_err(std::io::Error::other)?,
+    );
+    Ok(())
+}
+
+/// Removes the shims of this process
+pub fn remove_shims() {
+    let mut shims = SHIMS.lock().unwrap_or_else(|e| e.into_inner());
+    shims.written.clear();
+    let dir = match shims.dir.take() {
+        Some(dir) => dir,
+        None => return,
+    };
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        debug!("Failed to remove {}: {}", dir.display(), e);
+    }
+}
+
+#[test]
+fn test_ssh_wrapper() {
+    let wrapper = vec![
+        "tsh".to_string(),
+        "ssh".to_string(),
+        "--proxy=teleport.example.com".to_string(),
+    ];
+    assert_eq!(argv(None), vec!["ssh"]);
+    assert_eq!(argv(Some(&[])), vec!["ssh"]);
+    assert_eq!(argv(Some(&wrapper)), wrapper);
+
+    assert_eq!(
+        shim_script(
+            &shell_words::split("boundary connect ssh --target-id 'ttcp 1' --").unwrap(),
+            Some("/run/current-system/sw/bin")
+        ),
+        "#!/bin/sh\nPATH=/run/current-system/sw/bin\nexec boundary connect ssh --target-id 'ttcp 1' -- \"$@\"\n"
+    );
+    assert_eq!(
+        shim_script(&wrapper, None),
+        "#!/bin/sh\nexec tsh ssh '--proxy=teleport.example.com' \"$@\"\n"
+    );
+
+    let tsh = shim(&wrapper).unwrap();
+    assert_eq!(shim(&wrapper).unwrap(), tsh);
+    let other = shim(&["ssh".to_string(), "-v".to_string()]).unwrap();
+    assert_ne!(other, tsh);
+    assert_eq!(tsh.parent().unwrap().parent(), other.parent().unwrap().parent());
+    remove_shims();
+    assert!(!tsh.exists());
+}
@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The state deploy-rs keeps on the deploying machine.
+//!
+//! Everything lives in one directory, `$XDG_STATE_HOME/deploy-rs` unless `--state-dir` says
+//! otherwise: the state of the last deployment (`last-run.json`), the schedule (`schedule.json`),
+//! the audit log of overridden restrictions (`audit.log`), the nodes marked as under maintenance
+//! (`maintenance.json`) and the history of past deployments
+//! (`history/<run id>.json`), which `deploy prune-history` keeps in check, and the confirmations
+//! of magic rollback activations still outstanding (`pending-confirmations/<run id>/`). Anything else
+//! persisted goes in there as well, through [`path`].
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use thiserror::Error;
+
+static DIR_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Keeps the state in `dir` instead of the default directory (`--state-dir`)
+pub fn set_dir(dir: PathBuf) {
+    *DIR_OVERRIDE.lock().unwrap_or_else(|e| e.into_inner()) = Some(dir);
+}
+
+pub fn dir() -> PathBuf {
+    if let Some(dir) = DIR_OVERRIDE.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return dir.clone();
+    }
+
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("deploy-rs")
+}
+
+/// The path of the state file (or directory) `name`
+pub fn path(name: &str) -> PathBuf {
+    dir().join(name)
+}
+
+pub fn history_dir() -> PathBuf {
+    path("history")
+}
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("Failed to read the deployment history in {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to remove {0} from the deployment history: {1}")]
+    Remove(PathBuf, std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+/// The entries of the deployment history, oldest first
+pub fn history_entries() -> Result<Vec<HistoryEntry>, StateError> {
+    let dir = history_dir();
+    let read_dir = match std::fs::read_dir(&dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StateError::Read(dir, e)),
+    };
+
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        let entry = entry.map_err(|e| StateError::Read(dir.clone(), e))?;
+        let metadata = entry
+            .metadata()
+            .map_err(|e| StateError::Read(entry.path(), e))?;
+        if !metadata.is_file() {
+            continue;
+        }
+        entries.push(HistoryEntry {
+            path: entry.path(),
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            size: metadata.len(),
+        });
+    }
+    entries.sort_by_key(|e| e.modified);
+
+    Ok(entries)
+}
+
+/// The indices of the `entries` (oldest first) to remove for none to be older than `max_age` and
+/// all together to take at most `max_size` bytes
+pub fn select_prunable(
+    entries: &[HistoryEntry],
+    now: SystemTime,
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+) -> Vec<usize> {
+    let mut total: u64 = entries.iter().map(|e| e.size).sum();
+
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| {
+            let too_old = max_age.is_some_and(|max_age| {
+                now.duration_since(entry.modified)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let too_big = max_size.is_some_and(|max_size| total > max_size);
+            if too_old || too_big {
+                total -= entry.size;
+            }
+            too_old || too_big
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Removes the history entries older than `max_age` and then the oldest ones until the history
+/// takes at most `max_size` bytes, returning the removed entries
+pub fn prune_history(
+    max_age: Option<Duration>,
+    max_size: Option<u64>,
+    dry_run: bool,
+) -> Result<Vec<HistoryEntry>, StateError> {
+    let entries = history_entries()?;
+    let prunable = select_prunable(&entries, SystemTime::now(), max_age, max_size);
+
+    let mut removed = Vec::new();
+    for i in prunable {
+        let entry = &entries[i];
+        if !dry_run {
+            std::fs::remove_file(&entry.path)
+                .map_err(|e| StateError::Remove(entry.path.clone(), e))?;
+        }
+        removed.push(entry.clone());
+    }
+
+    Ok(removed)
+}
+
+/// Writes `content` to `path` in the state directory, creating the directories leading to it
+pub fn write(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Through a temporary file renamed over `path`, so that a crash never leaves it half-written
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    std::fs::write(&temp, content)?;
+    std::fs::rename(&temp, path)
+}
+
+/// Parses an age like `90d`, `12h`, `30m` or `45s`
+pub fn parse_age(s: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid age `{}`, expected e.g. 90d, 12h or 30m", s);
+    let unit = s.chars().last().ok_or_else(invalid)?;
+    let value: u64 = s[..s.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let seconds = match unit {
+        'd' => value * 24 * 60 * 60,
+        'h' => value * 60 * 60,
+        'm' => value * 60,
+        's' => value,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses a size like `500K`, `10M` or `1G` (powers of 1024), or a number of bytes
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size `{}`, expected e.g. 500K, 10M or 1G", s);
+    let (value, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    value
+        .parse::<u64>()
+        .map(|v| v * multiplier)
+        .map_err(|_| invalid())
+}
+
+#[test]
+fn test_prune_history() {
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100 * 24 * 60 * 60);
+    let day = Duration::from_secs(24 * 60 * 60);
+    let entry = |days_old: u32, size| HistoryEntry {
+        path: PathBuf::from(format!("history/{}.json", days_old)),
+        modified: now - day * days_old,
+        size,
+    };
+    let entries = vec![entry(40, 100), entry(20, 300), entry(10, 200), entry(1, 100)];
+
+    assert_eq!(
+        select_prunable(&entries, now, Some(day * 30), None),
+        vec![0]
+    );
+    // The oldest go until the rest fits
+    assert_eq!(select_prunable(&entries, now, None, Some(350)), vec![0, 1]);
+    assert_eq!(
+        select_prunable(&entries, now, Some(day * 5), Some(1000)),
+        vec![0, 1, 2]
+    );
+    assert!(select_prunable(&entries, now, None, None).is_empty());
+
+    assert_eq!(parse_age("90d"), Ok(day * 90));
+    assert_eq!(parse_age("30m"), Ok(Duration::from_secs(30 * 60)));
+    assert!(parse_age("soon").is_err());
+    assert_eq!(parse_size("10M"), Ok(10 << 20));
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert!(parse_size("big").is_err());
+}
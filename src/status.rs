@@ -0,0 +1,471 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! State of a profile on the target, reported as JSON by `activate-rs status` and
+//! `activate-rs list` and read back by `deploy doctor`.
+//!
+//! Labels of generations (`deploy --label`) are kept in a sidecar file next to the profile,
+//! `<profile>.deploy-rs-labels.json`, which Nix ignores as it doesn't look like a generation.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Where NixOS links the running system, to tell whether the system profile awaits a reboot
+pub const CURRENT_SYSTEM_PATH: &str = "/run/current-system";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Generation {
+    pub number: u64,
+    pub path: String,
+    pub current: bool,
+    pub label: Option<String>,
+    /// When the generation was created, in seconds since the epoch
+    #[serde(default)]
+    pub date: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileStatus {
+    /// Version of the `activate-rs` reporting the status
+    pub version: String,
+    pub profile_path: String,
+    pub current_generation: Option<u64>,
+    pub current_closure: Option<String>,
+    /// When the current generation was created, in seconds since the epoch
+    #[serde(default)]
+    pub current_date: Option<u64>,
+    /// The closure of a system profile activated with `--boot`, which isn't running yet
+    pub pending_boot_closure: Option<String>,
+    /// Canary files left in `tempPath`, e.g. by an interrupted deployment
+    pub canaries: Vec<String>,
+    /// The last lines of the most recent activation log, if logging to `--log-dir`
+    pub log_tail: Vec<String>,
+    /// The directory the profile is in, if the profile user can't write to it
+    #[serde(default)]
+    pub read_only_profile_dir: Option<String>,
+}
+
+fn labels_path(profile_path: &Path) -> PathBuf {
+    let mut path = profile_path.as_os_str().to_owned();
+    path.push(".deploy-rs-labels.json");
+    PathBuf::from(path)
+}
+
+/// The labels of the generations of the profile at `profile_path`, by generation number
+pub fn read_labels(profile_path: &Path) -> BTreeMap<u64, String> {
+    std::fs::read_to_string(labels_path(profile_path))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Labels the current generation of the profile at `profile_path`, returning its number
+pub fn record_label(profile_path: &Path, label: &str) -> std::io::Result<Option<u64>> {
+    let generation = match list_generations(profile_path)?.into_iter().find(|g| g.current) {
+        Some(x) => x.number,
+        None => return Ok(None),
+    };
+
+    let mut labels = read_labels(profile_path);
+    labels.insert(generation, label.to_string());
+    // Labels of generations that were deleted in the meantime are dropped
+    let existing: Vec<u64> = list_generations(profile_path)?
+        .iter()
+        .map(|g| g.number)
+        .collect();
+    labels.retain(|number, _| existing.contains(number));
+
+    let content = serde_json::to_string_pretty(&labels)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::fs::write(labels_path(profile_path), content)?;
+
+    Ok(Some(generation))
+}
+
+/// The newest generation of the profile at `profile_path` labelled `label`
+pub fn find_labelled_generation(
+    profile_path: &Path,
+    label: &str,
+) -> std::io::Result<Option<Generation>> {
+    Ok(list_generations(profile_path)?
+        .into_iter()
+        .rev()
+        .find(|g| g.label.as_deref() == Some(label)))
+}
+
+fn generation_number(file_name: &str, profile_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(profile_name)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+/// Checks that the profile at `profile_path` can be switched, i.e. that the directory it is in
+/// (or the closest existing parent, which Nix creates it in) is writable. Read-only images
+/// (e.g. ostree-like systems) otherwise only fail deep inside `nix-env`.
+pub fn check_profile_dir(profile_path: &Path) -> Result<(), (PathBuf, std::io::Error)> {
+    let mut dir = match profile_path.parent() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    while !dir.exists() {
+        dir = match dir.parent() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+    }
+
+    let probe = dir.join(format!(".deploy-rs-write-test-{}", std::process::id()));
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) => Err((dir.to_path_buf(), e)),
+    }
+}
+
+/// The engine matching the format of the profile at `profile_path`: new-style profiles (from
+/// `nix profile`) have a `manifest.json`, which `nix-env` refuses to work with
+pub fn detect_profile_engine(profile_path: &Path) -> crate::data::ProfileEngine {
+    match profile_path.join("manifest.json").exists() {
+        true => crate::data::ProfileEngine::NixProfile,
+        false => crate::data::ProfileEngine::NixEnv,
+    }
+}
+
+/// Points the profile at `profile_path` to generation `number` by replacing the symlink
+/// atomically, like `nix-env --switch-generation`
+pub fn switch_generation(profile_path: &Path, number: u64) -> std::io::Result<()> {
+    let profile_name = profile_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid profile path"))?;
+
+    let mut temp_link = profile_path.as_os_str().to_owned();
+    temp_link.push(format!(".tmp-{}", std::process::id()));
+    let temp_link = PathBuf::from(temp_link);
+
+    let _ = std::fs::remove_file(&temp_link);
+    std::os::unix::fs::symlink(format!("{}-{}-link", profile_name, number), &temp_link)?;
+    std::fs::rename(&temp_link, profile_path)
+}
+
+/// Makes `closure` the current generation of the profile at `profile_path` without evaluating
+/// anything, returning its number. Like `nix-env --set`, the newest generation is reused if it
+/// already is `closure`, and a new one is registered as a garbage collector root if `gc_root` is
+/// set (so that it isn't collected if the profile isn't in `/nix/var/nix/profiles`).
+pub fn set_generation(profile_path: &Path, closure: &str, gc_root: bool) -> std::io::Result<u64> {
+    if let Some(parent) = profile_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let generations = list_generations(profile_path)?;
+
+    let number = match generations.last() {
+        Some(newest) if newest.path == closure => newest.number,
+        newest => {
+            let number = newest.map_or(1, |g| g.number + 1);
+            let mut link = profile_path.as_os_str().to_owned();
+            link.push(format!("-{}-link", number));
+            match gc_root {
+                true => add_indirect_root(closure, Path::new(&link))?,
+                false => std::os::unix::fs::symlink(closure, PathBuf::from(link))?,
+            }
+            number
+        }
+    };
+
+    switch_generation(profile_path, number)?;
+    Ok(number)
+}
+
+/// Links `link` to `closure` and registers it as an indirect garbage collector root, with
+/// `nix-store --realise --add-root --indirect`
+fn add_indirect_root(closure: &str, link: &Path) -> std::io::Result<()> {
+    let mut command = std::process::Command::new("nix-store");
+    command
+        .arg("--realise")
+        .arg(closure)
+        .arg("--add-root")
+        .arg(link)
+        .arg("--indirect")
+        .stdout(std::process::Stdio::null());
+
+    let id = crate::trace::started(&command);
+    let status = command.status();
+    crate::trace::exited(id, &status);
+    match status?.code() {
+        Some(0) => Ok(()),
+        a => Err(std::io::Error::other(format!(
+            "nix-store --add-root exited with {:?}",
+            a
+        ))),
+    }
+}
+
+/// Switches the profile at `profile_path` back to the generation before the current one and
+/// deletes the current one, returning the number of the generation switched to
+pub fn drop_current_generation(profile_path: &Path) -> std::io::Result<u64> {
+    let generations = list_generations(profile_path)?;
+    let current = generations.iter().find(|g| g.current).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "the profile has no current generation")
+    })?;
+    let previous = generations
+        .iter()
+        .rev()
+        .find(|g| g.number < current.number)
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no generation to roll back to")
+        })?;
+
+    switch_generation(profile_path, previous.number)?;
+
+    let mut link = profile_path.as_os_str().to_owned();
+    link.push(format!("-{}-link", current.number));
+    std::fs::remove_file(PathBuf::from(link))?;
+
+    Ok(previous.number)
+}
+
+/// Lists the generations of the profile at `profile_path`, oldest first
+pub fn list_generations(profile_path: &Path) -> std::io::Result<Vec<Generation>> {
+    let profile_name = match profile_path.file_name().and_then(|n| n.to_str()) {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let profiles_dir = match profile_path.parent() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let current = std::fs::read_link(profile_path).ok();
+    let labels = read_labels(profile_path);
+
+    let mut generations = Vec::new();
+    for entry in std::fs::read_dir(profiles_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let number = match file_name.to_str().and_then(|n| generation_number(n, profile_name)) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        generations.push(Generation {
+            number,
+            path: std::fs::read_link(entry.path())?.display().to_string(),
+            current: current
+                .as_ref()
+                .is_some_and(|c| c.file_name() == Some(file_name.as_os_str())),
+            label: labels.get(&number).cloned(),
+            date: entry
+                .path()
+                .symlink_metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+        });
+    }
+
+    generations.sort_by_key(|g| g.number);
+    Ok(generations)
+}
+
+fn find_canaries(temp_path: &Path) -> Vec<String> {
+    let mut canaries = Vec::new();
+
+    let mut dirs = vec![temp_path.to_path_buf()];
+    if let Ok(entries) = std::fs::read_dir(temp_path) {
+        dirs.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_dir() && crate::is_run_temp_path(p)),
+        );
+    }
+
+    for dir in dirs {
+        if let Ok(entries) = std::fs::read_dir(&dir) {
+            canaries.extend(
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|e| e.file_name().to_string_lossy().starts_with("deploy-rs-canary-"))
+                    .map(|e| e.path().display().to_string()),
+            );
+        }
+    }
+
+    canaries.sort();
+    canaries
+}
+
+fn latest_activation_log(log_dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(log_dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.contains("_activate") && name.ends_with(".log")
+        })
+        .max_by_key(|e| e.metadata().and_then(|m| m.modified()).ok())
+        .map(|e| e.path())
+}
+
+fn tail(path: &Path, lines: usize) -> Vec<String> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    let all: Vec<&str> = content.lines().collect();
+    all[all.len().saturating_sub(lines)..]
+        .iter()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+/// Gathers the status of the profile at `profile_path`
+pub fn profile_status(
+    profile_path: &Path,
+    temp_path: Option<&Path>,
+    log_dir: Option<&Path>,
+    log_lines: usize,
+) -> std::io::Result<ProfileStatus> {
+    let generations = if profile_path.symlink_metadata().is_ok() {
+        list_generations(profile_path)?
+    } else {
+        Vec::new()
+    };
+    let current = generations.iter().find(|g| g.current);
+    let current_closure = current.map(|g| g.path.clone());
+
+    let pending_boot_closure = match (&current_closure, profile_path.ends_with("profiles/system")) {
+        (Some(closure), true) => match std::fs::read_link(CURRENT_SYSTEM_PATH) {
+            Ok(running) if running != Path::new(closure) => Some(closure.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    Ok(ProfileStatus {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        profile_path: profile_path.display().to_string(),
+        current_generation: current.map(|g| g.number),
+        current_closure,
+        current_date: current.and_then(|g| g.date),
+        pending_boot_closure,
+        canaries: temp_path.map(find_canaries).unwrap_or_default(),
+        log_tail: log_dir
+            .and_then(latest_activation_log)
+            .map(|l| tail(&l, log_lines))
+            .unwrap_or_default(),
+        read_only_profile_dir: check_profile_dir(profile_path)
+            .err()
+            .map(|(dir, _)| dir.display().to_string()),
+    })
+}
+
+#[test]
+fn test_profile_status() {
+    use std::os::unix::fs::symlink;
+
+    let dir = std::env::temp_dir().join(format!("deploy-rs-status-test-{}", std::process::id()));
+    let profiles = dir.join("profiles");
+    let temp = dir.join("tmp");
+    std::fs::create_dir_all(&profiles).unwrap();
+    std::fs::create_dir_all(temp.join("deploy-rs-run-1234")).unwrap();
+
+    symlink("/nix/store/aaaa-hello", profiles.join("hello-1-link")).unwrap();
+    symlink("/nix/store/bbbb-hello", profiles.join("hello-2-link")).unwrap();
+    symlink("/nix/store/cccc-other", profiles.join("other-1-link")).unwrap();
+    symlink("hello-2-link", profiles.join("hello")).unwrap();
+    assert!(check_profile_dir(&profiles.join("hello")).is_ok());
+    assert!(check_profile_dir(&dir.join("per-user/someone/hello")).is_ok());
+    assert!(!dir.join("per-user").exists());
+    assert_eq!(
+        detect_profile_engine(&profiles.join("hello")),
+        crate::data::ProfileEngine::NixEnv
+    );
+    std::fs::write(temp.join("deploy-rs-run-1234/deploy-rs-canary-bbbb"), "").unwrap();
+
+    let generations = list_generations(&profiles.join("hello")).unwrap();
+    assert!(generations.iter().all(|g| g.date.is_some()));
+    assert_eq!(
+        generations
+            .into_iter()
+            .map(|g| Generation { date: None, ..g })
+            .collect::<Vec<_>>(),
+        vec![
+            Generation {
+                number: 1,
+                path: "/nix/store/aaaa-hello".to_string(),
+                current: false,
+                label: None,
+                date: None,
+            },
+            Generation {
+                number: 2,
+                path: "/nix/store/bbbb-hello".to_string(),
+                current: true,
+                label: None,
+                date: None,
+            },
+        ]
+    );
+
+    assert_eq!(record_label(&profiles.join("hello"), "v1.2.0").unwrap(), Some(2));
+    assert_eq!(
+        find_labelled_generation(&profiles.join("hello"), "v1.2.0")
+            .unwrap()
+            .map(|g| g.number),
+        Some(2)
+    );
+    assert_eq!(
+        find_labelled_generation(&profiles.join("hello"), "v1.1.0").unwrap(),
+        None
+    );
+    // The sidecar isn't mistaken for a generation
+    assert_eq!(list_generations(&profiles.join("hello")).unwrap().len(), 2);
+
+    assert_eq!(set_generation(&profiles.join("hello"), "/nix/store/bbbb-hello", false).unwrap(), 2);
+    assert_eq!(set_generation(&profiles.join("hello"), "/nix/store/dddd-hello", false).unwrap(), 3);
+    assert_eq!(
+        std::fs::read_link(profiles.join("hello")).unwrap(),
+        PathBuf::from("hello-3-link")
+    );
+    assert_eq!(drop_current_generation(&profiles.join("hello")).unwrap(), 2);
+    assert!(!profiles.join("hello-3-link").exists());
+    assert_eq!(
+        list_generations(&profiles.join("hello"))
+            .unwrap()
+            .iter()
+            .map(|g| (g.number, g.current))
+            .collect::<Vec<_>>(),
+        vec![(1, false), (2, true)]
+    );
+
+    let status = profile_status(&profiles.join("hello"), Some(&temp), None, 20).unwrap();
+    assert_eq!(status.current_generation, Some(2));
+    assert_eq!(status.current_closure.as_deref(), Some("/nix/store/bbbb-hello"));
+    assert_eq!(status.pending_boot_closure, None);
+    assert_eq!(status.read_only_profile_dir, None);
+    assert_eq!(
+        status.canaries,
+        vec![temp
+            .join("deploy-rs-run-1234/deploy-rs-canary-bbbb")
+            .display()
+            .to_string()]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
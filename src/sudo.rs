@@ -0,0 +1,248 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Checking how sudo behaves on a node before deploying to it (`sudoPreflight`).
+//!
+//! Some sudo configurations break the commands deploy-rs runs through sudo halfway through a
+//! deployment: `requiretty` refuses to run without a terminal, the lecture shown on first use
+//! ends up in the output of the activation, a restrictive `umask` makes the files the activation
+//! writes unreadable and `env_reset` drops the environment. A harmless command is run through the
+//! profile's sudo first to find out. Commands on nodes requiring a terminal are run with one
+//! allocated by SSH; for everything else, the fix for the sudoers file is logged.
+
+use std::process::Stdio;
+
+use log::{debug, info, warn};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::deploy::{in_container, node_command};
+use crate::trace;
+use crate::{DeployData, DeployDefs};
+
+/// Printed by the probe before what it found out, after anything sudo printed itself
+const PROBE_MARKER: &str = "deploy-rs-sudo-probe";
+
+#[derive(Error, Debug)]
+pub enum SudoPreflightError {
+    #[error("Failed to run the sudo check over SSH: {0}")]
+    Ssh(std::io::Error),
+    #[error("sudo on node {0} requires a terminal (`requiretty`), which can't be allocated for commands on the deploying machine; add `Defaults:{1} !requiretty` to the sudoers file")]
+    RequiresTty(String, String),
+    #[error("sudo on node {0} asks for a password; set `interactiveSudo = true` or allow `{1}` to use sudo without one (`NOPASSWD:`)")]
+    PasswordRequired(String, String),
+    #[error("sudo on node {0} doesn't allow `{1}` to run commands as the profile user: {2}")]
+    NotAllowed(String, String, String),
+    #[error("sudo on node {0} failed with exit code {1:?}: {2}")]
+    Failed(String, Option<i32>, String),
+}
+
+/// What the sudo of a node does differently from the defaults
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SudoQuirks {
+    /// Commands run through sudo need a terminal (`requiretty`)
+    pub requires_tty: bool,
+    /// sudo printed its lecture
+    pub lectured: bool,
+    /// The umask of commands run through sudo
+    pub umask: Option<u32>,
+    /// sudo drops the environment of the commands it runs (`env_reset`)
+    pub resets_env: bool,
+}
+
+/// A command running a harmless probe through `sudo`, printing the umask and whether the
+/// environment was kept
+pub fn build_probe_command(sudo: &str) -> String {
+    format!(
+        "DEPLOY_RS_SUDO_PROBE=1 {} sh -c 'echo {}; umask; echo \"env=${{DEPLOY_RS_SUDO_PROBE:-}}\"'",
+        sudo, PROBE_MARKER
+    )
+}
+
+/// What the output of the probe tells about sudo on `node`, or why sudo refused to run it
+pub fn parse_probe(
+    node: &str,
+    ssh_user: &str,
+    code: Option<i32>,
+    stdout: &str,
+    stderr: &str,
+) -> Result<SudoQuirks, SudoPreflightError> {
+    // With a terminal allocated, sudo's own messages end up in stdout
+    let (before, after) = stdout.split_once(PROBE_MARKER).unwrap_or((stdout, ""));
+    let messages = format!("{}{}", stderr, before);
+    let mut quirks = SudoQuirks {
+        lectured: messages.contains("usual lecture"),
+        ..SudoQuirks::default()
+    };
+
+    if code != Some(0) || !stdout.contains(PROBE_MARKER) {
+        let message = messages.trim().to_string();
+        return Err(if message.contains("must have a tty") {
+            SudoPreflightError::RequiresTty(node.to_string(), ssh_user.to_string())
+        } else if message.contains("password is required")
+            || message.contains("terminal is required")
+            || message.contains("incorrect password")
+        {
+            SudoPreflightError::PasswordRequired(node.to_string(), ssh_user.to_string())
+        } else if message.contains("not in the sudoers file")
+            || message.contains("is not allowed to")
+        {
+            SudoPreflightError::NotAllowed(node.to_string(), ssh_user.to_string(), message)
+        } else {
+            SudoPreflightError::Failed(node.to_string(), code, message)
+        });
+    }
+
+    let mut lines = after.lines().map(str::trim).filter(|line| !line.is_empty());
+    quirks.umask = lines.next().and_then(|umask| u32::from_str_radix(umask, 8).ok());
+    quirks.resets_env = lines.next() != Some("env=1");
+
+    Ok(quirks)
+}
+
+impl SudoQuirks {
+    /// What to change in the sudoers file of the node for commands run by `ssh_user`
+    pub fn warnings(&self, ssh_user: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.lectured {
+            warnings.push(format!(
+                "sudo printed its lecture, which may end up in the output of the activation; add `Defaults:{} lecture=never` to the sudoers file",
+                ssh_user
+            ));
+        }
+        if let Some(umask) = self.umask.filter(|umask| umask & !0o022 != 0) {
+            warnings.push(format!(
+                "commands run through sudo get umask {:04o}, so the files the activation writes may not be readable by other users; add `Defaults:{} umask=0022` to the sudoers file",
+                umask, ssh_user
+            ));
+        }
+        warnings
+    }
+}
+
+/// Runs the probe through the sudo of the profile
+async fn probe(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    sudo: &str,
+) -> Result<SudoQuirks, SudoPreflightError> {
+    let probe_command = build_probe_command(sudo);
+    debug!("Constructed sudo probe command: {}", probe_command);
+
+    let mut child = trace::spawn(
+        node_command(deploy_data, deploy_defs)
+            .arg(in_container(deploy_defs, probe_command))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )
+    .map_err(SudoPreflightError::Ssh)?;
+
+    if let (Some(mut stdin), Some(password)) = (child.stdin.take(), &deploy_defs.sudo_password) {
+        let _ = stdin.write_all(format!("{}\n", password).as_bytes()).await;
+    }
+
+    let output = trace::wait_with_output(child)
+        .await
+        .map_err(SudoPreflightError::Ssh)?;
+
+    parse_probe(
+        deploy_data.node_name,
+        &deploy_defs.ssh_user,
+        output.status.code(),
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    )
+}
+
+/// Finds out how sudo behaves on the node, allocating a terminal for the commands run on it if
+/// sudo requires one and logging what else to change
+pub async fn preflight(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &mut DeployDefs,
+) -> Result<SudoQuirks, SudoPreflightError> {
+    let sudo = match deploy_defs.sudo.clone() {
+        Some(x) => x,
+        None => return Ok(SudoQuirks::default()),
+    };
+
+    let quirks = match probe(deploy_data, deploy_defs, &sudo).await {
+        Err(SudoPreflightError::RequiresTty(..)) if !deploy_defs.local => {
+            info!(
+                "sudo on node `{}` requires a terminal, allocating one for the commands run on it",
+                deploy_data.node_name
+            );
+            deploy_defs.sudo_tty = true;
+            SudoQuirks {
+                requires_tty: true,
+                ..probe(deploy_data, deploy_defs, &sudo).await?
+            }
+        }
+        result => result?,
+    };
+
+    for warning in quirks.warnings(&deploy_defs.ssh_user) {
+        warn!("On node `{}`, {}", deploy_data.node_name, warning);
+    }
+    if quirks.resets_env {
+        debug!(
+            "sudo on node `{}` resets the environment, the activation gets its environment on the command line",
+            deploy_data.node_name
+        );
+    }
+
+    Ok(quirks)
+}
+
+#[test]
+fn test_parse_probe() {
+    assert_eq!(
+        build_probe_command("sudo -u root"),
+        "DEPLOY_RS_SUDO_PROBE=1 sudo -u root sh -c 'echo deploy-rs-sudo-probe; umask; echo \"env=${DEPLOY_RS_SUDO_PROBE:-}\"'"
+    );
+
+    let parse = |code, stdout, stderr| parse_probe("web1", "deploy", Some(code), stdout, stderr);
+
+    let quirks = parse(0, "deploy-rs-sudo-probe\n0022\nenv=\n", "").unwrap();
+    assert_eq!(
+        quirks,
+        SudoQuirks {
+            umask: Some(0o022),
+            resets_env: true,
+            ..SudoQuirks::default()
+        }
+    );
+    assert!(quirks.warnings("deploy").is_empty());
+
+    // With a terminal, everything comes in on stdout with carriage returns
+    let quirks = parse(
+        0,
+        "\r\nWe trust you have received the usual lecture from the local System\r\nAdministrator.\r\ndeploy-rs-sudo-probe\r\n0077\r\nenv=1\r\n",
+        "",
+    )
+    .unwrap();
+    assert!(quirks.lectured && !quirks.resets_env);
+    assert_eq!(quirks.umask, Some(0o077));
+    let warnings = quirks.warnings("deploy");
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].contains("`Defaults:deploy lecture=never`"));
+    assert!(warnings[1].contains("umask 0077"));
+
+    assert!(matches!(
+        parse(1, "", "sudo: sorry, you must have a tty to run sudo\n"),
+        Err(SudoPreflightError::RequiresTty(..))
+    ));
+    assert!(matches!(
+        parse(1, "", "sudo: a terminal is required to read the password; either use the -S option to read from standard input or configure an askpass helper\n"),
+        Err(SudoPreflightError::PasswordRequired(..))
+    ));
+    assert!(matches!(
+        parse(1, "", "deploy is not in the sudoers file.  This incident will be reported.\n"),
+        Err(SudoPreflightError::NotAllowed(..))
+    ));
+    assert!(matches!(
+        parse(127, "", "sh: sudo: not found\n"),
+        Err(SudoPreflightError::Failed(_, Some(127), _))
+    ));
+}
@@ -0,0 +1,70 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+/// At most this many names are suggested for a typo
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Names in `candidates` similar enough to `name` to likely be what was meant, closest first
+pub fn did_you_mean<'a>(name: &str, candidates: &[&'a str]) -> Vec<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+
+    let mut close: Vec<(usize, &str)> = candidates
+        .iter()
+        .map(|candidate| {
+            let distance = match candidate.eq_ignore_ascii_case(name) {
+                true => 0,
+                false => strsim::levenshtein(name, candidate),
+            };
+            (distance, *candidate)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    close.sort();
+
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Explains a `name` that wasn't found among `candidates`, with suggestions and the valid names,
+/// meant to be appended to a "no {kind} named ... was found" message
+pub fn not_found_hint(name: &str, kind: &str, candidates: &[&str]) -> String {
+    let mut hint = String::new();
+
+    let suggestions = did_you_mean(name, candidates);
+    if !suggestions.is_empty() {
+        let suggestions: Vec<String> = suggestions.iter().map(|s| format!("`{}`", s)).collect();
+        hint.push_str(&format!(", did you mean {}?", suggestions.join(" or ")));
+    }
+
+    let mut valid = candidates.to_vec();
+    valid.sort_unstable();
+    match valid.is_empty() {
+        true => hint.push_str(&format!("\nThere are no {}s", kind)),
+        false => hint.push_str(&format!("\nAvailable {}s: {}", kind, valid.join(", "))),
+    }
+
+    hint
+}
+
+#[test]
+fn test_not_found_hint() {
+    let profiles = ["system", "home", "backup"];
+
+    assert_eq!(did_you_mean("sytsem", &profiles), vec!["system"]);
+    assert_eq!(did_you_mean("HOME", &profiles), vec!["home"]);
+    assert!(did_you_mean("database", &profiles).is_empty());
+
+    assert_eq!(
+        not_found_hint("sytem", "profile", &profiles),
+        ", did you mean `system`?\nAvailable profiles: backup, home, system"
+    );
+    assert_eq!(
+        not_found_hint("database", "profile", &profiles),
+        "\nAvailable profiles: backup, home, system"
+    );
+    assert_eq!(not_found_hint("web", "node", &[]), "\nThere are no nodes");
+}
@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The deployment didn't get to this profile
+    Pending,
+    Succeeded,
+    Failed,
+    /// The profile was activated, but revoked again because a later deployment failed
+    RolledBack,
+    /// The profile was already up to date (`--skip-if-unchanged`)
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Pending => "not deployed",
+            Outcome::Succeeded => "succeeded",
+            Outcome::Failed => "failed",
+            Outcome::RolledBack => "rolled back",
+            Outcome::Skipped => "skipped",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub node: String,
+    pub profile: String,
+    pub outcome: Outcome,
+    pub message: Option<String>,
+    /// The bytes copied to the node when pushing the profile, if any were
+    pub pushed: Option<u64>,
+}
+
+/// The final status of every profile selected for a deployment
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub entries: Vec<Entry>,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, node: &str, profile: &str) {
+        self.entries.push(Entry {
+            node: node.to_string(),
+            profile: profile.to_string(),
+            outcome: Outcome::Pending,
+            message: None,
+            pushed: None,
+        });
+    }
+
+    /// Records the bytes copied to the node when pushing the profile
+    pub fn set_pushed(&mut self, node: &str, profile: &str, bytes: u64) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.node == node && e.profile == profile)
+        {
+            entry.pushed = Some(bytes).filter(|&bytes| bytes > 0);
+        }
+    }
+
+    pub fn set(&mut self, node: &str, profile: &str, outcome: Outcome, message: Option<String>) {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .find(|e| e.node == node && e.profile == profile)
+        {
+            entry.outcome = outcome;
+            entry.message = message;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Renders the summary as an aligned plain text table
+    pub fn render_table(&self) -> String {
+        let header = ("NODE", "PROFILE", "STATUS");
+
+        let node_width = self
+            .entries
+            .iter()
+            .map(|e| e.node.len())
+            .chain(std::iter::once(header.0.len()))
+            .max()
+            .unwrap_or_default();
+        let profile_width = self
+            .entries
+            .iter()
+            .map(|e| e.profile.len())
+            .chain(std::iter::once(header.1.len()))
+            .max()
+            .unwrap_or_default();
+
+        let mut table = format!(
+            "{:node_width$}  {:profile_width$}  {}\n",
+            header.0,
+            header.1,
+            header.2,
+            node_width = node_width,
+            profile_width = profile_width
+        );
+
+        for entry in &self.entries {
+            let mut status = match &entry.message {
+                Some(message) => format!("{}: {}", entry.outcome, message),
+                None => entry.outcome.to_string(),
+            };
+            if let Some(bytes) = entry.pushed {
+                status.push_str(&format!(" (pushed {})", crate::resources::format_bytes(bytes)));
+            }
+            table.push_str(&format!(
+                "{:node_width$}  {:profile_width$}  {}\n",
+                entry.node,
+                entry.profile,
+                status,
+                node_width = node_width,
+                profile_width = profile_width
+            ));
+        }
+
+        table
+    }
+}
+
+#[test]
+fn test_render_table() {
+    let mut summary = Summary::new();
+    summary.add("web1", "system");
+    summary.add("database", "system");
+    summary.add("database", "backup");
+    summary.set("web1", "system", Outcome::Succeeded, None);
+    summary.set_pushed("web1", "system", 3 * 1024 * 1024);
+    summary.set_pushed("database", "backup", 0);
+    summary.set(
+        "database",
+        "system",
+        Outcome::Failed,
+        Some("activation timed out".to_string()),
+    );
+
+    assert_eq!(
+        summary.render_table(),
+        "NODE      PROFILE  STATUS\n\
+         web1      system   succeeded (pushed 3.0 MiB)\n\
+         database  system   failed: activation timed out\n\
+         database  backup   not deployed\n"
+    );
+}
@@ -0,0 +1,297 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The audit trail of the commands deploy-rs runs (`--trace-commands <file>`).
+//!
+//! Every command run locally, including the `ssh` running commands on the nodes, is written to
+//! the trace as a line of shell, preceded by a comment with the time it was started and followed
+//! by one with the time it exited and its exit code, so the trace reads (and runs) as a script.
+//! Command lines are redacted like the logs (see [`crate::redact`]). Commands are numbered, as
+//! those running at the same time interleave.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+use tokio::process::{Child, Command};
+
+struct Trace {
+    file: File,
+    next_id: u64,
+    /// The numbers of the spawned commands not waited for yet, by pid
+    running: HashMap<u32, u64>,
+}
+
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// Starts tracing all commands to `path`, replacing what it contained
+pub fn start(path: &Path) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(
+        file,
+        "#!/bin/sh\n# Commands run by deploy-rs (run {})",
+        crate::run_id()
+    )?;
+    *TRACE.lock().unwrap_or_else(|e| e.into_inner()) = Some(Trace {
+        file,
+        next_id: 1,
+        running: HashMap::new(),
+    });
+    Ok(())
+}
+
+fn timestamp() -> String {
+    chrono::Utc::now()
+        .format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        .to_string()
+}
+
+/// `word` quoted for the shell, unless it doesn't need to be
+fn quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    match !word.is_empty() && word.chars().all(plain) {
+        true => word.to_string(),
+        false => crate::deploy::shell_quote(word),
+    }
+}
+
+/// The string a Rust string literal in `chars` (after its opening quote) stands for
+fn unescape(chars: &mut std::str::Chars<'_>) -> String {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('0') => value.push('\0'),
+                Some('u') => {
+                    let code: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                    value.extend(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32));
+                }
+                Some(c) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+/// The command line of `command` (a std or tokio `Command`) for the shell, with the environment
+/// variables it sets in front
+pub fn command_line<C: std::fmt::Debug>(command: &C) -> String {
+    // The `Debug` of commands shows the command line with each word as a string literal, that of
+    // tokio's wraps the one of std's
+    let debug = format!("{:?}", command);
+    let debug = debug
+        .strip_prefix("Command { std: ")
+        .and_then(|c| c.rsplit_once(", kill_on_drop"))
+        .map_or(debug.as_str(), |(c, _)| c);
+
+    let mut words = Vec::new();
+    let mut chars = debug.chars();
+    let mut word = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            // Either a whole word or the value of an environment variable (`NAME="value"`)
+            '"' => word.push_str(&quote(&unescape(&mut chars))),
+            ' ' => words.push(std::mem::take(&mut word)),
+            c => word.push(c),
+        }
+    }
+    words.push(word);
+
+    words.retain(|word| !word.is_empty());
+    words.join(" ")
+}
+
+impl Trace {
+    fn write(&mut self, text: String) {
+        // A trace that can't be written mustn't stop the deployment
+        let _ = self.file.write_all(crate::redact::redact(&text).as_bytes());
+    }
+
+    fn started<C: std::fmt::Debug>(&mut self, command: &C) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.write(format!(
+            "\n# {} [{}] started\n{}\n",
+            timestamp(),
+            id,
+            command_line(command)
+        ));
+        id
+    }
+
+    fn exited(&mut self, id: u64, status: &std::io::Result<ExitStatus>) {
+        let outcome = match status {
+            Ok(status) => match status.code() {
+                Some(code) => format!("exited with {}", code),
+                None => "was killed by a signal".to_string(),
+            },
+            Err(e) => format!("failed to run: {}", e),
+        };
+        self.write(format!("# {} [{}] {}\n", timestamp(), id, outcome));
+    }
+}
+
+/// Records that `command` is about to run, returning its number if tracing
+pub fn started<C: std::fmt::Debug>(command: &C) -> Option<u64> {
+    TRACE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+        .map(|trace| trace.started(command))
+}
+
+/// Records how command `id` ended
+pub fn exited(id: Option<u64>, status: &std::io::Result<ExitStatus>) {
+    if let (Some(id), Some(trace)) = (id, TRACE.lock().unwrap_or_else(|e| e.into_inner()).as_mut())
+    {
+        trace.exited(id, status);
+    }
+}
+
+/// Like `Command::output`, traced
+pub async fn output(command: &mut Command) -> std::io::Result<Output> {
+    let id = started(command);
+    let output = command.output().await;
+    exited(id, &output.as_ref().map(|o| o.status).map_err(clone_error));
+    output
+}
+
+/// Like `Command::status`, traced
+pub async fn status(command: &mut Command) -> std::io::Result<ExitStatus> {
+    let id = started(command);
+    let status = command.status().await;
+    exited(id, &status);
+    status
+}
+
+/// Like `Command::spawn`, traced until the child is waited for with [`wait`] or
+/// [`wait_with_output`]
+pub fn spawn(command: &mut Command) -> std::io::Result<Child> {
+    let id = started(command);
+    let child = command.spawn();
+    match (&child, id) {
+        (Ok(child), Some(id)) => {
+            if let (Some(pid), Some(trace)) = (
+                child.id(),
+                TRACE.lock().unwrap_or_else(|e| e.into_inner()).as_mut(),
+            ) {
+                trace.running.insert(pid, id);
+            }
+        }
+        (Err(e), _) => exited(id, &Err(clone_error(e))),
+        _ => (),
+    }
+    child
+}
+
+/// The number of the spawned command `pid` is the process of, forgetting it
+fn take_running(pid: Option<u32>) -> Option<u64> {
+    let pid = pid?;
+    TRACE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()?
+        .running
+        .remove(&pid)
+}
+
+/// Records how the spawned command `pid` is the process of ended
+pub fn child_exited(pid: Option<u32>, status: &std::io::Result<ExitStatus>) {
+    exited(take_running(pid), status);
+}
+
+/// Like `Child::wait`, recording how a command started with [`spawn`] ended
+pub async fn wait(child: &mut Child) -> std::io::Result<ExitStatus> {
+    let pid = child.id();
+    let status = child.wait().await;
+    child_exited(pid, &status);
+    status
+}
+
+/// Like `Child::wait_with_output`, recording how a command started with [`spawn`] ended
+pub async fn wait_with_output(child: Child) -> std::io::Result<Output> {
+    let pid = child.id();
+    let output = child.wait_with_output().await;
+    child_exited(pid, &output.as_ref().map(|o| o.status).map_err(clone_error));
+    output
+}
+
+fn clone_error(e: &std::io::Error) -> std::io::Error {
+    std::io::Error::new(e.kind(), e.to_string())
+}
+
+#[test]
+fn test_trace_commands() {
+    let mut command = Command::new("ssh");
+    command
+        .env("NIX_SSHOPTS", "-p 2222")
+        .arg("deploy@web1")
+        .arg("echo 'hi'; exit 3");
+    assert_eq!(
+        command_line(&command),
+        "NIX_SSHOPTS='-p 2222' ssh deploy@web1 'echo '\\''hi'\\''; exit 3'"
+    );
+    let mut command = std::process::Command::new("nix");
+    command
+        .env_remove("NIX_PATH")
+        .arg("eval")
+        .arg("--expr")
+        .arg("\"a\\b\"\n\u{1b}");
+    assert_eq!(
+        command_line(&command),
+        "env -u NIX_PATH nix eval --expr '\"a\\b\"\n\u{1b}'"
+    );
+
+    let path = std::env::temp_dir().join(format!("deployrstrace{}.sh", std::process::id()));
+    let mut trace = Trace {
+        file: File::create(&path).unwrap(),
+        next_id: 1,
+        running: HashMap::new(),
+    };
+    crate::redact::register_secret("hunter22");
+
+    let mut command = std::process::Command::new("sh");
+    command.arg("-c").arg("echo hunter22; exit 3");
+    let id = trace.started(&command);
+    let status = command.output().map(|o| o.status);
+    trace.exited(id, &status);
+    let mut command = std::process::Command::new("/nonexistent/deploy-rs");
+    let id = trace.started(&command);
+    let status = command.status();
+    trace.exited(id, &status);
+    drop(trace);
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = trace
+        .lines()
+        .filter(|line| !line.is_empty())
+        // Without the timestamps
+        .map(|line| match line.strip_prefix("# ") {
+            Some(comment) => comment.split_once(' ').map_or(comment, |(_, rest)| rest),
+            None => line,
+        })
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "[1] started",
+            "sh -c 'echo ********; exit 3'",
+            "[1] exited with 3",
+            "[2] started",
+            "/nonexistent/deploy-rs",
+            "[2] failed to run: No such file or directory (os error 2)",
+        ]
+    );
+
+    let _ = std::fs::remove_file(path);
+}
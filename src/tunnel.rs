@@ -0,0 +1,130 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Reverse SSH tunnels, making a port of the deploying machine reachable from a node which can't
+//! connect to it directly (e.g. because the deployer is behind NAT).
+//!
+//! A [`Tunnel`] is an `ssh -N -R` running next to the other connections to the node, with the
+//! remote port allocated by `sshd`. It is closed when dropped, so it lives exactly as long as the
+//! part of the deployment holding it.
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use log::debug;
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Child;
+
+use crate::trace;
+use crate::{DeployData, DeployDefs};
+
+/// How long opening the tunnel may take
+const OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum TunnelError {
+    #[error("Failed to run ssh for the tunnel: {0}")]
+    Ssh(std::io::Error),
+    #[error("The ssh opening the tunnel exited before forwarding a port: {0:?}")]
+    Exit(Option<i32>),
+    #[error("Opening the tunnel took more than {}s", OPEN_TIMEOUT.as_secs())]
+    Timeout,
+}
+
+pub struct Tunnel {
+    child: Option<Child>,
+    /// The port on the node leading to the local port
+    pub remote_port: u16,
+}
+
+/// The port `ssh` printed it allocated for a remote forward to `-R 0:...`
+fn parse_allocated_port(line: &str) -> Option<u16> {
+    line.strip_prefix("Allocated port ")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Makes `local_port` on the loopback interface of the deploying machine reachable on the node
+pub async fn open(
+    deploy_data: &DeployData<'_>,
+    deploy_defs: &DeployDefs,
+    local_port: u16,
+) -> Result<Tunnel, TunnelError> {
+    if deploy_defs.local {
+        return Ok(Tunnel {
+            child: None,
+            remote_port: local_port,
+        });
+    }
+
+    let mut child = trace::spawn(
+        deploy_data
+            .ssh_command()
+            .arg("-N")
+            .arg("-oExitOnForwardFailure=yes")
+            .arg("-R")
+            .arg(format!("0:127.0.0.1:{}", local_port))
+            .args(deploy_data.ssh_opts())
+            .arg(format!("{}@{}", deploy_defs.ssh_user, deploy_data.hostname))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true),
+    )
+    .map_err(TunnelError::Ssh)?;
+
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    let remote_port = tokio::time::timeout(OPEN_TIMEOUT, async {
+        while let Some(line) = lines.next_line().await.map_err(TunnelError::Ssh)? {
+            match parse_allocated_port(&line) {
+                Some(port) => return Ok(port),
+                None => debug!("[tunnel] {}", line),
+            }
+        }
+        let status = trace::wait(&mut child).await.map_err(TunnelError::Ssh)?;
+        Err(TunnelError::Exit(status.code()))
+    })
+    .await
+    .map_err(|_| TunnelError::Timeout)??;
+
+    debug!(
+        "Tunnelling port {} of node `{}` to local port {}",
+        remote_port, deploy_data.node_name, local_port
+    );
+
+    // Keeps ssh from blocking on a full pipe
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("[tunnel] {}", line);
+        }
+    });
+
+    Ok(Tunnel {
+        child: Some(child),
+        remote_port,
+    })
+}
+
+impl Tunnel {
+    pub async fn close(mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill().await;
+        }
+    }
+}
+
+#[test]
+fn test_parse_allocated_port() {
+    assert_eq!(
+        parse_allocated_port("Allocated port 40123 for remote forward to 127.0.0.1:8080"),
+        Some(40123)
+    );
+    assert_eq!(
+        parse_allocated_port("Warning: Permanently added 'web1' to the list of known hosts."),
+        None
+    );
+}
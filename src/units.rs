@@ -0,0 +1,168 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The systemd units an activation on NixOS stopped, restarted, reloaded or started, as reported
+//! by `switch-to-configuration` in the output of the activation.
+//!
+//! Units stopped and started again are counted as restarted, as that is how
+//! `switch-to-configuration` restarts units which can't be restarted in place. With
+//! `--dry-activate`, its "would restart ..." lines are reported the same way.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UnitChanges {
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    pub reloaded: Vec<String>,
+    pub started: Vec<String>,
+}
+
+fn parse_units(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.split(',')
+        .map(str::trim)
+        .filter(|unit| !unit.is_empty())
+        .map(str::to_string)
+}
+
+/// The unit changes reported in `output`, `None` if it has no report of `switch-to-configuration`
+/// (e.g. because the profile isn't a NixOS system)
+pub fn parse_switch_output(output: &str) -> Option<UnitChanges> {
+    let mut changes = UnitChanges::default();
+    let mut reported = false;
+
+    for line in output.lines() {
+        let line = line.trim();
+        let line = line.strip_prefix("would ").unwrap_or(line);
+
+        if line.starts_with("activating the configuration...") {
+            reported = true;
+            continue;
+        }
+
+        let (units, list) = if let Some(list) = line
+            .strip_prefix("stopping the following units:")
+            .or_else(|| line.strip_prefix("stop the following units:"))
+        {
+            (&mut changes.stopped, list)
+        } else if let Some(list) = line
+            .strip_prefix("restarting the following units:")
+            .or_else(|| line.strip_prefix("restart the following units:"))
+        {
+            (&mut changes.restarted, list)
+        } else if let Some(list) = line
+            .strip_prefix("reloading the following units:")
+            .or_else(|| line.strip_prefix("reload the following units:"))
+        {
+            (&mut changes.reloaded, list)
+        } else if let Some(list) = line
+            .strip_prefix("starting the following units:")
+            .or_else(|| line.strip_prefix("start the following units:"))
+            .or_else(|| line.strip_prefix("the following new units were started:"))
+        {
+            (&mut changes.started, list)
+        } else {
+            continue;
+        };
+
+        reported = true;
+        for unit in parse_units(list) {
+            if !units.contains(&unit) {
+                units.push(unit);
+            }
+        }
+    }
+
+    if !reported {
+        return None;
+    }
+
+    let bounced: Vec<String> = changes
+        .stopped
+        .iter()
+        .filter(|unit| changes.started.contains(unit))
+        .cloned()
+        .collect();
+    changes.stopped.retain(|unit| !bounced.contains(unit));
+    changes.started.retain(|unit| !bounced.contains(unit));
+    for unit in bounced {
+        if !changes.restarted.contains(&unit) {
+            changes.restarted.push(unit);
+        }
+    }
+
+    Some(changes)
+}
+
+impl UnitChanges {
+    pub fn is_empty(&self) -> bool {
+        self.stopped.is_empty()
+            && self.restarted.is_empty()
+            && self.reloaded.is_empty()
+            && self.started.is_empty()
+    }
+}
+
+impl fmt::Display for UnitChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no units restarted or reloaded");
+        }
+
+        let parts: Vec<String> = [
+            ("restarted", &self.restarted),
+            ("reloaded", &self.reloaded),
+            ("stopped", &self.stopped),
+            ("started", &self.started),
+        ]
+        .iter()
+        .filter(|(_, units)| !units.is_empty())
+        .map(|(verb, units)| format!("{} {}", verb, units.join(", ")))
+        .collect();
+
+        f.write_str(&parts.join("; "))
+    }
+}
+
+#[test]
+fn test_parse_switch_output() {
+    let output = "\
+updating GRUB 2 menu...
+stopping the following units: nginx.service, old-timer.timer
+NOT restarting the following changed units: systemd-logind.service
+activating the configuration...
+setting up /etc...
+reloading user units for alice...
+restarting the following units: sshd.service
+starting the following units: nginx.service
+the following new units were started: backup.timer
+reloading the following units: dbus.service
+";
+
+    let changes = parse_switch_output(output).unwrap();
+    assert_eq!(
+        changes,
+        UnitChanges {
+            stopped: vec!["old-timer.timer".to_string()],
+            restarted: vec!["sshd.service".to_string(), "nginx.service".to_string()],
+            reloaded: vec!["dbus.service".to_string()],
+            started: vec!["backup.timer".to_string()],
+        }
+    );
+    assert_eq!(
+        changes.to_string(),
+        "restarted sshd.service, nginx.service; reloaded dbus.service; stopped old-timer.timer; started backup.timer"
+    );
+
+    let dry = parse_switch_output("would restart the following units: postgresql.service\n");
+    assert_eq!(dry.unwrap().restarted, vec!["postgresql.service"]);
+
+    let config_only = parse_switch_output("activating the configuration...\nsetting up /etc...\n");
+    assert_eq!(
+        config_only.unwrap().to_string(),
+        "no units restarted or reloaded"
+    );
+
+    assert_eq!(parse_switch_output("Starting home manager activation\n"), None);
+}
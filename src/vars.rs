@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Deploy-time variables passed with `--vars <file>`.
+//!
+//! The JSON object in the file is written into a synthetic flake, whose `vars` output holds it,
+//! which replaces the `deploy-vars` input of the deployed flake (`--override-input`). Without
+//! flakes, it is passed as the `deployVars` argument of the top-level function instead, if the
+//! function takes one.
+
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// The flake input replaced by the synthetic vars flake
+pub const VARS_INPUT: &str = "deploy-vars";
+
+const VARS_FLAKE: &str = r#"{
+  description = "Variables passed to deploy-rs with --vars";
+  outputs = _: { vars = builtins.fromJSON (builtins.readFile ./vars.json); };
+}
+"#;
+
+#[derive(Error, Debug)]
+pub enum VarsError {
+    #[error("Failed to read vars file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Failed to parse vars file {0}: {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("Vars file {0} must contain a JSON object")]
+    NotAnObject(PathBuf),
+    #[error("Failed to write the vars flake to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+}
+
+/// The synthetic flake holding the vars, removed again when dropped
+#[derive(Debug)]
+pub struct Vars {
+    dir: PathBuf,
+}
+
+impl Vars {
+    /// Validates the vars in `file` and writes them into a synthetic flake
+    pub fn prepare(file: &Path) -> Result<Self, VarsError> {
+        let content =
+            std::fs::read_to_string(file).map_err(|e| VarsError::Read(file.to_path_buf(), e))?;
+        let vars: serde_json::Value =
+            serde_json::from_str(&content).map_err(|e| VarsError::Parse(file.to_path_buf(), e))?;
+        if !vars.is_object() {
+            return Err(VarsError::NotAnObject(file.to_path_buf()));
+        }
+
+        // Removed again if writing the flake fails
+        let prepared = Vars {
+            dir: crate::create_private_temp_dir("deploy-rs-vars-")
+                .map_err(|e| VarsError::Write(std::env::temp_dir(), e))?,
+        };
+        let write = |name: &str, content: &str| {
+            std::fs::write(prepared.dir.join(name), content)
+                .map_err(|e| VarsError::Write(prepared.dir.clone(), e))
+        };
+        write("flake.nix", VARS_FLAKE)?;
+        write("vars.json", &vars.to_string())?;
+
+        Ok(prepared)
+    }
+
+    /// Arguments for flake evaluations replacing the vars input
+    pub fn flake_args(&self) -> Vec<String> {
+        vec![
+            "--override-input".to_string(),
+            VARS_INPUT.to_string(),
+            format!("path:{}", self.dir.display()),
+        ]
+    }
+
+    /// A Nix expression evaluating to the vars, for evaluations without flakes
+    pub fn nix_expr(&self) -> String {
+        format!(
+            "(builtins.fromJSON (builtins.readFile {}/vars.json))",
+            self.dir.display()
+        )
+    }
+}
+
+impl Drop for Vars {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The arguments to call the top-level function of a repository without flakes with
+pub fn call_args_expr(vars: Option<&Vars>) -> String {
+    match vars {
+        Some(vars) => format!(
+            "(builtins.intersectAttrs (builtins.functionArgs r) {{ deployVars = {}; }})",
+            vars.nix_expr()
+        ),
+        None => "{}".to_string(),
+    }
+}
+
+#[test]
+fn test_prepare_vars() {
+    let file = std::env::temp_dir().join(format!("deploy-rs-vars-test-{}.json", std::process::id()));
+
+    std::fs::write(&file, r#"["not", "an", "object"]"#).unwrap();
+    assert!(matches!(Vars::prepare(&file), Err(VarsError::NotAnObject(_))));
+
+    std::fs::write(&file, r#"{ "imageTag": "v1.2.3", "replicas": 3 }"#).unwrap();
+    let vars = Vars::prepare(&file).unwrap();
+    let dir = vars.dir.clone();
+    let other = Vars::prepare(&file).unwrap();
+    assert_ne!(other.dir, dir);
+
+    assert_eq!(
+        vars.flake_args(),
+        vec![
+            "--override-input".to_string(),
+            "deploy-vars".to_string(),
+            format!("path:{}", dir.display())
+        ]
+    );
+    assert!(dir.join("flake.nix").is_file());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("vars.json")).unwrap(),
+        r#"{"imageTag":"v1.2.3","replicas":3}"#
+    );
+    assert_eq!(call_args_expr(None), "{}");
+
+    drop(vars);
+    assert!(!dir.exists());
+    std::fs::remove_file(&file).unwrap();
+}
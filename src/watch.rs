@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! Watching the directory of a flake for changes, to redeploy on every change (`deploy watch`).
+//!
+//! Changes are collected until none came for the debounce time, so saving a few files at once (or
+//! a formatter rewriting them) deploys once. Files that aren't part of the flake's sources, like
+//! `.git`, `result` links or editor swap files, are ignored. Changes made during a deployment are
+//! queued and deployed once it is done.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::debug;
+use notify::{recommended_watcher, RecommendedWatcher, RecursiveMode, Watcher};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("Failed to watch {0} for changes: {1}")]
+    Watch(PathBuf, notify::Error),
+    #[error("Stopped receiving changes to {0}")]
+    Closed(PathBuf),
+}
+
+/// Directories in a flake whose changes don't change what is deployed
+const IGNORED_DIRS: &[&str] = &[".git", ".direnv", ".deploy-gc"];
+
+/// Whether a change to `path` (in the flake in `dir`) may change what is deployed
+pub fn is_relevant(dir: &Path, path: &Path) -> bool {
+    let relative = path.strip_prefix(dir).unwrap_or(path);
+
+    if relative.components().any(|c| {
+        IGNORED_DIRS
+            .iter()
+            .any(|ignored| c.as_os_str() == *ignored)
+    }) {
+        return false;
+    }
+
+    let name = match relative.file_name().and_then(|n| n.to_str()) {
+        Some(x) => x,
+        None => return true,
+    };
+    // `nix build` links and editor backup, swap and lock files
+    let top_level = relative.components().count() == 1;
+    !((top_level && (name == "result" || name.starts_with("result-")))
+        || name.ends_with('~')
+        || name.ends_with(".swp")
+        || name.ends_with(".swx")
+        || name.starts_with(".#"))
+}
+
+pub struct FlakeWatcher {
+    dir: PathBuf,
+    changes: mpsc::UnboundedReceiver<Result<Vec<PathBuf>, notify::Error>>,
+    _watcher: RecommendedWatcher,
+}
+
+impl FlakeWatcher {
+    /// Starts watching the flake in `dir`, including its subdirectories
+    pub fn new(dir: &Path) -> Result<Self, WatchError> {
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+
+        let (sender, changes) = mpsc::unbounded_channel();
+        let mut watcher: RecommendedWatcher =
+            recommended_watcher(move |res: Result<notify::event::Event, notify::Error>| {
+                let paths = match res {
+                    // Evaluating the flake reads it, which mustn't count as a change
+                    Ok(e) if e.kind.is_create() || e.kind.is_modify() || e.kind.is_remove() => {
+                        Ok(e.paths)
+                    }
+                    Ok(_) => return,
+                    Err(e) => Err(e),
+                };
+                // The receiver is only dropped along with the watcher
+                let _ = sender.send(paths);
+            })
+            .map_err(|e| WatchError::Watch(dir.clone(), e))?;
+        watcher
+            .watch(&dir, RecursiveMode::Recursive)
+            .map_err(|e| WatchError::Watch(dir.clone(), e))?;
+
+        Ok(FlakeWatcher {
+            dir,
+            changes,
+            _watcher: watcher,
+        })
+    }
+
+    /// The changed files of the next event, if any of them are relevant
+    async fn next(&mut self) -> Result<Vec<PathBuf>, WatchError> {
+        match self.changes.recv().await {
+            Some(Ok(paths)) => Ok(paths
+                .into_iter()
+                .filter(|path| is_relevant(&self.dir, path))
+                .collect()),
+            Some(Err(e)) => Err(WatchError::Watch(self.dir.clone(), e)),
+            None => Err(WatchError::Closed(self.dir.clone())),
+        }
+    }
+
+    /// Waits for the flake to change and then to stay unchanged for `debounce`, returning the
+    /// changed files
+    pub async fn changed(&mut self, debounce: Duration) -> Result<Vec<PathBuf>, WatchError> {
+        let mut changed = Vec::new();
+        while changed.is_empty() {
+            changed = self.next().await?;
+        }
+
+        while let Ok(paths) = tokio::time::timeout(debounce, self.next()).await {
+            for path in paths? {
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+        }
+        debug!("Changed in {}: {:?}", self.dir.display(), changed);
+
+        Ok(changed)
+    }
+}
+
+#[tokio::test]
+async fn test_flake_watcher() {
+    let dir = std::env::temp_dir().join(format!("deployrswatch{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join(".git")).unwrap();
+    std::fs::create_dir_all(dir.join("hosts")).unwrap();
+    let dir = dir.canonicalize().unwrap();
+
+    assert!(is_relevant(&dir, &dir.join("flake.nix")));
+    assert!(is_relevant(&dir, &dir.join("hosts/result.nix")));
+    assert!(!is_relevant(&dir, &dir.join(".git/index")));
+    assert!(!is_relevant(&dir, &dir.join("result")));
+    assert!(!is_relevant(&dir, &dir.join("result-web1")));
+    assert!(!is_relevant(&dir, &dir.join("hosts/.web1.nix.swp")));
+    assert!(!is_relevant(&dir, &dir.join("hosts/web1.nix~")));
+
+    let mut watcher = FlakeWatcher::new(&dir).unwrap();
+    std::fs::write(dir.join(".git/index"), "index").unwrap();
+    std::fs::write(dir.join("flake.nix"), "{ }").unwrap();
+    std::fs::write(dir.join("hosts/web1.nix"), "{ }").unwrap();
+    std::fs::write(dir.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+
+    let changed = tokio::time::timeout(
+        Duration::from_secs(10),
+        watcher.changed(Duration::from_millis(200)),
+    )
+    .await
+    .expect("the changes are reported")
+    .unwrap();
+    let mut names: Vec<_> = changed
+        .iter()
+        .map(|path| path.strip_prefix(&dir).unwrap().to_path_buf())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![PathBuf::from("flake.nix"), PathBuf::from("hosts/web1.nix")]
+    );
+
+    // Reading the flake isn't a change
+    std::fs::read_to_string(dir.join("flake.nix")).unwrap();
+    assert!(tokio::time::timeout(
+        Duration::from_millis(300),
+        watcher.changed(Duration::from_millis(50))
+    )
+    .await
+    .is_err());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2020 Serokell <https://serokell.io/>
+//
+// SPDX-License-Identifier: MPL-2.0
+
+//! The magic rollback protocol (activate, wait, confirm or roll back) run against the local
+//! machine, with `activate-rs` as built and profiles of fake closures switched with the `lite`
+//! profile engine, so neither SSH nor Nix are needed.
+
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+use deploy::CanaryState;
+
+const ACTIVATE: &str = env!("CARGO_BIN_EXE_activate");
+
+/// A directory standing in for a node: its store, profiles and temporary files
+struct Node {
+    root: PathBuf,
+}
+
+impl Node {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!(
+            "deployrsloopback{}{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("store")).unwrap();
+        // Compared with the resolved profile later on
+        let root = std::fs::canonicalize(root).unwrap();
+        Node { root }
+    }
+
+    fn profile_path(&self) -> PathBuf {
+        self.root.join("profiles").join("test")
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.root.join("deploy-rs-run-test")
+    }
+
+    fn log_dir(&self) -> PathBuf {
+        self.root.join("logs")
+    }
+
+    /// The log files written by `activate-rs` of `kind` (e.g. `activate`)
+    fn logs(&self, kind: &str) -> Vec<PathBuf> {
+        std::fs::read_dir(self.log_dir())
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|e| e.path())
+                    .filter(|p| p.to_string_lossy().contains(&format!("_{}_", kind)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// A closure whose activation script records the closure it activates (and the
+    /// specialisation, if any)
+    fn closure(&self, hash: &str) -> String {
+        let closure = self.root.join("store").join(format!("{}-test-profile", hash));
+        std::fs::create_dir_all(&closure).unwrap();
+        let script = closure.join("deploy-rs-activate");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$(basename \"$(readlink -f \"$PROFILE\")\")${{SPECIALISATION:+ ($SPECIALISATION)}}\" >> {}\n",
+                self.root.join("activations").display()
+            ),
+        )
+        .unwrap();
+        let mut permissions = std::fs::metadata(&script).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, 0o755);
+        std::fs::set_permissions(&script, permissions).unwrap();
+        closure.to_string_lossy().into_owned()
+    }
+
+    fn activate(&self, closure: &str, magic_rollback: Option<u16>) -> Child {
+        self.activate_with(closure, magic_rollback, &[])
+    }
+
+    fn activate_with(&self, closure: &str, magic_rollback: Option<u16>, args: &[&str]) -> Child {
+        let mut command = Command::new(ACTIVATE);
+        command
+            .arg("--log-dir")
+            .arg(self.log_dir())
+            .arg("activate")
+            .arg(closure)
+            .arg("--profile-path")
+            .arg(self.profile_path())
+            .arg("--temp-path")
+            .arg(self.temp_path())
+            .arg("--profile-engine")
+            .arg("lite")
+            .arg("--auto-rollback")
+            .arg("--confirm-timeout")
+            .arg(magic_rollback.unwrap_or(30).to_string());
+        if magic_rollback.is_some() {
+            command.arg("--magic-rollback");
+        }
+        command.args(args);
+        command.stderr(Stdio::null()).spawn().unwrap()
+    }
+
+    fn wait(&self, closure: &str) -> Output {
+        Command::new(ACTIVATE)
+            .arg("--log-dir")
+            .arg(self.log_dir())
+            .arg("wait")
+            .arg(closure)
+            .arg("--temp-path")
+            .arg(self.temp_path())
+            .arg("--activation-timeout")
+            .arg("30")
+            .stderr(Stdio::null())
+            .output()
+            .unwrap()
+    }
+
+    fn confirm(&self, closure: &str) {
+        std::fs::remove_file(deploy::make_lock_path(&self.temp_path(), closure)).unwrap();
+    }
+
+    /// What the deployer does while it is alive, with `heartbeatTimeout`
+    fn heartbeat(&self, closure: &str) {
+        std::fs::write(deploy::make_heartbeat_path(&self.temp_path(), closure), "").unwrap();
+    }
+
+    fn canary_state(&self, closure: &str) -> Option<CanaryState> {
+        deploy::read_canary_state(&deploy::make_canary_state_path(&self.temp_path(), closure))
+    }
+
+    /// The closures activated so far, in order
+    fn activations(&self) -> Vec<String> {
+        std::fs::read_to_string(self.root.join("activations"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn current(&self) -> PathBuf {
+        std::fs::canonicalize(self.profile_path()).unwrap()
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> std::process::ExitStatus {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            panic!("activate-rs didn't exit within {:?}", timeout);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Activates `first` without magic rollback, so there is a generation to roll back to
+fn with_first_generation(node: &Node, first: &str) {
+    let status = wait_for_exit(&mut node.activate(first, None), Duration::from_secs(30));
+    assert!(status.success());
+    assert_eq!(node.current(), Path::new(first));
+}
+
+#[test]
+fn test_confirmed_activation() {
+    let node = Node::new("confirmed");
+    let (first, second) = (node.closure("1111"), node.closure("2222"));
+    with_first_generation(&node, &first);
+
+    let mut activate = node.activate(&second, Some(20));
+    let wait = node.wait(&second);
+    assert!(wait.status.success());
+
+    // The deployer learns how long it has left to confirm, counted from the canary
+    let remaining = deploy::parse_confirm_remaining(&String::from_utf8_lossy(&wait.stdout))
+        .expect("wait reports the time left to confirm");
+    assert!((15..=20).contains(&remaining), "{}s left", remaining);
+    assert_eq!(node.canary_state(&second), Some(CanaryState::Created));
+
+    node.confirm(&second);
+    assert!(wait_for_exit(&mut activate, Duration::from_secs(10)).success());
+
+    assert_eq!(node.canary_state(&second), Some(CanaryState::Confirmed));
+    assert_eq!(node.current(), Path::new(&second));
+    assert_eq!(node.activations(), ["1111-test-profile", "2222-test-profile"]);
+
+    // The activations leave their logs on the node
+    assert!(!node.logs("activate").is_empty());
+    assert_eq!(node.logs("wait").len(), 1);
+}
+
+#[test]
+fn test_unconfirmed_activation_rolls_back() {
+    let node = Node::new("unconfirmed");
+    let (first, second) = (node.closure("3333"), node.closure("4444"));
+    with_first_generation(&node, &first);
+
+    let started = Instant::now();
+    let mut activate = node.activate(&second, Some(2));
+    assert!(node.wait(&second).status.success());
+
+    let status = wait_for_exit(&mut activate, Duration::from_secs(30));
+    assert!(!status.success());
+    assert!(
+        started.elapsed() >= Duration::from_secs(2),
+        "rolled back after {:?}, before the confirmation timeout",
+        started.elapsed()
+    );
+
+    // The new generation is dropped, and the previous one activated again after it
+    assert_eq!(node.canary_state(&second), Some(CanaryState::RolledBack));
+    assert_eq!(node.current(), Path::new(&first));
+    assert_eq!(
+        node.activations(),
+        ["3333-test-profile", "4444-test-profile", "3333-test-profile"]
+    );
+
+    // A deployer only attaching now is told about the rollback instead of waiting
+    let late_wait = Instant::now();
+    assert!(!node.wait(&second).status.success());
+    assert!(late_wait.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_heartbeats_delay_rollback() {
+    let node = Node::new("heartbeat");
+    let (first, second) = (node.closure("5555"), node.closure("6666"));
+    with_first_generation(&node, &first);
+
+    let mut activate = node.activate_with(&second, Some(1), &["--heartbeat-timeout", "2"]);
+    assert!(node.wait(&second).status.success());
+
+    // While the deployer is alive, the activation outlives the confirmation timeout
+    let alive = Instant::now();
+    while alive.elapsed() < Duration::from_secs(4) {
+        node.heartbeat(&second);
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(activate.try_wait().unwrap().is_none(), "rolled back despite heartbeats");
+    }
+
+    // Once the heartbeats stop, it is rolled back after the heartbeat timeout
+    let stopped = Instant::now();
+    let status = wait_for_exit(&mut activate, Duration::from_secs(30));
+    assert!(!status.success());
+    assert!(
+        stopped.elapsed() >= Duration::from_secs(1),
+        "rolled back {:?} after the last heartbeat",
+        stopped.elapsed()
+    );
+    assert_eq!(node.canary_state(&second), Some(CanaryState::RolledBack));
+    assert_eq!(node.current(), Path::new(&first));
+}
+
+#[test]
+fn test_specialisation() {
+    let node = Node::new("specialisation");
+    let (first, second) = (node.closure("7777"), node.closure("8888"));
+    with_first_generation(&node, &first);
+
+    // A missing specialisation fails the activation before the profile is touched
+    let mut activate = node.activate_with(&second, None, &["--specialisation", "gpu"]);
+    assert!(!wait_for_exit(&mut activate, Duration::from_secs(30)).success());
+    assert_eq!(node.current(), Path::new(&first));
+    assert_eq!(node.activations(), ["7777-test-profile"]);
+
+    let switch = Path::new(&second).join("specialisation/gpu/bin");
+    std::fs::create_dir_all(&switch).unwrap();
+    std::fs::write(switch.join("switch-to-configuration"), "").unwrap();
+
+    let mut activate = node.activate_with(&second, None, &["--specialisation", "gpu"]);
+    assert!(wait_for_exit(&mut activate, Duration::from_secs(30)).success());
+    assert_eq!(node.current(), Path::new(&second));
+    assert_eq!(
+        node.activations(),
+        ["7777-test-profile", "8888-test-profile (gpu)"]
+    );
+}